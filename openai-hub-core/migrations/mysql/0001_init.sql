@@ -0,0 +1,25 @@
+CREATE TABLE IF NOT EXISTS audit_log (
+    id INTEGER PRIMARY KEY AUTO_INCREMENT,
+    timestamp TIMESTAMP NOT NULL,
+    ray_id VARCHAR(16) NOT NULL,
+    user VARCHAR(255),
+    method VARCHAR(10),
+    uri VARCHAR(255),
+    headers TEXT,
+    body TEXT,
+    response_status SMALLINT UNSIGNED,
+    response_headers TEXT,
+    response_body TEXT
+);
+
+CREATE TABLE IF NOT EXISTS tokens_log (
+    id INTEGER PRIMARY KEY AUTO_INCREMENT,
+    timestamp TIMESTAMP NOT NULL,
+    ray_id VARCHAR(16) NOT NULL,
+    user VARCHAR(255),
+    model VARCHAR(255) NOT NULL,
+    is_estimated BOOLEAN NOT NULL,
+    prompt_tokens BIGINT UNSIGNED NOT NULL,
+    completion_tokens BIGINT UNSIGNED NOT NULL,
+    total_tokens BIGINT UNSIGNED NOT NULL
+);
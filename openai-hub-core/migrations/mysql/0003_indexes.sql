@@ -0,0 +1,6 @@
+CREATE INDEX audit_log_timestamp ON audit_log (timestamp);
+CREATE INDEX audit_log_user ON audit_log (user);
+CREATE INDEX audit_log_ray_id ON audit_log (ray_id);
+CREATE INDEX tokens_log_timestamp ON tokens_log (timestamp);
+CREATE INDEX tokens_log_user ON tokens_log (user);
+CREATE INDEX tokens_log_ray_id ON tokens_log (ray_id);
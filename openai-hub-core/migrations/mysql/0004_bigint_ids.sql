@@ -0,0 +1,2 @@
+ALTER TABLE audit_log MODIFY id BIGINT NOT NULL AUTO_INCREMENT;
+ALTER TABLE tokens_log MODIFY id BIGINT NOT NULL AUTO_INCREMENT;
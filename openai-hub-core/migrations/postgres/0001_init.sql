@@ -0,0 +1,25 @@
+CREATE TABLE IF NOT EXISTS audit_log (
+    id SERIAL PRIMARY KEY,
+    timestamp TIMESTAMPTZ NOT NULL,
+    ray_id VARCHAR(16) NOT NULL,
+    "user" VARCHAR(255),
+    method VARCHAR(10),
+    uri VARCHAR(255),
+    headers TEXT,
+    body TEXT,
+    response_status SMALLINT,
+    response_headers TEXT,
+    response_body TEXT
+);
+
+CREATE TABLE IF NOT EXISTS tokens_log (
+    id SERIAL PRIMARY KEY,
+    timestamp TIMESTAMPTZ NOT NULL,
+    ray_id VARCHAR(16) NOT NULL,
+    "user" VARCHAR(255),
+    model VARCHAR(255) NOT NULL,
+    is_estimated BOOL NOT NULL,
+    prompt_tokens BIGINT NOT NULL,
+    completion_tokens BIGINT NOT NULL,
+    total_tokens BIGINT NOT NULL
+);
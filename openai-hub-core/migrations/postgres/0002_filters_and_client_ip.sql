@@ -0,0 +1,4 @@
+ALTER TABLE audit_log
+    ADD COLUMN content_filter TEXT,
+    ADD COLUMN moderation TEXT,
+    ADD COLUMN client_ip VARCHAR(45);
@@ -0,0 +1,4 @@
+ALTER TABLE audit_log ALTER COLUMN id TYPE BIGINT;
+ALTER SEQUENCE audit_log_id_seq AS BIGINT;
+ALTER TABLE tokens_log ALTER COLUMN id TYPE BIGINT;
+ALTER SEQUENCE tokens_log_id_seq AS BIGINT;
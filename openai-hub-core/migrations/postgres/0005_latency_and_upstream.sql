@@ -0,0 +1,7 @@
+ALTER TABLE audit_log
+    ADD COLUMN latency_ms BIGINT,
+    ADD COLUMN ttfb_ms BIGINT,
+    ADD COLUMN upstream_status SMALLINT,
+    ADD COLUMN upstream_request_id VARCHAR(255),
+    ADD COLUMN key_fingerprint VARCHAR(64);
+CREATE INDEX audit_log_key_fingerprint ON audit_log (key_fingerprint);
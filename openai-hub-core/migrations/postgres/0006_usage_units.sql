@@ -0,0 +1,5 @@
+ALTER TABLE tokens_log
+    ADD COLUMN unit VARCHAR(16) NOT NULL DEFAULT 'tokens',
+    ADD COLUMN quantity DOUBLE PRECISION NOT NULL DEFAULT 0,
+    ADD COLUMN details VARCHAR(255);
+UPDATE tokens_log SET quantity = total_tokens;
@@ -0,0 +1,25 @@
+CREATE TABLE IF NOT EXISTS audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    timestamp DATETIME NOT NULL,
+    ray_id TEXT NOT NULL,
+    user TEXT,
+    method TEXT,
+    uri TEXT,
+    headers TEXT,
+    body TEXT,
+    response_status INTEGER,
+    response_headers TEXT,
+    response_body TEXT
+);
+
+CREATE TABLE IF NOT EXISTS tokens_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    timestamp DATETIME,
+    ray_id TEXT NOT NULL,
+    user TEXT,
+    model TEXT NOT NULL,
+    is_estimated BOOLEAN NOT NULL,
+    prompt_tokens INTEGER NOT NULL,
+    completion_tokens INTEGER NOT NULL,
+    total_tokens INTEGER NOT NULL
+);
@@ -0,0 +1,3 @@
+ALTER TABLE audit_log ADD COLUMN content_filter TEXT;
+ALTER TABLE audit_log ADD COLUMN moderation TEXT;
+ALTER TABLE audit_log ADD COLUMN client_ip TEXT;
@@ -0,0 +1,6 @@
+ALTER TABLE audit_log ADD COLUMN latency_ms INTEGER;
+ALTER TABLE audit_log ADD COLUMN ttfb_ms INTEGER;
+ALTER TABLE audit_log ADD COLUMN upstream_status INTEGER;
+ALTER TABLE audit_log ADD COLUMN upstream_request_id TEXT;
+ALTER TABLE audit_log ADD COLUMN key_fingerprint TEXT;
+CREATE INDEX audit_log_key_fingerprint ON audit_log (key_fingerprint);
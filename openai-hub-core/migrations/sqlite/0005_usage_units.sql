@@ -0,0 +1,4 @@
+ALTER TABLE tokens_log ADD COLUMN unit TEXT NOT NULL DEFAULT 'tokens';
+ALTER TABLE tokens_log ADD COLUMN quantity REAL NOT NULL DEFAULT 0;
+ALTER TABLE tokens_log ADD COLUMN details TEXT;
+UPDATE tokens_log SET quantity = total_tokens;
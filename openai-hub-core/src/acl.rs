@@ -1,14 +1,17 @@
 use crate::helpers::{endpoints_to_regex, wildcards_to_regex};
 
 use axum::http::{Method, StatusCode};
+use ipnet::IpNet;
 
 use once_cell::sync::Lazy;
 use regex::Regex;
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::Value;
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 use std::fmt::Debug;
 use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
 use tracing::{event, instrument, Level};
 
 static DEPLOYMENT_ID_REGEX: Lazy<Regex> =
@@ -41,8 +44,13 @@ impl<'de> Deserialize<'de> for MethodSerde {
 pub struct ApiAcl {
     pub global: Global,
     pub endpoint: HashMap<Method, Regex>,
+    /// The endpoint rules of each method, with the regex of each rule
+    pub endpoint_rules: HashMap<Method, Vec<(String, Regex)>>,
     pub model_body: HashMap<Method, HashMap<String, ModelOption>>,
     pub model_path: HashMap<Method, Vec<(Regex, ModelOption)>>,
+    pub deployments: HashMap<String, Deployment>,
+    pub roles: HashMap<String, Role>,
+    pub subjects: HashMap<String, Subject>,
 }
 
 #[derive(Debug, Clone)]
@@ -52,6 +60,47 @@ pub struct Global {
     pub allow_deployments: HashSet<String>,
 }
 
+/// An azure deployment and the model it serves
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Deployment {
+    #[serde(default)]
+    pub model: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Role {
+    #[serde(default)]
+    pub allow_deployments: HashSet<String>,
+    #[serde(default)]
+    pub allow_ips: Vec<IpNet>,
+    #[serde(default)]
+    pub deny_ips: Vec<IpNet>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Subject {
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub allow_deployments: HashSet<String>,
+    #[serde(default)]
+    pub allow_ips: Vec<IpNet>,
+    #[serde(default)]
+    pub deny_ips: Vec<IpNet>,
+}
+
+/// Where a deployment is granted from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeploymentGrant {
+    Global,
+    Role(String),
+    Subject(String),
+}
+
 #[derive(Debug, Clone)]
 pub struct ModelOption {
     pub allows: Regex,
@@ -91,24 +140,49 @@ impl Default for Global {
 
 pub enum AclError {
     MethodNotAllowed(Method),
+    IpNotAllowed(IpAddr),
     DeploymentNotAllowed(String),
     EndpointNotAllowed(Method, String),
     ModelNotAllowed(String),
     MissingModel,
 }
 
+#[cfg(feature = "metrics")]
+impl AclError {
+    /// Label of the rejection in metrics.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            AclError::MethodNotAllowed(_) => "method",
+            AclError::IpNotAllowed(_) => "ip",
+            AclError::DeploymentNotAllowed(_) => "deployment",
+            AclError::EndpointNotAllowed(_, _) => "endpoint",
+            AclError::ModelNotAllowed(_) => "model",
+            AclError::MissingModel => "missing_model",
+        }
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum LoadError {
     #[error(transparent)]
     InvalidToml(#[from] toml::de::Error),
-    #[error(transparent)]
-    InvalidRegex(#[from] regex::Error),
+    /// `key` is the dotted path of the rule, e.g. `endpoint.GET."/models/{model}"`
+    #[error("invalid pattern {key}: {error}")]
+    InvalidPattern { key: String, error: regex::Error },
+    /// A path rule that matches paths without telling their model
+    #[error("path rule {0} has no {{model}} placeholder")]
+    PathWithoutModel(String),
+    #[error("subject {0} refers to unknown role {1}")]
+    UnknownRole(String, String),
+    #[error("role {0} is assigned to subject {1}")]
+    RoleInUse(String, String),
 }
 
 impl ApiAcl {
     #[instrument(skip_all)]
     pub fn load(s: &str) -> Result<Self, LoadError> {
         #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
         struct GlobalDe {
             #[serde(default = "default_true")]
             whitelist: bool,
@@ -119,6 +193,7 @@ impl ApiAcl {
         }
 
         #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
         struct ModelOptionDe {
             #[serde(default)]
             path: bool,
@@ -131,20 +206,36 @@ impl ApiAcl {
         }
 
         #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
         struct ApiAclDe {
             pub global: GlobalDe,
             #[serde(default)]
             pub endpoint: HashMap<MethodSerde, BTreeMap<String, bool>>,
             #[serde(default)]
             pub model: HashMap<MethodSerde, HashMap<String, ModelOptionDe>>,
+            #[serde(default)]
+            pub deployments: HashMap<String, Deployment>,
+            #[serde(default)]
+            pub roles: HashMap<String, Role>,
+            #[serde(default)]
+            pub subjects: HashMap<String, Subject>,
         }
 
         let ApiAclDe {
             global: global_de,
             endpoint,
             model: model_de,
+            deployments,
+            roles,
+            subjects,
         } = toml::from_str(s)?;
 
+        for (name, subject) in subjects.iter() {
+            if let Some(role) = subject.roles.iter().find(|r| !roles.contains_key(*r)) {
+                return Err(LoadError::UnknownRole(name.clone(), role.clone()));
+            }
+        }
+
         let global = Global {
             whitelist: global_de.whitelist,
             methods: global_de
@@ -156,12 +247,31 @@ impl ApiAcl {
         };
 
         let mut endpoint_regex: HashMap<Method, Regex> = HashMap::new();
+        let mut endpoint_rules: HashMap<Method, Vec<(String, Regex)>> = HashMap::new();
         for (method, endpoints) in endpoint.into_iter() {
-            let endpoints = endpoints
+            let endpoints: Vec<String> = endpoints
                 .into_iter()
                 .filter(|(_, allow)| if global.whitelist { *allow } else { !*allow })
-                .map(|(endpoint, _)| endpoint);
-            endpoint_regex.insert(method.0, endpoints_to_regex(endpoints)?);
+                .map(|(endpoint, _)| endpoint)
+                .collect();
+            let mut rules = Vec::with_capacity(endpoints.len());
+            for endpoint in endpoints.iter() {
+                let regex = endpoints_to_regex(std::iter::once(endpoint)).map_err(|error| {
+                    LoadError::InvalidPattern {
+                        key: format!("endpoint.{}.\"{}\"", method.0, endpoint),
+                        error,
+                    }
+                })?;
+                rules.push((endpoint.clone(), regex));
+            }
+            let regex = endpoints_to_regex(endpoints.iter()).map_err(|error| {
+                LoadError::InvalidPattern {
+                    key: format!("endpoint.{}", method.0),
+                    error,
+                }
+            })?;
+            endpoint_regex.insert(method.0.clone(), regex);
+            endpoint_rules.insert(method.0, rules);
         }
 
         let mut model_body = HashMap::new();
@@ -171,19 +281,33 @@ impl ApiAcl {
             model_body.insert(method.0.clone(), HashMap::new());
             model_path.insert(method.0.clone(), Vec::new());
             for (path, model_de) in models.into_iter() {
+                let key = format!("model.{}.\"{}\"", method.0, path);
+                let wildcards = |list: &str, wildcards: Vec<String>| {
+                    wildcards_to_regex(wildcards.into_iter()).map_err(|error| {
+                        LoadError::InvalidPattern {
+                            key: format!("{key}.{list}"),
+                            error,
+                        }
+                    })
+                };
                 let option = ModelOption {
-                    allows: wildcards_to_regex(model_de.allows.into_iter())?,
-                    disallows: wildcards_to_regex(model_de.disallows.into_iter())?,
+                    allows: wildcards("allows", model_de.allows)?,
+                    disallows: wildcards("disallows", model_de.disallows)?,
                     allow_omitted: model_de.allow_omitted,
                 };
                 if model_de.path {
                     event!(Level::DEBUG, "should be a regex rule: {}", path);
-                    let path = path.replace("{model}", "(?P<model>[^/]+)");
-                    event!(Level::DEBUG, "transformed regex rule: {}", path);
-                    model_path
-                        .get_mut(&method.0)
-                        .unwrap()
-                        .push((Regex::new(&path).unwrap(), option));
+                    let regex = Regex::new(&path.replace("{model}", "(?P<model>[^/]+)")).map_err(
+                        |error| LoadError::InvalidPattern {
+                            key: key.clone(),
+                            error,
+                        },
+                    )?;
+                    if !regex.capture_names().any(|name| name == Some("model")) {
+                        return Err(LoadError::PathWithoutModel(key));
+                    }
+                    event!(Level::DEBUG, "transformed regex rule: {}", regex);
+                    model_path.get_mut(&method.0).unwrap().push((regex, option));
                 } else {
                     event!(Level::DEBUG, "seems to be a normal rule: {}", path);
                     model_body.get_mut(&method.0).unwrap().insert(path, option);
@@ -194,16 +318,94 @@ impl ApiAcl {
         Ok(Self {
             global,
             endpoint: endpoint_regex,
+            endpoint_rules,
             model_body,
             model_path,
+            deployments,
+            roles,
+            subjects,
         })
     }
 
+    /// Add or replace a role.
+    pub fn set_role(&mut self, name: String, role: Role) {
+        self.roles.insert(name, role);
+    }
+
+    /// Remove a role, unless it is assigned to a subject.
+    pub fn remove_role(&mut self, name: &str) -> Result<Option<Role>, LoadError> {
+        if let Some((subject, _)) = self
+            .subjects
+            .iter()
+            .find(|(_, subject)| subject.roles.iter().any(|role| role == name))
+        {
+            return Err(LoadError::RoleInUse(name.to_string(), subject.clone()));
+        }
+        Ok(self.roles.remove(name))
+    }
+
+    /// Add or replace a subject, its roles must exist.
+    pub fn set_subject(&mut self, name: String, subject: Subject) -> Result<(), LoadError> {
+        if let Some(role) = subject.roles.iter().find(|r| !self.roles.contains_key(*r)) {
+            return Err(LoadError::UnknownRole(name, role.clone()));
+        }
+        self.subjects.insert(name, subject);
+        Ok(())
+    }
+
+    pub fn remove_subject(&mut self, name: &str) -> Option<Subject> {
+        self.subjects.remove(name)
+    }
+
+    /// Find the role or subject rule rejecting the client address, if any.
+    ///
+    /// Every role of the subject and the subject itself are checked, an address in `deny_ips`
+    /// or outside a non-empty `allow_ips` is rejected.
+    pub fn ip_rejection(&self, subject: Option<&str>, ip: IpAddr) -> Option<String> {
+        let (name, subject) = subject.and_then(|s| self.subjects.get_key_value(s))?;
+        let rejects = |allow_ips: &[IpNet], deny_ips: &[IpNet]| {
+            deny_ips.iter().any(|net| net.contains(&ip))
+                || (!allow_ips.is_empty() && !allow_ips.iter().any(|net| net.contains(&ip)))
+        };
+        subject
+            .roles
+            .iter()
+            .filter_map(|role| self.roles.get_key_value(role))
+            .find(|(_, role)| rejects(&role.allow_ips, &role.deny_ips))
+            .map(|(name, _)| format!("role {}", name))
+            .or_else(|| {
+                rejects(&subject.allow_ips, &subject.deny_ips).then(|| format!("subject {}", name))
+            })
+    }
+
+    /// Find out whether the deployment is granted to the subject, either globally, through one
+    /// of its roles, or directly.
+    pub fn deployment_grant(&self, id: &str, subject: Option<&str>) -> Option<DeploymentGrant> {
+        if self.global.allow_deployments.contains(id) {
+            return Some(DeploymentGrant::Global);
+        }
+        let (name, subject) = subject.and_then(|s| self.subjects.get_key_value(s))?;
+        if let Some(role) = subject.roles.iter().find(|role| {
+            self.roles
+                .get(*role)
+                .map(|r| r.allow_deployments.contains(id))
+                .unwrap_or(false)
+        }) {
+            return Some(DeploymentGrant::Role(role.clone()));
+        }
+        subject
+            .allow_deployments
+            .contains(id)
+            .then(|| DeploymentGrant::Subject(name.clone()))
+    }
+
     #[instrument(skip_all)]
     pub fn validate(
         &self,
         method: &Method,
         path: &str,
+        subject: Option<&str>,
+        client_ip: Option<IpAddr>,
     ) -> Result<Option<Box<dyn ModelValidator>>, AclError> {
         // global method check
         event!(
@@ -216,24 +418,28 @@ impl ApiAcl {
             event!(Level::DEBUG, "method not allowed: {:?}", method);
             return Err(AclError::MethodNotAllowed(method.clone()));
         }
+
+        // client address check
+        if let Some(ip) = client_ip {
+            if let Some(rule) = self.ip_rejection(subject, ip) {
+                event!(Level::DEBUG, "client address {} rejected by {}", ip, rule);
+                return Err(AclError::IpNotAllowed(ip));
+            }
+        }
         event!(Level::DEBUG, "path: {}", path);
 
         // deployment check
-        let endpoint = if let Some(deployment_id) = DEPLOYMENT_ID_REGEX.captures(path) {
-            let id = deployment_id.get(1).unwrap();
-            event!(
-                Level::DEBUG,
-                "seems contains deployment id: {}",
-                id.as_str()
-            );
-            if !self.global.allow_deployments.contains(id.as_str()) {
-                event!(Level::DEBUG, "deployment {} not allowed", id.as_str());
-                return Err(AclError::DeploymentNotAllowed(id.as_str().to_string()));
+        let (deployment_id, endpoint) = split_deployment(path);
+        if let Some(id) = deployment_id {
+            event!(Level::DEBUG, "seems contains deployment id: {}", id);
+            match self.deployment_grant(id, subject) {
+                Some(grant) => event!(Level::DEBUG, "deployment {} granted by {:?}", id, grant),
+                None => {
+                    event!(Level::DEBUG, "deployment {} not allowed", id);
+                    return Err(AclError::DeploymentNotAllowed(id.to_string()));
+                }
             }
-            &path[id.end()..]
-        } else {
-            path
-        };
+        }
         event!(Level::DEBUG, "endpoint: {}", endpoint);
 
         // per endpoint check
@@ -256,6 +462,32 @@ impl ApiAcl {
             ));
         }
 
+        // the model of a deployment is fixed, apply the rules to the mapped model instead
+        let deployment_model = deployment_id
+            .and_then(|id| self.deployments.get(id))
+            .and_then(|d| d.model.as_ref());
+        if let Some(model) = deployment_model {
+            event!(Level::DEBUG, "deployment serves model {}", model);
+            return Ok(self
+                .model_body
+                .get(method)
+                .and_then(|per_method| per_method.get(endpoint))
+                .or_else(|| {
+                    self.model_path.get(method).and_then(|regexes| {
+                        regexes
+                            .iter()
+                            .find(|(re, _)| re.is_match(endpoint))
+                            .map(|(_, o)| o)
+                    })
+                })
+                .map(|o| {
+                    Box::new(DeploymentModel {
+                        option: o.clone(),
+                        model: model.clone(),
+                    }) as Box<dyn ModelValidator>
+                }));
+        }
+
         Ok(self
             .model_body
             .get(method)
@@ -276,6 +508,279 @@ impl ApiAcl {
     }
 }
 
+impl ApiAcl {
+    /// Walk through the rules like [`ApiAcl::validate`] does, recording which rule matched
+    /// at each step instead of stopping at the first error.
+    #[instrument(skip(self, body))]
+    pub fn explain(
+        &self,
+        method: &Method,
+        path: &str,
+        body: Option<&Value>,
+        subject: Option<&str>,
+        client_ip: Option<IpAddr>,
+    ) -> AclExplanation {
+        let mut explanation = AclExplanation {
+            method: method.to_string(),
+            path: path.to_string(),
+            subject: subject.map(ToString::to_string),
+            client_ip: client_ip.map(|ip| IpMatch {
+                ip,
+                rejected_by: self.ip_rejection(subject, ip),
+            }),
+            global: GlobalMatch {
+                whitelist: self.global.whitelist,
+                method_allowed: self.global.methods.get(method).copied(),
+            },
+            deployment: None,
+            endpoint: None,
+            model: None,
+            allowed: false,
+            error: None,
+        };
+
+        let result = self
+            .validate(method, path, subject, client_ip)
+            .and_then(|validator| {
+                if let Some(validator) = validator {
+                    validator.validate_path(path)?;
+                    if let Some(body) = body {
+                        validator.validate_body(body)?;
+                    }
+                }
+                Ok(())
+            });
+        match result {
+            Ok(()) => explanation.allowed = true,
+            Err(e) => explanation.error = Some(e.to_string()),
+        }
+
+        if !explanation.global.method_allowed.unwrap_or(false)
+            || explanation
+                .client_ip
+                .as_ref()
+                .map(|ip| ip.rejected_by.is_some())
+                .unwrap_or(false)
+        {
+            return explanation;
+        }
+
+        let (deployment_id, endpoint) = split_deployment(path);
+        let mut deployment_model = None;
+        if let Some(id) = deployment_id {
+            let grant = self.deployment_grant(id, subject);
+            let allowed = grant.is_some();
+            deployment_model = self.deployments.get(id).and_then(|d| d.model.as_deref());
+            explanation.deployment = Some(DeploymentMatch {
+                id: id.to_string(),
+                model: deployment_model.map(ToString::to_string),
+                granted_by: grant,
+            });
+            if !allowed {
+                return explanation;
+            }
+        }
+
+        let rule = self.endpoint_rules.get(method).and_then(|rules| {
+            rules
+                .iter()
+                .find(|(_, re)| re.is_match(endpoint))
+                .map(|(rule, _)| rule.clone())
+        });
+        // the rules of a whitelist allow the endpoint, those of a blacklist deny it
+        let whitelist = self.global.whitelist;
+        explanation.endpoint = Some(EndpointMatch {
+            endpoint: endpoint.to_string(),
+            regex: self.endpoint.get(method).map(|re| re.as_str().to_string()),
+            allowed: whitelist == rule.is_some(),
+            allowed_by: rule.clone().filter(|_| whitelist),
+            denied_by: rule.filter(|_| !whitelist),
+        });
+
+        let model_rule = self
+            .model_body
+            .get(method)
+            .and_then(|per_method| per_method.get_key_value(endpoint))
+            .map(|(rule, option)| {
+                let model = deployment_model.or_else(|| {
+                    body.and_then(|body| body.get("model"))
+                        .and_then(|m| m.as_str())
+                });
+                (rule.clone(), false, option, model)
+            })
+            .or_else(|| {
+                self.model_path.get(method).and_then(|regexes| {
+                    regexes
+                        .iter()
+                        .find(|(re, _)| re.is_match(endpoint))
+                        .map(|(re, option)| {
+                            let model = deployment_model.or_else(|| {
+                                re.captures(endpoint)
+                                    .and_then(|c| c.name("model"))
+                                    .map(|m| m.as_str())
+                            });
+                            (re.as_str().to_string(), true, option, model)
+                        })
+                })
+            });
+        explanation.model = model_rule.map(|(rule, in_path, option, model)| ModelMatch {
+            rule,
+            in_path,
+            allows: option.allows.as_str().to_string(),
+            disallows: option.disallows.as_str().to_string(),
+            allow_omitted: option.allow_omitted,
+            model: model.map(ToString::to_string),
+        });
+
+        explanation
+    }
+}
+
+/// The result of [`ApiAcl::explain`].
+#[derive(Debug, Serialize)]
+pub struct AclExplanation {
+    pub method: String,
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_ip: Option<IpMatch>,
+    pub global: GlobalMatch,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deployment: Option<DeploymentMatch>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<EndpointMatch>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<ModelMatch>,
+    pub allowed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GlobalMatch {
+    pub whitelist: bool,
+    pub method_allowed: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct IpMatch {
+    pub ip: IpAddr,
+    pub rejected_by: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeploymentMatch {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    pub granted_by: Option<DeploymentGrant>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EndpointMatch {
+    pub endpoint: String,
+    pub regex: Option<String>,
+    /// The whitelist rule matching the endpoint
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_by: Option<String>,
+    /// The blacklist rule matching the endpoint
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub denied_by: Option<String>,
+    pub allowed: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ModelMatch {
+    pub rule: String,
+    pub in_path: bool,
+    pub allows: String,
+    pub disallows: String,
+    pub allow_omitted: bool,
+    pub model: Option<String>,
+}
+
+impl fmt::Display for AclExplanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "request:    {} {}", self.method, self.path)?;
+        if let Some(ref subject) = self.subject {
+            writeln!(f, "subject:    {}", subject)?;
+        }
+        if let Some(ref client_ip) = self.client_ip {
+            match client_ip.rejected_by {
+                Some(ref rule) => writeln!(f, "client ip:  {} rejected by {}", client_ip.ip, rule)?,
+                None => writeln!(f, "client ip:  {}", client_ip.ip)?,
+            }
+        }
+        writeln!(
+            f,
+            "global:     whitelist = {}, method {} = {}",
+            self.global.whitelist,
+            self.method,
+            match self.global.method_allowed {
+                Some(true) => "allowed",
+                Some(false) => "disallowed",
+                None => "unlisted (disallowed)",
+            }
+        )?;
+        if let Some(ref deployment) = self.deployment {
+            write!(f, "deployment: {}", deployment.id)?;
+            if let Some(ref model) = deployment.model {
+                write!(f, " serving {}", model)?;
+            }
+            match deployment.granted_by {
+                Some(DeploymentGrant::Global) => writeln!(f, " (granted globally)")?,
+                Some(DeploymentGrant::Role(ref role)) => {
+                    writeln!(f, " (granted by role {})", role)?
+                }
+                Some(DeploymentGrant::Subject(ref subject)) => {
+                    writeln!(f, " (granted to subject {})", subject)?
+                }
+                None => writeln!(f, " (not granted)")?,
+            }
+        }
+        if let Some(ref endpoint) = self.endpoint {
+            match (&endpoint.allowed_by, &endpoint.denied_by) {
+                (Some(rule), _) => writeln!(
+                    f,
+                    "endpoint:   {} allowed by rule \"{}\"",
+                    endpoint.endpoint, rule
+                )?,
+                (_, Some(rule)) => writeln!(
+                    f,
+                    "endpoint:   {} denied by rule \"{}\"",
+                    endpoint.endpoint, rule
+                )?,
+                _ if self.global.whitelist => {
+                    writeln!(f, "endpoint:   {} matched no allow rule", endpoint.endpoint)?
+                }
+                _ => writeln!(f, "endpoint:   {} matched no deny rule", endpoint.endpoint)?,
+            }
+            writeln!(
+                f,
+                "            regex {}",
+                endpoint.regex.as_deref().unwrap_or("<none>")
+            )?;
+        }
+        if let Some(ref model) = self.model {
+            writeln!(
+                f,
+                "model:      rule \"{}\" ({}), model = {}",
+                model.rule,
+                if model.in_path { "path" } else { "body" },
+                model.model.as_deref().unwrap_or("<omitted>")
+            )?;
+            writeln!(f, "            allows {}", model.allows)?;
+            writeln!(f, "            disallows {}", model.disallows)?;
+            writeln!(f, "            allow_omitted = {}", model.allow_omitted)?;
+        }
+        match self.error {
+            None => write!(f, "result:     allowed"),
+            Some(ref error) => write!(f, "result:     denied, {}", error),
+        }
+    }
+}
+
 impl ModelOption {
     #[instrument(skip(self))]
     fn validate(&self, model: Option<&str>) -> Result<(), AclError> {
@@ -309,11 +814,22 @@ impl ModelValidator for (Regex, ModelOption) {
         let model = self
             .0
             .captures(path)
-            .unwrap()
-            .name("model")
-            .unwrap()
-            .as_str();
-        self.1.validate(Some(model))
+            .and_then(|c| c.name("model"))
+            .map(|m| m.as_str());
+        self.1.validate(model)
+    }
+}
+
+/// Validates the model rules against the model served by a deployment.
+struct DeploymentModel {
+    option: ModelOption,
+    model: String,
+}
+
+impl ModelValidator for DeploymentModel {
+    #[instrument(skip(self))]
+    fn validate_path(&self, _path: &str) -> Result<(), AclError> {
+        self.option.validate(Some(&self.model))
     }
 }
 
@@ -337,6 +853,7 @@ impl ToString for AclError {
     fn to_string(&self) -> String {
         match self {
             AclError::MethodNotAllowed(method) => format!("Method {} not allowed", method.as_str()),
+            AclError::IpNotAllowed(ip) => format!("Client address {} not allowed", ip),
             AclError::DeploymentNotAllowed(id) => format!("Deployment {} not allowed", id),
             AclError::EndpointNotAllowed(method, endpoint) => {
                 format!("Endpoint {} {} not allowed", method.as_str(), endpoint)
@@ -349,6 +866,311 @@ impl ToString for AclError {
     }
 }
 
+/// Split an azure style `/engines/{deployment}/...` path into the deployment id and the
+/// remaining endpoint.
+fn split_deployment(path: &str) -> (Option<&str>, &str) {
+    match DEPLOYMENT_ID_REGEX.captures(path) {
+        Some(captures) => {
+            let id = captures.get(1).unwrap();
+            (Some(id.as_str()), &path[id.end()..])
+        }
+        None => (None, path),
+    }
+}
+
 const fn default_true() -> bool {
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const ACL: &str = r#"
+[global]
+whitelist = true
+allow_deployments = ["shared-gpt-35"]
+
+[global.methods]
+GET = true
+POST = true
+
+[deployments.my-gpt-4]
+model = "gpt-4"
+
+[deployments.shared-gpt-35]
+model = "gpt-3.5-turbo"
+
+[roles.developer]
+allow_deployments = ["my-gpt-4"]
+
+[subjects.alice]
+roles = ["developer"]
+deny_ips = ["10.0.13.0/24"]
+
+[subjects.carol]
+allow_deployments = ["carol-gpt-35"]
+
+[endpoint.GET]
+"/models/{model}" = true
+
+[endpoint.POST]
+"/chat/completions" = true
+
+[model.POST."/chat/completions"]
+allows = ["gpt-3.5-*"]
+disallows = ["gpt-3.5-turbo-0301"]
+
+[model.GET."/models/{model}"]
+path = true
+allows = ["gpt-*"]
+"#;
+
+    fn explain(
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+        subject: Option<&str>,
+        ip: Option<&str>,
+    ) -> AclExplanation {
+        let acl = ApiAcl::load(ACL).unwrap();
+        let ip = ip.map(|ip| ip.parse().unwrap());
+        acl.explain(&method, path, body.as_ref(), subject, ip)
+    }
+
+    #[test]
+    fn allowed_by_endpoint_and_model_rules() {
+        let explanation = explain(
+            Method::POST,
+            "/chat/completions",
+            Some(json!({"model": "gpt-3.5-turbo"})),
+            None,
+            None,
+        );
+        assert!(explanation.allowed, "{explanation}");
+        let endpoint = explanation.endpoint.unwrap();
+        assert!(endpoint.allowed);
+        assert_eq!(endpoint.allowed_by.as_deref(), Some("/chat/completions"));
+        assert!(endpoint.denied_by.is_none());
+        let model = explanation.model.unwrap();
+        assert!(!model.in_path);
+        assert_eq!(model.model.as_deref(), Some("gpt-3.5-turbo"));
+    }
+
+    #[test]
+    fn rejected_by_model_rule() {
+        let explanation = explain(
+            Method::POST,
+            "/chat/completions",
+            Some(json!({"model": "gpt-3.5-turbo-0301"})),
+            None,
+            None,
+        );
+        assert!(!explanation.allowed);
+        assert!(explanation.error.is_some());
+        assert!(explanation.model.is_some());
+    }
+
+    #[test]
+    fn model_in_path() {
+        let explanation = explain(Method::GET, "/models/gpt-4", None, None, None);
+        assert!(explanation.allowed, "{explanation}");
+        let model = explanation.model.unwrap();
+        assert!(model.in_path);
+        assert_eq!(model.model.as_deref(), Some("gpt-4"));
+
+        let explanation = explain(Method::GET, "/models/davinci", None, None, None);
+        assert!(!explanation.allowed);
+    }
+
+    #[test]
+    fn unlisted_endpoint_and_method() {
+        let explanation = explain(Method::POST, "/embeddings", None, None, None);
+        assert!(!explanation.allowed);
+        let endpoint = explanation.endpoint.unwrap();
+        assert!(!endpoint.allowed);
+        assert!(endpoint.allowed_by.is_none());
+        assert!(endpoint.denied_by.is_none());
+
+        let explanation = explain(Method::DELETE, "/files/file-1", None, None, None);
+        assert!(!explanation.allowed);
+        assert_eq!(explanation.global.method_allowed, None);
+        assert!(explanation.endpoint.is_none());
+    }
+
+    #[test]
+    fn denied_by_blacklist_rule() {
+        let acl = ApiAcl::load(
+            r#"
+[global]
+whitelist = false
+
+[global.methods]
+POST = true
+
+[endpoint.POST]
+"/chat/completions" = true
+"/fine-tunes" = false
+"#,
+        )
+        .unwrap();
+        let explanation = acl.explain(&Method::POST, "/fine-tunes", None, None, None);
+        assert!(!explanation.allowed);
+        let endpoint = explanation.endpoint.unwrap();
+        assert!(!endpoint.allowed);
+        assert_eq!(endpoint.denied_by.as_deref(), Some("/fine-tunes"));
+        assert!(endpoint.allowed_by.is_none());
+
+        let explanation = acl.explain(&Method::POST, "/chat/completions", None, None, None);
+        assert!(explanation.allowed, "{explanation}");
+        let endpoint = explanation.endpoint.unwrap();
+        assert!(endpoint.allowed);
+        assert!(endpoint.denied_by.is_none());
+    }
+
+    #[test]
+    fn deployment_granted_globally() {
+        let acl = ApiAcl::load(ACL).unwrap();
+        assert_eq!(
+            acl.deployment_grant("shared-gpt-35", None),
+            Some(DeploymentGrant::Global)
+        );
+        assert_eq!(
+            acl.deployment_grant("shared-gpt-35", Some("alice")),
+            Some(DeploymentGrant::Global)
+        );
+    }
+
+    #[test]
+    fn deployment_granted_by_role() {
+        let acl = ApiAcl::load(ACL).unwrap();
+        assert_eq!(
+            acl.deployment_grant("my-gpt-4", Some("alice")),
+            Some(DeploymentGrant::Role("developer".to_string()))
+        );
+        assert_eq!(acl.deployment_grant("my-gpt-4", Some("carol")), None);
+        assert_eq!(acl.deployment_grant("my-gpt-4", None), None);
+
+        let explanation = explain(
+            Method::POST,
+            "/engines/my-gpt-4/chat/completions",
+            None,
+            Some("alice"),
+            None,
+        );
+        assert!(matches!(
+            explanation.deployment.unwrap().granted_by,
+            Some(DeploymentGrant::Role(ref role)) if role == "developer"
+        ));
+    }
+
+    #[test]
+    fn deployment_granted_to_subject() {
+        let acl = ApiAcl::load(ACL).unwrap();
+        assert_eq!(
+            acl.deployment_grant("carol-gpt-35", Some("carol")),
+            Some(DeploymentGrant::Subject("carol".to_string()))
+        );
+        assert_eq!(acl.deployment_grant("carol-gpt-35", Some("alice")), None);
+    }
+
+    #[test]
+    fn unknown_deployment() {
+        let acl = ApiAcl::load(ACL).unwrap();
+        assert_eq!(acl.deployment_grant("unknown", Some("alice")), None);
+        let result = acl.validate(
+            &Method::POST,
+            "/engines/unknown/chat/completions",
+            Some("alice"),
+            None,
+        );
+        assert!(matches!(result, Err(AclError::DeploymentNotAllowed(ref id)) if id == "unknown"));
+
+        let explanation = explain(
+            Method::POST,
+            "/engines/unknown/chat/completions",
+            Some(json!({"model": "gpt-3.5-turbo"})),
+            Some("alice"),
+            None,
+        );
+        let deployment = explanation.deployment.unwrap();
+        assert!(deployment.granted_by.is_none());
+        assert!(deployment.model.is_none());
+        assert!(explanation.endpoint.is_none());
+        assert!(!explanation.allowed);
+    }
+
+    #[test]
+    fn deployment_model_overrides_body() {
+        let acl = ApiAcl::load(ACL).unwrap();
+        let path = "/engines/my-gpt-4/chat/completions";
+        // gpt-3.5-turbo in the body is allowed, but the deployment serves gpt-4
+        let Ok(Some(validator)) = acl.validate(&Method::POST, path, Some("alice"), None) else {
+            panic!("no model rule for {path}");
+        };
+        assert!(matches!(
+            validator.validate_path(path),
+            Err(AclError::ModelNotAllowed(ref model)) if model == "gpt-4"
+        ));
+        let explanation = explain(
+            Method::POST,
+            path,
+            Some(json!({"model": "gpt-3.5-turbo"})),
+            Some("alice"),
+            None,
+        );
+        assert_eq!(explanation.model.unwrap().model.as_deref(), Some("gpt-4"));
+        assert!(!explanation.allowed);
+
+        // and the other way around, gpt-4 in the body is not checked
+        let path = "/engines/shared-gpt-35/chat/completions";
+        let Ok(Some(validator)) = acl.validate(&Method::POST, path, None, None) else {
+            panic!("no model rule for {path}");
+        };
+        assert!(validator.validate_path(path).is_ok());
+        assert!(validator.validate_body(&json!({"model": "gpt-4"})).is_ok());
+        let explanation = explain(
+            Method::POST,
+            path,
+            Some(json!({"model": "gpt-4"})),
+            None,
+            None,
+        );
+        assert!(explanation.allowed, "{explanation}");
+        assert_eq!(
+            explanation.model.unwrap().model.as_deref(),
+            Some("gpt-3.5-turbo")
+        );
+    }
+
+    #[test]
+    fn client_ip_rejected() {
+        let explanation = explain(
+            Method::GET,
+            "/models/gpt-4",
+            None,
+            Some("alice"),
+            Some("10.0.13.7"),
+        );
+        assert!(!explanation.allowed);
+        assert!(explanation.client_ip.unwrap().rejected_by.is_some());
+        assert!(explanation.endpoint.is_none());
+    }
+
+    #[test]
+    fn path_rule_without_model_rejected() {
+        let acl = r#"
+[global]
+whitelist = true
+
+[model.GET."/models"]
+path = true
+allows = ["gpt-*"]
+"#;
+        let Err(LoadError::PathWithoutModel(key)) = ApiAcl::load(acl) else {
+            panic!("path rule without {{model}} loaded");
+        };
+        assert_eq!(key, r#"model.GET."/models""#);
+    }
+}
@@ -0,0 +1,575 @@
+use super::retention::cutoff;
+use crate::config::{AuditRetentionConfig, FileBackendConfig, FileCompression};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde_json::{Map, Value};
+use std::io::{self, BufRead, Read, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+use tracing::{event, Level};
+
+/// An append-only log file rotated by size and / or day.
+///
+/// Rotated files are renamed to `{filename}.{timestamp}`, then compressed and pruned
+/// in the background.
+pub struct LogFile {
+    config: FileBackendConfig,
+    file: File,
+    size: u64,
+    day: NaiveDate,
+    /// Incremented whenever the file is reopened, e.g. rotated
+    generation: u64,
+    /// Held while rotated files are compressed, pruned or compacted
+    maintenance: Arc<parking_lot::Mutex<()>>,
+}
+
+impl LogFile {
+    pub async fn open(config: &FileBackendConfig) -> io::Result<Self> {
+        let (file, size, day) = Self::open_file(&config.filename).await?;
+        Ok(Self {
+            config: config.clone(),
+            file,
+            size,
+            day,
+            generation: 0,
+            maintenance: Arc::default(),
+        })
+    }
+
+    async fn open_file(filename: &str) -> io::Result<(File, u64, NaiveDate)> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(filename)
+            .await?;
+        let metadata = file.metadata().await?;
+        let day = match metadata.modified() {
+            Ok(modified) if metadata.len() > 0 => DateTime::<Utc>::from(modified).date_naive(),
+            _ => Utc::now().date_naive(),
+        };
+        Ok((file, metadata.len(), day))
+    }
+
+    /// Reopen the file, e.g. after it was moved away by logrotate.
+    pub async fn reopen(&mut self) -> io::Result<()> {
+        self.file.flush().await?;
+        let (file, size, day) = Self::open_file(&self.config.filename).await?;
+        self.file = file;
+        self.size = size;
+        self.day = day;
+        self.generation += 1;
+        Ok(())
+    }
+
+    pub async fn flush(&mut self) -> io::Result<()> {
+        self.file.flush().await
+    }
+
+    /// Flush the file and check that it can still be opened for writing.
+    pub async fn check(&mut self) -> io::Result<()> {
+        self.file.flush().await?;
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.config.filename)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        if self.should_rotate(buf.len() as u64) {
+            if let Err(e) = self.rotate().await {
+                event!(Level::ERROR, error = ?e, "Failed to rotate audit log file");
+            }
+        }
+        self.file.write_all(buf).await?;
+        self.size += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Drop expired records and clear expired columns of the current file and of the rotated
+    /// files, removing the rotated files left empty.
+    ///
+    /// The current file is filtered without holding the lock of `log`, which is only taken
+    /// again to append the records written meanwhile and replace the file.
+    pub async fn compact(log: &Mutex<LogFile>, retention: &AuditRetentionConfig) -> io::Result<()> {
+        let cutoffs = Cutoffs::new(retention, Utc::now());
+        let (filename, len, generation, maintenance) = {
+            let mut log = log.lock().await;
+            log.file.flush().await?;
+            let len = log.file.metadata().await?.len();
+            let filename = log.config.filename.clone();
+            (filename, len, log.generation, log.maintenance.clone())
+        };
+
+        let temp = format!("{filename}.compact");
+        let compacted = {
+            let (cutoffs, filename, temp) = (cutoffs.clone(), filename.clone(), temp.clone());
+            tokio::task::spawn_blocking(move || {
+                let records = || {
+                    let file = std::fs::File::open(&filename)?;
+                    Ok::<_, io::Error>(io::BufReader::new(file.take(len)))
+                };
+                let compacted = compact_records(&cutoffs, records()?, io::sink())?;
+                if compacted.changed() {
+                    let mut writer = io::BufWriter::new(std::fs::File::create(&temp)?);
+                    compact_records(&cutoffs, records()?, &mut writer)?;
+                    writer.flush()?;
+                }
+                Ok::<_, io::Error>(compacted)
+            })
+            .await??
+        };
+
+        if compacted.changed() {
+            let mut log = log.lock().await;
+            if log.generation != generation {
+                // rotated meanwhile, compacted with the other rotated files
+                drop(log);
+                tokio::fs::remove_file(&temp).await?;
+            } else {
+                log.file.flush().await?;
+                let mut appended = File::open(&filename).await?;
+                appended.seek(SeekFrom::Start(len)).await?;
+                let mut target = OpenOptions::new().append(true).open(&temp).await?;
+                tokio::io::copy(&mut appended, &mut target).await?;
+                target.flush().await?;
+                tokio::fs::rename(&temp, &filename).await?;
+                log.reopen().await?;
+                event!(
+                    Level::INFO,
+                    "compacted audit log, dropped {} records and cleared {} columns",
+                    compacted.dropped,
+                    compacted.cleared
+                );
+            }
+        }
+
+        let filename = PathBuf::from(filename);
+        tokio::task::spawn_blocking(move || {
+            let _maintenance = maintenance.lock();
+            for path in rotated_files(&filename)? {
+                if let Err(e) = compact_rotated(&path, &cutoffs) {
+                    event!(Level::ERROR, error = ?e, "Failed to compact {}", path.display());
+                }
+            }
+            Ok::<_, io::Error>(())
+        })
+        .await?
+    }
+
+    fn should_rotate(&self, len: u64) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        if self.config.daily && Utc::now().date_naive() != self.day {
+            return true;
+        }
+        matches!(self.config.max_size, Some(max_size) if self.size + len > max_size)
+    }
+
+    async fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush().await?;
+        let rotated = format!(
+            "{}.{}",
+            self.config.filename,
+            Utc::now().format("%Y%m%d-%H%M%S%.3f")
+        );
+        tokio::fs::rename(&self.config.filename, &rotated).await?;
+        event!(Level::INFO, "rotated audit log to {}", rotated);
+        self.reopen().await?;
+
+        let config = self.config.clone();
+        let maintenance = self.maintenance.clone();
+        tokio::task::spawn_blocking(move || {
+            let _maintenance = maintenance.lock();
+            if let Err(e) = compress(Path::new(&rotated), config.compression) {
+                event!(Level::ERROR, error = ?e, "Failed to compress {}", rotated);
+            }
+            if let Some(max_files) = config.max_files {
+                if let Err(e) = prune(Path::new(&config.filename), max_files) {
+                    event!(Level::ERROR, error = ?e, "Failed to prune rotated audit logs");
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg_attr(
+    not(any(feature = "log-gzip", feature = "log-zstd")),
+    allow(unused_variables)
+)]
+fn compress(path: &Path, compression: FileCompression) -> io::Result<()> {
+    match compression {
+        FileCompression::None => Ok(()),
+        #[cfg(feature = "log-gzip")]
+        FileCompression::Gzip => encode(path, "gz", |reader, writer| {
+            let mut encoder = flate2::write::GzEncoder::new(writer, flate2::Compression::default());
+            io::copy(reader, &mut encoder)?;
+            encoder.finish().map(|_| ())
+        }),
+        #[cfg(feature = "log-zstd")]
+        FileCompression::Zstd => encode(path, "zst", |reader, writer| {
+            zstd::stream::copy_encode(reader, writer, 0)
+        }),
+    }
+}
+
+/// Write the encoded content of `path` to `{path}.{extension}` and remove `path`.
+#[cfg(any(feature = "log-gzip", feature = "log-zstd"))]
+fn encode<F>(path: &Path, extension: &str, f: F) -> io::Result<()>
+where
+    F: FnOnce(&mut std::fs::File, std::fs::File) -> io::Result<()>,
+{
+    let mut target = path.as_os_str().to_owned();
+    target.push(".");
+    target.push(extension);
+    let mut reader = std::fs::File::open(path)?;
+    f(&mut reader, std::fs::File::create(&target)?)?;
+    std::fs::remove_file(path)
+}
+
+/// Retention of the records and columns of a log file, as cutoff times.
+#[derive(Clone)]
+struct Cutoffs {
+    access: Option<DateTime<Utc>>,
+    tokens: Option<DateTime<Utc>>,
+    columns: Vec<(&'static str, DateTime<Utc>)>,
+}
+
+impl Cutoffs {
+    fn new(retention: &AuditRetentionConfig, now: DateTime<Utc>) -> Self {
+        Self {
+            access: retention.access_log.map(|days| cutoff(now, days)),
+            tokens: retention.tokens_log.map(|days| cutoff(now, days)),
+            columns: retention
+                .columns
+                .iter()
+                .map(|(column, days)| (column.as_str(), cutoff(now, *days)))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Compacted {
+    kept: usize,
+    dropped: usize,
+    cleared: usize,
+}
+
+impl Compacted {
+    fn changed(&self) -> bool {
+        self.dropped > 0 || self.cleared > 0
+    }
+}
+
+/// Write the records of `reader` to `writer`, without the expired records and columns.
+fn compact_records<R: BufRead, W: Write>(
+    cutoffs: &Cutoffs,
+    reader: R,
+    mut writer: W,
+) -> io::Result<Compacted> {
+    let mut compacted = Compacted::default();
+    for line in reader.split(b'\n') {
+        let line = line?;
+        let mut record = serde_json::from_slice::<Map<String, Value>>(&line).unwrap_or_default();
+        let timestamp = record
+            .get("timestamp")
+            .and_then(Value::as_i64)
+            .and_then(DateTime::<Utc>::from_timestamp_millis);
+        if let Some(timestamp) = timestamp {
+            // token usage records are the ones with usage
+            let is_tokens = record.contains_key("usage");
+            let table_cutoff = if is_tokens {
+                cutoffs.tokens
+            } else {
+                cutoffs.access
+            };
+            if matches!(table_cutoff, Some(cutoff) if timestamp < cutoff) {
+                compacted.dropped += 1;
+                continue;
+            }
+            if !is_tokens {
+                let before = compacted.cleared;
+                for (column, cutoff) in cutoffs.columns.iter() {
+                    if timestamp < *cutoff && record.remove(*column).is_some() {
+                        compacted.cleared += 1;
+                    }
+                }
+                if compacted.cleared != before {
+                    compacted.kept += 1;
+                    serde_json::to_writer(&mut writer, &record)?;
+                    writer.write_all(b"\n")?;
+                    continue;
+                }
+            }
+        }
+        compacted.kept += 1;
+        writer.write_all(&line)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(compacted)
+}
+
+/// Compact a rotated file in place, keeping its compression.
+fn compact_rotated(path: &Path, cutoffs: &Cutoffs) -> io::Result<()> {
+    let Some(compression) = compression_of(path) else {
+        event!(
+            Level::WARN,
+            "cannot compact {}, its compression is not enabled in this build",
+            path.display()
+        );
+        return Ok(());
+    };
+    let compacted = compact_records(cutoffs, decoder(path, compression)?, io::sink())?;
+    if !compacted.changed() {
+        return Ok(());
+    }
+    if compacted.kept == 0 {
+        event!(Level::INFO, "removing expired audit log {}", path.display());
+        return std::fs::remove_file(path);
+    }
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".compact");
+    let mut encoder = Encoder::create(Path::new(&temp), compression)?;
+    compact_records(cutoffs, decoder(path, compression)?, &mut encoder)?;
+    encoder.finish()?;
+    std::fs::rename(&temp, path)?;
+    event!(
+        Level::INFO,
+        "compacted {}, dropped {} records and cleared {} columns",
+        path.display(),
+        compacted.dropped,
+        compacted.cleared
+    );
+    Ok(())
+}
+
+/// Compression of a rotated file by its extension, unknown if not enabled in this build.
+fn compression_of(path: &Path) -> Option<FileCompression> {
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    #[cfg(feature = "log-gzip")]
+    if extension == Some("gz") {
+        return Some(FileCompression::Gzip);
+    }
+    #[cfg(feature = "log-zstd")]
+    if extension == Some("zst") {
+        return Some(FileCompression::Zstd);
+    }
+    match extension {
+        Some("gz" | "zst") => None,
+        _ => Some(FileCompression::None),
+    }
+}
+
+fn decoder(path: &Path, compression: FileCompression) -> io::Result<Box<dyn BufRead>> {
+    let file = std::fs::File::open(path)?;
+    Ok(match compression {
+        FileCompression::None => Box::new(io::BufReader::new(file)),
+        #[cfg(feature = "log-gzip")]
+        FileCompression::Gzip => Box::new(io::BufReader::new(flate2::read::GzDecoder::new(file))),
+        #[cfg(feature = "log-zstd")]
+        FileCompression::Zstd => {
+            Box::new(io::BufReader::new(zstd::stream::read::Decoder::new(file)?))
+        }
+    })
+}
+
+/// Writer of a compacted rotated file.
+enum Encoder {
+    None(io::BufWriter<std::fs::File>),
+    #[cfg(feature = "log-gzip")]
+    Gzip(flate2::write::GzEncoder<std::fs::File>),
+    #[cfg(feature = "log-zstd")]
+    Zstd(zstd::stream::write::Encoder<'static, std::fs::File>),
+}
+
+impl Encoder {
+    fn create(path: &Path, compression: FileCompression) -> io::Result<Self> {
+        let file = std::fs::File::create(path)?;
+        Ok(match compression {
+            FileCompression::None => Encoder::None(io::BufWriter::new(file)),
+            #[cfg(feature = "log-gzip")]
+            FileCompression::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                file,
+                flate2::Compression::default(),
+            )),
+            #[cfg(feature = "log-zstd")]
+            FileCompression::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(file, 0)?),
+        })
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Encoder::None(mut writer) => writer.flush(),
+            #[cfg(feature = "log-gzip")]
+            Encoder::Gzip(encoder) => encoder.finish().map(|_| ()),
+            #[cfg(feature = "log-zstd")]
+            Encoder::Zstd(encoder) => encoder.finish().map(|_| ()),
+        }
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::None(writer) => writer.write(buf),
+            #[cfg(feature = "log-gzip")]
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            #[cfg(feature = "log-zstd")]
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::None(writer) => writer.flush(),
+            #[cfg(feature = "log-gzip")]
+            Encoder::Gzip(encoder) => encoder.flush(),
+            #[cfg(feature = "log-zstd")]
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Rotated files of `filename`, i.e. `{filename}.{timestamp}` with an optional compression suffix.
+fn rotated_files(filename: &Path) -> io::Result<Vec<PathBuf>> {
+    let dir = match filename.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = format!(
+        "{}.",
+        filename.file_name().unwrap_or_default().to_string_lossy()
+    );
+    Ok(std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_prefix(&prefix))
+                .is_some_and(|suffix| {
+                    // files being compacted are not rotated files
+                    suffix.starts_with(|c: char| c.is_ascii_digit())
+                        && !suffix.ends_with(".compact")
+                })
+        })
+        .collect())
+}
+
+/// Remove the oldest rotated files of `filename`, keeping `max_files` of them.
+fn prune(filename: &Path, max_files: usize) -> io::Result<()> {
+    let mut rotated = rotated_files(filename)?;
+    if rotated.len() <= max_files {
+        return Ok(());
+    }
+    // timestamps sort lexicographically
+    rotated.sort();
+    for path in rotated.iter().take(rotated.len() - max_files) {
+        event!(Level::INFO, "removing rotated audit log {}", path.display());
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AuditColumn;
+    use chrono::Duration;
+    use std::collections::HashMap;
+
+    fn retention() -> AuditRetentionConfig {
+        AuditRetentionConfig {
+            access_log: Some(30),
+            tokens_log: Some(90),
+            columns: HashMap::from([(AuditColumn::Body, 7)]),
+            ..Default::default()
+        }
+    }
+
+    fn records(now: DateTime<Utc>) -> String {
+        let at = |days: i64| (now - Duration::days(days)).timestamp_millis();
+        [
+            format!(r#"{{"timestamp":{},"path":"/old","body":"x"}}"#, at(40)),
+            format!(r#"{{"timestamp":{},"path":"/week","body":"x"}}"#, at(10)),
+            format!(r#"{{"timestamp":{},"path":"/new","body":"x"}}"#, at(1)),
+            format!(r#"{{"timestamp":{},"usage":{{"total_tokens":1}}}}"#, at(40)),
+            format!(
+                r#"{{"timestamp":{},"usage":{{"total_tokens":1}}}}"#,
+                at(100)
+            ),
+        ]
+        .map(|line| line + "\n")
+        .concat()
+    }
+
+    #[test]
+    fn compact_records_by_table_and_column() {
+        let now = Utc::now();
+        let cutoffs = Cutoffs::new(&retention(), now);
+        let mut out = vec![];
+        let compacted = compact_records(&cutoffs, records(now).as_bytes(), &mut out).unwrap();
+        assert_eq!(
+            (compacted.kept, compacted.dropped, compacted.cleared),
+            (3, 2, 1)
+        );
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(lines[0].contains("/week") && !lines[0].contains("body"));
+        assert!(lines[1].contains("/new") && lines[1].contains("body"));
+        assert!(lines[2].contains("usage"));
+    }
+
+    #[test]
+    fn compact_rotated_files() {
+        let dir = std::env::temp_dir().join(format!("openai-hub-compact-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let now = Utc::now();
+        let cutoffs = Cutoffs::new(&retention(), now);
+
+        let kept = dir.join("access.log.20260101-000000");
+        std::fs::write(&kept, records(now)).unwrap();
+        compact_rotated(&kept, &cutoffs).unwrap();
+        assert_eq!(std::fs::read_to_string(&kept).unwrap().lines().count(), 3);
+
+        let expired = dir.join("access.log.20250101-000000");
+        let old = (now - Duration::days(100)).timestamp_millis();
+        std::fs::write(
+            &expired,
+            format!("{{\"timestamp\":{old},\"path\":\"/\"}}\n"),
+        )
+        .unwrap();
+        compact_rotated(&expired, &cutoffs).unwrap();
+        assert!(!expired.exists());
+
+        assert_eq!(rotated_files(&dir.join("access.log")).unwrap(), vec![kept]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "log-gzip")]
+    #[test]
+    fn compact_compressed_rotated_file() {
+        let dir =
+            std::env::temp_dir().join(format!("openai-hub-compact-gz-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let now = Utc::now();
+        let path = dir.join("access.log.20260101-000000");
+        std::fs::write(&path, records(now)).unwrap();
+        compress(&path, FileCompression::Gzip).unwrap();
+        let path = dir.join("access.log.20260101-000000.gz");
+        compact_rotated(&path, &Cutoffs::new(&retention(), now)).unwrap();
+        let mut out = String::new();
+        decoder(&path, FileCompression::Gzip)
+            .unwrap()
+            .read_to_string(&mut out)
+            .unwrap();
+        assert_eq!(out.lines().count(), 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,241 @@
+use super::BackendCreationError;
+
+/// A versioned schema migration, applied in a transaction where the database allows.
+///
+/// MySQL commits every DDL statement at once, a migration failing there has to be
+/// completed by hand before the hub starts again.
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    sql: &'static str,
+}
+
+impl Migration {
+    pub fn statements(&self) -> impl Iterator<Item = &'static str> {
+        self.sql.split(';').map(str::trim).filter(|s| !s.is_empty())
+    }
+}
+
+pub const SCHEMA_VERSION_DDL: &str = r#"CREATE TABLE IF NOT EXISTS schema_version (
+    version BIGINT PRIMARY KEY,
+    description VARCHAR(255) NOT NULL,
+    applied_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+)"#;
+
+pub const SQLITE: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "init",
+        sql: include_str!("../../migrations/sqlite/0001_init.sql"),
+    },
+    Migration {
+        version: 2,
+        description: "filters and client ip",
+        sql: include_str!("../../migrations/sqlite/0002_filters_and_client_ip.sql"),
+    },
+    Migration {
+        version: 3,
+        description: "indexes",
+        sql: include_str!("../../migrations/sqlite/0003_indexes.sql"),
+    },
+    Migration {
+        version: 4,
+        description: "latency and upstream",
+        sql: include_str!("../../migrations/sqlite/0004_latency_and_upstream.sql"),
+    },
+    Migration {
+        version: 5,
+        description: "usage units",
+        sql: include_str!("../../migrations/sqlite/0005_usage_units.sql"),
+    },
+];
+
+pub const MYSQL: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "init",
+        sql: include_str!("../../migrations/mysql/0001_init.sql"),
+    },
+    Migration {
+        version: 2,
+        description: "filters and client ip",
+        sql: include_str!("../../migrations/mysql/0002_filters_and_client_ip.sql"),
+    },
+    Migration {
+        version: 3,
+        description: "indexes",
+        sql: include_str!("../../migrations/mysql/0003_indexes.sql"),
+    },
+    Migration {
+        version: 4,
+        description: "bigint ids",
+        sql: include_str!("../../migrations/mysql/0004_bigint_ids.sql"),
+    },
+    Migration {
+        version: 5,
+        description: "latency and upstream",
+        sql: include_str!("../../migrations/mysql/0005_latency_and_upstream.sql"),
+    },
+    Migration {
+        version: 6,
+        description: "usage units",
+        sql: include_str!("../../migrations/mysql/0006_usage_units.sql"),
+    },
+];
+
+pub const POSTGRES: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "init",
+        sql: include_str!("../../migrations/postgres/0001_init.sql"),
+    },
+    Migration {
+        version: 2,
+        description: "filters and client ip",
+        sql: include_str!("../../migrations/postgres/0002_filters_and_client_ip.sql"),
+    },
+    Migration {
+        version: 3,
+        description: "indexes",
+        sql: include_str!("../../migrations/postgres/0003_indexes.sql"),
+    },
+    Migration {
+        version: 4,
+        description: "bigint ids",
+        sql: include_str!("../../migrations/postgres/0004_bigint_ids.sql"),
+    },
+    Migration {
+        version: 5,
+        description: "latency and upstream",
+        sql: include_str!("../../migrations/postgres/0005_latency_and_upstream.sql"),
+    },
+    Migration {
+        version: 6,
+        description: "usage units",
+        sql: include_str!("../../migrations/postgres/0006_usage_units.sql"),
+    },
+];
+
+/// Version of the schema the hub created before migrations were versioned, once the
+/// columns it added one by one are there
+pub const UNVERSIONED: i64 = 2;
+
+/// Columns added to the access log table before migrations were versioned, by the hub
+/// versions that had the feature
+pub const SQLITE_UNVERSIONED_COLUMNS: &[(&str, &str)] = &[
+    ("content_filter", "TEXT"),
+    ("moderation", "TEXT"),
+    ("client_ip", "TEXT"),
+];
+pub const UNVERSIONED_COLUMNS: &[(&str, &str)] = &[
+    ("content_filter", "TEXT"),
+    ("moderation", "TEXT"),
+    ("client_ip", "VARCHAR(45)"),
+];
+
+/// Refuse to run against a schema written by a newer version of the hub.
+pub fn check_version(current: i64, migrations: &[Migration]) -> Result<(), BackendCreationError> {
+    let latest = migrations.last().map(|m| m.version).unwrap_or(0);
+    if current > latest {
+        return Err(BackendCreationError::UnsupportedSchema(current, latest));
+    }
+    Ok(())
+}
+
+/// Statements taking and releasing the session lock held while migrating
+pub const MYSQL_LOCK: (&str, &str) = (
+    "SELECT GET_LOCK('openai_hub_migrate', -1)",
+    "SELECT RELEASE_LOCK('openai_hub_migrate')",
+);
+pub const POSTGRES_LOCK: (&str, &str) = (
+    "SELECT pg_advisory_lock(8030029660356700277)",
+    "SELECT pg_advisory_unlock(8030029660356700277)",
+);
+
+/// Bring the schema of a pool up to date, `$insert` records an applied migration.
+///
+/// A schema created before migrations were versioned is adopted as version
+/// [`UNVERSIONED`], after adding the `$columns` it misses.
+///
+/// Replicas starting at once migrate one after another, when `$lock` holds the statements
+/// taking and releasing a session lock around the migrations.
+macro_rules! migrate {
+    ($pool:expr, $migrations:expr, $columns:expr, $insert:literal) => {
+        migrate!($pool, $migrations, $columns, $insert, None::<(&str, &str)>)
+    };
+    ($pool:expr, $migrations:expr, $columns:expr, $insert:literal, $lock:expr) => {{
+        let lock = $lock;
+        let mut conn = $pool.acquire().await?;
+        if let Some((lock, _)) = lock {
+            sqlx::query(lock).execute(&mut *conn).await?;
+        }
+        let migrated: Result<(), BackendCreationError> = async {
+            sqlx::query($crate::audit::migrations::SCHEMA_VERSION_DDL)
+                .execute(&mut *conn)
+                .await?;
+            let current: Option<i64> =
+                sqlx::query_scalar("SELECT MAX(version) FROM schema_version")
+                    .fetch_one(&mut *conn)
+                    .await?;
+            let mut current = current.unwrap_or(0);
+            let unversioned = current == 0
+                && sqlx::query("SELECT 1 FROM audit_log LIMIT 0")
+                    .execute(&mut *conn)
+                    .await
+                    .is_ok();
+            if unversioned {
+                for (column, ty) in $columns.iter() {
+                    let probe = format!("SELECT {column} FROM audit_log LIMIT 0");
+                    if sqlx::query(&probe).execute(&mut *conn).await.is_err() {
+                        let add = format!("ALTER TABLE audit_log ADD COLUMN {column} {ty}");
+                        sqlx::query(&add).execute(&mut *conn).await?;
+                    }
+                }
+                current = $crate::audit::migrations::UNVERSIONED;
+                for migration in $migrations.iter().filter(|m| m.version <= current) {
+                    sqlx::query($insert)
+                        .bind(migration.version)
+                        .bind(migration.description)
+                        .execute(&mut *conn)
+                        .await?;
+                }
+                event!(
+                    Level::INFO,
+                    "adopted unversioned audit schema as version {}",
+                    current
+                );
+            }
+            $crate::audit::migrations::check_version(current, $migrations)?;
+            for migration in $migrations.iter().filter(|m| m.version > current) {
+                let mut tx = sqlx::Connection::begin(&mut *conn).await?;
+                for statement in migration.statements() {
+                    sqlx::query(statement).execute(&mut *tx).await?;
+                }
+                sqlx::query($insert)
+                    .bind(migration.version)
+                    .bind(migration.description)
+                    .execute(&mut *tx)
+                    .await?;
+                tx.commit().await?;
+                event!(
+                    Level::INFO,
+                    "applied audit schema migration {} ({})",
+                    migration.version,
+                    migration.description
+                );
+            }
+            Ok(())
+        }
+        .await;
+        if let Some((_, unlock)) = lock {
+            if let Err(e) = sqlx::query(unlock).execute(&mut *conn).await {
+                // the lock is released with the connection
+                drop(conn.detach());
+                return Err(e.into());
+            }
+        }
+        migrated
+    }};
+}
+
+pub(crate) use migrate;
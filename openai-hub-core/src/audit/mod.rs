@@ -0,0 +1,865 @@
+use crate::config::{AuditBackendType, AuditConfig, AuditRetentionConfig};
+use base64::engine::general_purpose;
+use base64::Engine;
+use chrono::serde::ts_milliseconds;
+use rand::distributions::{Alphanumeric, DistString};
+use rand::thread_rng;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sqlx::mysql::MySqlConnectOptions;
+use sqlx::postgres::PgConnectOptions;
+use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::{ConnectOptions, Connection, MySql, Pool, Postgres, QueryBuilder, Sqlite};
+use std::collections::BTreeMap;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::{event, Level};
+
+mod file;
+mod migrations;
+#[cfg(feature = "jwt-auth")]
+mod query;
+mod retention;
+mod writer;
+
+use file::LogFile;
+use migrations::migrate;
+#[cfg(feature = "jwt-auth")]
+pub use query::{AccessLogEntry, AccessLogFilter, QueryError, UsageEntry, UsageFilter};
+pub use retention::spawn_retention;
+use retention::{purge, PurgeTask};
+pub use writer::AuditWriter;
+
+#[async_trait::async_trait]
+pub trait BackendEngine {
+    async fn init(&self) -> Result<(), BackendCreationError> {
+        Ok(())
+    }
+    /// Write the records, returning the ones that could not be written.
+    async fn log_batch(&self, records: Vec<AuditRecord>) -> Result<(), Vec<AuditRecord>>;
+    /// Delete or clear the records expired according to the retention policy.
+    async fn purge(&self, retention: &AuditRetentionConfig);
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum AuditRecord {
+    Access(Box<AccessLog>),
+    Tokens(TokenUsageLog),
+}
+
+impl<'de> Deserialize<'de> for AuditRecord {
+    /// Read back a spilled record, usage records have required fields access records lack.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Spilled {
+            Tokens(TokenUsageLog),
+            Access(Box<AccessLog>),
+        }
+        Ok(match Spilled::deserialize(deserializer)? {
+            Spilled::Tokens(log) => AuditRecord::Tokens(log),
+            Spilled::Access(log) => AuditRecord::Access(log),
+        })
+    }
+}
+
+impl AuditRecord {
+    fn partition(records: Vec<AuditRecord>) -> (Vec<AccessLog>, Vec<TokenUsageLog>) {
+        let mut access = vec![];
+        let mut tokens = vec![];
+        for record in records {
+            match record {
+                AuditRecord::Access(log) => access.push(*log),
+                AuditRecord::Tokens(log) => tokens.push(log),
+            }
+        }
+        (access, tokens)
+    }
+}
+
+#[derive(Default, Debug, Serialize, Deserialize)]
+pub struct AccessLog {
+    #[serde(with = "ts_milliseconds")]
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    pub ray_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub headers: Option<BTreeMap<String, String>>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "might_as_base64_option",
+        deserialize_with = "string_as_bytes_option"
+    )]
+    pub body: Option<Vec<u8>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_headers: Option<BTreeMap<String, String>>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "might_as_base64_option",
+        deserialize_with = "string_as_bytes_option"
+    )]
+    pub response_body: Option<Vec<u8>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_filter: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub moderation: Option<Vec<String>>,
+    /// Milliseconds until the response was finished
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    /// Milliseconds until the first byte of the response body
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttfb_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream_status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream_request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_fingerprint: Option<String>,
+}
+
+impl AccessLog {
+    pub fn now() -> Self {
+        let ray_id = Alphanumeric.sample_string(&mut thread_rng(), 16);
+        Self {
+            timestamp: chrono::Utc::now(),
+            ray_id,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_timing(&mut self, total: Duration, ttfb: Option<Duration>) {
+        self.latency_ms = Some(total.as_millis() as u64);
+        self.ttfb_ms = ttfb.map(|ttfb| ttfb.as_millis() as u64);
+    }
+
+    fn body_as_string(&self) -> Option<String> {
+        self.body.as_ref().map(|b| {
+            String::from_utf8(b.clone()).unwrap_or_else(|_| general_purpose::STANDARD.encode(b))
+        })
+    }
+
+    fn response_body_as_string(&self) -> Option<String> {
+        self.response_body.as_ref().map(|b| {
+            String::from_utf8(b.clone()).unwrap_or_else(|_| general_purpose::STANDARD.encode(b))
+        })
+    }
+
+    fn content_filter_as_string(&self) -> Option<String> {
+        self.content_filter
+            .as_ref()
+            .map(|rules| serde_json::to_string(rules).unwrap())
+    }
+
+    fn moderation_as_string(&self) -> Option<String> {
+        self.moderation
+            .as_ref()
+            .map(|categories| serde_json::to_string(categories).unwrap())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TokenUsageLog {
+    #[serde(with = "ts_milliseconds")]
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    pub ray_id: String,
+    pub model: String,
+    /// Token usage, zero for other units
+    pub usage: TokenUsage,
+    pub unit: UsageUnit,
+    /// Consumption in `unit`, the total tokens for tokens
+    pub quantity: f64,
+    /// Billing relevant request details, e.g. the size and quality of images
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<String>,
+    pub is_estimated: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TokenUsage {
+    pub prompt_tokens: usize,
+    /// Missing from embeddings responses
+    #[serde(default)]
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UsageUnit {
+    #[default]
+    Tokens,
+    Images,
+    Seconds,
+    Characters,
+}
+
+impl UsageUnit {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UsageUnit::Tokens => "tokens",
+            UsageUnit::Images => "images",
+            UsageUnit::Seconds => "seconds",
+            UsageUnit::Characters => "characters",
+        }
+    }
+}
+
+/// `user` is a reserved word in Postgres, so it has to be quoted there.
+fn access_log_insert(user: &str) -> String {
+    format!("INSERT INTO audit_log (timestamp, ray_id, {user}, method, uri, headers, body, response_status, response_headers, response_body, content_filter, moderation, client_ip, latency_ms, ttfb_ms, upstream_status, upstream_request_id, key_fingerprint) ")
+}
+
+fn tokens_log_insert(user: &str) -> String {
+    format!("INSERT INTO tokens_log (timestamp, ray_id, {user}, model, is_estimated, prompt_tokens, completion_tokens, total_tokens, unit, quantity, details) ")
+}
+
+/// Binds of an access log row
+const ACCESS_LOG_COLUMNS: usize = 18;
+/// Binds of a tokens log row
+const TOKENS_LOG_COLUMNS: usize = 11;
+/// Maximum binds of a SQLite statement, `SQLITE_MAX_VARIABLE_NUMBER` since 3.32
+const SQLITE_MAX_BINDS: usize = 32766;
+/// Maximum binds of a MySQL or Postgres statement
+const MAX_BINDS: usize = 65535;
+
+/// Take the first logs fitting into a single insert statement.
+fn take_rows<T>(logs: &mut Vec<T>, max_binds: usize, columns: usize) -> Vec<T> {
+    let rows = logs.len().min(max_binds / columns);
+    logs.drain(..rows).collect()
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BackendCreationError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    DatabaseError(#[from] sqlx::Error),
+    #[error("audit database schema version {0} is newer than the supported version {1}")]
+    UnsupportedSchema(i64, i64),
+}
+
+#[derive(Clone)]
+pub enum Backend {
+    Text(TextBackend),
+    Database(DatabaseBackend),
+}
+
+impl Backend {
+    pub async fn create_with(config: &AuditConfig) -> Result<Self, BackendCreationError> {
+        let this = match config.backend {
+            AuditBackendType::File => Self::Text(TextBackend::create_with(config).await?),
+            _ => Self::Database(DatabaseBackend::create_with(config).await?),
+        };
+        this.init().await?;
+        Ok(this)
+    }
+
+    /// Connect to the database of the backend, without creating or migrating anything.
+    pub async fn ping(config: &AuditConfig) -> Result<(), sqlx::Error> {
+        let backends = &config.backends;
+        match config.backend {
+            AuditBackendType::File => Ok(()),
+            AuditBackendType::Sqlite => {
+                let sqlite = &backends.sqlite_backend;
+                // created on start
+                if sqlite.create_if_missing && !std::path::Path::new(&sqlite.filename).exists() {
+                    return Ok(());
+                }
+                SqliteConnectOptions::from(sqlite)
+                    .create_if_missing(false)
+                    .connect()
+                    .await?
+                    .close()
+                    .await
+            }
+            AuditBackendType::Mysql => {
+                MySqlConnectOptions::from(&backends.mysql_backend)
+                    .connect()
+                    .await?
+                    .close()
+                    .await
+            }
+            AuditBackendType::Postgres => {
+                PgConnectOptions::from(&backends.postgres_backend)
+                    .connect()
+                    .await?
+                    .close()
+                    .await
+            }
+        }
+    }
+
+    /// Check that records can be written to the backend.
+    pub async fn check(&self) -> Result<(), String> {
+        match self {
+            Self::Text(backend) => backend.check().await,
+            Self::Database(backend) => backend.check().await,
+        }
+    }
+
+    /// Flush the log file or close the database connections, on shutdown.
+    pub async fn close(&self) {
+        match self {
+            Self::Text(backend) => backend.close().await,
+            Self::Database(backend) => backend.close().await,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BackendEngine for Backend {
+    async fn init(&self) -> Result<(), BackendCreationError> {
+        match self {
+            Self::Text(backend) => backend.init().await,
+            Self::Database(backend) => backend.init().await,
+        }
+    }
+
+    async fn log_batch(&self, records: Vec<AuditRecord>) -> Result<(), Vec<AuditRecord>> {
+        match self {
+            Backend::Text(backend) => backend.log_batch(records).await,
+            Backend::Database(backend) => backend.log_batch(records).await,
+        }
+    }
+
+    async fn purge(&self, retention: &AuditRetentionConfig) {
+        match self {
+            Backend::Text(backend) => backend.purge(retention).await,
+            Backend::Database(backend) => backend.purge(retention).await,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TextBackend {
+    writer: Arc<Mutex<LogFile>>,
+}
+
+impl TextBackend {
+    async fn create_with(config: &AuditConfig) -> Result<Self, BackendCreationError> {
+        let writer = Arc::new(Mutex::new(
+            LogFile::open(&config.backends.file_backend).await?,
+        ));
+
+        // reopen the file on SIGHUP, so that external tools like logrotate can move it away
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut hangup = signal(SignalKind::hangup())?;
+            let writer = writer.clone();
+            tokio::spawn(async move {
+                while hangup.recv().await.is_some() {
+                    match writer.lock().await.reopen().await {
+                        Ok(_) => event!(Level::INFO, "reopened audit log file"),
+                        Err(e) => event!(
+                            Level::ERROR,
+                            error = ?e,
+                            "Failed to reopen audit log file"
+                        ),
+                    }
+                }
+            });
+        }
+
+        Ok(Self { writer })
+    }
+
+    async fn check(&self) -> Result<(), String> {
+        self.writer
+            .lock()
+            .await
+            .check()
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn close(&self) {
+        if let Err(e) = self.writer.lock().await.flush().await {
+            event!(Level::ERROR, error = ?e, "Failed to flush audit log file");
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BackendEngine for TextBackend {
+    async fn log_batch(&self, records: Vec<AuditRecord>) -> Result<(), Vec<AuditRecord>> {
+        let mut vec = vec![];
+        for record in records.iter() {
+            serde_json::to_writer(&mut vec, record).unwrap();
+            vec.push(b'\n');
+        }
+        let mut writer = self.writer.lock().await;
+        if let Err(e) = writer.write(&vec).await {
+            event!(
+                Level::ERROR,
+                error = ?e,
+                "Failed to write audit log to file"
+            );
+            return Err(records);
+        }
+        Ok(())
+    }
+
+    async fn purge(&self, retention: &AuditRetentionConfig) {
+        if let Err(e) = LogFile::compact(&self.writer, retention).await {
+            event!(
+                Level::ERROR,
+                error = ?e,
+                "Failed to compact audit log file"
+            );
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum DatabaseBackend {
+    Sqlite(Pool<Sqlite>),
+    MySql(Pool<MySql>),
+    Postgres(Pool<Postgres>),
+}
+
+impl DatabaseBackend {
+    async fn create_with(config: &AuditConfig) -> Result<Self, BackendCreationError> {
+        Ok(match config.backend {
+            AuditBackendType::Sqlite => {
+                Self::Sqlite(Pool::connect_with((&config.backends.sqlite_backend).into()).await?)
+            }
+            AuditBackendType::Mysql => {
+                Self::MySql(Pool::connect_with((&config.backends.mysql_backend).into()).await?)
+            }
+            AuditBackendType::Postgres => Self::Postgres(
+                Pool::connect_with((&config.backends.postgres_backend).into()).await?,
+            ),
+            _ => unreachable!(),
+        })
+    }
+
+    async fn check(&self) -> Result<(), String> {
+        // takes the write lock without changing anything, fails on read-only databases
+        let sql = "UPDATE schema_version SET version = version WHERE 1 = 0";
+        let result = match self {
+            Self::Sqlite(pool) => sqlx::query(sql).execute(pool).await.map(|_| ()),
+            Self::MySql(pool) => sqlx::query(sql).execute(pool).await.map(|_| ()),
+            Self::Postgres(pool) => sqlx::query(sql).execute(pool).await.map(|_| ()),
+        };
+        result.map_err(|e| e.to_string())
+    }
+
+    async fn close(&self) {
+        match self {
+            Self::Sqlite(pool) => pool.close().await,
+            Self::MySql(pool) => pool.close().await,
+            Self::Postgres(pool) => pool.close().await,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BackendEngine for DatabaseBackend {
+    async fn init(&self) -> Result<(), BackendCreationError> {
+        match self {
+            Self::Sqlite(pool) => pool.init().await,
+            Self::MySql(pool) => pool.init().await,
+            Self::Postgres(pool) => pool.init().await,
+        }
+    }
+
+    async fn log_batch(&self, records: Vec<AuditRecord>) -> Result<(), Vec<AuditRecord>> {
+        match self {
+            DatabaseBackend::Sqlite(pool) => pool.log_batch(records).await,
+            DatabaseBackend::MySql(pool) => pool.log_batch(records).await,
+            DatabaseBackend::Postgres(pool) => pool.log_batch(records).await,
+        }
+    }
+
+    async fn purge(&self, retention: &AuditRetentionConfig) {
+        match self {
+            DatabaseBackend::Sqlite(pool) => pool.purge(retention).await,
+            DatabaseBackend::MySql(pool) => pool.purge(retention).await,
+            DatabaseBackend::Postgres(pool) => pool.purge(retention).await,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BackendEngine for Pool<Sqlite> {
+    async fn init(&self) -> Result<(), BackendCreationError> {
+        migrate!(
+            self,
+            migrations::SQLITE,
+            migrations::SQLITE_UNVERSIONED_COLUMNS,
+            "INSERT INTO schema_version (version, description) VALUES (?, ?)"
+        )
+    }
+
+    async fn purge(&self, retention: &AuditRetentionConfig) {
+        purge!(self, retention, |task: &PurgeTask| task
+            .sql(false, ("?", "?")))
+    }
+
+    async fn log_batch(&self, records: Vec<AuditRecord>) -> Result<(), Vec<AuditRecord>> {
+        let (mut access, mut tokens) = AuditRecord::partition(records);
+        let mut failed = vec![];
+        while !access.is_empty() {
+            let rows = take_rows(&mut access, SQLITE_MAX_BINDS, ACCESS_LOG_COLUMNS);
+            let mut query = QueryBuilder::<Sqlite>::new(access_log_insert("\"user\""));
+            query.push_values(rows.iter(), |mut row, log| {
+                let body = log.body_as_string();
+                let response_body = log.response_body_as_string();
+                let content_filter = log.content_filter_as_string();
+                let moderation = log.moderation_as_string();
+                row.push_bind(log.timestamp)
+                    .push_bind(log.ray_id.as_str())
+                    .push_bind(log.user.as_deref())
+                    .push_bind(log.method.as_deref())
+                    .push_bind(log.uri.as_deref())
+                    .push_bind(serde_json::to_string(&log.headers).unwrap())
+                    .push_bind(body)
+                    .push_bind(log.response_status)
+                    .push_bind(serde_json::to_string(&log.response_headers).unwrap())
+                    .push_bind(response_body)
+                    .push_bind(content_filter)
+                    .push_bind(moderation)
+                    .push_bind(log.client_ip.as_deref())
+                    .push_bind(log.latency_ms.map(|ms| ms as i64))
+                    .push_bind(log.ttfb_ms.map(|ms| ms as i64))
+                    .push_bind(log.upstream_status)
+                    .push_bind(log.upstream_request_id.as_deref())
+                    .push_bind(log.key_fingerprint.as_deref());
+            });
+            let result = query.build().execute(self).await;
+            if let Err(e) = result {
+                event!(
+                    Level::ERROR,
+                    error = ?e,
+                    "Failed to write access log to sqlite"
+                );
+                failed.extend(
+                    rows.into_iter()
+                        .map(|log| AuditRecord::Access(Box::new(log))),
+                );
+            }
+        }
+        while !tokens.is_empty() {
+            let rows = take_rows(&mut tokens, SQLITE_MAX_BINDS, TOKENS_LOG_COLUMNS);
+            let mut query = QueryBuilder::<Sqlite>::new(tokens_log_insert("\"user\""));
+            query.push_values(rows.iter(), |mut row, log| {
+                row.push_bind(log.timestamp)
+                    .push_bind(log.ray_id.as_str())
+                    .push_bind(log.user.as_deref())
+                    .push_bind(log.model.as_str())
+                    .push_bind(log.is_estimated)
+                    .push_bind(log.usage.prompt_tokens as u32)
+                    .push_bind(log.usage.completion_tokens as u32)
+                    .push_bind(log.usage.total_tokens as u32)
+                    .push_bind(log.unit.as_str())
+                    .push_bind(log.quantity)
+                    .push_bind(log.details.as_deref());
+            });
+            let result = query.build().execute(self).await;
+            if let Err(e) = result {
+                event!(
+                    Level::ERROR,
+                    error = ?e,
+                    "Failed to write tokens log to sqlite"
+                );
+                failed.extend(rows.into_iter().map(AuditRecord::Tokens));
+            }
+        }
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(failed)
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BackendEngine for Pool<MySql> {
+    async fn init(&self) -> Result<(), BackendCreationError> {
+        migrate!(
+            self,
+            migrations::MYSQL,
+            migrations::UNVERSIONED_COLUMNS,
+            "INSERT INTO schema_version (version, description) VALUES (?, ?)",
+            Some(migrations::MYSQL_LOCK)
+        )
+    }
+
+    async fn purge(&self, retention: &AuditRetentionConfig) {
+        purge!(self, retention, |task: &PurgeTask| task
+            .sql(true, ("?", "?")))
+    }
+
+    async fn log_batch(&self, records: Vec<AuditRecord>) -> Result<(), Vec<AuditRecord>> {
+        let (mut access, mut tokens) = AuditRecord::partition(records);
+        let mut failed = vec![];
+        while !access.is_empty() {
+            let rows = take_rows(&mut access, MAX_BINDS, ACCESS_LOG_COLUMNS);
+            let mut query = QueryBuilder::<MySql>::new(access_log_insert("user"));
+            query.push_values(rows.iter(), |mut row, log| {
+                let body = log.body_as_string();
+                let response_body = log.response_body_as_string();
+                let content_filter = log.content_filter_as_string();
+                let moderation = log.moderation_as_string();
+                row.push_bind(log.timestamp)
+                    .push_bind(log.ray_id.as_str())
+                    .push_bind(log.user.as_deref())
+                    .push_bind(log.method.as_deref())
+                    .push_bind(log.uri.as_deref())
+                    .push_bind(serde_json::to_string(&log.headers).unwrap())
+                    .push_bind(body)
+                    .push_bind(log.response_status)
+                    .push_bind(serde_json::to_string(&log.response_headers).unwrap())
+                    .push_bind(response_body)
+                    .push_bind(content_filter)
+                    .push_bind(moderation)
+                    .push_bind(log.client_ip.as_deref())
+                    .push_bind(log.latency_ms)
+                    .push_bind(log.ttfb_ms)
+                    .push_bind(log.upstream_status)
+                    .push_bind(log.upstream_request_id.as_deref())
+                    .push_bind(log.key_fingerprint.as_deref());
+            });
+            let result = query.build().execute(self).await;
+            if let Err(e) = result {
+                event!(
+                    Level::ERROR,
+                    error = ?e,
+                    "Failed to write access log to MySql"
+                );
+                failed.extend(
+                    rows.into_iter()
+                        .map(|log| AuditRecord::Access(Box::new(log))),
+                );
+            }
+        }
+        while !tokens.is_empty() {
+            let rows = take_rows(&mut tokens, MAX_BINDS, TOKENS_LOG_COLUMNS);
+            let mut query = QueryBuilder::<MySql>::new(tokens_log_insert("user"));
+            query.push_values(rows.iter(), |mut row, log| {
+                row.push_bind(log.timestamp)
+                    .push_bind(log.ray_id.as_str())
+                    .push_bind(log.user.as_deref())
+                    .push_bind(log.model.as_str())
+                    .push_bind(log.is_estimated)
+                    .push_bind(log.usage.prompt_tokens as u64)
+                    .push_bind(log.usage.completion_tokens as u64)
+                    .push_bind(log.usage.total_tokens as u64)
+                    .push_bind(log.unit.as_str())
+                    .push_bind(log.quantity)
+                    .push_bind(log.details.as_deref());
+            });
+            let result = query.build().execute(self).await;
+            if let Err(e) = result {
+                event!(
+                    Level::ERROR,
+                    error = ?e,
+                    "Failed to write tokens log to MySql"
+                );
+                failed.extend(rows.into_iter().map(AuditRecord::Tokens));
+            }
+        }
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(failed)
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BackendEngine for Pool<Postgres> {
+    async fn init(&self) -> Result<(), BackendCreationError> {
+        migrate!(
+            self,
+            migrations::POSTGRES,
+            migrations::UNVERSIONED_COLUMNS,
+            "INSERT INTO schema_version (version, description) VALUES ($1, $2)",
+            Some(migrations::POSTGRES_LOCK)
+        )
+    }
+
+    async fn purge(&self, retention: &AuditRetentionConfig) {
+        purge!(self, retention, |task: &PurgeTask| task
+            .sql(false, ("$1", "$2")))
+    }
+
+    async fn log_batch(&self, records: Vec<AuditRecord>) -> Result<(), Vec<AuditRecord>> {
+        let (mut access, mut tokens) = AuditRecord::partition(records);
+        let mut failed = vec![];
+        while !access.is_empty() {
+            let rows = take_rows(&mut access, MAX_BINDS, ACCESS_LOG_COLUMNS);
+            let mut query = QueryBuilder::<Postgres>::new(access_log_insert("\"user\""));
+            query.push_values(rows.iter(), |mut row, log| {
+                let body = log.body_as_string();
+                let response_body = log.response_body_as_string();
+                let content_filter = log.content_filter_as_string();
+                let moderation = log.moderation_as_string();
+                row.push_bind(log.timestamp)
+                    .push_bind(log.ray_id.as_str())
+                    .push_bind(log.user.as_deref())
+                    .push_bind(log.method.as_deref())
+                    .push_bind(log.uri.as_deref())
+                    .push_bind(serde_json::to_string(&log.headers).unwrap())
+                    .push_bind(body)
+                    .push_bind(log.response_status.map(|s| s as i16))
+                    .push_bind(serde_json::to_string(&log.response_headers).unwrap())
+                    .push_bind(response_body)
+                    .push_bind(content_filter)
+                    .push_bind(moderation)
+                    .push_bind(log.client_ip.as_deref())
+                    .push_bind(log.latency_ms.map(|ms| ms as i64))
+                    .push_bind(log.ttfb_ms.map(|ms| ms as i64))
+                    .push_bind(log.upstream_status.map(|s| s as i16))
+                    .push_bind(log.upstream_request_id.as_deref())
+                    .push_bind(log.key_fingerprint.as_deref());
+            });
+            let result = query.build().execute(self).await;
+            if let Err(e) = result {
+                event!(
+                    Level::ERROR,
+                    error = ?e,
+                    "Failed to write access log to Postgres"
+                );
+                failed.extend(
+                    rows.into_iter()
+                        .map(|log| AuditRecord::Access(Box::new(log))),
+                );
+            }
+        }
+        while !tokens.is_empty() {
+            let rows = take_rows(&mut tokens, MAX_BINDS, TOKENS_LOG_COLUMNS);
+            let mut query = QueryBuilder::<Postgres>::new(tokens_log_insert("\"user\""));
+            query.push_values(rows.iter(), |mut row, log| {
+                row.push_bind(log.timestamp)
+                    .push_bind(log.ray_id.as_str())
+                    .push_bind(log.user.as_deref())
+                    .push_bind(log.model.as_str())
+                    .push_bind(log.is_estimated)
+                    .push_bind(log.usage.prompt_tokens as i64)
+                    .push_bind(log.usage.completion_tokens as i64)
+                    .push_bind(log.usage.total_tokens as i64)
+                    .push_bind(log.unit.as_str())
+                    .push_bind(log.quantity)
+                    .push_bind(log.details.as_deref());
+            });
+            let result = query.build().execute(self).await;
+            if let Err(e) = result {
+                event!(
+                    Level::ERROR,
+                    error = ?e,
+                    "Failed to write tokens log to Postgres"
+                );
+                failed.extend(rows.into_iter().map(AuditRecord::Tokens));
+            }
+        }
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(failed)
+        }
+    }
+}
+
+fn might_as_base64_option<T, S>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: Deref<Target = [u8]>,
+    S: Serializer,
+{
+    value
+        .as_ref()
+        .map(|v| {
+            String::from_utf8(v.deref().to_vec())
+                .unwrap_or_else(|_| general_purpose::STANDARD.encode(v.deref()))
+        })
+        .serialize(serializer)
+}
+
+/// Bodies are kept as they were serialized, i.e. base64 encoded when not UTF-8, which is
+/// how they are written anyway.
+fn string_as_bytes_option<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<u8>>, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?.map(String::into_bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn log_batch_within_bind_limit() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        pool.init().await.unwrap();
+        // 18 binds per row, more than a single statement can take
+        let records = (0..SQLITE_MAX_BINDS / ACCESS_LOG_COLUMNS + 10)
+            .map(|_| AuditRecord::Access(Box::new(AccessLog::now())))
+            .collect::<Vec<_>>();
+        let len = records.len() as i64;
+        assert!(pool.log_batch(records).await.is_ok());
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM audit_log")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, len);
+    }
+
+    #[tokio::test]
+    async fn log_batch_returns_failed_records() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        // no tables
+        let records = vec![AuditRecord::Access(Box::new(AccessLog::now()))];
+        assert_eq!(pool.log_batch(records).await.unwrap_err().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn unversioned_schema_is_adopted() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        // as created by a hub with content filters but without versioned migrations
+        for statement in migrations::SQLITE[0].statements() {
+            sqlx::query(statement).execute(&pool).await.unwrap();
+        }
+        sqlx::query("ALTER TABLE audit_log ADD COLUMN content_filter TEXT")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        pool.init().await.unwrap();
+        pool.init().await.unwrap();
+        let version: i64 = sqlx::query_scalar("SELECT MAX(version) FROM schema_version")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(version, migrations::SQLITE.last().unwrap().version);
+        let records = vec![AuditRecord::Access(Box::new(AccessLog::now()))];
+        assert!(pool.log_batch(records).await.is_ok());
+    }
+}
@@ -0,0 +1,555 @@
+use super::{Backend, DatabaseBackend};
+use crate::config::ModelPricing;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{ColumnIndex, Database, Decode, Encode, QueryBuilder, Row, Type};
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct AccessLogFilter {
+    pub user: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    /// Exact path, or a prefix when ending with `*`
+    pub path: Option<String>,
+    pub status: Option<u16>,
+    pub ray_id: Option<String>,
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccessLogEntry {
+    pub id: i64,
+    pub timestamp: DateTime<Utc>,
+    pub ray_id: String,
+    pub user: Option<String>,
+    pub client_ip: Option<String>,
+    pub method: Option<String>,
+    pub uri: Option<String>,
+    pub response_status: Option<i64>,
+    pub upstream_status: Option<i64>,
+    pub latency_ms: Option<i64>,
+    pub ttfb_ms: Option<i64>,
+    pub upstream_request_id: Option<String>,
+    pub key_fingerprint: Option<String>,
+    pub content_filter: Option<String>,
+    pub moderation: Option<String>,
+    pub headers: Option<String>,
+    pub body: Option<String>,
+    pub response_headers: Option<String>,
+    pub response_body: Option<String>,
+}
+
+impl AccessLogEntry {
+    pub const COLUMNS: &'static [&'static str] = &[
+        "id",
+        "timestamp",
+        "ray_id",
+        "user",
+        "client_ip",
+        "method",
+        "uri",
+        "response_status",
+        "upstream_status",
+        "latency_ms",
+        "ttfb_ms",
+        "upstream_request_id",
+        "key_fingerprint",
+        "content_filter",
+        "moderation",
+        "headers",
+        "body",
+        "response_headers",
+        "response_body",
+    ];
+
+    pub fn to_record(&self) -> Vec<String> {
+        fn opt<T: ToString>(v: &Option<T>) -> String {
+            v.as_ref().map(|v| v.to_string()).unwrap_or_default()
+        }
+        vec![
+            self.id.to_string(),
+            self.timestamp.to_rfc3339(),
+            self.ray_id.clone(),
+            opt(&self.user),
+            opt(&self.client_ip),
+            opt(&self.method),
+            opt(&self.uri),
+            opt(&self.response_status),
+            opt(&self.upstream_status),
+            opt(&self.latency_ms),
+            opt(&self.ttfb_ms),
+            opt(&self.upstream_request_id),
+            opt(&self.key_fingerprint),
+            opt(&self.content_filter),
+            opt(&self.moderation),
+            opt(&self.headers),
+            opt(&self.body),
+            opt(&self.response_headers),
+            opt(&self.response_body),
+        ]
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct UsageFilter {
+    pub user: Option<String>,
+    pub model: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    /// Comma separated dimensions out of `user`, `model` and `day`, `user,model` by default
+    pub group_by: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct UsageEntry {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<Option<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub day: Option<String>,
+    /// Entries of different units are never merged
+    pub unit: String,
+    pub requests: i64,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub total_tokens: i64,
+    /// Consumption in `unit`
+    pub quantity: f64,
+    /// Cost in USD, unknown if any of the models has no pricing
+    pub cost: Option<f64>,
+}
+
+impl UsageEntry {
+    pub const COLUMNS: &'static [&'static str] = &[
+        "user",
+        "model",
+        "day",
+        "unit",
+        "requests",
+        "prompt_tokens",
+        "completion_tokens",
+        "total_tokens",
+        "quantity",
+        "cost",
+    ];
+
+    pub fn to_record(&self) -> Vec<String> {
+        vec![
+            self.user.clone().flatten().unwrap_or_default(),
+            self.model.clone().unwrap_or_default(),
+            self.day.clone().unwrap_or_default(),
+            self.unit.clone(),
+            self.requests.to_string(),
+            self.prompt_tokens.to_string(),
+            self.completion_tokens.to_string(),
+            self.total_tokens.to_string(),
+            self.quantity.to_string(),
+            self.cost.map(|cost| cost.to_string()).unwrap_or_default(),
+        ]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsageDimension {
+    User,
+    Model,
+    Day,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum QueryError {
+    #[error("queries are not supported by the file backend")]
+    Unsupported,
+    #[error("invalid group_by dimension `{0}`")]
+    InvalidDimension(String),
+    #[error(transparent)]
+    Database(#[from] sqlx::Error),
+}
+
+impl UsageFilter {
+    pub fn dimensions(&self) -> Result<Vec<UsageDimension>, QueryError> {
+        let group_by = self.group_by.as_deref().unwrap_or("user,model");
+        let mut dimensions = vec![];
+        for dimension in group_by.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let dimension = match dimension {
+                "user" => UsageDimension::User,
+                "model" => UsageDimension::Model,
+                "day" => UsageDimension::Day,
+                _ => return Err(QueryError::InvalidDimension(dimension.to_string())),
+            };
+            if !dimensions.contains(&dimension) {
+                dimensions.push(dimension);
+            }
+        }
+        Ok(dimensions)
+    }
+}
+
+/// SQL differences between the supported databases.
+struct Dialect {
+    user: &'static str,
+    bigint: &'static str,
+    double: &'static str,
+    day: &'static str,
+}
+
+const SQLITE: Dialect = Dialect {
+    user: "\"user\"",
+    bigint: "INTEGER",
+    double: "REAL",
+    day: "substr(timestamp, 1, 10)",
+};
+
+const MYSQL: Dialect = Dialect {
+    user: "user",
+    bigint: "SIGNED",
+    double: "DOUBLE",
+    day: "DATE_FORMAT(timestamp, '%Y-%m-%d')",
+};
+
+const POSTGRES: Dialect = Dialect {
+    user: "\"user\"",
+    bigint: "BIGINT",
+    double: "DOUBLE PRECISION",
+    day: "to_char(timestamp AT TIME ZONE 'UTC', 'YYYY-MM-DD')",
+};
+
+const DEFAULT_LIMIT: u32 = 100;
+const MAX_LIMIT: u32 = 1000;
+
+impl AccessLogFilter {
+    fn limit(&self) -> u32 {
+        self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccessLogPage {
+    pub data: Vec<AccessLogEntry>,
+    /// Offset of the next page, if there is one
+    pub next_offset: Option<u32>,
+}
+
+impl Backend {
+    /// A page of access log records matching the filter, newest first.
+    pub async fn query_access(
+        &self,
+        filter: &AccessLogFilter,
+    ) -> Result<AccessLogPage, QueryError> {
+        let Backend::Database(backend) = self else {
+            return Err(QueryError::Unsupported);
+        };
+        let mut data: Vec<AccessLogEntry> = match backend {
+            DatabaseBackend::Sqlite(pool) => {
+                let rows = access_query(&SQLITE, filter)
+                    .build()
+                    .fetch_all(pool)
+                    .await?;
+                rows.iter().map(decode_access).collect::<Result<_, _>>()?
+            }
+            DatabaseBackend::MySql(pool) => {
+                let rows = access_query(&MYSQL, filter).build().fetch_all(pool).await?;
+                rows.iter().map(decode_access).collect::<Result<_, _>>()?
+            }
+            DatabaseBackend::Postgres(pool) => {
+                let rows = access_query(&POSTGRES, filter)
+                    .build()
+                    .fetch_all(pool)
+                    .await?;
+                rows.iter().map(decode_access).collect::<Result<_, _>>()?
+            }
+        };
+        // one record more than requested tells whether there is a next page
+        let limit = filter.limit();
+        let next_offset = (data.len() > limit as usize).then(|| filter.offset.unwrap_or(0) + limit);
+        data.truncate(limit as usize);
+        Ok(AccessLogPage { data, next_offset })
+    }
+
+    /// Token usage aggregated by the requested dimensions, with cost from the pricing table.
+    pub async fn query_usage(
+        &self,
+        filter: &UsageFilter,
+        pricing: &HashMap<String, ModelPricing>,
+    ) -> Result<Vec<UsageEntry>, QueryError> {
+        let dimensions = filter.dimensions()?;
+        let Backend::Database(backend) = self else {
+            return Err(QueryError::Unsupported);
+        };
+        let rows: Vec<UsageRow> = match backend {
+            DatabaseBackend::Sqlite(pool) => {
+                let rows = usage_query(&SQLITE, filter).build().fetch_all(pool).await?;
+                rows.iter().map(decode_usage).collect::<Result<_, _>>()?
+            }
+            DatabaseBackend::MySql(pool) => {
+                let rows = usage_query(&MYSQL, filter).build().fetch_all(pool).await?;
+                rows.iter().map(decode_usage).collect::<Result<_, _>>()?
+            }
+            DatabaseBackend::Postgres(pool) => {
+                let rows = usage_query(&POSTGRES, filter)
+                    .build()
+                    .fetch_all(pool)
+                    .await?;
+                rows.iter().map(decode_usage).collect::<Result<_, _>>()?
+            }
+        };
+        Ok(aggregate_usage(rows, &dimensions, pricing))
+    }
+}
+
+fn access_query<'a, DB: Database>(
+    dialect: &Dialect,
+    filter: &'a AccessLogFilter,
+) -> QueryBuilder<'a, DB>
+where
+    <DB as sqlx::database::HasArguments<'a>>::Arguments: Default,
+    &'a str: Encode<'a, DB> + Type<DB>,
+    String: Encode<'a, DB> + Type<DB>,
+    i64: Encode<'a, DB> + Type<DB>,
+    DateTime<Utc>: Encode<'a, DB> + Type<DB>,
+{
+    let columns: Vec<String> = AccessLogEntry::COLUMNS
+        .iter()
+        .map(|column| match *column {
+            "id" | "response_status" | "upstream_status" | "latency_ms" | "ttfb_ms" => {
+                format!("CAST({column} AS {}) AS {column}", dialect.bigint)
+            }
+            "user" => format!("{} AS {}", dialect.user, dialect.user),
+            _ => column.to_string(),
+        })
+        .collect();
+    let mut query = QueryBuilder::new(format!(
+        "SELECT {} FROM audit_log WHERE 1 = 1",
+        columns.join(", ")
+    ));
+    if let Some(ref user) = filter.user {
+        query
+            .push(format!(" AND {} = ", dialect.user))
+            .push_bind(user.as_str());
+    }
+    if let Some(from) = filter.from {
+        query.push(" AND timestamp >= ").push_bind(from);
+    }
+    if let Some(to) = filter.to {
+        query.push(" AND timestamp < ").push_bind(to);
+    }
+    match filter
+        .path
+        .as_deref()
+        .map(|path| (path.strip_suffix('*'), path))
+    {
+        Some((Some(prefix), _)) => {
+            // `!` rather than a backslash, which MySQL treats as an escape in literals too
+            let pattern = prefix
+                .replace('!', "!!")
+                .replace('%', "!%")
+                .replace('_', "!_");
+            query
+                .push(" AND uri LIKE ")
+                .push_bind(format!("{pattern}%"))
+                .push(" ESCAPE '!'");
+        }
+        Some((None, path)) => {
+            query.push(" AND uri = ").push_bind(path);
+        }
+        None => {}
+    }
+    if let Some(status) = filter.status {
+        query
+            .push(" AND response_status = ")
+            .push_bind(status as i64);
+    }
+    if let Some(ref ray_id) = filter.ray_id {
+        query.push(" AND ray_id = ").push_bind(ray_id.as_str());
+    }
+    query
+        .push(" ORDER BY timestamp DESC, id DESC LIMIT ")
+        .push_bind(filter.limit() as i64 + 1)
+        .push(" OFFSET ")
+        .push_bind(filter.offset.unwrap_or(0) as i64);
+    query
+}
+
+fn decode_access<'r, R: Row>(row: &'r R) -> Result<AccessLogEntry, sqlx::Error>
+where
+    &'static str: ColumnIndex<R>,
+    i64: Decode<'r, R::Database> + Type<R::Database>,
+    String: Decode<'r, R::Database> + Type<R::Database>,
+    DateTime<Utc>: Decode<'r, R::Database> + Type<R::Database>,
+{
+    Ok(AccessLogEntry {
+        id: row.try_get("id")?,
+        timestamp: row.try_get("timestamp")?,
+        ray_id: row.try_get("ray_id")?,
+        user: row.try_get("user")?,
+        client_ip: row.try_get("client_ip")?,
+        method: row.try_get("method")?,
+        uri: row.try_get("uri")?,
+        response_status: row.try_get("response_status")?,
+        upstream_status: row.try_get("upstream_status")?,
+        latency_ms: row.try_get("latency_ms")?,
+        ttfb_ms: row.try_get("ttfb_ms")?,
+        upstream_request_id: row.try_get("upstream_request_id")?,
+        key_fingerprint: row.try_get("key_fingerprint")?,
+        content_filter: row.try_get("content_filter")?,
+        moderation: row.try_get("moderation")?,
+        headers: row.try_get("headers")?,
+        body: row.try_get("body")?,
+        response_headers: row.try_get("response_headers")?,
+        response_body: row.try_get("response_body")?,
+    })
+}
+
+struct UsageRow {
+    user: Option<String>,
+    model: String,
+    day: String,
+    unit: String,
+    details: Option<String>,
+    requests: i64,
+    prompt_tokens: i64,
+    completion_tokens: i64,
+    total_tokens: i64,
+    quantity: f64,
+}
+
+fn usage_query<'a, DB: Database>(dialect: &Dialect, filter: &'a UsageFilter) -> QueryBuilder<'a, DB>
+where
+    <DB as sqlx::database::HasArguments<'a>>::Arguments: Default,
+    &'a str: Encode<'a, DB> + Type<DB>,
+    DateTime<Utc>: Encode<'a, DB> + Type<DB>,
+{
+    let Dialect {
+        user,
+        bigint,
+        double,
+        day,
+    } = dialect;
+    let mut query = QueryBuilder::new(format!(
+        "SELECT {user} AS {user}, model, {day} AS day, unit, details, \
+         CAST(COUNT(*) AS {bigint}) AS requests, \
+         CAST(SUM(prompt_tokens) AS {bigint}) AS prompt_tokens, \
+         CAST(SUM(completion_tokens) AS {bigint}) AS completion_tokens, \
+         CAST(SUM(total_tokens) AS {bigint}) AS total_tokens, \
+         CAST(SUM(quantity) AS {double}) AS quantity \
+         FROM tokens_log WHERE 1 = 1"
+    ));
+    if let Some(ref user_filter) = filter.user {
+        query
+            .push(format!(" AND {user} = "))
+            .push_bind(user_filter.as_str());
+    }
+    if let Some(ref model) = filter.model {
+        query.push(" AND model = ").push_bind(model.as_str());
+    }
+    if let Some(from) = filter.from {
+        query.push(" AND timestamp >= ").push_bind(from);
+    }
+    if let Some(to) = filter.to {
+        query.push(" AND timestamp < ").push_bind(to);
+    }
+    query.push(format!(" GROUP BY {user}, model, {day}, unit, details"));
+    query
+}
+
+fn decode_usage<'r, R: Row>(row: &'r R) -> Result<UsageRow, sqlx::Error>
+where
+    &'static str: ColumnIndex<R>,
+    i64: Decode<'r, R::Database> + Type<R::Database>,
+    f64: Decode<'r, R::Database> + Type<R::Database>,
+    String: Decode<'r, R::Database> + Type<R::Database>,
+{
+    Ok(UsageRow {
+        user: row.try_get("user")?,
+        model: row.try_get("model")?,
+        day: row.try_get("day")?,
+        unit: row.try_get("unit")?,
+        details: row.try_get("details")?,
+        quantity: row.try_get("quantity")?,
+        requests: row.try_get("requests")?,
+        prompt_tokens: row.try_get("prompt_tokens")?,
+        completion_tokens: row.try_get("completion_tokens")?,
+        total_tokens: row.try_get("total_tokens")?,
+    })
+}
+
+/// Price of a model, either by its exact name or the longest matching `prefix*` entry.
+pub fn find_pricing<'a>(
+    pricing: &'a HashMap<String, ModelPricing>,
+    model: &str,
+) -> Option<&'a ModelPricing> {
+    pricing.get(model).or_else(|| {
+        pricing
+            .iter()
+            .filter_map(|(name, price)| {
+                let prefix = name.strip_suffix('*')?;
+                model.starts_with(prefix).then_some((prefix.len(), price))
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, price)| price)
+    })
+}
+
+/// Values of the user, model and day dimensions, `None` when not grouped by, and the unit
+type UsageKey = (
+    Option<Option<String>>,
+    Option<String>,
+    Option<String>,
+    String,
+);
+
+fn aggregate_usage(
+    rows: Vec<UsageRow>,
+    dimensions: &[UsageDimension],
+    pricing: &HashMap<String, ModelPricing>,
+) -> Vec<UsageEntry> {
+    let mut groups: BTreeMap<UsageKey, UsageEntry> = BTreeMap::new();
+    for row in rows {
+        let key = (
+            dimensions
+                .contains(&UsageDimension::User)
+                .then(|| row.user.clone()),
+            dimensions
+                .contains(&UsageDimension::Model)
+                .then(|| row.model.clone()),
+            dimensions
+                .contains(&UsageDimension::Day)
+                .then(|| row.day.clone()),
+            row.unit.clone(),
+        );
+        // e.g. `dall-e-3/1024x1024/hd` before `dall-e-3`
+        let price = row
+            .details
+            .as_ref()
+            .and_then(|details| find_pricing(pricing, &format!("{}/{}", row.model, details)))
+            .or_else(|| find_pricing(pricing, &row.model));
+        let cost = price.and_then(|price| match row.unit.as_str() {
+            "tokens" => Some(
+                (row.prompt_tokens as f64 * price.prompt
+                    + row.completion_tokens as f64 * price.completion)
+                    / 1000.0,
+            ),
+            _ => price.per_unit.map(|per_unit| per_unit * row.quantity),
+        });
+        let entry = groups.entry(key.clone()).or_insert_with(|| UsageEntry {
+            user: key.0,
+            model: key.1,
+            day: key.2,
+            unit: key.3,
+            cost: Some(0.0),
+            ..Default::default()
+        });
+        entry.requests += row.requests;
+        entry.prompt_tokens += row.prompt_tokens;
+        entry.completion_tokens += row.completion_tokens;
+        entry.total_tokens += row.total_tokens;
+        entry.quantity += row.quantity;
+        entry.cost = entry.cost.zip(cost).map(|(a, b)| a + b);
+    }
+    groups.into_values().collect()
+}
@@ -0,0 +1,133 @@
+use super::{Backend, BackendEngine};
+use crate::config::{AuditColumn, AuditRetentionConfig};
+use chrono::{DateTime, Duration, Utc};
+use tokio::time::{interval, MissedTickBehavior};
+
+/// A single purge step: delete the expired rows of a table, or clear one of its columns.
+pub struct PurgeTask {
+    pub table: &'static str,
+    pub column: Option<AuditColumn>,
+    pub cutoff: DateTime<Utc>,
+}
+
+impl PurgeTask {
+    /// Statement purging one chunk, with `?` or `$n` placeholders for the cutoff and the chunk size.
+    ///
+    /// MySQL supports `LIMIT` in `DELETE` / `UPDATE` but not in subqueries, the others only in subqueries.
+    pub fn sql(&self, mysql: bool, placeholders: (&str, &str)) -> String {
+        let (cutoff, limit) = placeholders;
+        let table = self.table;
+        match (self.column.map(|c| c.as_str()), mysql) {
+            (None, true) => format!("DELETE FROM {table} WHERE timestamp < {cutoff} LIMIT {limit}"),
+            (None, false) => format!(
+                "DELETE FROM {table} WHERE id IN (SELECT id FROM {table} WHERE timestamp < {cutoff} LIMIT {limit})"
+            ),
+            (Some(column), true) => format!(
+                "UPDATE {table} SET {column} = NULL WHERE timestamp < {cutoff} AND {column} IS NOT NULL LIMIT {limit}"
+            ),
+            (Some(column), false) => format!(
+                "UPDATE {table} SET {column} = NULL WHERE id IN (SELECT id FROM {table} WHERE timestamp < {cutoff} AND {column} IS NOT NULL LIMIT {limit})"
+            ),
+        }
+    }
+}
+
+pub fn cutoff(now: DateTime<Utc>, days: u32) -> DateTime<Utc> {
+    now - Duration::days(days as i64)
+}
+
+pub fn purge_tasks(retention: &AuditRetentionConfig, now: DateTime<Utc>) -> Vec<PurgeTask> {
+    let mut tasks = vec![];
+    if let Some(days) = retention.access_log {
+        tasks.push(PurgeTask {
+            table: "audit_log",
+            column: None,
+            cutoff: cutoff(now, days),
+        });
+    }
+    if let Some(days) = retention.tokens_log {
+        tasks.push(PurgeTask {
+            table: "tokens_log",
+            column: None,
+            cutoff: cutoff(now, days),
+        });
+    }
+    for (column, days) in retention.columns.iter() {
+        // clearing a column of rows which are deleted anyway is pointless
+        if matches!(retention.access_log, Some(access_days) if access_days <= *days) {
+            continue;
+        }
+        tasks.push(PurgeTask {
+            table: "audit_log",
+            column: Some(*column),
+            cutoff: cutoff(now, *days),
+        });
+    }
+    tasks
+}
+
+/// Periodically purge expired records of the backend.
+pub fn spawn_retention(backend: Backend, retention: AuditRetentionConfig) {
+    if !retention.is_enabled() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut ticker = interval(std::time::Duration::from_secs(retention.interval.max(1)));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            backend.purge(&retention).await;
+        }
+    });
+}
+
+/// Run all purge tasks against a pool chunk by chunk, `$sql` builds the statement of a task.
+macro_rules! purge {
+    ($pool:expr, $retention:expr, $sql:expr) => {{
+        let pool = $pool;
+        let retention = $retention;
+        let chunk_size = retention.chunk_size.max(1) as u64;
+        for task in $crate::audit::retention::purge_tasks(retention, chrono::Utc::now()) {
+            let sql: String = $sql(&task);
+            let mut total = 0;
+            loop {
+                let result = sqlx::query(&sql)
+                    .bind(task.cutoff)
+                    .bind(chunk_size as i64)
+                    .execute(pool)
+                    .await;
+                match result {
+                    Ok(result) => {
+                        total += result.rows_affected();
+                        if result.rows_affected() < chunk_size {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        event!(
+                            Level::ERROR,
+                            error = ?e,
+                            "Failed to purge {}",
+                            task.table
+                        );
+                        break;
+                    }
+                }
+            }
+            if total > 0 {
+                match task.column {
+                    Some(column) => event!(
+                        Level::INFO,
+                        "cleared {} of {} rows of {}",
+                        column.as_str(),
+                        total,
+                        task.table
+                    ),
+                    None => event!(Level::INFO, "purged {} rows of {}", total, task.table),
+                }
+            }
+        }
+    }};
+}
+
+pub(crate) use purge;
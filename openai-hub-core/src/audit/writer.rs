@@ -0,0 +1,500 @@
+#[cfg(feature = "metrics")]
+use super::UsageUnit;
+use super::{AccessLog, AuditRecord, Backend, BackendEngine, TokenUsageLog};
+use crate::config::{AuditOverflowPolicy, AuditWriterConfig};
+use std::collections::VecDeque;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::{oneshot, Notify};
+use tokio::time::{interval, Instant, MissedTickBehavior};
+use tokio_util::task::TaskTracker;
+use tracing::{event, Level};
+
+/// Attempts to write a batch before its records are spilled
+const WRITE_ATTEMPTS: u32 = 3;
+/// Delay before the first retry, doubled on every attempt
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+/// Lines kept in memory before being written back to the spill file during a replay
+const REPLAY_KEPT_BYTES: usize = 64 * 1024;
+
+/// Handle to the background audit writer.
+///
+/// Records are pushed into a bounded queue and written by a single task in batches,
+/// either when a batch is full or when the flush interval elapses.
+///
+/// Spilled records are written back on start, and after the next successful write once
+/// records were spilled. The spill file is renamed to `{spill_file}.replay` meanwhile, the
+/// records that fail again go back to the spill file.
+#[derive(Clone)]
+pub struct AuditWriter {
+    queue: Arc<Queue>,
+    /// Tasks collecting the records of in-flight responses
+    tasks: TaskTracker,
+}
+
+struct Queue {
+    config: AuditWriterConfig,
+    records: Mutex<VecDeque<AuditRecord>>,
+    /// Wakes the writer when a full batch is available
+    batch_ready: Notify,
+    /// Wakes blocked producers after the writer drained the queue
+    space_available: Notify,
+    dropped: AtomicUsize,
+    spill: tokio::sync::Mutex<Option<tokio::fs::File>>,
+    /// Records were spilled since the last replay
+    spilled: AtomicBool,
+    /// Notified once everything queued before the flush was requested is written
+    flushed: Mutex<Vec<oneshot::Sender<()>>>,
+}
+
+impl AuditWriter {
+    /// Spawn the background writer for the backend.
+    pub fn spawn(backend: Backend, config: &AuditWriterConfig) -> Self {
+        let mut config = config.clone();
+        config.queue_size = config.queue_size.max(1);
+        config.batch_size = config.batch_size.clamp(1, config.queue_size);
+        let queue = Arc::new(Queue {
+            records: Mutex::new(VecDeque::with_capacity(config.queue_size)),
+            config,
+            batch_ready: Notify::new(),
+            space_available: Notify::new(),
+            dropped: AtomicUsize::new(0),
+            spill: tokio::sync::Mutex::new(None),
+            spilled: AtomicBool::new(false),
+            flushed: Mutex::new(vec![]),
+        });
+        tokio::spawn(write_loop(queue.clone(), backend));
+        Self {
+            queue,
+            tasks: TaskTracker::new(),
+        }
+    }
+
+    /// Tracker to spawn the tasks collecting records on, so that they are waited for on
+    /// shutdown.
+    pub fn tasks(&self) -> TaskTracker {
+        self.tasks.clone()
+    }
+
+    /// Wait for the tasks collecting records until the deadline, then write everything
+    /// queued. The records the backend has not taken by the deadline are spilled.
+    pub async fn close(&self, deadline: Instant) {
+        self.tasks.close();
+        if tokio::time::timeout_at(deadline, self.tasks.wait())
+            .await
+            .is_err()
+        {
+            event!(
+                Level::WARN,
+                pending = self.tasks.len(),
+                "audit records of unfinished responses are lost"
+            );
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.queue.flushed.lock().unwrap().push(tx);
+        self.queue.batch_ready.notify_one();
+        if tokio::time::timeout_at(deadline, rx).await.is_err() {
+            let records: Vec<AuditRecord> = self.queue.records.lock().unwrap().drain(..).collect();
+            event!(
+                Level::ERROR,
+                records = records.len(),
+                "audit backend is not done by the shutdown deadline, spilling the queued records, the batch being written is lost"
+            );
+            self.queue.spill(&records).await;
+        }
+
+        if let Some(spill) = self.queue.spill.lock().await.as_mut() {
+            if let Err(e) = spill.flush().await {
+                event!(Level::ERROR, error = ?e, "Failed to flush audit spill file");
+            }
+        }
+    }
+
+    /// Wait until the queue has room with the `block` policy, so that requests are
+    /// slowed down instead of piling up records of in-flight responses.
+    pub async fn ready(&self) {
+        if self.queue.config.overflow != AuditOverflowPolicy::Block {
+            return;
+        }
+        loop {
+            let space_available = self.queue.space_available.notified();
+            if self.queue.records.lock().unwrap().len() < self.queue.config.queue_size {
+                return;
+            }
+            space_available.await;
+        }
+    }
+
+    pub async fn log_access(&self, access: AccessLog) {
+        self.queue.push(AuditRecord::Access(Box::new(access))).await
+    }
+
+    pub async fn log_tokens(&self, tokens: TokenUsageLog) {
+        #[cfg(feature = "metrics")]
+        if tokens.unit == UsageUnit::Tokens {
+            let user = tokens.user.as_deref().unwrap_or("");
+            let metrics = &crate::metrics::METRICS;
+            let model = metrics.model_label(&tokens.model);
+            let usage = &tokens.usage;
+            metrics
+                .tokens
+                .inc_by(&[model, user, "prompt"], usage.prompt_tokens as f64);
+            metrics
+                .tokens
+                .inc_by(&[model, user, "completion"], usage.completion_tokens as f64);
+        }
+        self.queue.push(AuditRecord::Tokens(tokens)).await
+    }
+
+    /// Records waiting to be written.
+    #[cfg(feature = "metrics")]
+    pub fn queue_depth(&self) -> usize {
+        self.queue.records.lock().unwrap().len()
+    }
+}
+
+impl Queue {
+    async fn push(&self, record: AuditRecord) {
+        let mut record = Some(record);
+        loop {
+            let space_available = self.space_available.notified();
+            {
+                let mut records = self.records.lock().unwrap();
+                if records.len() < self.config.queue_size {
+                    records.push_back(record.take().unwrap());
+                    if records.len() >= self.config.batch_size {
+                        self.batch_ready.notify_one();
+                    }
+                    return;
+                }
+                if self.config.overflow == AuditOverflowPolicy::DropOldest {
+                    records.pop_front();
+                    records.push_back(record.take().unwrap());
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    #[cfg(feature = "metrics")]
+                    crate::metrics::METRICS.audit_dropped.inc(&[]);
+                    self.batch_ready.notify_one();
+                    return;
+                }
+            }
+            match self.config.overflow {
+                AuditOverflowPolicy::Block => space_available.await,
+                AuditOverflowPolicy::Spill => return self.spill(&[record.take().unwrap()]).await,
+                AuditOverflowPolicy::DropOldest => unreachable!(),
+            }
+        }
+    }
+
+    async fn spill(&self, records: &[AuditRecord]) {
+        self.spilled.store(true, Ordering::Relaxed);
+        self.write_spill(&encode(records)).await;
+    }
+
+    async fn write_spill(&self, vec: &[u8]) {
+        let mut spill = self.spill.lock().await;
+        if spill.is_none() {
+            match tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.config.spill_file)
+                .await
+            {
+                Ok(file) => *spill = Some(file),
+                Err(e) => {
+                    event!(Level::ERROR, error = ?e, "Failed to open audit spill file");
+                    return;
+                }
+            }
+        }
+        if let Err(e) = spill.as_mut().unwrap().write_all(vec).await {
+            event!(Level::ERROR, error = ?e, "Failed to write audit spill file");
+        }
+    }
+
+    /// Retry writing the records of a failed batch, then spill the ones still failing.
+    async fn retry(&self, backend: &Backend, mut records: Vec<AuditRecord>) {
+        let mut delay = RETRY_DELAY;
+        for _ in 1..WRITE_ATTEMPTS {
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+            match backend.log_batch(records).await {
+                Ok(()) => return,
+                Err(failed) => records = failed,
+            }
+        }
+        event!(
+            Level::ERROR,
+            records = records.len(),
+            "spilling audit records that could not be written"
+        );
+        self.spill(&records).await;
+    }
+
+    /// Write the spilled records back to the backend.
+    ///
+    /// Once a whole batch fails, the remaining records are written back to the spill file
+    /// without trying, like the records that cannot be read. They are replayed after the next
+    /// recovery, or on restart.
+    async fn replay(&self, backend: &Backend) {
+        let replay_file = format!("{}.replay", self.config.spill_file);
+        if !self.take_spill_file(&replay_file).await {
+            return;
+        }
+        let file = match tokio::fs::File::open(&replay_file).await {
+            Ok(file) => file,
+            Err(e) => {
+                event!(Level::ERROR, error = ?e, "Failed to open audit replay file");
+                return;
+            }
+        };
+        let mut lines = BufReader::new(file).lines();
+        let mut batch = Vec::with_capacity(self.config.batch_size);
+        let mut kept = vec![];
+        let (mut replayed, mut failed, mut unreadable) = (0usize, 0usize, 0usize);
+        let mut down = false;
+        loop {
+            let line = match lines.next_line().await {
+                Ok(line) => line,
+                Err(e) => {
+                    // kept for the next replay, records before are written twice
+                    event!(Level::ERROR, error = ?e, "Failed to read audit replay file");
+                    break;
+                }
+            };
+            let end = line.is_none();
+            match line.filter(|line| !line.is_empty()) {
+                Some(line) if down => {
+                    failed += 1;
+                    kept.extend_from_slice(line.as_bytes());
+                    kept.push(b'\n');
+                }
+                Some(line) => match serde_json::from_str(&line) {
+                    Ok(record) => batch.push(record),
+                    Err(_) => {
+                        unreadable += 1;
+                        kept.extend_from_slice(line.as_bytes());
+                        kept.push(b'\n');
+                    }
+                },
+                None => {}
+            }
+            if batch.len() >= self.config.batch_size || (end && !batch.is_empty()) {
+                let n = batch.len();
+                if let Err(records) = backend.log_batch(std::mem::take(&mut batch)).await {
+                    down = records.len() == n;
+                    replayed += n - records.len();
+                    failed += records.len();
+                    kept.extend_from_slice(&encode(&records));
+                } else {
+                    replayed += n;
+                }
+            }
+            if kept.len() >= REPLAY_KEPT_BYTES || (end && !kept.is_empty()) {
+                self.write_spill(&std::mem::take(&mut kept)).await;
+            }
+            if end {
+                if let Err(e) = tokio::fs::remove_file(&replay_file).await {
+                    event!(Level::ERROR, error = ?e, "Failed to remove audit replay file");
+                }
+                break;
+            }
+        }
+        if unreadable > 0 {
+            event!(
+                Level::ERROR,
+                records = unreadable,
+                "spilled audit records cannot be read, kept in the spill file"
+            );
+        }
+        event!(
+            Level::INFO,
+            replayed,
+            failed,
+            "replayed spilled audit records"
+        );
+    }
+
+    /// Move the spill file aside to be replayed, unless a replay was interrupted, which is
+    /// finished first. Returns whether there is anything to replay.
+    async fn take_spill_file(&self, replay_file: &str) -> bool {
+        if tokio::fs::metadata(replay_file).await.is_ok() {
+            self.spilled.store(true, Ordering::Relaxed);
+            return true;
+        }
+        let mut spill = self.spill.lock().await;
+        if let Some(mut file) = spill.take() {
+            if let Err(e) = file.flush().await {
+                event!(Level::ERROR, error = ?e, "Failed to flush audit spill file");
+            }
+        }
+        match tokio::fs::rename(&self.config.spill_file, replay_file).await {
+            Ok(()) => true,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+            Err(e) => {
+                event!(Level::ERROR, error = ?e, "Failed to move audit spill file");
+                false
+            }
+        }
+    }
+
+    fn take_batch(&self) -> Vec<AuditRecord> {
+        let mut records = self.records.lock().unwrap();
+        let n = records.len().min(self.config.batch_size);
+        records.drain(..n).collect()
+    }
+}
+
+fn encode(records: &[AuditRecord]) -> Vec<u8> {
+    let mut vec = vec![];
+    for record in records {
+        serde_json::to_writer(&mut vec, record).unwrap();
+        vec.push(b'\n');
+    }
+    vec
+}
+
+async fn write_loop(queue: Arc<Queue>, backend: Backend) {
+    queue.replay(&backend).await;
+    let mut ticker = interval(Duration::from_millis(queue.config.flush_interval.max(1)));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = queue.batch_ready.notified() => {}
+            _ = ticker.tick() => {}
+        }
+        let flushed = std::mem::take(&mut *queue.flushed.lock().unwrap());
+        let dropped = queue.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            event!(
+                Level::WARN,
+                dropped,
+                "audit queue overflowed, dropped oldest records"
+            );
+        }
+        let mut written = false;
+        loop {
+            let batch = queue.take_batch();
+            if batch.is_empty() {
+                break;
+            }
+            let full = batch.len() == queue.config.batch_size;
+            event!(Level::DEBUG, "writing {} audit records", batch.len());
+            match backend.log_batch(batch).await {
+                Ok(()) => written = true,
+                Err(failed) => queue.retry(&backend, failed).await,
+            }
+            queue.space_available.notify_waiters();
+            if !full {
+                break;
+            }
+        }
+        // the backend recovered, or records overflowed
+        if written && queue.spilled.swap(false, Ordering::Relaxed) {
+            queue.replay(&backend).await;
+        }
+        for tx in flushed {
+            let _ = tx.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::{DatabaseBackend, TokenUsage, UsageUnit};
+
+    #[tokio::test]
+    async fn spilled_records_are_replayed_on_start() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        pool.init().await.unwrap();
+        let dir = std::env::temp_dir().join(format!("openai-hub-spill-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let spill_file = dir.join("audit-spill.log");
+        let mut spilled = encode(&[
+            AuditRecord::Access(Box::new(AccessLog::now())),
+            AuditRecord::Tokens(TokenUsageLog {
+                timestamp: chrono::Utc::now(),
+                user: Some("alice".to_string()),
+                ray_id: "ray".to_string(),
+                model: "gpt-4".to_string(),
+                usage: TokenUsage {
+                    prompt_tokens: 1,
+                    completion_tokens: 2,
+                    total_tokens: 3,
+                },
+                unit: UsageUnit::Tokens,
+                quantity: 3.0,
+                details: None,
+                is_estimated: false,
+            }),
+        ]);
+        spilled.extend_from_slice(b"not a record\n");
+        std::fs::write(&spill_file, spilled).unwrap();
+
+        let config = AuditWriterConfig {
+            spill_file: spill_file.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let backend = Backend::Database(DatabaseBackend::Sqlite(pool.clone()));
+        AuditWriter::spawn(backend, &config)
+            .close(Instant::now() + Duration::from_secs(10))
+            .await;
+
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM audit_log")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+        let model: String = sqlx::query_scalar("SELECT model FROM tokens_log")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(model, "gpt-4");
+        // unreadable records are kept
+        assert_eq!(std::fs::read(&spill_file).unwrap(), b"not a record\n");
+        assert!(!dir.join("audit-spill.log.replay").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn queued_records_are_spilled_at_the_deadline() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        // the writer waits for the only connection
+        let _conn = pool.acquire().await.unwrap();
+        let dir = std::env::temp_dir().join(format!("openai-hub-deadline-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let spill_file = dir.join("audit-spill.log");
+        let config = AuditWriterConfig {
+            spill_file: spill_file.to_str().unwrap().to_string(),
+            batch_size: 1,
+            ..Default::default()
+        };
+        let writer = AuditWriter::spawn(
+            Backend::Database(DatabaseBackend::Sqlite(pool.clone())),
+            &config,
+        );
+        // the first record is taken by the hanging write, the second one stays queued
+        writer.log_access(AccessLog::now()).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        writer.log_access(AccessLog::now()).await;
+
+        let start = Instant::now();
+        writer.close(start + Duration::from_millis(200)).await;
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let spilled = std::fs::read_to_string(&spill_file).unwrap();
+        assert_eq!(spilled.lines().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
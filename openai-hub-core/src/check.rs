@@ -0,0 +1,404 @@
+use crate::config::{line_at, line_of, LoadError, Origin, ServerConfig};
+use std::fmt;
+
+#[cfg(feature = "acl")]
+use crate::acl::{self, ApiAcl};
+#[cfg(feature = "content-filter")]
+use crate::content_filter::ContentFilter;
+#[cfg(feature = "jwt-auth")]
+use crate::key::KeyPool;
+#[cfg(feature = "jwt-auth")]
+use crate::managed::Managed;
+#[cfg(feature = "tls")]
+use crate::tls::TlsServer;
+
+/// Time given to the audit database to accept a connection
+#[cfg(feature = "audit")]
+const PING_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A problem found in a configuration file
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn error(line: Option<usize>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            line,
+            message: message.into(),
+        }
+    }
+
+    #[cfg(feature = "acl")]
+    fn warning(line: Option<usize>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// Check the configuration file the way the server loads it, with the files it refers to,
+/// and that the audit database accepts connections.
+///
+/// The state file is applied over `acl`, when it is valid.
+pub async fn check_config(s: &str, #[cfg(feature = "acl")] acl: Option<&str>) -> Vec<Diagnostic> {
+    let config = match ServerConfig::load(s) {
+        Ok(config) => config,
+        Err(e) => {
+            let line = match e {
+                LoadError::Toml(ref e) => e.span().map(|span| line_at(s, span.start)),
+                LoadError::Invalid {
+                    origin: Origin::Line(line),
+                    ..
+                } => Some(line),
+                _ => None,
+            };
+            return vec![Diagnostic::error(line, e.to_string())];
+        }
+    };
+
+    let mut diagnostics = vec![];
+    if config.api_keys.is_empty() {
+        diagnostics.push(Diagnostic::error(
+            line_of(s, "api_keys"),
+            "api_keys is empty, requests cannot be forwarded",
+        ));
+    }
+    for (index, key) in config.api_keys.iter().enumerate() {
+        if key.trim().is_empty() {
+            diagnostics.push(Diagnostic::error(
+                line_of(s, "api_keys"),
+                format!("api_keys[{index}] is empty"),
+            ));
+        }
+    }
+
+    #[cfg(feature = "content-filter")]
+    if let Some(ref content_filter) = config.content_filter {
+        if let Err(e) = ContentFilter::create_with(content_filter) {
+            diagnostics.push(Diagnostic::error(
+                line_of(s, "content-filter"),
+                format!("invalid content filter pattern: {e}"),
+            ));
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    if let Some(ref tls) = config.tls {
+        if let Err(e) = TlsServer::check(tls) {
+            diagnostics.push(Diagnostic::error(line_of(s, "tls"), e.to_string()));
+        }
+    }
+
+    #[cfg(feature = "jwt-auth")]
+    if let Some(state_file) = config
+        .admin
+        .as_ref()
+        .and_then(|admin| admin.state_file.as_ref())
+    {
+        #[cfg(feature = "acl")]
+        let acl = acl
+            .and_then(|acl| ApiAcl::load(acl).ok())
+            .map(|acl| std::sync::Arc::new(parking_lot::RwLock::new(acl)));
+        if let Err(e) = Managed::load(
+            Some(state_file.into()),
+            std::sync::Arc::new(KeyPool::new(config.api_keys.clone())),
+            #[cfg(feature = "acl")]
+            acl,
+            #[cfg(feature = "audit")]
+            config.audit.as_ref(),
+        ) {
+            diagnostics.push(Diagnostic::error(
+                line_of(s, "admin.state_file"),
+                format!("{state_file}: {e}"),
+            ));
+        }
+    }
+
+    #[cfg(feature = "audit")]
+    if let Some(ref audit) = config.audit {
+        let section = format!("audit.backends.{:?}", audit.backend).to_lowercase();
+        match tokio::time::timeout(PING_TIMEOUT, crate::audit::Backend::ping(audit)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => diagnostics.push(Diagnostic::error(
+                line_of(s, &section),
+                format!("audit database is unreachable: {e}"),
+            )),
+            Err(_) => diagnostics.push(Diagnostic::error(
+                line_of(s, &section),
+                "audit database is unreachable: connection timed out",
+            )),
+        }
+    }
+    diagnostics
+}
+
+/// Check the ACL file the way the server loads it, and the patterns it would load without
+/// ever matching as intended.
+#[cfg(feature = "acl")]
+pub fn check_acl(s: &str) -> Vec<Diagnostic> {
+    let acl = match ApiAcl::load(s) {
+        Ok(acl) => acl,
+        Err(e) => {
+            let line = match e {
+                acl::LoadError::InvalidToml(ref e) => e.span().map(|span| line_at(s, span.start)),
+                acl::LoadError::InvalidPattern { ref key, .. }
+                | acl::LoadError::PathWithoutModel(ref key) => line_of(s, key),
+                acl::LoadError::UnknownRole(ref subject, _) => {
+                    line_of(s, &format!("subjects.\"{subject}\".roles"))
+                }
+                _ => None,
+            };
+            return vec![Diagnostic::error(line, e.to_string())];
+        }
+    };
+
+    let mut diagnostics = vec![];
+    for (method, endpoints) in acl.endpoint_rules.iter() {
+        for (endpoint, _) in endpoints {
+            if !endpoint.starts_with('/') {
+                let key = format!("endpoint.{method}.\"{endpoint}\"");
+                diagnostics.push(Diagnostic::warning(
+                    line_of(s, &key),
+                    format!("endpoint {endpoint} does not start with `/` and never matches"),
+                ));
+            }
+        }
+    }
+
+    // loaded without error, the rules are a table of methods and patterns
+    let Ok(table) = s.parse::<toml::Table>() else {
+        return diagnostics;
+    };
+    let models = table.get("model").and_then(toml::Value::as_table);
+    for (method, rules) in models.into_iter().flatten() {
+        for (path, rule) in rules.as_table().into_iter().flatten() {
+            let key = format!("model.{method}.\"{path}\"");
+            for list in ["allows", "disallows"] {
+                let wildcards = rule.get(list).and_then(toml::Value::as_array);
+                for wildcard in wildcards.into_iter().flatten().filter_map(|w| w.as_str()) {
+                    if let Some(problem) = wildcard_problem(wildcard) {
+                        diagnostics.push(Diagnostic::warning(
+                            line_of(s, &format!("{key}.{list}")),
+                            format!("wildcard \"{wildcard}\" in {list} {problem}"),
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    diagnostics
+}
+
+/// Why a model wildcard does not match what it likely means to, only `*` is special.
+#[cfg(feature = "acl")]
+fn wildcard_problem(wildcard: &str) -> Option<&'static str> {
+    if wildcard.is_empty() {
+        return Some("is empty and matches no model");
+    }
+    if wildcard.contains(['^', '$', '(', ')', '[', ']', '|', '+', '?', '\\', '{', '}']) {
+        return Some("is matched literally except `*`, it is not a regular expression");
+    }
+    if wildcard.contains(".*") {
+        return Some("contains `.*`, which matches a literal `.`");
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn check(s: &str) -> Vec<Diagnostic> {
+        check_config(
+            s,
+            #[cfg(feature = "acl")]
+            None,
+        )
+        .await
+    }
+
+    fn only(diagnostics: Vec<Diagnostic>) -> Diagnostic {
+        assert_eq!(diagnostics.len(), 1, "{diagnostics:?}");
+        diagnostics.into_iter().next().unwrap()
+    }
+
+    #[tokio::test]
+    async fn empty_api_keys() {
+        let diagnostic = only(check("bind = \"127.0.0.1:8080\"\napi_keys = []\n").await);
+        assert_eq!(diagnostic.severity, Severity::Error);
+        assert_eq!(diagnostic.line, Some(2));
+        assert!(
+            diagnostic.message.contains("api_keys is empty"),
+            "{diagnostic:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_key() {
+        let config = "bind = \"127.0.0.1:8080\"\napi_keys = [\"sk-1\"]\napi_bsae = \"x\"\n";
+        let diagnostic = only(check(config).await);
+        assert_eq!(diagnostic.severity, Severity::Error);
+        assert_eq!(diagnostic.line, Some(3));
+        assert!(
+            diagnostic.message.contains("unknown field `api_bsae`"),
+            "{diagnostic:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn toml_syntax_error() {
+        let config = "bind = \"127.0.0.1:8080\"\napi_keys = [\"sk-1\"]\nshutdown_timeout = = 3\n";
+        let diagnostic = only(check(config).await);
+        assert_eq!(diagnostic.severity, Severity::Error);
+        assert_eq!(diagnostic.line, Some(3));
+    }
+
+    #[cfg(not(feature = "otel"))]
+    #[tokio::test]
+    async fn feature_gated_section() {
+        let config = r#"
+bind = "127.0.0.1:8080"
+api_keys = ["sk-1"]
+
+[otel]
+endpoint = "http://localhost:4317"
+"#;
+        let diagnostic = only(check(config).await);
+        assert_eq!(diagnostic.severity, Severity::Error);
+        assert_eq!(diagnostic.line, Some(5));
+        assert!(
+            diagnostic.message.contains("requires the `otel` feature"),
+            "{diagnostic:?}"
+        );
+    }
+
+    #[cfg(feature = "content-filter")]
+    #[tokio::test]
+    async fn invalid_content_filter_pattern() {
+        let config = r#"
+bind = "127.0.0.1:8080"
+api_keys = ["sk-1"]
+
+[[content-filter.rules]]
+name = "api-key"
+patterns = ["sk-[a-z"]
+"#;
+        let diagnostic = only(check(config).await);
+        assert_eq!(diagnostic.severity, Severity::Error);
+        assert_eq!(diagnostic.line, Some(5));
+        assert!(
+            diagnostic
+                .message
+                .contains("invalid content filter pattern"),
+            "{diagnostic:?}"
+        );
+    }
+
+    #[cfg(feature = "acl")]
+    #[test]
+    fn unknown_acl_key() {
+        let acl = r#"
+[global]
+whitelist = true
+methods = { GET = true }
+allow_deployment = ["gpt-4"]
+"#;
+        let diagnostic = only(check_acl(acl));
+        assert_eq!(diagnostic.severity, Severity::Error);
+        assert_eq!(diagnostic.line, Some(5));
+        assert!(
+            diagnostic
+                .message
+                .contains("unknown field `allow_deployment`"),
+            "{diagnostic:?}"
+        );
+    }
+
+    #[cfg(feature = "acl")]
+    #[test]
+    fn endpoint_without_leading_slash() {
+        let acl = r#"
+[global]
+whitelist = true
+
+[endpoint.POST]
+"/chat/completions" = true
+"completions" = true
+"#;
+        let diagnostic = only(check_acl(acl));
+        assert_eq!(diagnostic.severity, Severity::Warning);
+        assert_eq!(diagnostic.line, Some(7));
+        assert!(
+            diagnostic.message.contains("does not start with `/`"),
+            "{diagnostic:?}"
+        );
+    }
+
+    #[cfg(feature = "acl")]
+    #[test]
+    fn path_rule_without_placeholder() {
+        let acl = r#"
+[global]
+whitelist = true
+
+[endpoint.GET]
+"/models/{model}" = true
+
+[model.GET."/models"]
+path = true
+allows = ["gpt-*"]
+"#;
+        let diagnostic = only(check_acl(acl));
+        assert_eq!(diagnostic.severity, Severity::Error);
+        assert_eq!(diagnostic.line, Some(8));
+        assert!(
+            diagnostic.message.contains("has no {model} placeholder"),
+            "{diagnostic:?}"
+        );
+    }
+
+    #[cfg(feature = "acl")]
+    #[test]
+    fn regex_looking_wildcard() {
+        let acl = r#"
+[global]
+whitelist = true
+
+[endpoint.POST]
+"/chat/completions" = true
+
+[model.POST."/chat/completions"]
+allows = ["gpt-*"]
+disallows = ["^gpt-4(-32k)?$"]
+"#;
+        let diagnostic = only(check_acl(acl));
+        assert_eq!(diagnostic.severity, Severity::Warning);
+        assert_eq!(diagnostic.line, Some(10));
+        assert!(
+            diagnostic.message.contains("not a regular expression"),
+            "{diagnostic:?}"
+        );
+    }
+}
@@ -0,0 +1,14 @@
+use serde::Deserialize;
+use std::collections::HashSet;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Authenticated subjects allowed to use the `/hub` admin endpoints
+    #[serde(default)]
+    pub subjects: HashSet<String>,
+    /// File the changes made through the admin API are saved to, they are lost on restart
+    /// without it
+    #[serde(default)]
+    pub state_file: Option<String>,
+}
@@ -1,16 +1,214 @@
-use serde::Deserialize;
+use crate::redact::JsonPath;
+use chrono::{DateTime, Datelike, Duration, Months, NaiveTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
 use sqlx::mysql::MySqlConnectOptions;
 use sqlx::postgres::PgConnectOptions;
 use sqlx::sqlite::SqliteConnectOptions;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 
 #[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct AuditConfig {
     pub backend: AuditBackendType,
     #[serde(default)]
     pub backends: AuditBackendConfig,
     #[serde(default)]
     pub filters: AuditFiltersConfig,
+    #[serde(default)]
+    pub writer: AuditWriterConfig,
+    #[serde(default)]
+    pub retention: AuditRetentionConfig,
+    /// Prices by model name, or by model name prefix when ending with `*`
+    #[serde(default)]
+    pub pricing: HashMap<String, ModelPricing>,
+    #[serde(default)]
+    pub usage: AuditUsageConfig,
+}
+
+/// Windows reported to users by `GET /hub/usage`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuditUsageConfig {
+    pub windows: Vec<UsageWindowConfig>,
+}
+
+impl Default for AuditUsageConfig {
+    fn default() -> Self {
+        Self {
+            windows: vec![
+                UsageWindowConfig::new("day", UsagePeriod::Day),
+                UsageWindowConfig::new("month", UsagePeriod::Month),
+            ],
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct UsageWindowConfig {
+    pub name: String,
+    pub period: UsagePeriod,
+    /// Quota of every user in this window
+    #[serde(flatten)]
+    pub quota: UsageQuota,
+    /// Quotas of single users, replacing the default one
+    #[serde(default)]
+    pub users: HashMap<String, UsageQuota>,
+}
+
+impl UsageWindowConfig {
+    fn new(name: &str, period: UsagePeriod) -> Self {
+        Self {
+            name: name.to_string(),
+            period,
+            quota: UsageQuota::default(),
+            users: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct UsageQuota {
+    /// Total tokens
+    pub tokens: Option<i64>,
+    /// Cost in USD
+    pub cost: Option<f64>,
+}
+
+/// Calendar periods in UTC
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UsagePeriod {
+    Day,
+    Week,
+    Month,
+}
+
+impl UsagePeriod {
+    /// Start and end of the period containing `now`
+    pub fn bounds(&self, now: DateTime<Utc>) -> (DateTime<Utc>, DateTime<Utc>) {
+        let today = now.date_naive();
+        let (start, end) = match self {
+            UsagePeriod::Day => (today, today + Duration::days(1)),
+            UsagePeriod::Week => {
+                let start = today - Duration::days(today.weekday().num_days_from_monday() as i64);
+                (start, start + Duration::days(7))
+            }
+            UsagePeriod::Month => {
+                let start = today.with_day(1).unwrap();
+                (start, start + Months::new(1))
+            }
+        };
+        (
+            start.and_time(NaiveTime::MIN).and_utc(),
+            end.and_time(NaiveTime::MIN).and_utc(),
+        )
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelPricing {
+    /// USD per 1K prompt tokens
+    pub prompt: f64,
+    /// USD per 1K completion tokens
+    pub completion: f64,
+    /// USD per image, second or character of the other endpoints
+    pub per_unit: Option<f64>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditRetentionConfig {
+    /// Seconds between two purge runs
+    pub interval: u64,
+    /// Maximum number of rows deleted or updated by a single statement
+    pub chunk_size: u32,
+    /// Days to keep access log records
+    pub access_log: Option<u32>,
+    /// Days to keep token usage records
+    pub tokens_log: Option<u32>,
+    /// Days to keep individual access log columns, which are cleared afterwards
+    pub columns: HashMap<AuditColumn, u32>,
+}
+
+impl Default for AuditRetentionConfig {
+    fn default() -> Self {
+        Self {
+            interval: 3600,
+            chunk_size: 1000,
+            access_log: None,
+            tokens_log: None,
+            columns: HashMap::new(),
+        }
+    }
+}
+
+impl AuditRetentionConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.access_log.is_some() || self.tokens_log.is_some() || !self.columns.is_empty()
+    }
+}
+
+/// Access log columns which can be cleared independently of their row
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditColumn {
+    Headers,
+    Body,
+    ResponseHeaders,
+    ResponseBody,
+}
+
+impl AuditColumn {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditColumn::Headers => "headers",
+            AuditColumn::Body => "body",
+            AuditColumn::ResponseHeaders => "response_headers",
+            AuditColumn::ResponseBody => "response_body",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditWriterConfig {
+    /// Maximum number of records waiting to be written
+    pub queue_size: usize,
+    /// Maximum number of records written at once
+    pub batch_size: usize,
+    /// Interval in milliseconds to flush records even if the batch is not full
+    pub flush_interval: u64,
+    /// What to do when the queue is full
+    pub overflow: AuditOverflowPolicy,
+    /// File receiving overflowed records with the `spill` policy, and records that failed
+    /// to be written after retries, they are written back later
+    pub spill_file: String,
+}
+
+impl Default for AuditWriterConfig {
+    fn default() -> Self {
+        Self {
+            queue_size: 10000,
+            batch_size: 100,
+            flush_interval: 1000,
+            overflow: AuditOverflowPolicy::default(),
+            spill_file: "audit-spill.log".to_string(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditOverflowPolicy {
+    /// Drop the oldest queued record
+    #[default]
+    DropOldest,
+    /// Wait for the writer to catch up, slowing down requests
+    Block,
+    /// Append the record to the spill file as JSON
+    Spill,
 }
 
 #[derive(Copy, Clone, Debug, Deserialize)]
@@ -23,22 +221,27 @@ pub enum AuditBackendType {
 }
 
 #[derive(Clone, Debug, Default, Deserialize)]
-#[serde(default)]
+#[serde(default, deny_unknown_fields)]
 pub struct AuditBackendConfig {
+    #[serde(rename = "file", alias = "file_backend")]
     pub file_backend: FileBackendConfig,
+    #[serde(rename = "sqlite", alias = "sqlite_backend")]
     pub sqlite_backend: SqliteBackendConfig,
+    #[serde(rename = "mysql", alias = "mysql_backend")]
     pub mysql_backend: MySqlBackendConfig,
+    #[serde(rename = "postgres", alias = "postgres_backend")]
     pub postgres_backend: PostgresBackendConfig,
 }
 
 #[derive(Clone, Debug, Default, Deserialize)]
-#[serde(default)]
+#[serde(default, deny_unknown_fields)]
 pub struct AuditFiltersConfig {
     pub access: AuditAccessFilterConfig,
     pub tokens: AuditTokensFilterConfig,
 }
 
 #[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct AuditAccessFilterConfig {
     pub enable: bool,
     pub method: bool,
@@ -46,6 +249,29 @@ pub struct AuditAccessFilterConfig {
     pub headers: bool,
     pub body: bool,
     pub response: bool,
+    /// Headers whose values are replaced before logging, in addition to the default ones
+    #[serde(
+        default = "default_redact_headers",
+        deserialize_with = "with_default_redact_headers"
+    )]
+    pub redact_headers: HashSet<String>,
+    /// JSON paths of request and response bodies replaced before logging, bodies that are
+    /// not JSON are replaced as a whole when set
+    #[serde(default)]
+    pub redact_body: Vec<JsonPath>,
+}
+
+fn default_redact_headers() -> HashSet<String> {
+    HashSet::from_iter(["authorization", "cookie", "api-key"].map(String::from))
+}
+
+/// The default headers carry credentials, configuring more headers must not log them.
+fn with_default_redact_headers<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashSet<String>, D::Error> {
+    let mut headers = default_redact_headers();
+    headers.extend(HashSet::<String>::deserialize(deserializer)?);
+    Ok(headers)
 }
 
 impl Default for AuditAccessFilterConfig {
@@ -57,11 +283,14 @@ impl Default for AuditAccessFilterConfig {
             headers: false,
             body: false,
             response: false,
+            redact_headers: default_redact_headers(),
+            redact_body: vec![],
         }
     }
 }
 
 #[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct AuditTokensFilterConfig {
     pub enable: bool,
     pub endpoints: HashSet<String>,
@@ -77,6 +306,12 @@ impl Default for AuditTokensFilterConfig {
                 "/chat/completions".to_string(),
                 "/edits".to_string(),
                 "/embeddings".to_string(),
+                "/images/generations".to_string(),
+                "/images/edits".to_string(),
+                "/images/variations".to_string(),
+                "/audio/transcriptions".to_string(),
+                "/audio/translations".to_string(),
+                "/audio/speech".to_string(),
             ]),
             stream_tokens: StreamTokensPolicy::default(),
         }
@@ -98,21 +333,44 @@ impl Default for StreamTokensPolicy {
 }
 
 #[derive(Clone, Debug, Deserialize)]
-#[serde(default)]
+#[serde(default, deny_unknown_fields)]
 pub struct FileBackendConfig {
     pub filename: String,
+    /// Rotate when the file would grow beyond this many bytes
+    pub max_size: Option<u64>,
+    /// Rotate when the UTC day changes
+    pub daily: bool,
+    /// Number of rotated files to keep, all of them when unset
+    pub max_files: Option<usize>,
+    /// Compression of rotated files
+    pub compression: FileCompression,
 }
 
 impl Default for FileBackendConfig {
     fn default() -> Self {
         Self {
             filename: "access.log".to_string(),
+            max_size: None,
+            daily: false,
+            max_files: None,
+            compression: FileCompression::default(),
         }
     }
 }
 
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileCompression {
+    #[default]
+    None,
+    #[cfg(feature = "log-gzip")]
+    Gzip,
+    #[cfg(feature = "log-zstd")]
+    Zstd,
+}
+
 #[derive(Clone, Debug, Deserialize)]
-#[serde(default)]
+#[serde(default, deny_unknown_fields)]
 pub struct SqliteBackendConfig {
     pub filename: String,
     pub create_if_missing: bool,
@@ -135,8 +393,11 @@ impl From<&SqliteBackendConfig> for SqliteConnectOptions {
     }
 }
 
-#[derive(Clone, Debug, Deserialize)]
-#[serde(default)]
+/// Printed in place of passwords
+const MASKED: &str = "***";
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct MySqlBackendConfig {
     pub host: Option<String>,
     pub port: Option<u16>,
@@ -146,6 +407,19 @@ pub struct MySqlBackendConfig {
     pub database: String,
 }
 
+impl fmt::Debug for MySqlBackendConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MySqlBackendConfig")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("socket", &self.socket)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| MASKED))
+            .field("database", &self.database)
+            .finish()
+    }
+}
+
 impl Default for MySqlBackendConfig {
     fn default() -> Self {
         Self {
@@ -182,8 +456,8 @@ impl From<&MySqlBackendConfig> for MySqlConnectOptions {
     }
 }
 
-#[derive(Clone, Debug, Deserialize)]
-#[serde(default)]
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct PostgresBackendConfig {
     pub host: Option<String>,
     pub port: Option<u16>,
@@ -193,6 +467,19 @@ pub struct PostgresBackendConfig {
     pub database: String,
 }
 
+impl fmt::Debug for PostgresBackendConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PostgresBackendConfig")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("socket", &self.socket)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| MASKED))
+            .field("database", &self.database)
+            .finish()
+    }
+}
+
 impl Default for PostgresBackendConfig {
     fn default() -> Self {
         Self {
@@ -0,0 +1,57 @@
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ContentFilterConfig {
+    #[serde(default)]
+    pub rules: Vec<ContentFilterRuleConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ContentFilterRuleConfig {
+    pub name: String,
+    /// Regular expressions to look for
+    #[serde(default)]
+    pub patterns: Vec<String>,
+    /// Plain keywords to look for
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    #[serde(default)]
+    pub case_insensitive: bool,
+    #[serde(default)]
+    pub action: ContentFilterAction,
+    #[serde(default = "default_replacement")]
+    pub replacement: String,
+}
+
+impl ContentFilterConfig {
+    /// Index of the first rule matching any text, without patterns and keywords or with an
+    /// empty one.
+    pub(crate) fn match_all_rule(&self) -> Option<usize> {
+        self.rules.iter().position(|rule| {
+            (rule.patterns.is_empty() && rule.keywords.is_empty())
+                || rule
+                    .patterns
+                    .iter()
+                    .chain(rule.keywords.iter())
+                    .any(String::is_empty)
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentFilterAction {
+    /// Reject the request
+    #[default]
+    Block,
+    /// Replace the matched text before forwarding the request
+    Redact,
+    /// Only record the match
+    Log,
+}
+
+fn default_replacement() -> String {
+    "[REDACTED]".to_string()
+}
@@ -0,0 +1,469 @@
+use super::locate::{key_segments, line_of};
+use super::{InvalidValue, LoadError};
+use std::fmt;
+use std::io;
+use toml::{Table, Value};
+
+/// Prefix of the environment variables overriding configuration values
+pub const ENV_PREFIX: &str = "OPENAI_HUB__";
+
+/// Where configuration values come from, appended to load errors
+pub const PRECEDENCE: &str = "configuration values are taken from, highest precedence first: \
+OPENAI_HUB__<SECTION>__<KEY> environment variables, where lists are given comma separated or \
+as a TOML array, then the configuration file, where ${VAR} is replaced by the environment \
+variable VAR and `<key>_file` reads a secret from a file instead of `<key>`";
+
+/// Keys that can be read from a file given as `<key>_file`
+const SECRET_KEYS: &[&[&str]] = &[
+    &["api_keys"],
+    &["jwt-auth", "secret"],
+    &["audit", "backends", "mysql", "password"],
+    &["audit", "backends", "postgres", "password"],
+];
+
+/// Keys holding a list of strings, which environment variables may give comma separated
+const LIST_KEYS: &[&[&str]] = &[
+    &["api_keys"],
+    &["admin", "subjects"],
+    &["network", "trusted_proxies"],
+    &["network", "allow"],
+    &["network", "deny"],
+    &["moderation", "endpoints"],
+    &["moderation", "deny_categories"],
+    &["audit", "filters", "access", "redact_headers"],
+    &["audit", "filters", "access", "redact_body"],
+    &["audit", "filters", "tokens", "endpoints"],
+];
+
+/// Sections named with a hyphen, which environment variable names cannot contain
+const HYPHENATED_SECTIONS: &[&str] = &["jwt-auth", "content-filter"];
+
+#[derive(Debug, thiserror::Error)]
+pub enum EnvError {
+    #[error("environment variable {0} is not set")]
+    Missing(String),
+    #[error("unterminated ${{")]
+    Unterminated,
+    #[error("not a table, {0} cannot set a key in it")]
+    NotATable(String),
+    #[error("both {0} and {0}_file are set")]
+    Conflict(String),
+    #[error("must be a path")]
+    InvalidPath,
+    #[error("failed to read {0}: {1}")]
+    SecretFile(String, io::Error),
+}
+
+/// Where a configuration value is set
+#[derive(Debug)]
+pub enum Origin {
+    Line(usize),
+    Env(String),
+    /// Not set, or the line is unknown
+    Unknown,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::Line(line) => write!(f, " at line {line}"),
+            Origin::Env(var) => write!(f, " set by {var}"),
+            Origin::Unknown => Ok(()),
+        }
+    }
+}
+
+/// The configuration file and the environment variables overriding it.
+pub(crate) struct Source<'a> {
+    text: &'a str,
+    vars: Vec<(String, String)>,
+    /// Key paths set by environment variables
+    overrides: Vec<(Vec<String>, String)>,
+}
+
+impl<'a> Source<'a> {
+    pub(crate) fn new<I>(text: &'a str, vars: I) -> Self
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut vars: Vec<_> = vars.into_iter().collect();
+        vars.sort();
+        Self {
+            text,
+            vars,
+            overrides: vec![],
+        }
+    }
+
+    /// Parse the configuration file, interpolate environment variables, apply the
+    /// `OPENAI_HUB__` overrides and read the secret files.
+    pub(crate) fn resolve(&mut self) -> Result<Table, LoadError> {
+        let mut table: Table = self.text.parse()?;
+        self.interpolate_table(&mut table, "")?;
+        let vars = std::mem::take(&mut self.vars);
+        for (name, value) in vars.iter() {
+            if let Some(path) = name.strip_prefix(ENV_PREFIX) {
+                self.apply_override(&mut table, name, path, value)?;
+            }
+        }
+        self.read_secret_files(&mut table)?;
+        Ok(table)
+    }
+
+    /// Where the value of a key path is set.
+    pub(crate) fn origin(&self, key: &str) -> Origin {
+        let segments = key_segments(key);
+        if let Some((_, var)) = self
+            .overrides
+            .iter()
+            .rev()
+            .find(|(path, _)| segments.starts_with(path))
+        {
+            return Origin::Env(var.clone());
+        }
+        line_of(self.text, key).map_or(Origin::Unknown, Origin::Line)
+    }
+
+    pub(crate) fn invalid(&self, key: &str, error: impl Into<InvalidValue>) -> LoadError {
+        LoadError::Invalid {
+            key: key.to_string(),
+            origin: self.origin(key),
+            error: error.into(),
+        }
+    }
+
+    fn interpolate_table(&self, table: &mut Table, prefix: &str) -> Result<(), LoadError> {
+        for (key, value) in table.iter_mut() {
+            self.interpolate_value(value, &format!("{prefix}{key}"))?;
+        }
+        Ok(())
+    }
+
+    fn interpolate_value(&self, value: &mut Value, key: &str) -> Result<(), LoadError> {
+        match value {
+            Value::String(s) => {
+                *s = self
+                    .interpolate(s)
+                    .map_err(|error| self.invalid(key, error))?
+            }
+            Value::Array(values) => {
+                for (index, value) in values.iter_mut().enumerate() {
+                    self.interpolate_value(value, &format!("{key}[{index}]"))?;
+                }
+            }
+            Value::Table(table) => self.interpolate_table(table, &format!("{key}."))?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Replace `${VAR}` by the value of VAR, `$${` is a literal `${`.
+    fn interpolate(&self, s: &str) -> Result<String, EnvError> {
+        let mut out = String::with_capacity(s.len());
+        let mut rest = s;
+        while let Some(start) = rest.find("${") {
+            if rest[..start].ends_with('$') {
+                out.push_str(&rest[..start - 1]);
+                out.push_str("${");
+                rest = &rest[start + 2..];
+                continue;
+            }
+            out.push_str(&rest[..start]);
+            let end = rest[start..].find('}').ok_or(EnvError::Unterminated)?;
+            let name = &rest[start + 2..start + end];
+            let value = self
+                .vars
+                .binary_search_by(|(var, _)| var.as_str().cmp(name))
+                .map(|index| &self.vars[index].1)
+                .map_err(|_| EnvError::Missing(name.to_string()))?;
+            out.push_str(value);
+            rest = &rest[start + end + 1..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+
+    /// Set the key at `path`, `SECTION__KEY` with sections separated by `__`.
+    fn apply_override(
+        &mut self,
+        table: &mut Table,
+        name: &str,
+        path: &str,
+        raw: &str,
+    ) -> Result<(), LoadError> {
+        let segments: Vec<String> = path.split("__").map(str::to_lowercase).collect();
+        let (last, sections) = segments.split_last().unwrap();
+        let mut keys = vec![];
+        let mut table = table;
+        for section in sections {
+            let key = existing_key(table, section, keys.is_empty());
+            keys.push(key.clone());
+            table = match table
+                .entry(key)
+                .or_insert_with(|| Value::Table(Table::new()))
+            {
+                Value::Table(table) => table,
+                _ => return Err(self.invalid(&keys.join("."), EnvError::NotATable(name.into()))),
+            };
+        }
+        let key = existing_key(table, last, keys.is_empty());
+
+        // the value given by the environment replaces the one given by a secret file, and
+        // the other way around
+        let listed = |list: &[&[&str]], key: &str| {
+            let mut path: Vec<&str> = keys.iter().map(String::as_str).collect();
+            path.push(key);
+            list.contains(&path.as_slice())
+        };
+        if listed(SECRET_KEYS, &key) {
+            table.remove(&format!("{key}_file"));
+        } else if let Some(secret_key) = key
+            .strip_suffix("_file")
+            .filter(|key| listed(SECRET_KEYS, key))
+        {
+            table.remove(secret_key);
+        }
+
+        let value = match table.get(&key) {
+            Some(Value::String(_)) => Value::String(raw.to_string()),
+            Some(Value::Array(_)) => parse_list(raw),
+            _ if listed(LIST_KEYS, &key) => parse_list(raw),
+            _ => parse_value(raw),
+        };
+        table.insert(key.clone(), value);
+        keys.push(key);
+        self.overrides.push((keys, name.to_string()));
+        Ok(())
+    }
+
+    fn read_secret_files(&self, table: &mut Table) -> Result<(), LoadError> {
+        for path in SECRET_KEYS {
+            let (key, sections) = path.split_last().unwrap();
+            let Some(table) = sections.iter().try_fold(&mut *table, |table, section| {
+                table.get_mut(*section).and_then(Value::as_table_mut)
+            }) else {
+                continue;
+            };
+            let Some(file) = table.remove(&format!("{key}_file")) else {
+                continue;
+            };
+            let dotted = path.join(".");
+            let file_key = format!("{dotted}_file");
+            if table.contains_key(*key) {
+                return Err(self.invalid(&file_key, EnvError::Conflict(key.to_string())));
+            }
+            let Value::String(file) = file else {
+                return Err(self.invalid(&file_key, EnvError::InvalidPath));
+            };
+            let contents = std::fs::read_to_string(&file)
+                .map_err(|e| self.invalid(&file_key, EnvError::SecretFile(file.clone(), e)))?;
+            let value = if *key == "api_keys" {
+                // one key per line
+                Value::Array(
+                    contents
+                        .lines()
+                        .map(str::trim)
+                        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                        .map(|line| Value::String(line.to_string()))
+                        .collect(),
+                )
+            } else {
+                Value::String(contents.trim_end_matches(['\r', '\n']).to_string())
+            };
+            table.insert(key.to_string(), value);
+        }
+        Ok(())
+    }
+}
+
+/// The resolved table without the values of the secret keys.
+pub(crate) fn without_secrets(table: &Table) -> Table {
+    let mut table = table.clone();
+    for path in SECRET_KEYS {
+        let (key, sections) = path.split_last().unwrap();
+        let section = sections.iter().try_fold(&mut table, |table, section| {
+            table.get_mut(*section).and_then(Value::as_table_mut)
+        });
+        if let Some(section) = section {
+            section.remove(*key);
+        }
+    }
+    table
+}
+
+/// Key of the table matching an environment variable segment, also when the key is named
+/// with hyphens.
+fn existing_key(table: &Table, segment: &str, top_level: bool) -> String {
+    if table.contains_key(segment) {
+        return segment.to_string();
+    }
+    let hyphenated = segment.replace('_', "-");
+    if table.contains_key(&hyphenated)
+        || (top_level && HYPHENATED_SECTIONS.contains(&hyphenated.as_str()))
+    {
+        return hyphenated;
+    }
+    segment.to_string()
+}
+
+/// A TOML value, e.g. a number, boolean or array, or a string if it does not parse as one.
+fn parse_value(raw: &str) -> Value {
+    format!("value = {raw}")
+        .parse::<Table>()
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_string()))
+}
+
+/// A TOML array, or the comma separated strings if it does not parse as one.
+fn parse_list(raw: &str) -> Value {
+    match parse_value(raw) {
+        list @ Value::Array(_) => list,
+        _ => Value::Array(
+            raw.split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| Value::String(item.to_string()))
+                .collect(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(text: &str, vars: &[(&str, &str)]) -> Result<Table, LoadError> {
+        let vars = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()));
+        Source::new(text, vars).resolve()
+    }
+
+    #[test]
+    fn interpolate() {
+        let source = Source::new("", [("HOST".to_string(), "db".to_string())]);
+        assert_eq!(source.interpolate("${HOST}:5432").unwrap(), "db:5432");
+        assert_eq!(source.interpolate("$${HOST}").unwrap(), "${HOST}");
+        assert!(matches!(
+            source.interpolate("${PORT}"),
+            Err(EnvError::Missing(name)) if name == "PORT"
+        ));
+        assert!(matches!(
+            source.interpolate("${HOST"),
+            Err(EnvError::Unterminated)
+        ));
+    }
+
+    #[test]
+    fn interpolate_nested_values() {
+        let table = resolve(
+            "api_keys = [\"${KEY}\"]\n[audit.backends.mysql]\nhost = \"${HOST}\"\n",
+            &[("KEY", "sk-1"), ("HOST", "db")],
+        )
+        .unwrap();
+        assert_eq!(table["api_keys"][0].as_str(), Some("sk-1"));
+        assert_eq!(
+            table["audit"]["backends"]["mysql"]["host"].as_str(),
+            Some("db")
+        );
+    }
+
+    #[test]
+    fn override_keeps_strings() {
+        let table = resolve(
+            "bind = \"0.0.0.0:8080\"\n",
+            &[("OPENAI_HUB__BIND", "127.0.0.1:9090")],
+        )
+        .unwrap();
+        assert_eq!(table["bind"].as_str(), Some("127.0.0.1:9090"));
+    }
+
+    #[test]
+    fn override_parses_other_values() {
+        let table = resolve(
+            "",
+            &[
+                ("OPENAI_HUB__SHUTDOWN_TIMEOUT", "5"),
+                ("OPENAI_HUB__AUDIT__BACKENDS__MYSQL__HOST", "db"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(table["shutdown_timeout"].as_integer(), Some(5));
+        assert_eq!(
+            table["audit"]["backends"]["mysql"]["host"].as_str(),
+            Some("db")
+        );
+    }
+
+    #[test]
+    fn override_lists() {
+        let table = resolve("", &[("OPENAI_HUB__API_KEYS", "sk-1, sk-2")]).unwrap();
+        let keys: Vec<_> = table["api_keys"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|key| key.as_str().unwrap())
+            .collect();
+        assert_eq!(keys, ["sk-1", "sk-2"]);
+
+        let table = resolve(
+            "api_keys = [\"sk-0\"]\n",
+            &[("OPENAI_HUB__API_KEYS", "[\"sk-1\"]")],
+        )
+        .unwrap();
+        assert_eq!(table["api_keys"].as_array().unwrap().len(), 1);
+        assert_eq!(table["api_keys"][0].as_str(), Some("sk-1"));
+    }
+
+    #[test]
+    fn override_hyphenated_section() {
+        let table = resolve("", &[("OPENAI_HUB__JWT_AUTH__SECRET", "s")]).unwrap();
+        assert_eq!(table["jwt-auth"]["secret"].as_str(), Some("s"));
+    }
+
+    #[test]
+    fn override_replaces_secret_file() {
+        let table = resolve(
+            "api_keys_file = \"/nonexistent\"\n",
+            &[("OPENAI_HUB__API_KEYS", "sk-1")],
+        )
+        .unwrap();
+        assert!(!table.contains_key("api_keys_file"));
+        assert_eq!(table["api_keys"][0].as_str(), Some("sk-1"));
+    }
+
+    #[test]
+    fn override_into_value_fails() {
+        let result = resolve(
+            "bind = \"0.0.0.0:8080\"\n",
+            &[("OPENAI_HUB__BIND__ADDR", "x")],
+        );
+        assert!(matches!(
+            result,
+            Err(LoadError::Invalid {
+                error: InvalidValue::Env(EnvError::NotATable(_)),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn origin() {
+        let text = "bind = \"0.0.0.0:8080\"\n\n[network]\nallow = []\n";
+        let mut source = Source::new(
+            text,
+            [(
+                "OPENAI_HUB__NETWORK__DENY".to_string(),
+                "10.0.0.0/8".to_string(),
+            )],
+        );
+        source.resolve().unwrap();
+        assert!(matches!(source.origin("bind"), Origin::Line(1)));
+        assert!(matches!(source.origin("network.allow[0]"), Origin::Line(4)));
+        assert!(matches!(
+            source.origin("network.deny[0]"),
+            Origin::Env(var) if var == "OPENAI_HUB__NETWORK__DENY"
+        ));
+    }
+}
@@ -9,6 +9,7 @@ pub struct JwtAuthConfig {
 }
 
 #[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct JwtAuthConfigDe {
     pub secret: String,
 }
@@ -0,0 +1,222 @@
+use axum::http::HeaderValue;
+use serde::de::value::{MapAccessDeserializer, SeqAccessDeserializer};
+use serde::de::{self, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// A listener and the middleware profile of the requests it accepts.
+#[derive(Clone, Debug)]
+pub struct ListenerConfig {
+    pub addr: ListenAddr,
+    /// Requests must carry a valid JWT, when `[jwt-auth]` is configured
+    pub jwt_auth: bool,
+    /// Authenticated subject of the requests skipping JWT, anonymous without it
+    pub subject: Option<String>,
+    /// Serve HTTPS when `[tls]` is configured, TCP listeners only
+    pub tls: bool,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    /// `unix:<path>`
+    Unix(PathBuf),
+    /// `systemd` for every socket passed by socket activation, `systemd:<n>` for the n-th one
+    Systemd(Option<usize>),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum InvalidListener {
+    #[error("invalid listen address {0}")]
+    Addr(String),
+    #[error("invalid subject {0}")]
+    Subject(String),
+}
+
+impl FromStr for ListenAddr {
+    type Err = InvalidListener;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidListener::Addr(s.to_string());
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(invalid());
+            }
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+        if s == "systemd" {
+            return Ok(Self::Systemd(None));
+        }
+        if let Some(index) = s.strip_prefix("systemd:") {
+            return index
+                .parse()
+                .map(|index| Self::Systemd(Some(index)))
+                .map_err(|_| invalid());
+        }
+        s.parse().map(Self::Tcp).map_err(|_| invalid())
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+            Self::Systemd(None) => write!(f, "systemd"),
+            Self::Systemd(Some(index)) => write!(f, "systemd:{index}"),
+        }
+    }
+}
+
+/// `bind` is an address, or a list of addresses and listener tables.
+pub(crate) enum BindDe {
+    One(String),
+    Many(Vec<ListenerDe>),
+}
+
+pub(crate) enum ListenerDe {
+    Addr(String),
+    Listener(ListenerTableDe),
+}
+
+/// Deserialized on its own, so that a misspelled key is reported as such.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ListenerTableDe {
+    bind: String,
+    #[serde(default = "default_true")]
+    jwt_auth: bool,
+    #[serde(default)]
+    subject: Option<String>,
+    #[serde(default = "default_true")]
+    tls: bool,
+}
+
+impl<'de> Deserialize<'de> for BindDe {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct BindVisitor;
+
+        impl<'de> Visitor<'de> for BindVisitor {
+            type Value = BindDe;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an address or a list of addresses and listener tables")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<BindDe, E> {
+                Ok(BindDe::One(v.to_string()))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<BindDe, A::Error> {
+                Vec::deserialize(SeqAccessDeserializer::new(seq)).map(BindDe::Many)
+            }
+        }
+
+        deserializer.deserialize_any(BindVisitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for ListenerDe {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ListenerVisitor;
+
+        impl<'de> Visitor<'de> for ListenerVisitor {
+            type Value = ListenerDe;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an address or a listener table")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<ListenerDe, E> {
+                Ok(ListenerDe::Addr(v.to_string()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<ListenerDe, A::Error> {
+                ListenerTableDe::deserialize(MapAccessDeserializer::new(map))
+                    .map(ListenerDe::Listener)
+            }
+        }
+
+        deserializer.deserialize_any(ListenerVisitor)
+    }
+}
+
+impl BindDe {
+    pub(crate) fn into_listeners(self) -> Result<Vec<ListenerConfig>, InvalidListener> {
+        let listeners = match self {
+            Self::One(addr) => vec![ListenerDe::Addr(addr)],
+            Self::Many(listeners) => listeners,
+        };
+        listeners
+            .into_iter()
+            .map(|listener| match listener {
+                ListenerDe::Addr(addr) => Ok(ListenerConfig {
+                    addr: addr.parse()?,
+                    jwt_auth: true,
+                    subject: None,
+                    tls: true,
+                }),
+                ListenerDe::Listener(ListenerTableDe {
+                    bind,
+                    jwt_auth,
+                    subject,
+                    tls,
+                }) => {
+                    // sent to the inner layers in a header
+                    if let Some(ref subject) = subject {
+                        HeaderValue::from_str(subject)
+                            .map_err(|_| InvalidListener::Subject(subject.clone()))?;
+                    }
+                    Ok(ListenerConfig {
+                        addr: bind.parse()?,
+                        jwt_auth,
+                        subject,
+                        tls,
+                    })
+                }
+            })
+            .collect()
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Config {
+        bind: BindDe,
+    }
+
+    fn listeners(toml: &str) -> Result<Vec<ListenerConfig>, String> {
+        let config: Config = toml::from_str(toml).map_err(|e| e.to_string())?;
+        config.bind.into_listeners().map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn bind_forms() {
+        let one = listeners(r#"bind = "127.0.0.1:8080""#).unwrap();
+        assert_eq!(one.len(), 1);
+        let many = listeners(
+            r#"bind = ["127.0.0.1:8080", { bind = "unix:/run/hub.sock", jwt_auth = false, subject = "local" }]"#,
+        )
+        .unwrap();
+        assert_eq!(many.len(), 2);
+        assert!(!many[1].jwt_auth);
+        assert_eq!(many[1].subject.as_deref(), Some("local"));
+        assert!(many[1].tls);
+    }
+
+    #[test]
+    fn misspelled_key() {
+        let e = listeners(r#"bind = [{ bind = "127.0.0.1:8080", jwt_uath = false }]"#).unwrap_err();
+        assert!(e.contains("unknown field `jwt_uath`"), "{e}");
+    }
+}
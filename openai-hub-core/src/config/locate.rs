@@ -0,0 +1,112 @@
+/// Line of a TOML document defining a dotted key path, e.g. `audit.backends.mysql.password`
+/// or `bind[1].subject`.
+///
+/// Keys inside inline tables and arrays are located at the line of the enclosing key, keys
+/// missing from the document at the line of their nearest parent table, and tables only
+/// defined by nested tables at the first line defining one.
+pub(crate) fn line_of(source: &str, key: &str) -> Option<usize> {
+    let target = key_segments(key);
+
+    let mut table: Vec<String> = vec![];
+    let mut best: Option<(usize, usize)> = None;
+    for (index, line) in source.lines().enumerate() {
+        let line = line.trim();
+        let is_header = line.starts_with('[')
+            && line
+                .split('#')
+                .next()
+                .unwrap_or_default()
+                .trim_end()
+                .ends_with(']');
+        let path = if let Some(header) = line.strip_prefix('[').filter(|_| is_header) {
+            let header = header.trim_start_matches('[');
+            let Some(end) = header.find(']') else {
+                continue;
+            };
+            table = split_key(&header[..end]);
+            table.clone()
+        } else if let Some(end) = key_end(line) {
+            let mut path = table.clone();
+            path.extend(split_key(&line[..end]));
+            path
+        } else {
+            continue;
+        };
+        // a table header also defines the tables it is nested in
+        let matched = path.len().min(target.len());
+        if path[..matched] == target[..matched] && !matches!(best, Some((len, _)) if len >= matched)
+        {
+            best = Some((matched, index + 1));
+        }
+    }
+    best.map(|(_, line)| line)
+}
+
+/// Line of a byte offset into a document, e.g. the start of the span of a TOML error.
+pub(crate) fn line_at(source: &str, offset: usize) -> usize {
+    let offset = offset.min(source.len());
+    source.as_bytes()[..offset]
+        .iter()
+        .filter(|b| **b == b'\n')
+        .count()
+        + 1
+}
+
+/// Segments of a dotted key path, without the array indices.
+pub(crate) fn key_segments(key: &str) -> Vec<String> {
+    split_key(key)
+        .into_iter()
+        .map(|mut segment| {
+            // `bind[1]` is the array `bind`
+            while let Some(index) =
+                segment
+                    .strip_suffix(']')
+                    .and_then(|s| s.rfind('['))
+                    .filter(|index| {
+                        segment[index + 1..segment.len() - 1]
+                            .parse::<usize>()
+                            .is_ok()
+                    })
+            {
+                segment.truncate(index);
+            }
+            segment
+        })
+        .filter(|segment| !segment.is_empty())
+        .collect()
+}
+
+/// End of the key of a `key = value` line.
+fn key_end(line: &str) -> Option<usize> {
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let mut quote = None;
+    for (index, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '=') => return Some(index),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Segments of a dotted key, quoted segments may contain dots.
+fn split_key(key: &str) -> Vec<String> {
+    let mut segments = vec![];
+    let mut segment = String::new();
+    let mut quote = None;
+    for c in key.chars() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '.') => segments.push(std::mem::take(&mut segment)),
+            (None, c) if c.is_whitespace() => {}
+            (_, c) => segment.push(c),
+        }
+    }
+    segments.push(segment);
+    segments
+}
@@ -1,9 +1,47 @@
+use crate::key::mask_key;
 use serde::{Deserialize, Serialize};
-use std::net::{AddrParseError, SocketAddr};
+use std::fmt;
 
 #[cfg(feature = "acl")]
 use crate::acl::ApiAcl;
 
+mod admin;
+pub use admin::AdminConfig;
+mod env;
+pub use env::{EnvError, Origin, ENV_PREFIX};
+mod locate;
+pub(crate) use locate::{line_at, line_of};
+mod listener;
+use listener::BindDe;
+pub use listener::{InvalidListener, ListenAddr, ListenerConfig};
+mod network;
+pub use network::{ForwardedHeader, NetworkConfig};
+
+#[cfg(feature = "content-filter")]
+mod content_filter;
+#[cfg(feature = "content-filter")]
+pub use content_filter::*;
+
+#[cfg(feature = "moderation")]
+mod moderation;
+#[cfg(feature = "moderation")]
+pub use moderation::ModerationConfig;
+
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "metrics")]
+pub use metrics::MetricsConfig;
+
+#[cfg(feature = "otel")]
+mod otel;
+#[cfg(feature = "otel")]
+pub use otel::OtelConfig;
+
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "tls")]
+pub use tls::{ClientSubject, TlsConfig};
+
 #[cfg(feature = "jwt-auth")]
 mod jwt_auth;
 #[cfg(feature = "jwt-auth")]
@@ -16,17 +54,66 @@ mod audit;
 #[cfg(feature = "audit")]
 pub use audit::*;
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct ServerConfig {
-    pub addr: SocketAddr,
+    /// Hash of the resolved configuration without secrets, to tell which configuration
+    /// is loaded
+    pub version: String,
+    pub listeners: Vec<ListenerConfig>,
+    /// Seconds to wait for in-flight requests and streams on shutdown
+    pub shutdown_timeout: u64,
     pub api_keys: Vec<String>,
     pub openai: OpenAIConfig,
+    pub admin: Option<AdminConfig>,
+    pub network: NetworkConfig,
     #[cfg(feature = "acl")]
     pub global_api_acl: Option<ApiAcl>,
     #[cfg(feature = "jwt-auth")]
     pub jwt_auth: Option<JwtAuthConfig>,
     #[cfg(feature = "audit")]
     pub audit: Option<AuditConfig>,
+    #[cfg(feature = "content-filter")]
+    pub content_filter: Option<ContentFilterConfig>,
+    #[cfg(feature = "moderation")]
+    pub moderation: Option<ModerationConfig>,
+    #[cfg(feature = "metrics")]
+    pub metrics: Option<MetricsConfig>,
+    #[cfg(feature = "otel")]
+    pub otel: Option<OtelConfig>,
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsConfig>,
+}
+
+impl fmt::Debug for ServerConfig {
+    /// Same as derived, with the API keys masked, since the configuration is logged.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let api_keys: Vec<_> = self.api_keys.iter().map(|key| mask_key(key)).collect();
+        let mut s = f.debug_struct("ServerConfig");
+        s.field("version", &self.version)
+            .field("listeners", &self.listeners)
+            .field("shutdown_timeout", &self.shutdown_timeout)
+            .field("api_keys", &api_keys)
+            .field("openai", &self.openai)
+            .field("admin", &self.admin)
+            .field("network", &self.network);
+        #[cfg(feature = "acl")]
+        s.field("global_api_acl", &self.global_api_acl);
+        #[cfg(feature = "jwt-auth")]
+        s.field("jwt_auth", &self.jwt_auth);
+        #[cfg(feature = "audit")]
+        s.field("audit", &self.audit);
+        #[cfg(feature = "content-filter")]
+        s.field("content_filter", &self.content_filter);
+        #[cfg(feature = "moderation")]
+        s.field("moderation", &self.moderation);
+        #[cfg(feature = "metrics")]
+        s.field("metrics", &self.metrics);
+        #[cfg(feature = "otel")]
+        s.field("otel", &self.otel);
+        #[cfg(feature = "tls")]
+        s.field("tls", &self.tls);
+        s.finish()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -50,17 +137,50 @@ pub enum ApiType {
 
 #[derive(Debug, thiserror::Error)]
 pub enum LoadError {
+    /// Not a TOML document
+    #[error("{0}\n{}", env::PRECEDENCE)]
+    Toml(#[from] toml::de::Error),
+    #[error("`{key}`{origin}: {error}\n{}", env::PRECEDENCE)]
+    Invalid {
+        key: String,
+        origin: Origin,
+        error: InvalidValue,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum InvalidValue {
     #[error(transparent)]
-    AddrParse(#[from] AddrParseError),
+    Listener(#[from] InvalidListener),
     #[error(transparent)]
-    Toml(#[from] toml::de::Error),
+    Env(#[from] EnvError),
+    #[error("{0}")]
+    Value(String),
+    #[error("requires the `{0}` feature, which this build does not include")]
+    FeatureDisabled(&'static str),
 }
 
+/// Sections of the features that can be left out of a build
+const FEATURE_SECTIONS: &[(&str, bool)] = &[
+    ("jwt-auth", cfg!(feature = "jwt-auth")),
+    ("audit", cfg!(feature = "audit")),
+    ("content-filter", cfg!(feature = "content-filter")),
+    ("moderation", cfg!(feature = "moderation")),
+    ("metrics", cfg!(feature = "metrics")),
+    ("otel", cfg!(feature = "otel")),
+    ("tls", cfg!(feature = "tls")),
+];
+
 impl ServerConfig {
+    /// Load the configuration file, with the environment variable overrides and secret files
+    /// it refers to.
     pub fn load(s: &str) -> Result<Self, LoadError> {
         #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
         struct ConfigDe {
-            bind: String,
+            bind: BindDe,
+            #[serde(default = "default_shutdown_timeout")]
+            shutdown_timeout: u64,
             api_keys: Vec<String>,
             #[serde(default)]
             organization: Option<String>,
@@ -70,6 +190,10 @@ impl ServerConfig {
             api_type: ApiType,
             #[serde(default)]
             api_version: Option<String>,
+            #[serde(default)]
+            admin: Option<AdminConfig>,
+            #[serde(default)]
+            network: NetworkConfig,
             #[cfg(feature = "jwt-auth")]
             #[serde(rename = "jwt-auth")]
             #[serde(default)]
@@ -77,10 +201,64 @@ impl ServerConfig {
             #[cfg(feature = "audit")]
             #[serde(default)]
             audit: Option<AuditConfig>,
+            #[cfg(feature = "content-filter")]
+            #[serde(rename = "content-filter")]
+            #[serde(default)]
+            content_filter: Option<ContentFilterConfig>,
+            #[cfg(feature = "moderation")]
+            #[serde(default)]
+            moderation: Option<ModerationConfig>,
+            #[cfg(feature = "metrics")]
+            #[serde(default)]
+            metrics: Option<MetricsConfig>,
+            #[cfg(feature = "otel")]
+            #[serde(default)]
+            otel: Option<OtelConfig>,
+            #[cfg(feature = "tls")]
+            #[serde(default)]
+            tls: Option<TlsConfig>,
+        }
+        // variables that are not unicode cannot be referenced
+        let vars = std::env::vars_os().filter_map(|(name, value)| {
+            Some((name.into_string().ok()?, value.into_string().ok()?))
+        });
+        let mut source = env::Source::new(s, vars);
+        let table = source.resolve()?;
+        let version = config_version(&table);
+        for (section, enabled) in FEATURE_SECTIONS {
+            if !enabled && table.contains_key(*section) {
+                return Err(source.invalid(section, InvalidValue::FeatureDisabled(section)));
+            }
+        }
+        let config_de: ConfigDe = serde_path_to_error::deserialize(toml::Value::Table(table))
+            .map_err(|e| {
+                let key = e.path().to_string();
+                source.invalid(
+                    &key,
+                    InvalidValue::Value(e.into_inner().message().to_string()),
+                )
+            })?;
+        #[cfg(feature = "content-filter")]
+        if let Some(index) = config_de
+            .content_filter
+            .as_ref()
+            .and_then(ContentFilterConfig::match_all_rule)
+        {
+            return Err(source.invalid(
+                &format!("content-filter.rules[{index}]"),
+                InvalidValue::Value(
+                    "the rule needs non-empty patterns or keywords, it would match any request"
+                        .to_string(),
+                ),
+            ));
         }
-        let config_de: ConfigDe = toml::from_str(s)?;
         Ok(Self {
-            addr: config_de.bind.parse()?,
+            version,
+            listeners: config_de
+                .bind
+                .into_listeners()
+                .map_err(|e| source.invalid("bind", e))?,
+            shutdown_timeout: config_de.shutdown_timeout,
             api_keys: config_de.api_keys,
             openai: OpenAIConfig {
                 organization: config_de.organization,
@@ -90,12 +268,24 @@ impl ServerConfig {
                 api_type: config_de.api_type,
                 api_version: config_de.api_version,
             },
+            admin: config_de.admin,
+            network: config_de.network,
             #[cfg(feature = "acl")]
             global_api_acl: None,
             #[cfg(feature = "jwt-auth")]
             jwt_auth: config_de.jwt_auth.map(Into::into),
             #[cfg(feature = "audit")]
             audit: config_de.audit,
+            #[cfg(feature = "content-filter")]
+            content_filter: config_de.content_filter,
+            #[cfg(feature = "moderation")]
+            moderation: config_de.moderation,
+            #[cfg(feature = "metrics")]
+            metrics: config_de.metrics,
+            #[cfg(feature = "otel")]
+            otel: config_de.otel,
+            #[cfg(feature = "tls")]
+            tls: config_de.tls,
         })
     }
 
@@ -105,3 +295,94 @@ impl ServerConfig {
         self
     }
 }
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+/// FNV-1a hash of the resolved configuration without its secrets.
+fn config_version(table: &toml::Table) -> String {
+    let s = toml::to_string(&env::without_secrets(table)).unwrap_or_default();
+    let hash = s.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{hash:016x}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(all(feature = "jwt-auth", feature = "audit"))]
+    fn debug_masks_secrets() {
+        let config = ServerConfig::load(
+            r#"
+bind = "127.0.0.1:8080"
+api_keys = ["sk-0123456789abcdef"]
+
+[jwt-auth]
+secret = "jwt-secret-value"
+
+[audit]
+backend = "mysql"
+
+[audit.backends.mysql]
+password = "db-password-value"
+"#,
+        )
+        .unwrap();
+        let debug = format!("{config:?}");
+        assert!(!debug.contains("0123456789abcdef"));
+        assert!(debug.contains("cdef"));
+        assert!(!debug.contains("jwt-secret-value"));
+        assert!(!debug.contains("db-password-value"));
+    }
+
+    #[test]
+    #[cfg(feature = "audit")]
+    fn default_redact_headers_are_kept() {
+        let config = ServerConfig::load(
+            r#"
+bind = "127.0.0.1:8080"
+api_keys = ["sk-a"]
+
+[audit]
+backend = "file"
+
+[audit.filters.access]
+enable = true
+method = true
+uri = true
+headers = true
+body = false
+response = false
+redact_headers = ["x-api-token"]
+"#,
+        )
+        .unwrap();
+        let redact_headers = &config.audit.unwrap().filters.access.redact_headers;
+        for header in ["authorization", "cookie", "api-key", "x-api-token"] {
+            assert!(redact_headers.contains(header), "{header}");
+        }
+    }
+
+    #[test]
+    fn version_of_resolved_config() {
+        let version = |s: &str| ServerConfig::load(s).unwrap().version;
+        let base = version("bind = \"127.0.0.1:8080\"\napi_keys = [\"sk-a\"]\n");
+        assert_eq!(
+            base,
+            version("# comment\napi_keys = [ \"sk-a\" ]\nbind = \"127.0.0.1:8080\"\n")
+        );
+        // secrets are not hashed
+        assert_eq!(
+            base,
+            version("bind = \"127.0.0.1:8080\"\napi_keys = [\"sk-b\"]\n")
+        );
+        assert_ne!(
+            base,
+            version("bind = \"127.0.0.1:8081\"\napi_keys = [\"sk-a\"]\n")
+        );
+    }
+}
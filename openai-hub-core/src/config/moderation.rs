@@ -0,0 +1,23 @@
+use serde::Deserialize;
+use std::collections::HashSet;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModerationConfig {
+    /// Endpoints whose requests are moderated before being forwarded
+    #[serde(default = "default_endpoints")]
+    pub endpoints: HashSet<String>,
+    /// Flagged categories that cause the request to be rejected
+    #[serde(default)]
+    pub deny_categories: HashSet<String>,
+    /// The moderation model, left to upstream when omitted
+    #[serde(default)]
+    pub model: Option<String>,
+    /// Forward the request when the moderation call itself fails
+    #[serde(default)]
+    pub fail_open: bool,
+}
+
+fn default_endpoints() -> HashSet<String> {
+    HashSet::from_iter(["/completions".to_string(), "/chat/completions".to_string()])
+}
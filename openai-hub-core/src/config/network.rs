@@ -0,0 +1,48 @@
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::net::{IpAddr, Ipv4Addr};
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// Proxies whose forwarded header is trusted
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    /// The header the trusted proxies append the client address to, the other one is ignored
+    #[serde(default)]
+    pub forwarded_header: ForwardedHeader,
+    /// Client addresses allowed to connect, any address when empty
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+    /// Client addresses rejected
+    #[serde(default)]
+    pub deny: Vec<IpNet>,
+    /// Address of the peers connected to unix socket listeners
+    #[serde(default = "default_local_address")]
+    pub local_address: IpAddr,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            trusted_proxies: vec![],
+            forwarded_header: ForwardedHeader::default(),
+            allow: vec![],
+            deny: vec![],
+            local_address: default_local_address(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedHeader {
+    #[default]
+    XForwardedFor,
+    /// RFC 7239 `Forwarded`
+    Forwarded,
+}
+
+fn default_local_address() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::LOCALHOST)
+}
@@ -0,0 +1,26 @@
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OtelConfig {
+    /// Base URL of the OTLP/HTTP collector, `/v1/traces` is appended
+    #[serde(default = "default_endpoint")]
+    pub endpoint: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    /// Ratio of traces sampled when the client did not decide
+    #[serde(default = "default_sample_ratio")]
+    pub sample_ratio: f64,
+}
+
+fn default_endpoint() -> String {
+    "http://localhost:4318".to_string()
+}
+
+fn default_service_name() -> String {
+    "openai-hub".to_string()
+}
+
+fn default_sample_ratio() -> f64 {
+    1.0
+}
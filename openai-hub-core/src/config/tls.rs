@@ -0,0 +1,42 @@
+use serde::Deserialize;
+use std::path::PathBuf;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM file of the certificate chain, leaf first
+    pub cert: PathBuf,
+    /// PEM file of the private key, PKCS#8, PKCS#1 or SEC1
+    pub key: PathBuf,
+    /// PEM bundle of the CAs client certificates are verified against, no client
+    /// certificate is asked for without it
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+    /// Reject clients without a certificate, otherwise they must authenticate another way
+    #[serde(default = "default_true")]
+    pub client_cert_required: bool,
+    /// Name of the client certificate used as the authenticated subject when JWT is disabled
+    #[serde(default)]
+    pub client_subject: ClientSubject,
+    /// Seconds between two checks of the files for changes, they are reloaded when modified
+    #[serde(default = "default_reload_interval")]
+    pub reload_interval: u64,
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientSubject {
+    /// Common name of the subject
+    #[default]
+    CommonName,
+    /// First DNS name, URI or email address of the subject alternative names
+    SubjectAltName,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_reload_interval() -> u64 {
+    60
+}
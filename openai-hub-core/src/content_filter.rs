@@ -0,0 +1,181 @@
+use crate::config::{ContentFilterAction, ContentFilterConfig};
+use crate::helpers::for_each_prompt_text;
+use regex::{NoExpand, Regex, RegexBuilder};
+use serde_json::Value;
+use tracing::{event, instrument, Level};
+
+/// Compiled content filter rules
+#[derive(Debug, Clone)]
+pub struct ContentFilter {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    name: String,
+    regex: Regex,
+    action: ContentFilterAction,
+    replacement: String,
+}
+
+/// Names of the content filter rules matched by a request, attached to the response extensions
+/// so that the access audit log can pick them up.
+#[derive(Debug, Clone)]
+pub struct ContentFilterMatches {
+    pub rules: Vec<String>,
+    /// The request body with the text matched by any rule redacted, whatever its action
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+pub struct Inspection {
+    /// Names of all matched rules
+    pub matched: Vec<String>,
+    /// The first matched rule with a block action
+    pub blocked_by: Option<String>,
+    /// Whether any text was redacted
+    pub redacted: bool,
+}
+
+impl ContentFilter {
+    pub fn create_with(config: &ContentFilterConfig) -> Result<Self, regex::Error> {
+        let mut rules = Vec::with_capacity(config.rules.len());
+        for rule in config.rules.iter() {
+            let candidates: Vec<String> = rule
+                .patterns
+                .iter()
+                .map(|p| format!("(?:{p})"))
+                .chain(rule.keywords.iter().map(|k| regex::escape(k)))
+                .collect();
+            let regex = RegexBuilder::new(&candidates.join("|"))
+                .case_insensitive(rule.case_insensitive)
+                .build()?;
+            event!(
+                Level::DEBUG,
+                "content filter rule {} compiled to {}",
+                rule.name,
+                regex
+            );
+            rules.push(Rule {
+                name: rule.name.clone(),
+                regex,
+                action: rule.action,
+                replacement: rule.replacement.clone(),
+            });
+        }
+        Ok(Self { rules })
+    }
+
+    /// Run all rules over the user supplied text of a request body, redacting in place.
+    #[instrument(skip_all)]
+    pub fn inspect(&self, body: &mut Value) -> Inspection {
+        let mut inspection = Inspection::default();
+        for rule in self.rules.iter() {
+            let mut hit = false;
+            for_each_prompt_text(body, |text| {
+                if !rule.regex.is_match(text) {
+                    return;
+                }
+                hit = true;
+                if rule.action == ContentFilterAction::Redact {
+                    *text = rule
+                        .regex
+                        .replace_all(text, NoExpand(&rule.replacement))
+                        .into_owned();
+                    inspection.redacted = true;
+                }
+            });
+            if !hit {
+                continue;
+            }
+            event!(Level::DEBUG, "content filter rule {} matched", rule.name);
+            if rule.action == ContentFilterAction::Block && inspection.blocked_by.is_none() {
+                inspection.blocked_by = Some(rule.name.clone());
+            }
+            inspection.matched.push(rule.name.clone());
+        }
+        inspection
+    }
+
+    /// Replace the text matched by any rule, also the rules that only block or log.
+    pub fn redact_matches(&self, body: &mut Value) {
+        for rule in self.rules.iter() {
+            for_each_prompt_text(body, |text| {
+                if rule.regex.is_match(text) {
+                    *text = rule
+                        .regex
+                        .replace_all(text, NoExpand(&rule.replacement))
+                        .into_owned();
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn filter(rules: &str) -> ContentFilter {
+        let config: ContentFilterConfig = toml::from_str(rules).unwrap();
+        ContentFilter::create_with(&config).unwrap()
+    }
+
+    const RULES: &str = r#"
+[[rules]]
+name = "api-key"
+patterns = ["sk-[a-z0-9]{8}"]
+action = "redact"
+
+[[rules]]
+name = "hostname"
+keywords = ["db.internal"]
+case_insensitive = true
+action = "log"
+"#;
+
+    #[test]
+    fn inspect_redacts() {
+        let mut body = json!({
+            "messages": [{"role": "user", "content": "key sk-abcd1234 on DB.internal"}]
+        });
+        let inspection = filter(RULES).inspect(&mut body);
+        assert_eq!(inspection.matched, ["api-key", "hostname"]);
+        assert!(inspection.blocked_by.is_none());
+        assert!(inspection.redacted);
+        assert_eq!(
+            body["messages"][0]["content"],
+            "key [REDACTED] on DB.internal"
+        );
+    }
+
+    #[test]
+    fn inspect_blocks() {
+        let filter = filter("[[rules]]\nname = \"card\"\npatterns = ['\\d{4}-\\d{4}']\n");
+        let mut body = json!({"prompt": "card 1234-5678"});
+        let inspection = filter.inspect(&mut body);
+        assert_eq!(inspection.blocked_by.as_deref(), Some("card"));
+        assert_eq!(body["prompt"], "card 1234-5678");
+    }
+
+    #[test]
+    fn redact_matches_of_all_actions() {
+        let mut body = json!({"input": "key sk-abcd1234 on db.internal"});
+        filter(RULES).redact_matches(&mut body);
+        assert_eq!(body["input"], "key [REDACTED] on [REDACTED]");
+    }
+
+    #[test]
+    fn match_all_rules() {
+        let config: ContentFilterConfig =
+            toml::from_str("[[rules]]\nname = \"a\"\nkeywords = [\"x\"]\n").unwrap();
+        assert_eq!(config.match_all_rule(), None);
+        let config: ContentFilterConfig = toml::from_str("[[rules]]\nname = \"a\"\n").unwrap();
+        assert_eq!(config.match_all_rule(), Some(0));
+        let config: ContentFilterConfig =
+            toml::from_str("[[rules]]\nname = \"a\"\nkeywords = [\"x\"]\n[[rules]]\nname = \"b\"\npatterns = [\"\"]\n")
+                .unwrap();
+        assert_eq!(config.match_all_rule(), Some(1));
+    }
+}
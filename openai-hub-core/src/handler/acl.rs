@@ -1,19 +1,32 @@
-use crate::acl::ApiAcl;
+use crate::acl::{AclError, AclExplanation, ApiAcl};
 use crate::error::ErrorResponse;
+use crate::handler::helpers::is_json;
+use crate::handler::{ClientIp, AUTHED_HEADER};
 use axum::body::Body;
 use axum::extract::{Request, State};
-use axum::http::{header, StatusCode};
+use axum::http::{header, Method, StatusCode};
 use axum::middleware::Next;
 use axum::response::Response;
+use axum::Json;
 use futures::TryStreamExt;
+use parking_lot::RwLock;
+use serde::Deserialize;
+use serde_json::Value;
 use std::io;
+use std::net::IpAddr;
 use std::sync::Arc;
 use tokio::io::AsyncReadExt;
 use tokio_util::io::StreamReader;
 use tracing::{event, Level};
 
+fn rejected(err: AclError) -> ErrorResponse {
+    #[cfg(feature = "metrics")]
+    crate::metrics::METRICS.acl_rejections.inc(&[err.reason()]);
+    ErrorResponse::from(err)
+}
+
 pub async fn global_acl_layer(
-    State(acl): State<Option<Arc<ApiAcl>>>,
+    State(acl): State<Option<Arc<RwLock<ApiAcl>>>>,
     req: Request,
     next: Next,
 ) -> Result<Response, ErrorResponse> {
@@ -24,14 +37,20 @@ pub async fn global_acl_layer(
     let (parts, mut body) = req.into_parts();
     event!(Level::DEBUG, "{} {}", parts.method, parts.uri.path());
 
+    let subject = parts
+        .headers
+        .get(AUTHED_HEADER)
+        .and_then(|h| h.to_str().ok());
+    let client_ip = parts.extensions.get::<ClientIp>().map(|ip| ip.0);
     let may_validate_model = acl
-        .validate(&parts.method, parts.uri.path())
-        .map_err(ErrorResponse::from)?;
+        .read()
+        .validate(&parts.method, parts.uri.path(), subject, client_ip)
+        .map_err(rejected)?;
 
     if let Some(validator) = may_validate_model {
         validator
             .validate_path(parts.uri.path())
-            .map_err(ErrorResponse::from)?;
+            .map_err(rejected)?;
         if !parts.method.is_safe() {
             let content_type = parts
                 .headers
@@ -41,7 +60,7 @@ pub async fn global_acl_layer(
                     ErrorResponse::new(StatusCode::BAD_REQUEST, "missing content-type header")
                 })?;
             event!(Level::DEBUG, "Content-Type: {}", content_type);
-            if content_type == "application/json" {
+            if is_json(content_type) {
                 let mut buf = vec![];
                 StreamReader::new(body.map_err(|e| io::Error::new(io::ErrorKind::Other, e)))
                     .read_to_end(&mut buf)
@@ -52,9 +71,7 @@ pub async fn global_acl_layer(
                 let json: serde_json::Value = serde_json::from_slice(&buf)
                     .map_err(|e| ErrorResponse::new(StatusCode::BAD_REQUEST, e.to_string()))?;
                 event!(Level::DEBUG, "json: {:?}", json);
-                validator
-                    .validate_body(&json)
-                    .map_err(ErrorResponse::from)?;
+                validator.validate_body(&json).map_err(rejected)?;
 
                 body = Body::from(serde_json::to_string(&json).unwrap());
             }
@@ -64,3 +81,32 @@ pub async fn global_acl_layer(
     let req = Request::from_parts(parts, body);
     Ok(next.run(req).await)
 }
+
+#[derive(Deserialize)]
+pub struct AclCheckRequest {
+    #[serde(with = "http_serde::method")]
+    method: Method,
+    path: String,
+    #[serde(default)]
+    body: Option<Value>,
+    #[serde(default)]
+    sub: Option<String>,
+    #[serde(default)]
+    ip: Option<IpAddr>,
+}
+
+pub async fn acl_check_handler(
+    State(acl): State<Option<Arc<RwLock<ApiAcl>>>>,
+    Json(check): Json<AclCheckRequest>,
+) -> Result<Json<AclExplanation>, ErrorResponse> {
+    let acl =
+        acl.ok_or_else(|| ErrorResponse::new(StatusCode::NOT_FOUND, "acl is not configured"))?;
+    let explanation = acl.read().explain(
+        &check.method,
+        &check.path,
+        check.body.as_ref(),
+        check.sub.as_deref(),
+        check.ip,
+    );
+    Ok(Json(explanation))
+}
@@ -0,0 +1,265 @@
+use crate::config::AdminConfig;
+use crate::error::ErrorResponse;
+use crate::handler::status::{key_statuses, KeyStatus};
+use crate::handler::AUTHED_HEADER;
+use crate::key::{mask_key, KeyMode, KeyPoolError};
+use crate::managed::{Managed, ManagedError};
+use axum::extract::{Path, Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tracing::{event, instrument, Level};
+
+#[cfg(feature = "acl")]
+use crate::acl::{LoadError, Role, Subject};
+#[cfg(feature = "audit")]
+use crate::audit::{AccessLog, AuditWriter};
+#[cfg(feature = "audit")]
+use crate::config::UsageQuota;
+#[cfg(feature = "audit")]
+use crate::handler::ClientIp;
+#[cfg(feature = "audit")]
+use axum::extract::OriginalUri;
+#[cfg(any(feature = "acl", feature = "audit"))]
+use std::collections::BTreeMap;
+
+#[instrument(skip_all)]
+pub async fn admin_layer(
+    State(admin_config): State<Option<Arc<AdminConfig>>>,
+    req: Request,
+    next: Next,
+) -> Result<Response, ErrorResponse> {
+    let subject = req
+        .headers()
+        .get(AUTHED_HEADER)
+        .and_then(|h| h.to_str().ok());
+    let is_admin = match (admin_config, subject) {
+        (Some(config), Some(subject)) => config.subjects.contains(subject),
+        _ => false,
+    };
+    if !is_admin {
+        event!(
+            Level::WARN,
+            "rejected admin request from {:?} to {}",
+            subject,
+            req.uri().path()
+        );
+        return Err(ErrorResponse::new(
+            StatusCode::FORBIDDEN,
+            "admin privilege required",
+        ));
+    }
+    Ok(next.run(req).await)
+}
+
+/// A change made through the admin API, recorded in the audit log without secrets.
+#[derive(Clone)]
+pub struct AdminChange(pub Value);
+
+/// Record the changes made by admins in the audit log.
+#[cfg(feature = "audit")]
+pub async fn admin_audit_layer(
+    State(writer): State<Option<AuditWriter>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(writer) = writer else {
+        return next.run(req).await;
+    };
+    let mut log = AccessLog::now();
+    log.user = req
+        .headers()
+        .get(AUTHED_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .map(|s| s.to_string());
+    log.client_ip = req
+        .extensions()
+        .get::<ClientIp>()
+        .map(|ip| ip.0.to_string());
+    log.method = Some(req.method().to_string());
+    // the uri of nested routes misses the `/hub` prefix
+    log.uri = Some(
+        req.extensions()
+            .get::<OriginalUri>()
+            .map(|uri| uri.0.to_string())
+            .unwrap_or_else(|| req.uri().to_string()),
+    );
+
+    let response = next.run(req).await;
+    if let Some(AdminChange(change)) = response.extensions().get::<AdminChange>() {
+        log.body = Some(serde_json::to_vec(change).unwrap());
+        log.response_status = Some(response.status().as_u16());
+        writer.log_access(log).await;
+    }
+    response
+}
+
+fn managed_error(err: ManagedError) -> ErrorResponse {
+    let status_code = match err {
+        ManagedError::KeyPool(KeyPoolError::NotFound(_))
+        | ManagedError::NotFound(_)
+        | ManagedError::NotConfigured(_) => StatusCode::NOT_FOUND,
+        ManagedError::KeyPool(KeyPoolError::AlreadyExists(_)) => StatusCode::CONFLICT,
+        #[cfg(feature = "acl")]
+        ManagedError::Acl(LoadError::RoleInUse(_, _)) => StatusCode::CONFLICT,
+        #[cfg(feature = "acl")]
+        ManagedError::Acl(_) => StatusCode::BAD_REQUEST,
+        ManagedError::UnknownWindow(_) => StatusCode::BAD_REQUEST,
+        ManagedError::InvalidState(_) | ManagedError::Io(_) => {
+            event!(Level::ERROR, error = ?err, "failed to save admin change");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+    ErrorResponse::new(status_code, err.to_string())
+}
+
+pub async fn list_keys_handler(State(managed): State<Arc<Managed>>) -> Json<Vec<KeyStatus>> {
+    Json(key_statuses(managed.key_pool()))
+}
+
+#[derive(Deserialize)]
+pub struct AddKeyRequest {
+    key: String,
+}
+
+pub async fn add_key_handler(
+    State(managed): State<Arc<Managed>>,
+    Json(req): Json<AddKeyRequest>,
+) -> Result<impl IntoResponse, ErrorResponse> {
+    let masked = mask_key(&req.key);
+    let id = managed.add_key(req.key).await.map_err(managed_error)?;
+    Ok((
+        StatusCode::CREATED,
+        Extension(AdminChange(json!({ "id": id, "key": masked }))),
+        Json(json!({ "id": id })),
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct SetKeyModeRequest {
+    mode: KeyMode,
+}
+
+pub async fn set_key_mode_handler(
+    State(managed): State<Arc<Managed>>,
+    Path(id): Path<String>,
+    Json(req): Json<SetKeyModeRequest>,
+) -> Result<impl IntoResponse, ErrorResponse> {
+    managed
+        .set_key_mode(&id, req.mode)
+        .await
+        .map_err(managed_error)?;
+    Ok((
+        Extension(AdminChange(json!({ "id": id, "mode": req.mode }))),
+        StatusCode::NO_CONTENT,
+    ))
+}
+
+pub async fn remove_key_handler(
+    State(managed): State<Arc<Managed>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, ErrorResponse> {
+    managed.remove_key(&id).await.map_err(managed_error)?;
+    Ok((
+        Extension(AdminChange(json!({ "id": id }))),
+        StatusCode::NO_CONTENT,
+    ))
+}
+
+#[cfg(feature = "acl")]
+pub async fn list_roles_handler(
+    State(managed): State<Arc<Managed>>,
+) -> Result<Json<BTreeMap<String, Role>>, ErrorResponse> {
+    managed.roles().map(Json).map_err(managed_error)
+}
+
+#[cfg(feature = "acl")]
+pub async fn set_role_handler(
+    State(managed): State<Arc<Managed>>,
+    Path(name): Path<String>,
+    Json(role): Json<Role>,
+) -> Result<impl IntoResponse, ErrorResponse> {
+    let change = json!({ "role": name, "rules": role });
+    managed.set_role(name, role).await.map_err(managed_error)?;
+    Ok((Extension(AdminChange(change)), StatusCode::NO_CONTENT))
+}
+
+#[cfg(feature = "acl")]
+pub async fn remove_role_handler(
+    State(managed): State<Arc<Managed>>,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse, ErrorResponse> {
+    managed.remove_role(&name).await.map_err(managed_error)?;
+    Ok((
+        Extension(AdminChange(json!({ "role": name }))),
+        StatusCode::NO_CONTENT,
+    ))
+}
+
+#[cfg(feature = "acl")]
+pub async fn list_users_handler(
+    State(managed): State<Arc<Managed>>,
+) -> Result<Json<BTreeMap<String, Subject>>, ErrorResponse> {
+    managed.users().map(Json).map_err(managed_error)
+}
+
+#[cfg(feature = "acl")]
+pub async fn set_user_handler(
+    State(managed): State<Arc<Managed>>,
+    Path(name): Path<String>,
+    Json(user): Json<Subject>,
+) -> Result<impl IntoResponse, ErrorResponse> {
+    let change = json!({ "user": name, "rules": user });
+    managed.set_user(name, user).await.map_err(managed_error)?;
+    Ok((Extension(AdminChange(change)), StatusCode::NO_CONTENT))
+}
+
+#[cfg(feature = "acl")]
+pub async fn remove_user_handler(
+    State(managed): State<Arc<Managed>>,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse, ErrorResponse> {
+    managed.remove_user(&name).await.map_err(managed_error)?;
+    Ok((
+        Extension(AdminChange(json!({ "user": name }))),
+        StatusCode::NO_CONTENT,
+    ))
+}
+
+#[cfg(feature = "audit")]
+pub async fn list_quotas_handler(
+    State(managed): State<Arc<Managed>>,
+) -> Result<Json<BTreeMap<String, BTreeMap<String, UsageQuota>>>, ErrorResponse> {
+    managed.quotas().map(Json).map_err(managed_error)
+}
+
+/// Replace the quotas of a user, by usage window name.
+#[cfg(feature = "audit")]
+pub async fn set_quota_handler(
+    State(managed): State<Arc<Managed>>,
+    Path(user): Path<String>,
+    Json(quota): Json<BTreeMap<String, UsageQuota>>,
+) -> Result<impl IntoResponse, ErrorResponse> {
+    let change = json!({ "user": user, "quota": quota });
+    managed
+        .set_quota(user, quota)
+        .await
+        .map_err(managed_error)?;
+    Ok((Extension(AdminChange(change)), StatusCode::NO_CONTENT))
+}
+
+#[cfg(feature = "audit")]
+pub async fn remove_quota_handler(
+    State(managed): State<Arc<Managed>>,
+    Path(user): Path<String>,
+) -> Result<impl IntoResponse, ErrorResponse> {
+    managed.remove_quota(&user).await.map_err(managed_error)?;
+    Ok((
+        Extension(AdminChange(json!({ "user": user }))),
+        StatusCode::NO_CONTENT,
+    ))
+}
@@ -1,37 +1,55 @@
-use crate::audit::{AccessLog, Backend, BackendEngine};
+use crate::audit::{AccessLog, AuditWriter};
 use crate::config::AuditConfig;
+#[cfg(feature = "content-filter")]
+use crate::content_filter::ContentFilterMatches;
 use crate::error::ErrorResponse;
-use crate::handler::helpers::{stream_read_req_body, stream_read_response_body};
-use crate::handler::jwt::AUTHED_HEADER;
-use crate::helpers::HeaderMapExt;
+use crate::handler::helpers::{
+    stream_read_req_body, stream_read_response_body, time_response_body,
+};
+#[cfg(feature = "otel")]
+use crate::handler::record_span_attribute;
+#[cfg(feature = "moderation")]
+use crate::handler::ModerationFlags;
+use crate::handler::{ClientIp, AUTHED_HEADER};
+use crate::helpers::{HeaderMapExt, UpstreamInfo};
+use crate::redact::{redact_headers, redact_json_body};
 use crate::short_circuit_if;
 use axum::extract::{Request, State};
 use axum::middleware::Next;
 use axum::response::Response;
 use std::sync::Arc;
-use tokio::spawn;
+use std::time::Instant;
 
 pub const RAY_ID_HEADER: &str = "X-Ray-Id";
 
 pub async fn audit_access_layer(
-    State(state): State<Option<(Arc<AuditConfig>, Backend)>>,
+    State(state): State<Option<(Arc<AuditConfig>, AuditWriter)>>,
     req: Request,
     next: Next,
 ) -> Result<Response, ErrorResponse> {
     short_circuit_if!(req, next, state.is_none());
 
-    let (config, backend) = state.unwrap();
+    let (config, writer) = state.unwrap();
     short_circuit_if!(req, next, !config.filters.access.enable);
+    writer.ready().await;
 
+    let started = Instant::now();
     let (mut parts, body) = req.into_parts();
     let mut log = AccessLog::now();
     parts.headers.remove(RAY_ID_HEADER);
     parts
         .headers
         .insert(RAY_ID_HEADER, log.ray_id.as_str().parse().unwrap());
+    #[cfg(feature = "otel")]
+    record_span_attribute(&parts.extensions, "ray_id", &log.ray_id);
 
-    if let Some(user) = parts.headers.get(AUTHED_HEADER) {
-        log.user = Some(user.to_str().unwrap().to_string());
+    log.user = parts
+        .headers
+        .get(AUTHED_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .map(ToString::to_string);
+    if let Some(ClientIp(ip)) = parts.extensions.get::<ClientIp>() {
+        log.client_ip = Some(ip.to_string());
     }
     if config.filters.access.method {
         log.method = Some(parts.method.as_str().to_string());
@@ -40,33 +58,74 @@ pub async fn audit_access_layer(
         log.uri = Some(parts.uri.path().to_string());
     }
     if config.filters.access.headers {
-        log.headers = Some(parts.headers.as_btree_map());
+        let mut headers = parts.headers.as_btree_map();
+        redact_headers(&mut headers, &config.filters.access.redact_headers);
+        log.headers = Some(headers);
     }
     let req = Request::from_parts(parts, body);
     let response = if config.filters.access.body {
         let (response, mut body_recv) = stream_read_req_body(req, next).await;
-        log.body = body_recv.recv().await.flatten();
+        log.body = body_recv
+            .recv()
+            .await
+            .flatten()
+            .map(|body| redact_json_body(body, &config.filters.access.redact_body));
         response
     } else {
         next.run(req).await
     };
 
+    #[cfg(feature = "content-filter")]
+    if let Some(matches) = response.extensions().get::<ContentFilterMatches>() {
+        log.content_filter = Some(matches.rules.clone());
+        // the body as received holds the matched text
+        if log.body.is_some() {
+            log.body = Some(redact_json_body(
+                matches.body.clone(),
+                &config.filters.access.redact_body,
+            ));
+        }
+    }
+    #[cfg(feature = "moderation")]
+    if let Some(flags) = response.extensions().get::<ModerationFlags>() {
+        log.moderation = Some(flags.0.clone());
+    }
+
+    if let Some(upstream) = response.extensions().get::<UpstreamInfo>() {
+        log.upstream_status = Some(upstream.status);
+        log.upstream_request_id = upstream.request_id.clone();
+        log.key_fingerprint = Some(upstream.key_fingerprint.clone());
+    }
+    let (response, timing_rx) = time_response_body(response, started);
+
     let response = if config.filters.access.response {
         let status = response.status();
         let headers = response.headers().clone();
 
         let (response, mut body_rx) = stream_read_response_body(response);
-        spawn(async move {
+        writer.tasks().spawn(async move {
             log.response_status = Some(status.as_u16());
-            log.response_headers = Some(headers.as_btree_map());
-            log.response_body = body_rx.recv().await.flatten();
-            backend.log_access(log).await;
+            let mut headers = headers.as_btree_map();
+            redact_headers(&mut headers, &config.filters.access.redact_headers);
+            log.response_headers = Some(headers);
+            log.response_body = body_rx
+                .recv()
+                .await
+                .flatten()
+                .map(|body| redact_json_body(body, &config.filters.access.redact_body));
+            if let Ok(timing) = timing_rx.await {
+                log.set_timing(timing.total, timing.ttfb);
+            }
+            writer.log_access(log).await;
         });
 
         response
     } else {
-        spawn(async move {
-            backend.log_access(log).await;
+        writer.tasks().spawn(async move {
+            if let Ok(timing) = timing_rx.await {
+                log.set_timing(timing.total, timing.ttfb);
+            }
+            writer.log_access(log).await;
         });
         response
     };
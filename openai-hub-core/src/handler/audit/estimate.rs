@@ -0,0 +1,376 @@
+use base64::engine::general_purpose;
+use base64::Engine;
+use parking_lot::Mutex;
+use serde_json::Value;
+use std::sync::Arc;
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer};
+use tiktoken_rs::{
+    cl100k_base_singleton, o200k_base_singleton, p50k_base_singleton, p50k_edit_singleton,
+    r50k_base_singleton, CoreBPE,
+};
+use tracing::{event, Level};
+
+/// Shared tokenizer of a model.
+pub fn bpe_for_model(model: &str) -> Option<Arc<Mutex<CoreBPE>>> {
+    Some(match get_tokenizer(model)? {
+        Tokenizer::O200kBase => o200k_base_singleton(),
+        Tokenizer::Cl100kBase => cl100k_base_singleton(),
+        Tokenizer::P50kBase => p50k_base_singleton(),
+        Tokenizer::P50kEdit => p50k_edit_singleton(),
+        Tokenizer::R50kBase | Tokenizer::Gpt2 => r50k_base_singleton(),
+    })
+}
+
+pub fn count_completions_prompt_tokens(model: &str, req_body: &Value) -> Option<usize> {
+    let bpe = bpe_for_model(model)?;
+    let bpe = bpe.lock();
+    let prompt_tokens = req_body
+        .get("prompt")
+        .and_then(|p| p.as_str())
+        .map(|s| bpe.encode_with_special_tokens(s).len())
+        .unwrap_or(0);
+    Some(prompt_tokens)
+}
+
+/// Estimate the prompt tokens of a chat completion request, including multimodal content,
+/// tool calls and function / tool definitions.
+///
+/// Follows the OpenAI cookbook, definitions are counted in the TypeScript-like format the
+/// model receives them in.
+pub fn count_chat_prompt_tokens(model: &str, req_body: &Value) -> Option<usize> {
+    if !matches!(
+        get_tokenizer(model)?,
+        Tokenizer::Cl100kBase | Tokenizer::O200kBase
+    ) {
+        return None;
+    }
+    let messages = req_body.get("messages")?.as_array()?;
+    let bpe = bpe_for_model(model)?;
+    let bpe = bpe.lock();
+    let count = |s: &str| bpe.encode_with_special_tokens(s).len() as i64;
+
+    let (tokens_per_message, tokens_per_name) = if model.starts_with("gpt-3.5") {
+        // every message follows <im_start>{role/name}\n{content}<im_end>\n,
+        // if there's a name, the role is omitted
+        (4, -1)
+    } else {
+        (3, 1)
+    };
+
+    let mut tokens: i64 = 0;
+    let mut has_system = false;
+    for message in messages.iter() {
+        tokens += tokens_per_message;
+        let role = message.get("role").and_then(Value::as_str).unwrap_or("");
+        has_system |= role == "system";
+        tokens += count(role);
+        match message.get("content") {
+            Some(Value::String(content)) => tokens += count(content),
+            Some(Value::Array(parts)) => {
+                for part in parts.iter() {
+                    match part.get("type").and_then(Value::as_str) {
+                        Some("text") => {
+                            tokens += count(part.get("text").and_then(Value::as_str).unwrap_or(""))
+                        }
+                        Some("image_url") => {
+                            tokens += part.get("image_url").map(image_tokens).unwrap_or(0) as i64
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+        if let Some(name) = message.get("name").and_then(Value::as_str) {
+            tokens += count(name) + tokens_per_name;
+        }
+        let calls = message.get("function_call").into_iter().chain(
+            message
+                .get("tool_calls")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(|call| call.get("function")),
+        );
+        for call in calls {
+            tokens += count(call.get("name").and_then(Value::as_str).unwrap_or(""));
+            tokens += count(call.get("arguments").and_then(Value::as_str).unwrap_or(""));
+        }
+        if let Some(id) = message.get("tool_call_id").and_then(Value::as_str) {
+            tokens += count(id);
+        }
+    }
+    // every reply is primed with <|start|>assistant<|message|>
+    tokens += 3;
+
+    let definitions: Vec<&Value> = req_body
+        .get("functions")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .chain(
+            req_body
+                .get("tools")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(|tool| tool.get("function")),
+        )
+        .collect();
+    if !definitions.is_empty() {
+        tokens += count(&format_function_definitions(&definitions)) + 9;
+        if has_system {
+            // definitions are merged into the system message
+            tokens -= 4;
+        }
+    }
+    Some(tokens.max(0) as usize)
+}
+
+fn format_function_definitions(definitions: &[&Value]) -> String {
+    let mut lines = vec!["namespace functions {".to_string(), String::new()];
+    for definition in definitions.iter() {
+        if let Some(description) = definition.get("description").and_then(Value::as_str) {
+            lines.push(format!("// {description}"));
+        }
+        let name = definition.get("name").and_then(Value::as_str).unwrap_or("");
+        let properties = definition
+            .get("parameters")
+            .and_then(|p| p.get("properties"))
+            .and_then(Value::as_object)
+            .filter(|properties| !properties.is_empty());
+        match properties {
+            Some(_) => {
+                lines.push(format!("type {name} = (_: {{"));
+                lines.push(format_object(definition.get("parameters").unwrap(), 0));
+                lines.push("}) => any;".to_string());
+            }
+            None => lines.push(format!("type {name} = () => any;")),
+        }
+        lines.push(String::new());
+    }
+    lines.push("} // namespace functions".to_string());
+    lines.join("\n")
+}
+
+fn format_object(schema: &Value, indent: usize) -> String {
+    let required: Vec<&str> = schema
+        .get("required")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .collect();
+    let padding = " ".repeat(indent);
+    let mut lines = vec![];
+    for (name, property) in schema
+        .get("properties")
+        .and_then(Value::as_object)
+        .into_iter()
+        .flatten()
+    {
+        if let Some(description) = property.get("description").and_then(Value::as_str) {
+            lines.push(format!("{padding}// {description}"));
+        }
+        let optional = if required.contains(&name.as_str()) {
+            ""
+        } else {
+            "?"
+        };
+        lines.push(format!(
+            "{padding}{name}{optional}: {},",
+            format_type(property, indent)
+        ));
+    }
+    lines.join("\n")
+}
+
+fn format_type(schema: &Value, indent: usize) -> String {
+    if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+        return values
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(" | ");
+    }
+    match schema.get("type").and_then(Value::as_str) {
+        Some("string") => "string".to_string(),
+        Some("number") | Some("integer") => "number".to_string(),
+        Some("boolean") => "boolean".to_string(),
+        Some("null") => "null".to_string(),
+        Some("array") => match schema.get("items") {
+            Some(items) => format!("{}[]", format_type(items, indent)),
+            None => "any[]".to_string(),
+        },
+        Some("object") => format!(
+            "{{\n{}\n{}}}",
+            format_object(schema, indent + 2),
+            " ".repeat(indent)
+        ),
+        _ => "any".to_string(),
+    }
+}
+
+/// Tokens of an image input: 85 for low detail, otherwise 170 per 512px tile of the image
+/// scaled to fit 2048x2048 and then to 768px on its shortest side, plus 85.
+fn image_tokens(image_url: &Value) -> usize {
+    const LOW_DETAIL: usize = 85;
+    const PER_TILE: usize = 170;
+    // dimensions of remote images are not known without fetching them
+    const FALLBACK_SIZE: (u32, u32) = (1024, 1024);
+
+    let detail = image_url.get("detail").and_then(Value::as_str);
+    if detail == Some("low") {
+        return LOW_DETAIL;
+    }
+    let url = image_url
+        .get("url")
+        .and_then(Value::as_str)
+        .or_else(|| image_url.as_str())
+        .unwrap_or("");
+    let (width, height) = data_url_image_size(url).unwrap_or_else(|| {
+        event!(
+            Level::DEBUG,
+            "unknown image size, assuming {:?}",
+            FALLBACK_SIZE
+        );
+        FALLBACK_SIZE
+    });
+
+    let (mut width, mut height) = (width.max(1) as f64, height.max(1) as f64);
+    if width > 2048.0 || height > 2048.0 {
+        let scale = 2048.0 / width.max(height);
+        width *= scale;
+        height *= scale;
+    }
+    let scale = 768.0 / width.min(height);
+    if scale < 1.0 {
+        width *= scale;
+        height *= scale;
+    }
+    let tiles = (width / 512.0).ceil() as usize * (height / 512.0).ceil() as usize;
+    PER_TILE * tiles + LOW_DETAIL
+}
+
+/// Size of a base64 encoded PNG, GIF, JPEG or WebP image in a `data:` URL.
+fn data_url_image_size(url: &str) -> Option<(u32, u32)> {
+    let (meta, data) = url.strip_prefix("data:")?.split_once(',')?;
+    if !meta.ends_with(";base64") {
+        return None;
+    }
+    let bytes = general_purpose::STANDARD.decode(data).ok()?;
+    image_size(&bytes)
+}
+
+fn image_size(bytes: &[u8]) -> Option<(u32, u32)> {
+    let be16 = |i: usize| Some(u16::from_be_bytes(bytes.get(i..i + 2)?.try_into().ok()?) as u32);
+    let le16 = |i: usize| Some(u16::from_le_bytes(bytes.get(i..i + 2)?.try_into().ok()?) as u32);
+    let le24 = |i: usize| {
+        let b = bytes.get(i..i + 3)?;
+        Some(b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16)
+    };
+
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        let be32 = |i: usize| Some(u32::from_be_bytes(bytes.get(i..i + 4)?.try_into().ok()?));
+        return Some((be32(16)?, be32(20)?));
+    }
+    if bytes.starts_with(b"GIF8") {
+        return Some((le16(6)?, le16(8)?));
+    }
+    if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WEBP") {
+        return match bytes.get(12..16)? {
+            b"VP8 " => Some((le16(26)? & 0x3fff, le16(28)? & 0x3fff)),
+            b"VP8L" => {
+                let b = bytes.get(21..25)?;
+                let width = 1 + (b[0] as u32 | (b[1] as u32 & 0x3f) << 8);
+                let height =
+                    1 + ((b[1] as u32) >> 6 | (b[2] as u32) << 2 | (b[3] as u32 & 0x0f) << 10);
+                Some((width, height))
+            }
+            b"VP8X" => Some((1 + le24(24)?, 1 + le24(27)?)),
+            _ => None,
+        };
+    }
+    if bytes.starts_with(b"\xff\xd8") {
+        let mut i = 2;
+        while i + 4 <= bytes.len() {
+            if bytes[i] != 0xff {
+                return None;
+            }
+            let marker = bytes[i + 1];
+            if marker == 0xff {
+                // fill byte
+                i += 1;
+                continue;
+            }
+            // start of frame markers, except DHT, JPG and DAC
+            if (0xc0..=0xcf).contains(&marker) && ![0xc4, 0xc8, 0xcc].contains(&marker) {
+                return Some((be16(i + 7)?, be16(i + 5)?));
+            }
+            i += 2 + be16(i + 2)? as usize;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        bytes.extend_from_slice(&width.to_be_bytes());
+        bytes.extend_from_slice(&height.to_be_bytes());
+        bytes
+    }
+
+    fn data_url(bytes: &[u8]) -> String {
+        format!(
+            "data:image/png;base64,{}",
+            general_purpose::STANDARD.encode(bytes)
+        )
+    }
+
+    #[test]
+    fn low_detail_image() {
+        let image = json!({"url": data_url(&png(4096, 4096)), "detail": "low"});
+        assert_eq!(image_tokens(&image), 85);
+    }
+
+    #[test]
+    fn high_detail_images() {
+        // fits in one tile
+        assert_eq!(image_tokens(&json!({"url": data_url(&png(512, 512))})), 255);
+        // scaled to 768x768, 2x2 tiles
+        assert_eq!(
+            image_tokens(&json!({"url": data_url(&png(1024, 1024))})),
+            765
+        );
+        // scaled to 1024x2048 then 768x1536, 2x3 tiles
+        assert_eq!(
+            image_tokens(&json!({"url": data_url(&png(2048, 4096)), "detail": "high"})),
+            1105
+        );
+        // the legacy form is the URL itself
+        assert_eq!(image_tokens(&json!(data_url(&png(512, 512)))), 255);
+    }
+
+    #[test]
+    fn remote_image_assumes_fallback_size() {
+        let image = json!({"url": "https://example.com/cat.png"});
+        assert_eq!(image_tokens(&image), 765);
+    }
+
+    #[test]
+    fn image_sizes() {
+        assert_eq!(image_size(&png(640, 480)), Some((640, 480)));
+        assert_eq!(image_size(b"GIF89a\x80\x02\xe0\x01"), Some((640, 480)));
+        let jpeg = b"\xff\xd8\xff\xe0\x00\x04\x00\x00\xff\xc0\x00\x11\x08\x01\xe0\x02\x80";
+        assert_eq!(image_size(jpeg), Some((640, 480)));
+        let mut webp = b"RIFF\0\0\0\0WEBPVP8X\0\0\0\0\0\0\0\0".to_vec();
+        webp.extend_from_slice(&[0x7f, 0x02, 0x00, 0xdf, 0x01, 0x00]);
+        assert_eq!(image_size(&webp), Some((640, 480)));
+        assert_eq!(image_size(b"not an image"), None);
+    }
+}
@@ -0,0 +1,482 @@
+use crate::audit::UsageUnit;
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::{event, Level};
+
+/// How the usage of an endpoint is measured.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Meter {
+    /// Text endpoints reporting token usage
+    Tokens,
+    ImageGeneration,
+    /// Image edits and variations, both multipart
+    ImageUpload,
+    /// Transcriptions and translations of an uploaded audio file
+    Transcription,
+    Speech,
+}
+
+impl Meter {
+    pub fn of_endpoint(path: &str) -> Self {
+        if path.ends_with("/images/generations") {
+            Meter::ImageGeneration
+        } else if path.ends_with("/images/edits") || path.ends_with("/images/variations") {
+            Meter::ImageUpload
+        } else if path.ends_with("/audio/transcriptions") || path.ends_with("/audio/translations") {
+            Meter::Transcription
+        } else if path.ends_with("/audio/speech") {
+            Meter::Speech
+        } else {
+            Meter::Tokens
+        }
+    }
+
+    /// Whether the response is needed to measure the usage.
+    pub fn reads_response(&self) -> bool {
+        !matches!(self, Meter::Speech)
+    }
+
+    /// Measure the usage of a request of other units than tokens.
+    pub fn measure_request(
+        &self,
+        content_type: Option<&str>,
+        body: &[u8],
+    ) -> Result<MeteredRequest, &'static str> {
+        match self {
+            Meter::Tokens => Err("tokens are measured from the response"),
+            Meter::ImageGeneration => {
+                let body: ImageGenerationRequest =
+                    serde_json::from_slice(body).map_err(|_| "failed to parse body")?;
+                Ok(MeteredRequest {
+                    meter: *self,
+                    model: body
+                        .model
+                        .unwrap_or_else(|| DEFAULT_IMAGE_MODEL.to_string()),
+                    quantity: body.n.unwrap_or(1) as f64,
+                    details: Some(image_details(body.size, body.quality)),
+                    audio: None,
+                })
+            }
+            Meter::ImageUpload => {
+                let form = multipart_form(content_type, body)?;
+                let n = match form.text("n") {
+                    Some(n) => n.trim().parse().map_err(|_| "invalid 'n' field")?,
+                    None => 1,
+                };
+                Ok(MeteredRequest {
+                    meter: *self,
+                    model: form
+                        .text("model")
+                        .unwrap_or_else(|| DEFAULT_IMAGE_MODEL.to_string()),
+                    quantity: n as f64,
+                    details: Some(image_details(form.text("size"), form.text("quality"))),
+                    audio: None,
+                })
+            }
+            Meter::Transcription => {
+                let form = multipart_form(content_type, body)?;
+                let model = form.text("model").ok_or("missing 'model' field")?;
+                Ok(MeteredRequest {
+                    meter: *self,
+                    model,
+                    quantity: 0.0,
+                    details: None,
+                    audio: form.get("file").and_then(audio_duration),
+                })
+            }
+            Meter::Speech => {
+                let body: SpeechRequest =
+                    serde_json::from_slice(body).map_err(|_| "failed to parse body")?;
+                Ok(MeteredRequest {
+                    meter: *self,
+                    model: body.model,
+                    quantity: body.input.chars().count() as f64,
+                    details: None,
+                    audio: None,
+                })
+            }
+        }
+    }
+}
+
+const DEFAULT_IMAGE_MODEL: &str = "dall-e-2";
+
+/// Usage known from the request alone, completed with the response.
+#[derive(Debug)]
+pub struct MeteredRequest {
+    meter: Meter,
+    model: String,
+    quantity: f64,
+    details: Option<String>,
+    /// Duration in seconds of the uploaded audio
+    audio: Option<f64>,
+}
+
+/// Usage of other units than tokens.
+#[derive(Debug)]
+pub struct MeteredUsage {
+    pub model: String,
+    pub unit: UsageUnit,
+    pub quantity: f64,
+    pub details: Option<String>,
+    pub is_estimated: bool,
+}
+
+impl MeteredRequest {
+    /// Complete the usage with the body of a successful response.
+    ///
+    /// The number of images and the duration reported by upstream are preferred, otherwise
+    /// they are taken from the request.
+    pub fn complete(self, res_body: Option<&[u8]>) -> Option<MeteredUsage> {
+        let res_body: Option<Value> = res_body.and_then(|body| serde_json::from_slice(body).ok());
+        let (unit, quantity, is_estimated) = match self.meter {
+            Meter::Tokens => return None,
+            Meter::ImageGeneration | Meter::ImageUpload => {
+                let images = res_body
+                    .as_ref()
+                    .and_then(|body| body.get("data"))
+                    .and_then(Value::as_array)
+                    .map(|data| data.len() as f64);
+                match images {
+                    Some(images) => (UsageUnit::Images, images, false),
+                    None => (UsageUnit::Images, self.quantity, true),
+                }
+            }
+            Meter::Transcription => {
+                // `verbose_json` responses carry the duration, newer models report it as usage
+                let duration = res_body.as_ref().and_then(|body| {
+                    body.get("duration")
+                        .or_else(|| body.pointer("/usage/seconds"))
+                        .and_then(Value::as_f64)
+                });
+                match (duration, self.audio) {
+                    (Some(duration), _) => (UsageUnit::Seconds, duration, false),
+                    (None, Some(duration)) => (UsageUnit::Seconds, duration, true),
+                    (None, None) => {
+                        event!(Level::WARN, "unknown duration of audio file");
+                        return None;
+                    }
+                }
+            }
+            Meter::Speech => (UsageUnit::Characters, self.quantity, false),
+        };
+        Some(MeteredUsage {
+            model: self.model,
+            unit,
+            quantity,
+            details: self.details,
+            is_estimated,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct ImageGenerationRequest {
+    model: Option<String>,
+    n: Option<u32>,
+    size: Option<String>,
+    quality: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SpeechRequest {
+    model: String,
+    input: String,
+}
+
+/// Size and quality the price of an image depends on, e.g. `1024x1024/hd`.
+fn image_details(size: Option<String>, quality: Option<String>) -> String {
+    format!(
+        "{}/{}",
+        size.as_deref().unwrap_or("1024x1024"),
+        quality.as_deref().unwrap_or("standard")
+    )
+}
+
+/// Fields of a `multipart/form-data` body by name.
+struct MultipartForm<'a> {
+    fields: Vec<(String, &'a [u8])>,
+}
+
+impl<'a> MultipartForm<'a> {
+    fn get(&self, name: &str) -> Option<&'a [u8]> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, data)| *data)
+    }
+
+    fn text(&self, name: &str) -> Option<String> {
+        self.get(name)
+            .map(|data| String::from_utf8_lossy(data).into_owned())
+    }
+}
+
+fn multipart_form<'a>(
+    content_type: Option<&str>,
+    body: &'a [u8],
+) -> Result<MultipartForm<'a>, &'static str> {
+    let boundary = content_type
+        .filter(|content_type| content_type.starts_with("multipart/form-data"))
+        .and_then(|content_type| {
+            content_type
+                .split(';')
+                .filter_map(|param| param.trim().strip_prefix("boundary="))
+                .next()
+        })
+        .map(|boundary| boundary.trim_matches('"'))
+        .ok_or("expected a multipart/form-data body")?;
+    let delimiter = format!("--{boundary}");
+    let delimiter = delimiter.as_bytes();
+
+    let mut fields = vec![];
+    let mut rest = match find(body, delimiter) {
+        Some(start) => &body[start + delimiter.len()..],
+        None => return Err("failed to parse multipart body"),
+    };
+    // every part is followed by a delimiter, the last one by `--`
+    while !rest.starts_with(b"--") {
+        let part = rest.strip_prefix(b"\r\n").unwrap_or(rest);
+        let end = find(part, delimiter).ok_or("failed to parse multipart body")?;
+        let (part, next) = (&part[..end], &part[end + delimiter.len()..]);
+        let part = part.strip_suffix(b"\r\n").unwrap_or(part);
+        let header_end = find(part, b"\r\n\r\n").ok_or("failed to parse multipart body")?;
+        let headers = String::from_utf8_lossy(&part[..header_end]);
+        let name = headers
+            .lines()
+            .filter(|line| {
+                line.to_ascii_lowercase()
+                    .starts_with("content-disposition:")
+            })
+            .flat_map(|line| line.split(';'))
+            .filter_map(|param| param.trim().strip_prefix("name="))
+            .map(|name| name.trim_matches('"').to_string())
+            .next();
+        if let Some(name) = name {
+            fields.push((name, &part[header_end + 4..]));
+        }
+        rest = next;
+    }
+    Ok(MultipartForm { fields })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Duration in seconds of a WAV, FLAC or MP3 file.
+///
+/// MP3 files are assumed to have a constant bitrate.
+fn audio_duration(bytes: &[u8]) -> Option<f64> {
+    let le32 = |i: usize| Some(u32::from_le_bytes(bytes.get(i..i + 4)?.try_into().ok()?));
+
+    if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WAVE") {
+        let (mut i, mut byte_rate) = (12, None);
+        while i + 8 <= bytes.len() {
+            let size = le32(i + 4)? as usize;
+            match &bytes[i..i + 4] {
+                b"fmt " => byte_rate = le32(i + 16).filter(|rate| *rate > 0),
+                b"data" => {
+                    // streamed files may not know the size of their data
+                    let size = size.min(bytes.len() - i - 8);
+                    return Some(size as f64 / byte_rate? as f64);
+                }
+                _ => {}
+            }
+            i += 8 + size + size % 2;
+        }
+        return None;
+    }
+    if bytes.starts_with(b"fLaC") {
+        // STREAMINFO is the first metadata block
+        let info = u64::from_be_bytes(bytes.get(18..26)?.try_into().ok()?);
+        let sample_rate = info >> 44;
+        let samples = info & ((1 << 36) - 1);
+        return (sample_rate > 0).then(|| samples as f64 / sample_rate as f64);
+    }
+
+    let mut i = 0;
+    if bytes.starts_with(b"ID3") {
+        let b = bytes.get(6..10)?;
+        i = 10
+            + ((b[0] as usize & 0x7f) << 21
+                | (b[1] as usize & 0x7f) << 14
+                | (b[2] as usize & 0x7f) << 7
+                | b[3] as usize & 0x7f);
+    }
+    // first frame header
+    while i + 4 <= bytes.len() && !(bytes[i] == 0xff && bytes[i + 1] & 0xe0 == 0xe0) {
+        i += 1;
+    }
+    let header = bytes.get(i..i + 4)?;
+    let version = (header[1] >> 3) & 0x03;
+    let layer = (header[1] >> 1) & 0x03;
+    if layer != 0x01 {
+        // only layer III
+        return None;
+    }
+    const MPEG1: [u32; 15] = [
+        0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ];
+    const MPEG2: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+    let bitrates = match version {
+        0x03 => MPEG1,
+        0x00 | 0x02 => MPEG2,
+        _ => return None,
+    };
+    let bitrate = *bitrates.get((header[2] >> 4) as usize)?;
+    (bitrate > 0).then(|| (bytes.len() - i) as f64 * 8.0 / (bitrate as f64 * 1000.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn form_body(boundary: &str, fields: &[(&str, &str)]) -> Vec<u8> {
+        let mut body = String::new();
+        for (name, value) in fields {
+            body.push_str(&format!(
+                "--{boundary}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n"
+            ));
+        }
+        body.push_str(&format!("--{boundary}--\r\n"));
+        body.into_bytes()
+    }
+
+    #[test]
+    fn multipart_quoted_boundary() {
+        let body = form_body("----abc", &[("model", "dall-e-3"), ("n", "2")]);
+        let form =
+            multipart_form(Some("multipart/form-data; boundary=\"----abc\""), &body).unwrap();
+        assert_eq!(form.text("model").as_deref(), Some("dall-e-3"));
+        assert_eq!(form.text("n").as_deref(), Some("2"));
+        assert!(form.get("size").is_none());
+    }
+
+    #[test]
+    fn multipart_without_closing_delimiter() {
+        let body = b"--abc\r\nContent-Disposition: form-data; name=\"model\"\r\n\r\nwhisper-1\r\n";
+        assert!(multipart_form(Some("multipart/form-data; boundary=abc"), body).is_err());
+    }
+
+    #[test]
+    fn multipart_empty_body() {
+        assert!(multipart_form(Some("multipart/form-data; boundary=abc"), b"").is_err());
+        assert!(multipart_form(Some("application/json"), b"{}").is_err());
+    }
+
+    #[test]
+    fn wav_data_larger_than_file() {
+        let mut wav = b"RIFF\0\0\0\0WAVE".to_vec();
+        wav.extend_from_slice(b"fmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        wav.extend_from_slice(&1u16.to_le_bytes()); // mono
+        wav.extend_from_slice(&8000u32.to_le_bytes()); // sample rate
+        wav.extend_from_slice(&16000u32.to_le_bytes()); // byte rate
+        wav.extend_from_slice(&2u16.to_le_bytes()); // block align
+        wav.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&u32::MAX.to_le_bytes());
+        wav.extend_from_slice(&[0; 8000]);
+        assert_eq!(audio_duration(&wav), Some(0.5));
+    }
+
+    #[test]
+    fn flac_streaminfo() {
+        let mut flac = b"fLaC".to_vec();
+        // last metadata block, STREAMINFO, 34 bytes
+        flac.extend_from_slice(&[0x80, 0, 0, 34]);
+        flac.extend_from_slice(&[0; 10]);
+        let info: u64 = 44100 << 44 | 1 << 41 | 15 << 36 | 88200;
+        flac.extend_from_slice(&info.to_be_bytes());
+        flac.extend_from_slice(&[0; 16]);
+        assert_eq!(audio_duration(&flac), Some(2.0));
+    }
+
+    #[test]
+    fn mp3_with_id3_header() {
+        let mut mp3 = b"ID3\x04\0\0".to_vec();
+        // syncsafe size of the tag, 200 bytes
+        mp3.extend_from_slice(&[0, 0, 1, 0x48]);
+        mp3.extend_from_slice(&[0; 200]);
+        // MPEG-1 layer III, 128 kbps, 44.1 kHz
+        let mut frames = vec![0xff, 0xfb, 0x90, 0x00];
+        frames.resize(16000, 0);
+        mp3.extend_from_slice(&frames);
+        assert_eq!(audio_duration(&mp3), Some(1.0));
+    }
+
+    #[test]
+    fn mp3_other_layers() {
+        // MPEG-1 layer II
+        let mut mp2 = vec![0xff, 0xfd, 0x90, 0x00];
+        mp2.resize(16000, 0);
+        assert_eq!(audio_duration(&mp2), None);
+        // MPEG-1 layer I
+        mp2[1] = 0xff;
+        assert_eq!(audio_duration(&mp2), None);
+        assert_eq!(audio_duration(b"not audio"), None);
+    }
+
+    #[test]
+    fn images_of_response_preferred() {
+        let request = Meter::ImageGeneration
+            .measure_request(None, br#"{"prompt":"a cat","n":2,"size":"512x512"}"#)
+            .unwrap();
+        let usage = request
+            .complete(Some(
+                br#"{"created":1,"data":[{"url":"https://example.com/1.png"}]}"#,
+            ))
+            .unwrap();
+        assert_eq!(usage.unit, UsageUnit::Images);
+        assert_eq!(usage.quantity, 1.0);
+        assert!(!usage.is_estimated);
+        assert_eq!(usage.model, DEFAULT_IMAGE_MODEL);
+        assert_eq!(usage.details.as_deref(), Some("512x512/standard"));
+
+        let request = Meter::ImageGeneration
+            .measure_request(None, br#"{"prompt":"a cat","n":2}"#)
+            .unwrap();
+        let usage = request.complete(None).unwrap();
+        assert_eq!(usage.quantity, 2.0);
+        assert!(usage.is_estimated);
+    }
+
+    #[test]
+    fn duration_of_response_preferred() {
+        let request = || MeteredRequest {
+            meter: Meter::Transcription,
+            model: "whisper-1".to_string(),
+            quantity: 0.0,
+            details: None,
+            audio: Some(3.0),
+        };
+        let usage = request()
+            .complete(Some(br#"{"text":"hi","duration":2.5}"#))
+            .unwrap();
+        assert_eq!(usage.unit, UsageUnit::Seconds);
+        assert_eq!(usage.quantity, 2.5);
+        assert!(!usage.is_estimated);
+
+        let usage = request()
+            .complete(Some(
+                br#"{"text":"hi","usage":{"type":"duration","seconds":2}}"#,
+            ))
+            .unwrap();
+        assert_eq!(usage.quantity, 2.0);
+        assert!(!usage.is_estimated);
+
+        let usage = request().complete(Some(br#"{"text":"hi"}"#)).unwrap();
+        assert_eq!(usage.quantity, 3.0);
+        assert!(usage.is_estimated);
+
+        let request = MeteredRequest {
+            audio: None,
+            ..request()
+        };
+        assert!(request.complete(Some(br#"{"text":"hi"}"#)).is_none());
+    }
+}
@@ -1,5 +1,20 @@
 mod access;
+mod estimate;
+mod meter;
+#[cfg(feature = "jwt-auth")]
+mod query;
+#[cfg(feature = "jwt-auth")]
+mod quota;
+mod stream;
 mod tokens;
+#[cfg(feature = "jwt-auth")]
+mod usage;
 
 pub use access::audit_access_layer;
+#[cfg(feature = "jwt-auth")]
+pub use query::{audit_access_handler, audit_usage_handler, AuditQueryState};
+#[cfg(feature = "jwt-auth")]
+pub use quota::{quota_layer, QuotaGuard};
 pub use tokens::audit_tokens_layer;
+#[cfg(feature = "jwt-auth")]
+pub use usage::user_usage_handler;
@@ -0,0 +1,138 @@
+use crate::audit::{AccessLogEntry, AccessLogFilter, Backend, QueryError, UsageEntry, UsageFilter};
+use crate::config::AuditConfig;
+use crate::error::ErrorResponse;
+use axum::extract::{Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{event, Level};
+
+pub type AuditQueryState = Option<(Backend, Arc<AuditConfig>)>;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct FormatQuery {
+    format: OutputFormat,
+}
+
+#[derive(Serialize)]
+struct UsageReport {
+    data: Vec<UsageEntry>,
+}
+
+pub async fn audit_access_handler(
+    State(state): State<AuditQueryState>,
+    Query(format): Query<FormatQuery>,
+    Query(filter): Query<AccessLogFilter>,
+) -> Result<Response, ErrorResponse> {
+    let (backend, _) = state.ok_or_else(not_configured)?;
+    let page = backend.query_access(&filter).await.map_err(query_error)?;
+    Ok(match format.format {
+        OutputFormat::Json => Json(page).into_response(),
+        OutputFormat::Csv => csv_response(
+            AccessLogEntry::COLUMNS,
+            page.data.iter().map(AccessLogEntry::to_record),
+        ),
+    })
+}
+
+pub async fn audit_usage_handler(
+    State(state): State<AuditQueryState>,
+    Query(format): Query<FormatQuery>,
+    Query(filter): Query<UsageFilter>,
+) -> Result<Response, ErrorResponse> {
+    let (backend, config) = state.ok_or_else(not_configured)?;
+    let usage = backend
+        .query_usage(&filter, &config.pricing)
+        .await
+        .map_err(query_error)?;
+    Ok(match format.format {
+        OutputFormat::Json => Json(UsageReport { data: usage }).into_response(),
+        OutputFormat::Csv => {
+            csv_response(UsageEntry::COLUMNS, usage.iter().map(UsageEntry::to_record))
+        }
+    })
+}
+
+pub(super) fn not_configured() -> ErrorResponse {
+    ErrorResponse::new(StatusCode::NOT_FOUND, "audit is not configured")
+}
+
+pub(super) fn query_error(err: QueryError) -> ErrorResponse {
+    match err {
+        QueryError::Unsupported => ErrorResponse::new(StatusCode::NOT_IMPLEMENTED, err.to_string()),
+        QueryError::InvalidDimension(_) => {
+            ErrorResponse::new(StatusCode::BAD_REQUEST, err.to_string())
+        }
+        QueryError::Database(e) => {
+            event!(Level::ERROR, error = ?e, "Failed to query audit log");
+            ErrorResponse::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to query audit log",
+            )
+        }
+    }
+}
+
+fn csv_response<I>(columns: &[&str], records: I) -> Response
+where
+    I: Iterator<Item = Vec<String>>,
+{
+    let mut buf = columns.join(",");
+    buf.push_str("\r\n");
+    for record in records {
+        let fields: Vec<String> = record.iter().map(|field| csv_escape(field)).collect();
+        buf.push_str(&fields.join(","));
+        buf.push_str("\r\n");
+    }
+    ([(header::CONTENT_TYPE, "text/csv; charset=utf-8")], buf).into_response()
+}
+
+/// Quote a field if needed, and prefix the ones a spreadsheet would evaluate as a formula
+/// with `'`.
+fn csv_escape(field: &str) -> String {
+    let field = if field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{field}")
+    } else {
+        field.to_string()
+    };
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_escape_quotes() {
+        assert_eq!(csv_escape("gpt-4"), "gpt-4");
+        assert_eq!(csv_escape("a,b"), "\"a,b\"");
+        assert_eq!(csv_escape("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn csv_escape_formulas() {
+        assert_eq!(csv_escape("=1+1"), "'=1+1");
+        assert_eq!(csv_escape("+1"), "'+1");
+        assert_eq!(csv_escape("-1"), "'-1");
+        assert_eq!(csv_escape("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(
+            csv_escape("=HYPERLINK(\"x\",\"y\")"),
+            "\"'=HYPERLINK(\"\"x\"\",\"\"y\"\")\""
+        );
+    }
+}
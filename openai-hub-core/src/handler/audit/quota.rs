@@ -0,0 +1,299 @@
+use crate::audit::{Backend, QueryError, UsageFilter};
+use crate::config::{AuditConfig, UsageWindowConfig};
+use crate::error::ErrorResponse;
+use crate::handler::AUTHED_HEADER;
+use crate::managed::Managed;
+use crate::short_circuit_if;
+use axum::extract::{Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::Response;
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{event, instrument, Level};
+
+/// How long a queried usage is reused before the database is asked again
+const USAGE_TTL: Duration = Duration::from_secs(10);
+
+/// Rejects the requests of subjects who used up the quota of a usage window.
+///
+/// Usage is only recorded once a response completes, so requests in flight and the last
+/// [`USAGE_TTL`] of usage are not counted yet.
+#[derive(Clone)]
+pub struct QuotaGuard {
+    backend: Backend,
+    config: Arc<AuditConfig>,
+    managed: Arc<Managed>,
+    usage: Arc<Mutex<HashMap<(String, String), WindowUsage>>>,
+}
+
+#[derive(Clone, Copy)]
+struct WindowUsage {
+    /// Start of the window the usage was queried for
+    from: DateTime<Utc>,
+    queried_at: Instant,
+    tokens: i64,
+    /// Cost of the models with pricing, the others are free as far as quotas are concerned
+    cost: f64,
+}
+
+impl QuotaGuard {
+    /// Usage is queried from the audit database, there is no guard for the file backend.
+    pub fn new(backend: Backend, config: Arc<AuditConfig>, managed: Arc<Managed>) -> Option<Self> {
+        if !matches!(backend, Backend::Database(_)) {
+            if !config.usage.windows.is_empty() {
+                event!(
+                    Level::WARN,
+                    "quotas are not enforced with the file audit backend"
+                );
+            }
+            return None;
+        }
+        Some(Self {
+            backend,
+            config,
+            managed,
+            usage: Default::default(),
+        })
+    }
+
+    async fn usage_of(
+        &self,
+        user: &str,
+        window: &UsageWindowConfig,
+        now: DateTime<Utc>,
+    ) -> Result<WindowUsage, QueryError> {
+        let (from, to) = window.period.bounds(now);
+        let key = (user.to_string(), window.name.clone());
+        let cached = self.usage.lock().get(&key).copied();
+        if let Some(usage) =
+            cached.filter(|usage| usage.from == from && usage.queried_at.elapsed() < USAGE_TTL)
+        {
+            return Ok(usage);
+        }
+
+        let filter = UsageFilter {
+            user: Some(user.to_string()),
+            from: Some(from),
+            to: Some(to),
+            group_by: Some("model".to_string()),
+            ..Default::default()
+        };
+        let entries = self
+            .backend
+            .query_usage(&filter, &self.config.pricing)
+            .await?;
+        let usage = WindowUsage {
+            from,
+            queried_at: Instant::now(),
+            tokens: entries.iter().map(|e| e.total_tokens).sum(),
+            cost: entries.iter().filter_map(|e| e.cost).sum(),
+        };
+        self.usage.lock().insert(key, usage);
+        Ok(usage)
+    }
+}
+
+#[instrument(skip_all)]
+pub async fn quota_layer(
+    State(guard): State<Option<QuotaGuard>>,
+    req: Request,
+    next: Next,
+) -> Result<Response, ErrorResponse> {
+    short_circuit_if!(req, next, guard.is_none());
+    let guard = guard.unwrap();
+    let user = req
+        .headers()
+        .get(AUTHED_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .map(ToString::to_string);
+    short_circuit_if!(req, next, user.is_none());
+    let user = user.unwrap();
+
+    let now = Utc::now();
+    for window in guard.config.usage.windows.iter() {
+        let quota = guard.managed.quota_of(window, &user);
+        if quota.tokens.is_none() && quota.cost.is_none() {
+            continue;
+        }
+        let usage = match guard.usage_of(&user, window, now).await {
+            Ok(usage) => usage,
+            Err(e) => {
+                // an unavailable audit database must not take the hub down with it
+                event!(Level::ERROR, error = %e, window = %window.name, "failed to query usage, quota not enforced");
+                continue;
+            }
+        };
+        let exceeded = quota.tokens.is_some_and(|tokens| usage.tokens >= tokens)
+            || quota.cost.is_some_and(|cost| usage.cost >= cost);
+        if exceeded {
+            let (_, to) = window.period.bounds(now);
+            event!(Level::INFO, user = %user, window = %window.name, "quota exceeded");
+            return Err(ErrorResponse::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                format!(
+                    "Quota of the {} window used up until {}",
+                    window.name,
+                    to.to_rfc3339()
+                ),
+            ));
+        }
+    }
+    Ok(next.run(req).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::{
+        AuditRecord, BackendEngine, DatabaseBackend, TokenUsage, TokenUsageLog, UsageUnit,
+    };
+    use crate::key::KeyPool;
+    use axum::body::Body;
+    use axum::middleware::from_fn_with_state;
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    const CONFIG: &str = r#"
+backend = "sqlite"
+
+[pricing]
+"gpt-4" = { prompt = 0.03, completion = 0.06 }
+
+[[usage.windows]]
+name = "day"
+period = "day"
+tokens = 100000
+cost = 1.0
+"#;
+
+    async fn guard() -> QuotaGuard {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        pool.init().await.unwrap();
+        let config: AuditConfig = toml::from_str(CONFIG).unwrap();
+        let managed = Managed::load(
+            None,
+            Arc::new(KeyPool::new(["sk-configured".to_string()])),
+            #[cfg(feature = "acl")]
+            None,
+            Some(&config),
+        )
+        .unwrap();
+        let backend = Backend::Database(DatabaseBackend::Sqlite(pool));
+        QuotaGuard::new(backend, Arc::new(config), Arc::new(managed)).unwrap()
+    }
+
+    async fn log(guard: &QuotaGuard, timestamp: DateTime<Utc>, model: &str, tokens: usize) {
+        let record = AuditRecord::Tokens(TokenUsageLog {
+            timestamp,
+            user: Some("alice".to_string()),
+            ray_id: "ray".to_string(),
+            model: model.to_string(),
+            usage: TokenUsage {
+                prompt_tokens: tokens / 2,
+                completion_tokens: tokens / 2,
+                total_tokens: tokens,
+            },
+            unit: UsageUnit::Tokens,
+            quantity: tokens as f64,
+            details: None,
+            is_estimated: false,
+        });
+        assert!(guard.backend.log_batch(vec![record]).await.is_ok());
+    }
+
+    async fn status(guard: &QuotaGuard) -> StatusCode {
+        Router::new()
+            .route("/", get(|| async {}))
+            .layer(from_fn_with_state(Some(guard.clone()), quota_layer))
+            .oneshot(
+                Request::get("/")
+                    .header(AUTHED_HEADER, "alice")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+            .status()
+    }
+
+    fn expire(guard: &QuotaGuard) {
+        for usage in guard.usage.lock().values_mut() {
+            usage.queried_at = Instant::now().checked_sub(USAGE_TTL).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_once_the_quota_is_used_up() {
+        let guard = guard().await;
+        log(&guard, Utc::now(), "local-llm", 60000).await;
+        assert_eq!(status(&guard).await, StatusCode::OK);
+        log(&guard, Utc::now(), "local-llm", 40000).await;
+        expire(&guard);
+        assert_eq!(status(&guard).await, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn usage_is_cached() {
+        let guard = guard().await;
+        let window = &guard.config.usage.windows[0];
+        let now = Utc::now();
+        assert_eq!(
+            guard.usage_of("alice", window, now).await.unwrap().tokens,
+            0
+        );
+        log(&guard, now, "local-llm", 100).await;
+        assert_eq!(
+            guard.usage_of("alice", window, now).await.unwrap().tokens,
+            0
+        );
+        expire(&guard);
+        assert_eq!(
+            guard.usage_of("alice", window, now).await.unwrap().tokens,
+            100
+        );
+    }
+
+    #[tokio::test]
+    async fn usage_is_queried_again_in_the_next_window() {
+        let guard = guard().await;
+        let window = &guard.config.usage.windows[0];
+        let now = Utc::now();
+        let yesterday = now - chrono::Duration::days(1);
+        log(&guard, yesterday, "local-llm", 100000).await;
+        assert_eq!(
+            guard
+                .usage_of("alice", window, yesterday)
+                .await
+                .unwrap()
+                .tokens,
+            100000
+        );
+        // cached for yesterday, but not for today
+        assert_eq!(
+            guard.usage_of("alice", window, now).await.unwrap().tokens,
+            0
+        );
+        assert_eq!(status(&guard).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn cost_of_priced_models_counts_next_to_unpriced_ones() {
+        let guard = guard().await;
+        // 10K prompt and 10K completion tokens, 0.9 USD
+        log(&guard, Utc::now(), "gpt-4", 20000).await;
+        log(&guard, Utc::now(), "local-llm", 100).await;
+        assert_eq!(status(&guard).await, StatusCode::OK);
+        log(&guard, Utc::now(), "gpt-4", 4000).await;
+        expire(&guard);
+        assert_eq!(status(&guard).await, StatusCode::TOO_MANY_REQUESTS);
+    }
+}
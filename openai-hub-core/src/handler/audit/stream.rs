@@ -0,0 +1,347 @@
+use crate::audit::TokenUsage;
+use crate::handler::audit::estimate::bpe_for_model;
+use axum::body::{Body, Bytes};
+use axum::response::Response;
+use futures::Stream;
+use parking_lot::Mutex;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use sync_wrapper::SyncStream;
+use tiktoken_rs::CoreBPE;
+use tokio::sync::oneshot;
+use tracing::{event, Level};
+
+/// Splits a byte stream into the data of server-sent events.
+///
+/// Only the current line and the data of the current event are kept in memory.
+#[derive(Default)]
+pub struct SseDecoder {
+    line: Vec<u8>,
+    data: String,
+}
+
+impl SseDecoder {
+    pub fn feed<F: FnMut(&str)>(&mut self, chunk: &[u8], mut on_event: F) {
+        for part in chunk.split_inclusive(|b| *b == b'\n') {
+            self.line.extend_from_slice(part);
+            if self.line.last() != Some(&b'\n') {
+                // the rest of the line is in the next chunk
+                continue;
+            }
+            let line = std::mem::take(&mut self.line);
+            let line = line.strip_suffix(b"\n").unwrap_or(&line);
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if line.is_empty() {
+                self.dispatch(&mut on_event);
+            } else if let Some(value) = line.strip_prefix(b"data:") {
+                let value = value.strip_prefix(b" ").unwrap_or(value);
+                if !self.data.is_empty() {
+                    self.data.push('\n');
+                }
+                self.data.push_str(&String::from_utf8_lossy(value));
+            }
+            // other fields and comments don't carry tokens
+        }
+    }
+
+    /// Dispatch an event not terminated by an empty line before the end of the stream.
+    pub fn finish<F: FnMut(&str)>(&mut self, mut on_event: F) {
+        if !self.line.is_empty() {
+            self.feed(b"\n", &mut on_event);
+        }
+        self.dispatch(&mut on_event);
+    }
+
+    fn dispatch<F: FnMut(&str)>(&mut self, on_event: &mut F) {
+        if !self.data.is_empty() {
+            on_event(&self.data);
+            self.data.clear();
+        }
+    }
+}
+
+/// Tokens counted from the events sent to the client.
+#[derive(Debug)]
+pub struct StreamedTokens {
+    /// Estimated completion tokens, unknown if there is no tokenizer for the model
+    pub completion_tokens: Option<usize>,
+    /// Usage reported by upstream in the final chunk
+    pub usage: Option<TokenUsage>,
+}
+
+/// Counts completion tokens of a streamed (chat) completion chunk by chunk.
+///
+/// Content is counted as it arrives, tool and function calls are streamed in fragments
+/// and counted once merged: a fragment can end in the middle of a token, counting each one
+/// on its own would overestimate. The names and arguments of the calls are therefore held
+/// until the stream finishes, as much memory as the calls sent to the client take.
+pub struct StreamTokenCounter {
+    decoder: SseDecoder,
+    bpe: Option<Arc<Mutex<CoreBPE>>>,
+    state: CountState,
+}
+
+#[derive(Default)]
+struct CountState {
+    content_tokens: usize,
+    /// Name and arguments of calls by choice index and tool call index, buffered until
+    /// [`StreamTokenCounter::finish`], a legacy function call has no tool call index
+    calls: BTreeMap<(usize, Option<usize>), (String, String)>,
+    usage: Option<TokenUsage>,
+}
+
+impl StreamTokenCounter {
+    pub fn new(model: &str) -> Self {
+        Self {
+            decoder: SseDecoder::default(),
+            bpe: bpe_for_model(model),
+            state: CountState::default(),
+        }
+    }
+
+    pub fn feed(&mut self, chunk: &[u8]) {
+        let (bpe, state) = (&self.bpe, &mut self.state);
+        self.decoder
+            .feed(chunk, |data| count_event(bpe.as_deref(), state, data));
+    }
+
+    pub fn finish(mut self) -> StreamedTokens {
+        let (bpe, state) = (&self.bpe, &mut self.state);
+        self.decoder
+            .finish(|data| count_event(bpe.as_deref(), state, data));
+        let completion_tokens = self.bpe.map(|bpe| {
+            let bpe = bpe.lock();
+            let call_tokens: usize = self
+                .state
+                .calls
+                .values()
+                .map(|(name, arguments)| {
+                    bpe.encode_with_special_tokens(name).len()
+                        + bpe.encode_with_special_tokens(arguments).len()
+                })
+                .sum();
+            self.state.content_tokens + call_tokens
+        });
+        StreamedTokens {
+            completion_tokens,
+            usage: self.state.usage,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct StreamChunk {
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+    #[serde(default)]
+    usage: Option<TokenUsage>,
+}
+
+#[derive(Deserialize)]
+struct StreamChoice {
+    #[serde(default)]
+    index: usize,
+    #[serde(default)]
+    text: Option<String>,
+    #[serde(default)]
+    delta: Option<Delta>,
+}
+
+#[derive(Deserialize)]
+struct Delta {
+    #[serde(default)]
+    content: Option<String>,
+    #[serde(default)]
+    function_call: Option<FunctionCallDelta>,
+    #[serde(default)]
+    tool_calls: Vec<ToolCallDelta>,
+}
+
+#[derive(Deserialize)]
+struct ToolCallDelta {
+    index: usize,
+    #[serde(default)]
+    function: Option<FunctionCallDelta>,
+}
+
+#[derive(Deserialize)]
+struct FunctionCallDelta {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    arguments: Option<String>,
+}
+
+fn count_event(bpe: Option<&Mutex<CoreBPE>>, state: &mut CountState, data: &str) {
+    if data == "[DONE]" {
+        return;
+    }
+    let chunk = match serde_json::from_str::<StreamChunk>(data) {
+        Ok(chunk) => chunk,
+        Err(e) => {
+            event!(Level::WARN, error = ?e, "failed to parse response event");
+            return;
+        }
+    };
+    if chunk.usage.is_some() {
+        state.usage = chunk.usage;
+    }
+    let Some(bpe) = bpe else {
+        return;
+    };
+    for choice in chunk.choices.into_iter() {
+        let delta = choice.delta.unwrap_or(Delta {
+            content: None,
+            function_call: None,
+            tool_calls: vec![],
+        });
+        for text in [choice.text, delta.content].into_iter().flatten() {
+            state.content_tokens += bpe.lock().encode_with_special_tokens(&text).len();
+        }
+        let calls = delta
+            .function_call
+            .map(|call| (None, call))
+            .into_iter()
+            .chain(
+                delta
+                    .tool_calls
+                    .into_iter()
+                    .filter_map(|call| Some((Some(call.index), call.function?))),
+            );
+        for (tool_index, call) in calls {
+            let (name, arguments) = state.calls.entry((choice.index, tool_index)).or_default();
+            name.push_str(call.name.as_deref().unwrap_or(""));
+            arguments.push_str(call.arguments.as_deref().unwrap_or(""));
+        }
+    }
+}
+
+/// Count the tokens of a streamed response body as it is sent to the client.
+///
+/// The counted tokens are reported once the body is finished or dropped, so a client
+/// disconnecting mid-stream is still accounted for what it received.
+pub fn count_stream_tokens(
+    response: Response,
+    counter: StreamTokenCounter,
+) -> (Response, oneshot::Receiver<StreamedTokens>) {
+    let (parts, body) = response.into_parts();
+    let (tx, rx) = oneshot::channel();
+    let body = CountedStream {
+        inner: SyncStream::new(body),
+        guard: CountGuard {
+            counter: Some(counter),
+            tx: Some(tx),
+        },
+    };
+    (Response::from_parts(parts, Body::from_stream(body)), rx)
+}
+
+#[pin_project::pin_project]
+struct CountedStream<S> {
+    #[pin]
+    inner: S,
+    guard: CountGuard,
+}
+
+struct CountGuard {
+    counter: Option<StreamTokenCounter>,
+    tx: Option<oneshot::Sender<StreamedTokens>>,
+}
+
+impl<S, E> Stream for CountedStream<S>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let poll = this.inner.poll_next(cx);
+        if let (Poll::Ready(Some(Ok(chunk))), Some(counter)) = (&poll, &mut this.guard.counter) {
+            counter.feed(chunk);
+        }
+        poll
+    }
+}
+
+impl Drop for CountGuard {
+    fn drop(&mut self) {
+        if let (Some(counter), Some(tx)) = (self.counter.take(), self.tx.take()) {
+            tx.send(counter.finish()).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(chunks: &[&[u8]]) -> Vec<String> {
+        let mut decoder = SseDecoder::default();
+        let mut events = vec![];
+        for chunk in chunks {
+            decoder.feed(chunk, |data| events.push(data.to_string()));
+        }
+        decoder.finish(|data| events.push(data.to_string()));
+        events
+    }
+
+    #[test]
+    fn events_split_across_chunks() {
+        let events = decode(&[b"data: {\"a\"", b":1}\n", b"\ndata: [DO", b"NE]\n\n"]);
+        assert_eq!(events, ["{\"a\":1}", "[DONE]"]);
+    }
+
+    #[test]
+    fn line_ending_split_across_chunks() {
+        let events = decode(&[b"data: 1\r", b"\n\r", b"\ndata: 2\r\n\r\n"]);
+        assert_eq!(events, ["1", "2"]);
+    }
+
+    #[test]
+    fn multibyte_character_split_across_chunks() {
+        let text = "data: é\n\n".as_bytes();
+        let events = decode(&[&text[..7], &text[7..]]);
+        assert_eq!(events, ["é"]);
+    }
+
+    #[test]
+    fn multiline_data_and_other_fields() {
+        let events = decode(&[b": comment\nevent: message\nid: 1\ndata: a\ndata:b\n\n"]);
+        assert_eq!(events, ["a\nb"]);
+    }
+
+    #[test]
+    fn unterminated_event_on_finish() {
+        assert_eq!(decode(&[b"data: last"]), ["last"]);
+        assert!(decode(&[b"\n\n"]).is_empty());
+    }
+
+    #[test]
+    fn counts_content_and_tool_calls() {
+        let mut counter = StreamTokenCounter::new("gpt-4");
+        let chunks = [
+            r#"{"choices":[{"index":0,"delta":{"content":"Hello"}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"content":" world"}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"name":"get_weather","arguments":"{\"ci"}}]}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"ty\":\"Paris\"}"}}]}}]}"#,
+            r#"{"choices":[],"usage":{"prompt_tokens":3,"completion_tokens":4,"total_tokens":7}}"#,
+            "[DONE]",
+        ];
+        for chunk in chunks {
+            counter.feed(format!("data: {chunk}\n\n").as_bytes());
+        }
+        let streamed = counter.finish();
+
+        let bpe = tiktoken_rs::cl100k_base().unwrap();
+        let expected = bpe.encode_with_special_tokens("Hello").len()
+            + bpe.encode_with_special_tokens(" world").len()
+            + bpe.encode_with_special_tokens("get_weather").len()
+            + bpe.encode_with_special_tokens("{\"city\":\"Paris\"}").len();
+        assert_eq!(streamed.completion_tokens, Some(expected));
+        assert_eq!(streamed.usage.unwrap().total_tokens, 7);
+    }
+}
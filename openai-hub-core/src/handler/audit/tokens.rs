@@ -1,40 +1,38 @@
-use crate::audit::{Backend, BackendEngine, TokenUsage, TokenUsageLog};
+use crate::audit::{AuditWriter, TokenUsage, TokenUsageLog, UsageUnit};
 use crate::config::{AuditConfig, StreamTokensPolicy};
 use crate::error::ErrorResponse;
 use crate::handler::audit::access::RAY_ID_HEADER;
+use crate::handler::audit::estimate::{count_chat_prompt_tokens, count_completions_prompt_tokens};
+use crate::handler::audit::meter::{Meter, MeteredRequest};
+use crate::handler::audit::stream::{count_stream_tokens, StreamTokenCounter, StreamedTokens};
 use crate::handler::helpers::stream_read_response_body;
-use crate::handler::jwt::AUTHED_HEADER;
+use crate::handler::AUTHED_HEADER;
 use crate::short_circuit_if;
 use axum::body::Body;
 use axum::extract::{Request, State};
-use axum::http::StatusCode;
+use axum::http::{header, StatusCode};
 use axum::middleware::Next;
 use axum::response::Response;
 use futures::TryStreamExt;
-use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde_json::Value;
 use std::io;
 use std::sync::Arc;
-use tiktoken_rs::tokenizer::get_tokenizer;
-use tiktoken_rs::{
-    get_bpe_from_tokenizer, num_tokens_from_messages, ChatCompletionRequestMessage, FunctionCall,
-};
 use tokio::io::AsyncReadExt;
-use tokio::spawn;
 use tokio::sync::mpsc::Receiver;
+use tokio::sync::oneshot;
 use tokio_util::io::StreamReader;
 use tracing::{event, instrument, Level};
 
 #[instrument(skip_all)]
 pub async fn audit_tokens_layer(
-    State(state): State<Option<(Arc<AuditConfig>, Backend)>>,
+    State(state): State<Option<(Arc<AuditConfig>, AuditWriter)>>,
     req: Request,
     next: Next,
 ) -> Result<Response, ErrorResponse> {
     short_circuit_if!(req, next, state.is_none());
 
-    let (config, backend) = state.unwrap();
+    let (config, writer) = state.unwrap();
 
     short_circuit_if!(req, next, !config.filters.tokens.enable);
     short_circuit_if!(
@@ -47,7 +45,8 @@ pub async fn audit_tokens_layer(
     let user = parts
         .headers
         .get(AUTHED_HEADER)
-        .map(|h| h.to_str().unwrap().to_string());
+        .and_then(|h| h.to_str().ok())
+        .map(ToString::to_string);
     let ray_id = parts
         .headers
         .get(RAY_ID_HEADER)
@@ -60,6 +59,39 @@ pub async fn audit_tokens_layer(
         .read_to_end(&mut req_body)
         .await
         .map_err(|_| ErrorResponse::new(StatusCode::BAD_REQUEST, "failed to read body"))?;
+
+    let meter = Meter::of_endpoint(parts.uri.path());
+    if meter != Meter::Tokens {
+        let content_type = parts
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|h| h.to_str().ok());
+        let metered = meter
+            .measure_request(content_type, &req_body)
+            .map_err(|e| ErrorResponse::new(StatusCode::BAD_REQUEST, e))?;
+        let response = next
+            .run(Request::from_parts(parts, Body::from(req_body)))
+            .await;
+        if !response.status().is_success() {
+            return Ok(response);
+        }
+        if !meter.reads_response() {
+            writer
+                .tasks()
+                .spawn(audit_metered_usage(user, metered, None, ray_id, writer));
+            return Ok(response);
+        }
+        let (response, res_body_rx) = stream_read_response_body(response);
+        writer.tasks().spawn(audit_metered_usage(
+            user,
+            metered,
+            Some(res_body_rx),
+            ray_id,
+            writer,
+        ));
+        return Ok(response);
+    }
+
     let parsed_body: Value = serde_json::from_slice(&req_body)
         .map_err(|_| ErrorResponse::new(StatusCode::BAD_REQUEST, "failed to parse body"))?;
     if parsed_body.get("model").is_none() {
@@ -82,80 +114,127 @@ pub async fn audit_tokens_layer(
             "stream requests are not allowed",
         ));
     }
+    if stream && config.filters.tokens.stream_tokens == StreamTokensPolicy::Skip {
+        return Ok(next
+            .run(Request::from_parts(parts, Body::from(req_body)))
+            .await);
+    }
     let endpoint = parts.uri.path().to_string();
+    let model = parsed_body
+        .get("model")
+        .and_then(|m| m.as_str())
+        .ok_or_else(|| ErrorResponse::new(StatusCode::BAD_REQUEST, "invalid 'model' field"))?
+        .to_string();
 
     let request = Request::from_parts(parts, Body::from(req_body));
     let response = next.run(request).await;
-    let (response, res_body_rx) = stream_read_response_body(response);
 
-    spawn(audit_tokens_layer_inner(
-        endpoint,
+    if stream {
+        if !response.status().is_success() {
+            return Ok(response);
+        }
+        let (response, tokens_rx) =
+            count_stream_tokens(response, StreamTokenCounter::new(model.as_str()));
+        writer.tasks().spawn(audit_stream_tokens(
+            endpoint,
+            user,
+            model,
+            parsed_body,
+            tokens_rx,
+            ray_id,
+            writer,
+        ));
+        return Ok(response);
+    }
+
+    let (response, res_body_rx) = stream_read_response_body(response);
+    writer.tasks().spawn(audit_tokens_layer_inner(
         user,
-        parsed_body,
+        model,
         res_body_rx,
         ray_id,
-        config,
-        backend,
+        writer,
     ));
 
     Ok(response)
 }
 
 async fn audit_tokens_layer_inner(
-    endpoint: String,
     user: Option<String>,
-    req_body: Value,
+    model: String,
     mut res_body_rx: Receiver<Option<Vec<u8>>>,
     ray_id: String,
-    config: Arc<AuditConfig>,
-    backend: Backend,
+    writer: AuditWriter,
 ) {
-    // TODO: stream read response body
-    let res_body = res_body_rx
-        .recv()
-        .await
-        .flatten()
-        .and_then(|v| String::from_utf8(v).ok());
+    let res_body = res_body_rx.recv().await.flatten();
     if res_body.is_none() {
         event!(Level::WARN, "failed to read response body");
         return;
     }
-    let res_body = res_body.unwrap();
-    let model = req_body.get("model").unwrap().as_str().unwrap().to_string();
+    let usage = match serde_json::from_slice::<ResponseWithUsage>(&res_body.unwrap()) {
+        Ok(res) => res.usage,
+        Err(_) => {
+            event!(Level::WARN, "failed to parse usage from response");
+            return;
+        }
+    };
 
-    let stream = req_body
-        .get("stream")
-        .and_then(|s| s.as_bool())
-        .unwrap_or(false);
-    let (usage, is_estimated) = match (stream, config.filters.tokens.stream_tokens) {
-        (true, StreamTokensPolicy::Skip) => return,
-        (true, StreamTokensPolicy::Reject) => unreachable!(),
-        (true, StreamTokensPolicy::Estimate) => {
-            let usage = match endpoint.as_str() {
-                "/completions" => count_completions_tokens(model.as_str(), req_body, res_body),
-                "/chat/completions" => count_chat_tokens(model.as_str(), req_body, res_body),
+    let log = TokenUsageLog {
+        timestamp: chrono::Utc::now(),
+        user,
+        ray_id,
+        model,
+        quantity: usage.total_tokens as f64,
+        usage,
+        unit: UsageUnit::Tokens,
+        details: None,
+        is_estimated: false,
+    };
+    writer.log_tokens(log).await;
+}
+
+/// Log the usage of a streamed response once the client received it or went away.
+///
+/// The usage reported by upstream in the final chunk is preferred over the estimate.
+async fn audit_stream_tokens(
+    endpoint: String,
+    user: Option<String>,
+    model: String,
+    req_body: Value,
+    tokens_rx: oneshot::Receiver<StreamedTokens>,
+    ray_id: String,
+    writer: AuditWriter,
+) {
+    let Ok(tokens) = tokens_rx.await else {
+        return;
+    };
+    let (usage, is_estimated) = match tokens.usage {
+        Some(usage) => (usage, false),
+        None => {
+            let prompt_tokens = match endpoint.as_str() {
+                "/completions" => count_completions_prompt_tokens(model.as_str(), &req_body),
+                "/chat/completions" => count_chat_prompt_tokens(model.as_str(), &req_body),
                 _ => {
                     event!(Level::ERROR, "unsupported endpoint {}", endpoint);
                     return;
                 }
             };
-            if usage.is_none() {
+            let (Some(prompt_tokens), Some(completion_tokens)) =
+                (prompt_tokens, tokens.completion_tokens)
+            else {
                 event!(
                     Level::WARN,
                     "failed to estimate usage for request, ray id = {}",
                     ray_id
                 );
                 return;
-            }
-            (usage.unwrap(), true)
-        }
-        (false, _) => {
-            if let Ok(res) = serde_json::from_str::<ResponseWithUsage>(res_body.as_str()) {
-                (res.usage, false)
-            } else {
-                event!(Level::WARN, "failed to parse usage from response");
-                return;
-            }
+            };
+            let usage = TokenUsage {
+                prompt_tokens,
+                completion_tokens,
+                total_tokens: prompt_tokens + completion_tokens,
+            };
+            (usage, true)
         }
     };
 
@@ -164,179 +243,51 @@ async fn audit_tokens_layer_inner(
         user,
         ray_id,
         model,
+        quantity: usage.total_tokens as f64,
         usage,
+        unit: UsageUnit::Tokens,
+        details: None,
         is_estimated,
     };
-    backend.log_tokens(log).await;
+    writer.log_tokens(log).await;
 }
 
-fn get_events<T: DeserializeOwned>(res_body: String) -> Option<Vec<StreamEvent<T>>> {
-    let events: Result<Vec<StreamEvent<T>>, _> = res_body
-        .split("\n\n")
-        .filter_map(|event| event.strip_prefix("data: "))
-        .filter(|event| *event != "[DONE]")
-        .map(|event| {
-            event!(Level::DEBUG, "paring event: {}", event);
-            serde_json::from_str(event)
-        })
-        .collect();
-    if events.is_err() {
-        event!(Level::ERROR, "failed to parse response events");
-        return None;
-    }
-    Some(events.unwrap())
-}
-
-fn count_completions_tokens(model: &str, req_body: Value, res_body: String) -> Option<TokenUsage> {
-    let tokenizer = get_tokenizer(model)?;
-    event!(Level::DEBUG, "got tokenizer {:?} for {}", tokenizer, model);
-    let bpe = get_bpe_from_tokenizer(tokenizer).ok()?;
-
-    let prompt_tokens = req_body
-        .get("prompt")
-        .and_then(|p| p.as_str())
-        .map(|s| bpe.encode_with_special_tokens(s).len())
-        .unwrap_or(0);
-
-    let events = get_events::<CompletionChoice>(res_body)?;
-    let mut choices = vec![];
-    for event in events.into_iter() {
-        for choice in event.choices.into_iter() {
-            if choices.len() < choice.index + 1 {
-                choices.resize(choice.index + 1, String::new());
-            }
-            choices[choice.index].push_str(choice.text.as_str());
-        }
-    }
-    let completion_tokens = choices
-        .iter()
-        .map(|s| bpe.encode_with_special_tokens(s).len())
-        .sum();
-
-    Some(TokenUsage {
-        prompt_tokens,
-        completion_tokens,
-        total_tokens: prompt_tokens + completion_tokens,
-    })
-}
-
-fn count_chat_tokens(model: &str, req_body: Value, res_body: String) -> Option<TokenUsage> {
-    #[derive(Deserialize)]
-    struct ChatCompletionRequestMessageDe {
-        role: String,
-        #[serde(default)]
-        content: Option<String>,
-        #[serde(default)]
-        name: Option<String>,
-        #[serde(default)]
-        function_call: Option<FunctionCallDe>,
-    }
-    let prompt_messages = req_body.get("messages")?;
-    let parsed_prompt =
-        serde_json::from_value::<Vec<ChatCompletionRequestMessageDe>>(prompt_messages.clone())
-            .ok()?;
-    let prompt: Vec<ChatCompletionRequestMessage> = parsed_prompt
-        .into_iter()
-        .map(|p| ChatCompletionRequestMessage {
-            role: p.role,
-            content: p.content,
-            name: p.name,
-            function_call: p.function_call.map(|f| f.into()),
-        })
-        .collect();
-    let prompt_tokens = num_tokens_from_messages(model, &prompt).ok()?;
-    event!(Level::DEBUG, "estimated prompt tokens: {}", prompt_tokens);
-
-    let events = get_events::<ChatChoice>(res_body)?;
-
-    let mut choices = vec![];
-    for event in events.into_iter() {
-        for choice in event.choices.into_iter() {
-            if choices.len() < choice.index + 1 {
-                choices.resize(choice.index + 1, ChatCompletionRequestMessage::default());
-            }
-            if let Some(r) = choice.delta.role {
-                choices[choice.index].role = r;
-            }
-            if let Some(c) = choice.delta.content {
-                if choices[choice.index].content.is_none() {
-                    choices[choice.index].content = Some(c);
-                } else {
-                    choices[choice.index]
-                        .content
-                        .as_mut()
-                        .unwrap()
-                        .push_str(c.as_str());
-                }
-            }
-            if let Some(f) = choice.delta.function_call {
-                choices[choice.index].function_call = Some(f.into());
-            }
-        }
-    }
-    event!(Level::DEBUG, "completions: {:?}", choices);
-    let completion_tokens = num_tokens_from_messages(model, &choices).ok()?;
-    event!(
-        Level::DEBUG,
-        "estimated completion tokens: {}",
-        completion_tokens
-    );
+/// Log the usage of an image or audio request once the response is read.
+async fn audit_metered_usage(
+    user: Option<String>,
+    metered: MeteredRequest,
+    res_body_rx: Option<Receiver<Option<Vec<u8>>>>,
+    ray_id: String,
+    writer: AuditWriter,
+) {
+    let res_body = match res_body_rx {
+        Some(mut rx) => rx.recv().await.flatten(),
+        None => None,
+    };
+    let Some(usage) = metered.complete(res_body.as_deref()) else {
+        event!(
+            Level::WARN,
+            "failed to measure usage for request, ray id = {}",
+            ray_id
+        );
+        return;
+    };
 
-    Some(TokenUsage {
-        prompt_tokens,
-        completion_tokens,
-        total_tokens: prompt_tokens + completion_tokens,
-    })
+    let log = TokenUsageLog {
+        timestamp: chrono::Utc::now(),
+        user,
+        ray_id,
+        model: usage.model,
+        usage: TokenUsage::default(),
+        unit: usage.unit,
+        quantity: usage.quantity,
+        details: usage.details,
+        is_estimated: usage.is_estimated,
+    };
+    writer.log_tokens(log).await;
 }
 
 #[derive(Deserialize)]
 struct ResponseWithUsage {
     usage: TokenUsage,
 }
-
-#[derive(Deserialize)]
-struct StreamEvent<T> {
-    choices: Vec<T>,
-}
-
-#[derive(Copy, Clone, Debug, Deserialize)]
-enum ObjectType {
-    #[serde(rename = "chat.completion.chunk")]
-    ChatCompletionChunk,
-    #[serde(rename = "text_completion")]
-    TextCompletion,
-}
-
-#[derive(Deserialize)]
-struct ChatChoice {
-    pub delta: Delta,
-    pub index: usize,
-}
-
-#[derive(Default, Deserialize)]
-struct Delta {
-    pub role: Option<String>,
-    pub content: Option<String>,
-    pub function_call: Option<FunctionCallDe>,
-}
-
-#[derive(Deserialize)]
-pub struct FunctionCallDe {
-    pub name: String,
-    pub arguments: String,
-}
-
-#[derive(Deserialize)]
-struct CompletionChoice {
-    pub text: String,
-    pub index: usize,
-}
-
-impl From<FunctionCallDe> for FunctionCall {
-    fn from(f: FunctionCallDe) -> Self {
-        FunctionCall {
-            name: f.name,
-            arguments: f.arguments,
-        }
-    }
-}
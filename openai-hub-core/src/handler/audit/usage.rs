@@ -0,0 +1,136 @@
+use super::query::{not_configured, query_error, AuditQueryState};
+use crate::audit::UsageFilter;
+use crate::config::{UsagePeriod, UsageQuota};
+use crate::error::ErrorResponse;
+use crate::handler::AUTHED_HEADER;
+use crate::managed::Managed;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::Arc;
+
+/// Usage of the authenticated subject, the schema is consumed by dashboards and must stay stable.
+#[derive(Serialize)]
+pub struct UserUsage {
+    user: String,
+    generated_at: DateTime<Utc>,
+    windows: Vec<WindowUsage>,
+}
+
+#[derive(Serialize)]
+struct WindowUsage {
+    name: String,
+    period: UsagePeriod,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    requests: i64,
+    prompt_tokens: i64,
+    completion_tokens: i64,
+    total_tokens: i64,
+    /// Estimated cost in USD, unknown if any of the models has no pricing
+    cost: Option<f64>,
+    quota: QuotaUsage,
+    remaining: QuotaUsage,
+    models: Vec<ModelUsage>,
+}
+
+#[derive(Serialize)]
+struct QuotaUsage {
+    tokens: Option<i64>,
+    cost: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct ModelUsage {
+    model: String,
+    /// Models of image and audio endpoints are billed by other units than tokens
+    unit: String,
+    requests: i64,
+    prompt_tokens: i64,
+    completion_tokens: i64,
+    total_tokens: i64,
+    quantity: f64,
+    cost: Option<f64>,
+}
+
+pub async fn user_usage_handler(
+    State((state, managed)): State<(AuditQueryState, Arc<Managed>)>,
+    headers: HeaderMap,
+) -> Result<Json<UserUsage>, ErrorResponse> {
+    let (backend, config) = state.ok_or_else(not_configured)?;
+    let user = headers
+        .get(AUTHED_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .ok_or_else(|| ErrorResponse::new(StatusCode::UNAUTHORIZED, "authentication required"))?
+        .to_string();
+
+    let now = Utc::now();
+    let mut windows = vec![];
+    for window in config.usage.windows.iter() {
+        let (from, to) = window.period.bounds(now);
+        let filter = UsageFilter {
+            user: Some(user.clone()),
+            from: Some(from),
+            to: Some(to),
+            group_by: Some("model".to_string()),
+            ..Default::default()
+        };
+        let entries = backend
+            .query_usage(&filter, &config.pricing)
+            .await
+            .map_err(query_error)?;
+
+        let models: Vec<ModelUsage> = entries
+            .into_iter()
+            .map(|entry| ModelUsage {
+                model: entry.model.unwrap_or_default(),
+                unit: entry.unit,
+                requests: entry.requests,
+                prompt_tokens: entry.prompt_tokens,
+                completion_tokens: entry.completion_tokens,
+                total_tokens: entry.total_tokens,
+                quantity: entry.quantity,
+                cost: entry.cost,
+            })
+            .collect();
+        let total_tokens = models.iter().map(|m| m.total_tokens).sum();
+        let cost = models
+            .iter()
+            .try_fold(0.0, |sum, m| m.cost.map(|cost| sum + cost));
+
+        let UsageQuota {
+            tokens: quota_tokens,
+            cost: quota_cost,
+        } = managed.quota_of(window, &user);
+        windows.push(WindowUsage {
+            name: window.name.clone(),
+            period: window.period,
+            from,
+            to,
+            requests: models.iter().map(|m| m.requests).sum(),
+            prompt_tokens: models.iter().map(|m| m.prompt_tokens).sum(),
+            completion_tokens: models.iter().map(|m| m.completion_tokens).sum(),
+            total_tokens,
+            cost,
+            quota: QuotaUsage {
+                tokens: quota_tokens,
+                cost: quota_cost,
+            },
+            remaining: QuotaUsage {
+                tokens: quota_tokens.map(|quota| (quota - total_tokens).max(0)),
+                cost: quota_cost
+                    .zip(cost)
+                    .map(|(quota, cost)| (quota - cost).max(0.0)),
+            },
+            models,
+        });
+    }
+
+    Ok(Json(UserUsage {
+        user,
+        generated_at: now,
+        windows,
+    }))
+}
@@ -0,0 +1,119 @@
+#[cfg(feature = "otel")]
+use crate::handler::record_span_attribute;
+use crate::handler::AUTHED_HEADER;
+use crate::tls::ClientCertSubject;
+use axum::http::{Extensions, HeaderMap};
+use tracing::{event, Level};
+
+#[cfg(not(feature = "jwt-auth"))]
+use axum::extract::Request;
+#[cfg(not(feature = "jwt-auth"))]
+use axum::middleware::Next;
+#[cfg(not(feature = "jwt-auth"))]
+use axum::response::Response;
+
+/// Take the subject of the verified client certificate, if any, as the authenticated subject.
+pub fn authenticate_client_cert(headers: &mut HeaderMap, extensions: &Extensions) {
+    let Some(ClientCertSubject(subject)) = extensions.get::<ClientCertSubject>() else {
+        return;
+    };
+    // UTF-8 names would be carried as opaque bytes the other layers cannot read
+    let visible = subject.bytes().all(|b| b.is_ascii_graphic() || b == b' ');
+    match subject.parse().ok().filter(|_| visible) {
+        Some(value) => {
+            event!(
+                Level::INFO,
+                "authed subject by client certificate: {}",
+                subject
+            );
+            headers.insert(AUTHED_HEADER, value);
+            #[cfg(feature = "otel")]
+            record_span_attribute(extensions, "user", subject);
+        }
+        None => {
+            event!(
+                Level::WARN,
+                "client certificate subject not usable: {}",
+                subject
+            );
+        }
+    }
+}
+
+/// Authenticate requests by their client certificate, in builds without JWT.
+#[cfg(not(feature = "jwt-auth"))]
+pub async fn client_cert_layer(req: Request, next: Next) -> Response {
+    let (mut parts, body) = req.into_parts();
+    // never trust a subject supplied by the client
+    parts.headers.remove(AUTHED_HEADER);
+    authenticate_client_cert(&mut parts.headers, &parts.extensions);
+    next.run(Request::from_parts(parts, body)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn authenticate(subject: &str) -> Option<String> {
+        let mut headers = HeaderMap::new();
+        let mut extensions = Extensions::new();
+        extensions.insert(ClientCertSubject(Arc::from(subject)));
+        authenticate_client_cert(&mut headers, &extensions);
+        headers
+            .get(AUTHED_HEADER)
+            .map(|h| h.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn subject_of_client_cert() {
+        assert_eq!(authenticate("alice").as_deref(), Some("alice"));
+        assert_eq!(authenticate("Alice Smith").as_deref(), Some("Alice Smith"));
+        assert_eq!(authenticate("Zoë"), None);
+        assert_eq!(authenticate("alice\tbob"), None);
+    }
+
+    #[cfg(not(feature = "jwt-auth"))]
+    mod layer {
+        use super::*;
+        use axum::body::Body;
+        use axum::http::StatusCode;
+        use axum::middleware::from_fn;
+        use axum::routing::get;
+        use axum::Router;
+        use tower::ServiceExt;
+
+        /// Status of a request carrying a subject of its own, with the subject of its
+        /// certificate if any.
+        async fn status(cert_subject: Option<&str>) -> StatusCode {
+            let app = Router::new()
+                .route(
+                    "/",
+                    get(|headers: HeaderMap| async move {
+                        match headers.get(AUTHED_HEADER).map(|h| h.as_bytes()) {
+                            Some(b"alice") => StatusCode::OK,
+                            Some(_) => StatusCode::BAD_REQUEST,
+                            None => StatusCode::UNAUTHORIZED,
+                        }
+                    }),
+                )
+                .layer(from_fn(client_cert_layer));
+            let mut req = Request::get("/")
+                .header(AUTHED_HEADER, "admin")
+                .body(Body::empty())
+                .unwrap();
+            if let Some(subject) = cert_subject {
+                req.extensions_mut()
+                    .insert(ClientCertSubject(Arc::from(subject)));
+            }
+            app.oneshot(req).await.unwrap().status()
+        }
+
+        #[tokio::test]
+        async fn client_supplied_subject_is_removed() {
+            assert_eq!(status(None).await, StatusCode::UNAUTHORIZED);
+            assert_eq!(status(Some("alice")).await, StatusCode::OK);
+            assert_eq!(status(Some("Zoë")).await, StatusCode::UNAUTHORIZED);
+        }
+    }
+}
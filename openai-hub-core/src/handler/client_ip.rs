@@ -0,0 +1,279 @@
+use crate::config::{ForwardedHeader, NetworkConfig};
+use crate::error::ErrorResponse;
+use crate::handler::AUTHED_HEADER;
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tracing::{event, instrument, Level};
+
+/// The resolved address of the client, inserted into the request extensions
+#[derive(Debug, Copy, Clone)]
+pub struct ClientIp(pub IpAddr);
+
+#[instrument(skip_all)]
+pub async fn client_ip_layer(
+    State(config): State<Arc<NetworkConfig>>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, ErrorResponse> {
+    // unix socket peers have no address
+    let peer = match req.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => canonical(addr.ip()),
+        None => canonical(config.local_address),
+    };
+    let ip = resolve_client_ip(&config, peer, req.headers());
+    event!(Level::DEBUG, "peer {}, client {}", peer, ip);
+
+    if config.deny.iter().any(|net| net.contains(&ip))
+        || (!config.allow.is_empty() && !config.allow.iter().any(|net| net.contains(&ip)))
+    {
+        event!(Level::WARN, "rejected client address {}", ip);
+        return Err(ErrorResponse::new(
+            StatusCode::FORBIDDEN,
+            format!("Client address {} not allowed", ip),
+        ));
+    }
+
+    req.extensions_mut().insert(ClientIp(ip));
+    // never trust a subject supplied by the client, whichever auth layers are built in
+    req.headers_mut().remove(AUTHED_HEADER);
+    Ok(next.run(req).await)
+}
+
+/// Walk the chain of the configured forwarded header from the nearest hop, the first address
+/// which is not a trusted proxy is the client.
+fn resolve_client_ip(config: &NetworkConfig, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| config.trusted_proxies.iter().any(|net| net.contains(ip));
+    if !is_trusted(&peer) {
+        return peer;
+    }
+    let chain = match config.forwarded_header {
+        ForwardedHeader::XForwardedFor => x_forwarded_for(headers),
+        ForwardedHeader::Forwarded => forwarded_for(headers),
+    };
+    let mut client = peer;
+    for ip in chain.into_iter().rev() {
+        client = ip;
+        if !is_trusted(&ip) {
+            break;
+        }
+    }
+    client
+}
+
+/// Addresses from the `for=` parameters of the RFC 7239 `Forwarded` headers
+fn forwarded_for(headers: &HeaderMap) -> Vec<IpAddr> {
+    headers
+        .get_all(header::FORWARDED)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                if !key.eq_ignore_ascii_case("for") {
+                    return None;
+                }
+                parse_node(value.trim_matches('"'))
+            })
+        })
+        .collect()
+}
+
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<IpAddr> {
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|node| parse_node(node.trim()))
+        .collect()
+}
+
+/// Parse `1.2.3.4`, `1.2.3.4:80`, `[::1]` or `[::1]:80`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(canonical(ip));
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(canonical(addr.ip()));
+    }
+    node.strip_prefix('[')
+        .and_then(|node| node.strip_suffix(']'))
+        .and_then(|node| node.parse::<IpAddr>().ok())
+        .map(canonical)
+}
+
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::middleware::from_fn_with_state;
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    async fn status(config: NetworkConfig, req: Request) -> StatusCode {
+        Router::new()
+            .route("/", get(|| async {}))
+            .layer(from_fn_with_state(Arc::new(config), client_ip_layer))
+            .oneshot(req)
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn unix_socket_peer_is_checked() {
+        let config = NetworkConfig {
+            allow: vec!["10.0.0.0/8".parse().unwrap()],
+            ..Default::default()
+        };
+        let req = || Request::get("/").body(Body::empty()).unwrap();
+        assert_eq!(status(config.clone(), req()).await, StatusCode::FORBIDDEN);
+
+        let config = NetworkConfig {
+            local_address: "10.0.0.1".parse().unwrap(),
+            ..config
+        };
+        assert_eq!(status(config, req()).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn unix_socket_peer_behind_trusted_proxy() {
+        let config = NetworkConfig {
+            trusted_proxies: vec!["127.0.0.1/32".parse().unwrap()],
+            deny: vec!["192.0.2.0/24".parse().unwrap()],
+            ..Default::default()
+        };
+        let req = Request::get("/")
+            .header("x-forwarded-for", "192.0.2.7")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(status(config, req).await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn client_supplied_subject_is_removed() {
+        let app = Router::new()
+            .route(
+                "/",
+                get(|headers: HeaderMap| async move {
+                    match headers.contains_key(AUTHED_HEADER) {
+                        true => StatusCode::BAD_REQUEST,
+                        false => StatusCode::OK,
+                    }
+                }),
+            )
+            .layer(from_fn_with_state(
+                Arc::new(NetworkConfig::default()),
+                client_ip_layer,
+            ));
+        let req = Request::get("/")
+            .header(AUTHED_HEADER, "admin")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(app.oneshot(req).await.unwrap().status(), StatusCode::OK);
+    }
+
+    fn resolve(config: &NetworkConfig, peer: &str, headers: &[(&'static str, &str)]) -> IpAddr {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, value.parse().unwrap());
+        }
+        resolve_client_ip(config, peer.parse().unwrap(), &map)
+    }
+
+    fn behind(proxies: &[&str], forwarded_header: ForwardedHeader) -> NetworkConfig {
+        NetworkConfig {
+            trusted_proxies: proxies.iter().map(|net| net.parse().unwrap()).collect(),
+            forwarded_header,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn untrusted_peer_headers_are_ignored() {
+        let config = behind(&["10.0.0.0/8"], ForwardedHeader::XForwardedFor);
+        let ip = resolve(&config, "192.0.2.7", &[("x-forwarded-for", "10.0.0.5")]);
+        assert_eq!(ip, "192.0.2.7".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn chain_is_walked_past_trusted_hops() {
+        let config = behind(&["10.0.0.0/8"], ForwardedHeader::XForwardedFor);
+        // the client forged the first entry, 10.0.0.2 is a trusted proxy
+        let ip = resolve(
+            &config,
+            "10.0.0.1",
+            &[("x-forwarded-for", "10.0.0.5, 198.51.100.9, 10.0.0.2")],
+        );
+        assert_eq!(ip, "198.51.100.9".parse::<IpAddr>().unwrap());
+
+        let ip = resolve(
+            &config,
+            "10.0.0.1",
+            &[
+                ("x-forwarded-for", "10.0.0.5, 198.51.100.9"),
+                ("x-forwarded-for", "10.0.0.2"),
+            ],
+        );
+        assert_eq!(ip, "198.51.100.9".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn forwarded_ipv6_nodes() {
+        let config = behind(&["10.0.0.0/8"], ForwardedHeader::Forwarded);
+        let ip = resolve(
+            &config,
+            "10.0.0.1",
+            &[("forwarded", r#"for="[2001:db8::1]:4711";proto=https"#)],
+        );
+        assert_eq!(ip, "2001:db8::1".parse::<IpAddr>().unwrap());
+
+        let ip = resolve(
+            &config,
+            "10.0.0.1",
+            &[("forwarded", r#"for="[2001:db8::2]", for=10.0.0.2:80"#)],
+        );
+        assert_eq!(ip, "2001:db8::2".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn forged_forwarded_behind_x_forwarded_for_proxy() {
+        let config = behind(&["10.0.0.0/8"], ForwardedHeader::XForwardedFor);
+        let ip = resolve(
+            &config,
+            "10.0.0.1",
+            &[
+                ("forwarded", "for=10.0.0.5"),
+                ("x-forwarded-for", "198.51.100.9"),
+            ],
+        );
+        assert_eq!(ip, "198.51.100.9".parse::<IpAddr>().unwrap());
+    }
+
+    #[tokio::test]
+    async fn forged_forwarded_is_not_allowed() {
+        let config = NetworkConfig {
+            allow: vec!["10.0.0.0/8".parse().unwrap()],
+            ..behind(&["127.0.0.1/32"], ForwardedHeader::XForwardedFor)
+        };
+        let req = Request::get("/")
+            .header("forwarded", "for=10.0.0.5")
+            .header("x-forwarded-for", "198.51.100.9")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(status(config, req).await, StatusCode::FORBIDDEN);
+    }
+}
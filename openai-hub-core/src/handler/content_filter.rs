@@ -0,0 +1,87 @@
+use crate::content_filter::{ContentFilter, ContentFilterMatches};
+use crate::error::ErrorResponse;
+use crate::handler::helpers::is_json;
+use crate::short_circuit_if;
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use futures::TryStreamExt;
+use serde_json::Value;
+use std::io;
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tokio_util::io::StreamReader;
+use tracing::{event, instrument, Level};
+
+#[instrument(skip_all)]
+pub async fn content_filter_layer(
+    State(filter): State<Option<Arc<ContentFilter>>>,
+    req: Request,
+    next: Next,
+) -> Result<Response, ErrorResponse> {
+    short_circuit_if!(req, next, filter.is_none());
+    let filter = filter.unwrap();
+
+    // without a content type the body is inspected if it parses as JSON
+    let content_type = req.headers().get(header::CONTENT_TYPE);
+    let has_content_type = content_type.is_some();
+    let is_json = content_type.map_or(true, |value| is_json(value.to_str().unwrap_or_default()));
+    short_circuit_if!(req, next, req.method().is_safe() || !is_json);
+
+    let (parts, body) = req.into_parts();
+    let mut buf = vec![];
+    StreamReader::new(body.map_err(|e| io::Error::new(io::ErrorKind::Other, e)))
+        .read_to_end(&mut buf)
+        .await
+        .map_err(|_| ErrorResponse::new(StatusCode::BAD_REQUEST, "failed to read body"))?;
+    let mut json: Value = match serde_json::from_slice(&buf) {
+        Ok(json) => json,
+        Err(_) if !has_content_type => {
+            return Ok(next.run(Request::from_parts(parts, Body::from(buf))).await)
+        }
+        Err(e) => return Err(ErrorResponse::new(StatusCode::BAD_REQUEST, e.to_string())),
+    };
+
+    let inspection = filter.inspect(&mut json);
+    if !inspection.matched.is_empty() {
+        event!(
+            Level::WARN,
+            rules = ?inspection.matched,
+            "content filter matched {} {}",
+            parts.method,
+            parts.uri.path()
+        );
+    }
+
+    // the access audit log records the body without the text matched by any rule
+    let audited_body = (!inspection.matched.is_empty()).then(|| {
+        let mut audited = json.clone();
+        filter.redact_matches(&mut audited);
+        serde_json::to_vec(&audited).unwrap()
+    });
+
+    let mut response = match inspection.blocked_by {
+        Some(rule) => ErrorResponse::new(
+            StatusCode::FORBIDDEN,
+            format!("Request blocked by content filter rule {}", rule),
+        )
+        .into_response(),
+        None => {
+            let body = if inspection.redacted {
+                Body::from(serde_json::to_string(&json).unwrap())
+            } else {
+                Body::from(buf)
+            };
+            next.run(Request::from_parts(parts, body)).await
+        }
+    };
+    if let Some(body) = audited_body {
+        response.extensions_mut().insert(ContentFilterMatches {
+            rules: inspection.matched,
+            body,
+        });
+    }
+    Ok(response)
+}
@@ -0,0 +1,83 @@
+use crate::key::KeyPool;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use serde::Serialize;
+use std::sync::Arc;
+
+#[cfg(feature = "audit")]
+use crate::audit::Backend;
+#[cfg(feature = "jwt-auth")]
+use crate::config::ServerConfig;
+#[cfg(feature = "audit")]
+use tracing::{event, Level};
+
+/// What the health endpoints look at.
+#[derive(Clone)]
+pub struct HealthState {
+    pub key_pool: Arc<KeyPool>,
+    #[cfg(feature = "jwt-auth")]
+    pub config: Arc<ServerConfig>,
+    #[cfg(feature = "audit")]
+    pub audit_backend: Option<Backend>,
+}
+
+#[derive(Serialize)]
+pub struct Readiness {
+    ready: bool,
+    listener: Check,
+    audit: Check,
+    keys: Check,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Check {
+    Ok,
+    Disabled,
+    Failed(String),
+}
+
+/// The process is up.
+pub async fn healthz_handler() -> StatusCode {
+    StatusCode::OK
+}
+
+/// The hub can serve requests: the audit backend can be written to and at least one key is
+/// healthy.
+pub async fn readyz_handler(State(state): State<HealthState>) -> (StatusCode, Json<Readiness>) {
+    #[cfg(feature = "audit")]
+    let audit = match state.audit_backend {
+        Some(ref backend) => match backend.check().await {
+            Ok(_) => Check::Ok,
+            Err(e) => {
+                // the error may name the database host and user, the route is unauthenticated
+                event!(Level::ERROR, error = %e, "audit backend is not ready");
+                Check::Failed("audit backend unavailable".to_string())
+            }
+        },
+        None => Check::Disabled,
+    };
+    #[cfg(not(feature = "audit"))]
+    let audit = Check::Disabled;
+    let keys = if state.key_pool.healthy() > 0 {
+        Check::Ok
+    } else {
+        Check::Failed("no healthy api key".to_string())
+    };
+
+    let ready = !matches!(audit, Check::Failed(_)) && !matches!(keys, Check::Failed(_));
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    // this request came in through the listener
+    let readiness = Readiness {
+        ready,
+        listener: Check::Ok,
+        audit,
+        keys,
+    };
+    (status, Json(readiness))
+}
@@ -3,15 +3,25 @@ use axum::body::{Body, Bytes};
 use axum::extract::Request;
 use axum::middleware::Next;
 use axum::response::Response;
-use futures::TryStreamExt;
+use futures::{Stream, TryStreamExt};
 use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use sync_wrapper::SyncStream;
 use tokio::io::AsyncReadExt;
 use tokio::spawn;
-use tokio::sync::mpsc;
 use tokio::sync::mpsc::Receiver;
+use tokio::sync::{mpsc, oneshot};
 use tokio_util::io::StreamReader;
 
+#[cfg(any(feature = "metrics", feature = "otel"))]
+use crate::error::ErrorResponse;
+#[cfg(any(feature = "metrics", feature = "otel"))]
+use axum::http::{header, Method, StatusCode};
+#[cfg(any(feature = "metrics", feature = "otel"))]
+use serde::Deserialize;
+
 #[macro_export]
 macro_rules! short_circuit_if {
     ($req:ident, $next:ident, $cond:expr) => {
@@ -21,6 +31,56 @@ macro_rules! short_circuit_if {
     };
 }
 
+/// Whether a content type is JSON, ignoring its parameters, e.g. `; charset=utf-8`.
+pub fn is_json(content_type: &str) -> bool {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .eq_ignore_ascii_case("application/json")
+}
+
+/// `model` of a JSON request, kept in the extensions once read.
+#[cfg(any(feature = "metrics", feature = "otel"))]
+#[derive(Clone)]
+struct PeekedModel(Option<String>);
+
+/// Read the `model` of a JSON request body, which is buffered and put back into the request.
+#[cfg(any(feature = "metrics", feature = "otel"))]
+pub async fn peek_model(req: Request) -> Result<(Request, Option<String>), ErrorResponse> {
+    #[derive(Deserialize)]
+    struct ModelOnly {
+        #[serde(default)]
+        model: Option<String>,
+    }
+
+    if let Some(PeekedModel(model)) = req.extensions().get::<PeekedModel>() {
+        let model = model.clone();
+        return Ok((req, model));
+    }
+    let is_json = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(is_json);
+    if req.method() != Method::POST || !is_json {
+        return Ok((req, None));
+    }
+
+    let (mut parts, body) = req.into_parts();
+    let mut buf = vec![];
+    StreamReader::new(body.map_err(|e| io::Error::new(io::ErrorKind::Other, e)))
+        .read_to_end(&mut buf)
+        .await
+        .map_err(|_| ErrorResponse::new(StatusCode::BAD_REQUEST, "failed to read body"))?;
+    let model = serde_json::from_slice::<ModelOnly>(&buf)
+        .ok()
+        .and_then(|body| body.model);
+    parts.extensions.insert(PeekedModel(model.clone()));
+    Ok((Request::from_parts(parts, Body::from(buf)), model))
+}
+
 pub async fn stream_read_req_body(
     req: Request,
     next: Next,
@@ -58,3 +118,74 @@ fn stream_read_body(body: ResultStream<Bytes, axum::Error>) -> Receiver<Option<V
     });
     rx
 }
+
+#[derive(Debug, Clone, Copy)]
+pub struct ResponseTiming {
+    /// Time until the first byte of the response body, or its end if it is empty
+    pub ttfb: Option<Duration>,
+    /// Time until the response body was finished or dropped
+    pub total: Duration,
+}
+
+/// Measure how long it takes to stream the response body, counting from `started`.
+pub fn time_response_body(
+    response: Response,
+    started: Instant,
+) -> (Response, oneshot::Receiver<ResponseTiming>) {
+    let (parts, body) = response.into_parts();
+    let (tx, rx) = oneshot::channel();
+    let body = TimedStream {
+        inner: SyncStream::new(body),
+        timing: TimingGuard {
+            started,
+            ttfb: None,
+            finished: None,
+            tx: Some(tx),
+        },
+    };
+    (Response::from_parts(parts, Body::from_stream(body)), rx)
+}
+
+#[pin_project::pin_project]
+struct TimedStream<S> {
+    #[pin]
+    inner: S,
+    timing: TimingGuard,
+}
+
+/// Reports the timing when the body is dropped, whether it was finished or not.
+struct TimingGuard {
+    started: Instant,
+    ttfb: Option<Duration>,
+    finished: Option<Duration>,
+    tx: Option<oneshot::Sender<ResponseTiming>>,
+}
+
+impl<S: Stream> Stream for TimedStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let poll = this.inner.poll_next(cx);
+        if let Poll::Ready(ref item) = poll {
+            let elapsed = this.timing.started.elapsed();
+            this.timing.ttfb.get_or_insert(elapsed);
+            if item.is_none() {
+                this.timing.finished.get_or_insert(elapsed);
+            }
+        }
+        poll
+    }
+}
+
+impl Drop for TimingGuard {
+    fn drop(&mut self) {
+        if let Some(tx) = self.tx.take() {
+            tx.send(ResponseTiming {
+                ttfb: self.ttfb,
+                total: self.finished.unwrap_or_else(|| self.started.elapsed()),
+            })
+            .ok();
+        }
+    }
+}
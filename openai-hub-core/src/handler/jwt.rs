@@ -1,5 +1,11 @@
 use crate::config::JwtAuthConfig;
 use crate::error::ErrorResponse;
+#[cfg(feature = "tls")]
+use crate::handler::authenticate_client_cert;
+#[cfg(feature = "otel")]
+use crate::handler::record_span_attribute;
+use crate::handler::AUTHED_HEADER;
+use crate::listener::ListenerProfile;
 use axum::extract::{Request, State};
 use axum::http::{header, StatusCode};
 use axum::middleware::Next;
@@ -8,22 +14,42 @@ use jwt::{RegisteredClaims, VerifyWithKey};
 use std::sync::Arc;
 use tracing::{event, instrument, Level};
 
-pub const AUTHED_HEADER: &str = "X-AUTHED-SUB";
-
 #[instrument(skip_all)]
 pub async fn jwt_auth_layer(
     State(jwt_config): State<Option<Arc<JwtAuthConfig>>>,
     req: Request,
     next: Next,
 ) -> Result<Response, ErrorResponse> {
+    let profile = req
+        .extensions()
+        .get::<ListenerProfile>()
+        .map(|ListenerProfile(profile)| profile.clone());
+    // a listener may skip JWT, e.g. a local socket
+    let skip_jwt = matches!(profile, Some(ref profile) if !profile.jwt_auth);
     match jwt_config {
-        Some(jwt_config) => jwt_auth_layer_inner(jwt_config, req, next)
-            .await
-            .map_err(|_| {
-                event!(Level::ERROR, "Failed to authenticate request");
-                ErrorResponse::new(StatusCode::FORBIDDEN, "invalid authorization header")
-            }),
-        None => Ok(next.run(req).await),
+        Some(jwt_config) if !skip_jwt => {
+            jwt_auth_layer_inner(jwt_config, req, next)
+                .await
+                .map_err(|_| {
+                    event!(Level::ERROR, "Failed to authenticate request");
+                    ErrorResponse::new(StatusCode::FORBIDDEN, "invalid authorization header")
+                })
+        }
+        _ => {
+            // never trust a subject supplied by the client
+            let (mut parts, body) = req.into_parts();
+            parts.headers.remove(AUTHED_HEADER);
+            // validated when the configuration is loaded
+            if let Some(subject) = profile
+                .and_then(|profile| profile.subject.clone())
+                .and_then(|subject| subject.parse().ok())
+            {
+                parts.headers.insert(AUTHED_HEADER, subject);
+            }
+            #[cfg(feature = "tls")]
+            authenticate_client_cert(&mut parts.headers, &parts.extensions);
+            Ok(next.run(Request::from_parts(parts, body)).await)
+        }
     }
 }
 
@@ -74,20 +100,21 @@ async fn jwt_auth_layer_inner(
     }
 
     event!(Level::INFO, "verified claims: {:?}", claims);
-    match claims.subject {
+    let sub = match claims.subject {
         Some(sub) => {
             event!(Level::INFO, "authed subject: {}", sub);
-            parts
-                .headers
-                .insert(AUTHED_HEADER, sub.parse().map_err(|_| ())?);
+            sub
         }
         None => {
             event!(Level::INFO, "anonymous claims");
-            parts
-                .headers
-                .insert(AUTHED_HEADER, "anonymous".parse().unwrap());
+            "anonymous".to_string()
         }
-    }
+    };
+    parts
+        .headers
+        .insert(AUTHED_HEADER, sub.parse().map_err(|_| ())?);
+    #[cfg(feature = "otel")]
+    record_span_attribute(&parts.extensions, "user", &sub);
 
     let req = Request::from_parts(parts, body);
     Ok(next.run(req).await)
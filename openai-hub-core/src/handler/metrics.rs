@@ -0,0 +1,74 @@
+use crate::handler::helpers::peek_model;
+use crate::handler::AUTHED_HEADER;
+use crate::key::KeyPool;
+use crate::metrics::{endpoint_label, METRICS, OTHER};
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::header;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::sync::Arc;
+
+#[cfg(feature = "audit")]
+use crate::audit::AuditWriter;
+
+/// Sources of the gauges sampled when metrics are scraped.
+#[derive(Clone)]
+pub struct MetricsState {
+    pub key_pool: Arc<KeyPool>,
+    #[cfg(feature = "audit")]
+    pub audit_writer: Option<AuditWriter>,
+}
+
+/// Count requests by route, status, user and model.
+///
+/// Paths and models are client supplied, the hub routes are labelled by their route, proxied
+/// paths by their endpoint, and the models of rejected requests as `other`.
+pub async fn metrics_layer(req: Request, next: Next) -> Response {
+    let path = match req.extensions().get::<MatchedPath>() {
+        Some(route) => route.as_str(),
+        None => endpoint_label(req.uri().path()),
+    }
+    .to_string();
+    let user = req
+        .headers()
+        .get(AUTHED_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+
+    let (response, model) = match peek_model(req).await {
+        Ok((req, model)) => (next.run(req).await, model),
+        Err(e) => (e.into_response(), None),
+    };
+    let model = match model {
+        Some(ref model) if response.status().is_success() => METRICS.model_label(model),
+        Some(_) => OTHER,
+        None => "",
+    };
+    METRICS
+        .requests
+        .inc(&[&path, response.status().as_str(), &user, model]);
+    response
+}
+
+pub async fn metrics_handler(State(state): State<MetricsState>) -> impl IntoResponse {
+    METRICS
+        .key_pool_size
+        .set(&[], state.key_pool.total() as f64);
+    METRICS
+        .key_pool_available
+        .set(&[], state.key_pool.available() as f64);
+    METRICS
+        .key_pool_healthy
+        .set(&[], state.key_pool.healthy() as f64);
+    #[cfg(feature = "audit")]
+    if let Some(ref writer) = state.audit_writer {
+        METRICS
+            .audit_queue_depth
+            .set(&[], writer.queue_depth() as f64);
+    }
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        METRICS.encode(),
+    )
+}
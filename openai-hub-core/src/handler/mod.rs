@@ -1,10 +1,26 @@
 #[cfg(feature = "acl")]
 mod acl;
+#[cfg(feature = "jwt-auth")]
+mod admin;
 #[cfg(feature = "audit")]
 mod audit;
+#[cfg(feature = "tls")]
+mod client_cert;
+mod client_ip;
+#[cfg(feature = "content-filter")]
+mod content_filter;
+mod health;
 mod helpers;
 #[cfg(feature = "jwt-auth")]
 mod jwt;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "moderation")]
+mod moderation;
+#[cfg(feature = "jwt-auth")]
+mod status;
+#[cfg(feature = "otel")]
+mod trace;
 
 use crate::config::OpenAIConfig;
 use crate::error::ErrorResponse;
@@ -23,9 +39,44 @@ use sync_wrapper::SyncStream;
 #[cfg(feature = "jwt-auth")]
 pub use self::jwt::jwt_auth_layer;
 #[cfg(feature = "acl")]
-pub use acl::global_acl_layer;
+pub use acl::{acl_check_handler, global_acl_layer};
+#[cfg(feature = "jwt-auth")]
+pub use admin::{
+    add_key_handler, admin_layer, list_keys_handler, remove_key_handler, set_key_mode_handler,
+};
+#[cfg(all(feature = "audit", feature = "jwt-auth"))]
+pub use admin::{admin_audit_layer, list_quotas_handler, remove_quota_handler, set_quota_handler};
+#[cfg(all(feature = "acl", feature = "jwt-auth"))]
+pub use admin::{
+    list_roles_handler, list_users_handler, remove_role_handler, remove_user_handler,
+    set_role_handler, set_user_handler,
+};
+#[cfg(all(feature = "audit", feature = "jwt-auth"))]
+pub use audit::{
+    audit_access_handler, audit_usage_handler, quota_layer, user_usage_handler, AuditQueryState,
+    QuotaGuard,
+};
 #[cfg(feature = "audit")]
 pub use audit::{audit_access_layer, audit_tokens_layer};
+#[cfg(all(feature = "tls", feature = "jwt-auth"))]
+pub use client_cert::authenticate_client_cert;
+#[cfg(all(feature = "tls", not(feature = "jwt-auth")))]
+pub use client_cert::client_cert_layer;
+pub use client_ip::{client_ip_layer, ClientIp};
+#[cfg(feature = "content-filter")]
+pub use content_filter::content_filter_layer;
+pub use health::{healthz_handler, readyz_handler, HealthState};
+#[cfg(feature = "metrics")]
+pub use metrics::{metrics_handler, metrics_layer, MetricsState};
+#[cfg(feature = "moderation")]
+pub use moderation::{moderation_layer, ModerationFlags, ModerationGate};
+#[cfg(feature = "jwt-auth")]
+pub use status::hub_status_handler;
+#[cfg(feature = "otel")]
+pub use trace::{record_span_attribute, trace_context_headers, trace_layer, trace_model_layer};
+
+/// Header carrying the authenticated subject, set by the auth layers
+pub const AUTHED_HEADER: &str = "X-AUTHED-SUB";
 
 #[derive(Clone)]
 pub struct RequestHandler {
@@ -47,14 +98,28 @@ impl RequestHandler {
         let (parts, body) = req.into_parts();
         let body = body.map_err(|e| io::Error::new(io::ErrorKind::Other, e));
 
-        proxy_request(
+        let key = self.key_pool.get().await;
+        #[cfg(feature = "metrics")]
+        let start = std::time::Instant::now();
+        let result = proxy_request(
             self.client,
             parts.method,
             format!("{}{}", self.config.api_base, parts.uri.path()),
-            self.key_pool.get().await,
+            key,
             parts.headers,
             reqwest::Body::wrap_stream(SyncStream::new(body)),
         )
-        .await
+        .await;
+        #[cfg(feature = "metrics")]
+        if let Ok(ref response) = result {
+            crate::metrics::METRICS.upstream_latency.observe(
+                &[
+                    crate::metrics::endpoint_label(parts.uri.path()),
+                    response.status().as_str(),
+                ],
+                start.elapsed().as_secs_f64(),
+            );
+        }
+        result
     }
 }
@@ -0,0 +1,265 @@
+use crate::config::{ModerationConfig, OpenAIConfig};
+use crate::error::ErrorResponse;
+use crate::helpers::{endpoint_of, for_each_prompt_text, proxy_request};
+use crate::key::KeyPool;
+use crate::short_circuit_if;
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use futures::TryStreamExt;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tokio_util::io::StreamReader;
+use tracing::{event, instrument, Level};
+
+/// Flagged moderation categories of a request, attached to the response extensions
+/// so that the access audit log can pick them up.
+#[derive(Debug, Clone)]
+pub struct ModerationFlags(pub Vec<String>);
+
+#[derive(Clone)]
+pub struct ModerationGate {
+    pub config: Arc<ModerationConfig>,
+    pub key_pool: Arc<KeyPool>,
+    pub client: reqwest::Client,
+    pub openai: Arc<OpenAIConfig>,
+}
+
+#[derive(Deserialize)]
+struct ModerationResponse {
+    results: Vec<ModerationResult>,
+}
+
+#[derive(Deserialize)]
+struct ModerationResult {
+    flagged: bool,
+    categories: BTreeMap<String, bool>,
+}
+
+#[instrument(skip_all)]
+pub async fn moderation_layer(
+    State(gate): State<Option<ModerationGate>>,
+    req: Request,
+    next: Next,
+) -> Result<Response, ErrorResponse> {
+    short_circuit_if!(req, next, gate.is_none());
+    let gate = gate.unwrap();
+    let endpoint = endpoint_of(req.uri().path());
+    short_circuit_if!(
+        req,
+        next,
+        req.method() != Method::POST || !gate.config.endpoints.contains(&endpoint)
+    );
+
+    let (parts, body) = req.into_parts();
+    let mut buf = vec![];
+    StreamReader::new(body.map_err(|e| io::Error::new(io::ErrorKind::Other, e)))
+        .read_to_end(&mut buf)
+        .await
+        .map_err(|_| ErrorResponse::new(StatusCode::BAD_REQUEST, "failed to read body"))?;
+    let mut json: Value = serde_json::from_slice(&buf)
+        .map_err(|e| ErrorResponse::new(StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    let mut input = vec![];
+    for_each_prompt_text(&mut json, |text| input.push(text.clone()));
+    if input.is_empty() {
+        return Ok(next.run(Request::from_parts(parts, Body::from(buf))).await);
+    }
+
+    let flagged = match gate.moderate(input).await {
+        Ok(flagged) => flagged,
+        Err(e) if gate.config.fail_open => {
+            event!(Level::WARN, "moderation failed, forwarding anyway: {}", e);
+            vec![]
+        }
+        Err(e) => {
+            event!(Level::ERROR, "moderation failed: {}", e);
+            return Err(ErrorResponse::new(
+                StatusCode::BAD_GATEWAY,
+                "moderation unavailable",
+            ));
+        }
+    };
+
+    let denied: Vec<&String> = flagged
+        .iter()
+        .filter(|c| gate.config.deny_categories.contains(*c))
+        .collect();
+    let mut response = if denied.is_empty() {
+        next.run(Request::from_parts(parts, Body::from(buf))).await
+    } else {
+        event!(
+            Level::WARN,
+            categories = ?denied,
+            "request blocked by moderation"
+        );
+        ErrorResponse::new(
+            StatusCode::FORBIDDEN,
+            format!(
+                "Request blocked by moderation: {}",
+                denied
+                    .iter()
+                    .map(|c| c.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        )
+        .into_response()
+    };
+    if !flagged.is_empty() {
+        response.extensions_mut().insert(ModerationFlags(flagged));
+    }
+    Ok(response)
+}
+
+impl ModerationGate {
+    /// Send the input to the upstream `/moderations` endpoint, returning the flagged categories.
+    async fn moderate(&self, input: Vec<String>) -> Result<Vec<String>, String> {
+        let mut request = json!({ "input": input });
+        if let Some(ref model) = self.config.model {
+            request["model"] = Value::String(model.clone());
+        }
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        let response = proxy_request(
+            self.client.clone(),
+            Method::POST,
+            format!("{}/moderations", self.openai.api_base),
+            self.key_pool.clone().get().await,
+            headers,
+            serde_json::to_vec(&request).unwrap(),
+        )
+        .await
+        .map_err(|e| format!("{:?}", e))?;
+        if !response.status().is_success() {
+            return Err(format!("upstream returns {}", response.status()));
+        }
+
+        let mut buf = vec![];
+        StreamReader::new(
+            response
+                .into_body()
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+        )
+        .read_to_end(&mut buf)
+        .await
+        .map_err(|e| e.to_string())?;
+        let moderation: ModerationResponse =
+            serde_json::from_slice(&buf).map_err(|e| e.to_string())?;
+
+        let mut flagged: Vec<String> = moderation
+            .results
+            .into_iter()
+            .filter(|r| r.flagged)
+            .flat_map(|r| r.categories.into_iter())
+            .filter_map(|(category, flagged)| flagged.then_some(category))
+            .collect();
+        flagged.sort();
+        flagged.dedup();
+        event!(Level::DEBUG, "flagged categories: {:?}", flagged);
+        Ok(flagged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::middleware::from_fn_with_state;
+    use axum::routing::post;
+    use axum::Router;
+    use std::collections::HashSet;
+    use tokio::net::TcpListener;
+    use tower::ServiceExt;
+
+    const FLAGGED: &str = r#"{"id":"modr-1","model":"text-moderation-007","results":[{"flagged":true,"categories":{"harassment":true,"violence":false}}]}"#;
+
+    /// Serve `/v1/moderations` like upstream, returning the api base.
+    async fn upstream(status: StatusCode, body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route(
+            "/v1/moderations",
+            post(
+                move || async move { (status, [(header::CONTENT_TYPE, "application/json")], body) },
+            ),
+        );
+        tokio::spawn(async move { axum::serve(listener, app.into_make_service()).await });
+        format!("http://{addr}/v1")
+    }
+
+    fn gate(api_base: String, deny: &str, fail_open: bool) -> ModerationGate {
+        ModerationGate {
+            config: Arc::new(ModerationConfig {
+                endpoints: HashSet::from(["/chat/completions".to_string()]),
+                deny_categories: HashSet::from([deny.to_string()]),
+                model: None,
+                fail_open,
+            }),
+            key_pool: Arc::new(KeyPool::new(["sk-test".to_string()])),
+            client: reqwest::Client::new(),
+            openai: Arc::new(OpenAIConfig {
+                organization: None,
+                api_base,
+                api_type: Default::default(),
+                api_version: None,
+            }),
+        }
+    }
+
+    async fn send(gate: ModerationGate) -> Response {
+        let req = Request::post("/chat/completions")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                r#"{"model":"gpt-4","messages":[{"role":"user","content":"hi"}]}"#,
+            ))
+            .unwrap();
+        Router::new()
+            .route("/chat/completions", post(|| async {}))
+            .layer(from_fn_with_state(Some(gate), moderation_layer))
+            .oneshot(req)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn denied_category_is_rejected() {
+        let api_base = upstream(StatusCode::OK, FLAGGED).await;
+        let response = send(gate(api_base, "harassment", false)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let flags = response.extensions().get::<ModerationFlags>().unwrap();
+        assert_eq!(flags.0, ["harassment"]);
+    }
+
+    #[tokio::test]
+    async fn other_category_is_forwarded_and_recorded() {
+        let api_base = upstream(StatusCode::OK, FLAGGED).await;
+        let response = send(gate(api_base, "violence", false)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let flags = response.extensions().get::<ModerationFlags>().unwrap();
+        assert_eq!(flags.0, ["harassment"]);
+    }
+
+    #[tokio::test]
+    async fn failed_moderation_fails_open() {
+        let api_base = upstream(StatusCode::INTERNAL_SERVER_ERROR, "{}").await;
+        let response = send(gate(api_base, "harassment", true)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.extensions().get::<ModerationFlags>().is_none());
+    }
+
+    #[tokio::test]
+    async fn failed_moderation_fails_closed() {
+        let api_base = upstream(StatusCode::INTERNAL_SERVER_ERROR, "{}").await;
+        let response = send(gate(api_base, "harassment", false)).await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+}
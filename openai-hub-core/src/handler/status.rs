@@ -0,0 +1,134 @@
+use crate::config::ApiType;
+use crate::handler::HealthState;
+use crate::key::{mask_key, KeyMode, KeyPool};
+use axum::extract::State;
+use axum::Json;
+use serde::Serialize;
+use std::time::UNIX_EPOCH;
+
+#[derive(Serialize)]
+pub struct HubStatus {
+    config: ConfigStatus,
+    key_pool: KeyPoolStatus,
+}
+
+#[derive(Serialize)]
+struct ConfigStatus {
+    version: String,
+    bind: Vec<String>,
+    api_base: String,
+    api_type: ApiType,
+    api_keys: Vec<String>,
+    /// Optional sections of the configuration in use
+    enabled: Vec<&'static str>,
+}
+
+#[derive(Serialize)]
+struct KeyPoolStatus {
+    size: usize,
+    available: usize,
+    healthy: usize,
+    keys: Vec<KeyStatus>,
+}
+
+#[derive(Serialize)]
+pub struct KeyStatus {
+    id: String,
+    key: String,
+    mode: KeyMode,
+    healthy: bool,
+    in_use: bool,
+    requests: u64,
+    consecutive_failures: usize,
+    rejected: bool,
+    last_status: Option<u16>,
+    /// Unix timestamp in seconds
+    last_used: Option<u64>,
+}
+
+/// Key pool and configuration of the hub, with the api keys masked.
+pub async fn hub_status_handler(State(state): State<HealthState>) -> Json<HubStatus> {
+    let keys = key_statuses(&state.key_pool);
+    let config = &state.config;
+    #[allow(unused_mut)]
+    let mut enabled = vec![];
+    if config.admin.is_some() {
+        enabled.push("admin");
+    }
+    #[cfg(feature = "acl")]
+    if config.global_api_acl.is_some() {
+        enabled.push("acl");
+    }
+    #[cfg(feature = "jwt-auth")]
+    if config.jwt_auth.is_some() {
+        enabled.push("jwt-auth");
+    }
+    #[cfg(feature = "audit")]
+    if config.audit.is_some() {
+        enabled.push("audit");
+    }
+    #[cfg(feature = "content-filter")]
+    if config.content_filter.is_some() {
+        enabled.push("content-filter");
+    }
+    #[cfg(feature = "moderation")]
+    if config.moderation.is_some() {
+        enabled.push("moderation");
+    }
+    #[cfg(feature = "metrics")]
+    if config.metrics.is_some() {
+        enabled.push("metrics");
+    }
+    #[cfg(feature = "otel")]
+    if config.otel.is_some() {
+        enabled.push("otel");
+    }
+    #[cfg(feature = "tls")]
+    if config.tls.is_some() {
+        enabled.push("tls");
+    }
+
+    Json(HubStatus {
+        config: ConfigStatus {
+            version: config.version.clone(),
+            bind: config
+                .listeners
+                .iter()
+                .map(|listener| listener.addr.to_string())
+                .collect(),
+            api_base: config.openai.api_base.clone(),
+            api_type: config.openai.api_type,
+            api_keys: config.api_keys.iter().map(|key| mask_key(key)).collect(),
+            enabled,
+        },
+        key_pool: KeyPoolStatus {
+            size: state.key_pool.total(),
+            available: state.key_pool.available(),
+            healthy: state.key_pool.healthy(),
+            keys,
+        },
+    })
+}
+
+pub fn key_statuses(key_pool: &KeyPool) -> Vec<KeyStatus> {
+    key_pool
+        .keys()
+        .into_iter()
+        .map(|info| KeyStatus {
+            id: info.id,
+            key: info.key,
+            mode: info.mode,
+            healthy: info.state.is_healthy(),
+            in_use: info.state.in_use,
+            requests: info.state.requests,
+            consecutive_failures: info.state.failures,
+            rejected: info.state.rejected,
+            last_status: info.state.last_status,
+            last_used: info
+                .state
+                .last_used
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|duration| duration.as_secs()),
+        })
+        .collect()
+}
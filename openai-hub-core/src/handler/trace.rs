@@ -0,0 +1,87 @@
+use crate::error::ErrorResponse;
+use crate::handler::helpers::peek_model;
+use axum::extract::Request;
+use axum::http::{Extensions, HeaderMap};
+use axum::middleware::Next;
+use axum::response::Response;
+use opentelemetry::global;
+use opentelemetry::propagation::{Extractor, Injector};
+use tracing::field::Empty;
+use tracing::{info_span, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Span of the whole request, inner layers record the attributes they know about on it.
+#[derive(Clone)]
+pub struct RequestSpan(Span);
+
+/// Record an attribute on the span of the request, if traced.
+pub fn record_span_attribute(extensions: &Extensions, field: &'static str, value: &str) {
+    if let Some(RequestSpan(span)) = extensions.get::<RequestSpan>() {
+        span.record(field, value);
+    }
+}
+
+/// Continue the trace of the client from its `traceparent` header.
+pub async fn trace_layer(mut req: Request, next: Next) -> Result<Response, ErrorResponse> {
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(req.headers()))
+    });
+    let span = info_span!(
+        "request",
+        otel.name = format!("{} {}", req.method(), req.uri().path()),
+        otel.kind = "server",
+        http.method = %req.method(),
+        http.target = req.uri().path(),
+        http.status_code = Empty,
+        ray_id = Empty,
+        user = Empty,
+        model = Empty,
+    );
+    span.set_parent(parent);
+
+    req.extensions_mut().insert(RequestSpan(span.clone()));
+
+    let response = next.run(req).instrument(span.clone()).await;
+    span.record("http.status_code", response.status().as_u16());
+    Ok(response)
+}
+
+/// Record the `model` of the request on its span, inside the auth layer so that the body of
+/// unauthenticated requests is not read.
+pub async fn trace_model_layer(req: Request, next: Next) -> Result<Response, ErrorResponse> {
+    let (req, model) = peek_model(req).await?;
+    if let Some(ref model) = model {
+        record_span_attribute(req.extensions(), "model", model);
+    }
+    Ok(next.run(req).await)
+}
+
+/// Headers carrying the context of the current span to upstream.
+pub fn trace_context_headers() -> Vec<(String, String)> {
+    let context = Span::current().context();
+    let mut injector = HeaderInjector(vec![]);
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut injector)
+    });
+    injector.0
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+struct HeaderInjector(Vec<(String, String)>);
+
+impl Injector for HeaderInjector {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.push((key.to_string(), value));
+    }
+}
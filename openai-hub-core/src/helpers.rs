@@ -7,6 +7,7 @@ use axum::http::{header, HeaderMap, Method, StatusCode};
 use axum::response::Response;
 use base64::Engine;
 use futures::{Stream, StreamExt};
+use serde_json::Value;
 use tokio::sync::mpsc;
 
 use tokio_stream::wrappers::ReceiverStream;
@@ -111,7 +112,17 @@ impl<S: futures::Stream> futures::Stream for StreamWithKey<S> {
     }
 }
 
-#[instrument(skip(client, key, body))]
+/// Details of the upstream response, attached to the response extensions
+/// so that the access audit log can pick them up.
+#[cfg(feature = "audit")]
+#[derive(Debug, Clone)]
+pub struct UpstreamInfo {
+    pub status: u16,
+    pub request_id: Option<String>,
+    pub key_fingerprint: String,
+}
+
+#[instrument(skip(client, key, headers, body))]
 pub async fn proxy_request<U, B>(
     client: reqwest::Client,
     method: Method,
@@ -134,15 +145,31 @@ where
     if let Some(accept) = headers.get(header::ACCEPT) {
         request = request.header(header::ACCEPT, accept);
     }
+    #[cfg(feature = "otel")]
+    for (name, value) in crate::handler::trace_context_headers() {
+        request = request.header(name, value);
+    }
     let result = request.send().await.map_err(request_error_into_response)?;
     let status = result.status();
     let headers = result.headers().clone();
     event!(Level::DEBUG, "openai returns status: {}", status);
+    key.report(status.as_u16());
+    #[cfg(feature = "audit")]
+    let upstream = UpstreamInfo {
+        status: status.as_u16(),
+        request_id: headers
+            .get("x-request-id")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string()),
+        key_fingerprint: key.fingerprint(),
+    };
     let body = StreamWithKey::new(result.bytes_stream(), key);
     let mut builder = Response::builder().status(status);
     for (k, v) in headers.iter() {
         builder = builder.header(k, v);
     }
+    #[cfg(feature = "audit")]
+    let builder = builder.extension(upstream);
     Ok(builder.body(Body::from_stream(body)).unwrap())
 }
 
@@ -204,3 +231,70 @@ impl HeaderMapExt for HeaderMap {
             .collect()
     }
 }
+
+/// Visit `messages[*].content`, `prompt` and `input` of a request body.
+pub fn for_each_prompt_text<F: FnMut(&mut String)>(body: &mut Value, mut f: F) {
+    if let Some(messages) = body.get_mut("messages").and_then(Value::as_array_mut) {
+        for message in messages.iter_mut() {
+            match message.get_mut("content") {
+                Some(Value::String(content)) => f(content),
+                Some(Value::Array(parts)) => {
+                    for part in parts.iter_mut() {
+                        if let Some(Value::String(text)) = part.get_mut("text") {
+                            f(text);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    for key in ["prompt", "input"] {
+        match body.get_mut(key) {
+            Some(Value::String(text)) => f(text),
+            Some(Value::Array(items)) => {
+                for item in items.iter_mut() {
+                    if let Value::String(text) = item {
+                        f(text);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Endpoint of a request path, without the Azure deployment prefix of
+/// `/engines/{deployment}/...` and `/openai/deployments/{deployment}/...`, and without empty
+/// segments, e.g. `/chat/completions`.
+pub fn endpoint_of(path: &str) -> String {
+    let mut segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let prefix = match segments.as_slice() {
+        ["engines" | "deployments", _, _, ..] => 2,
+        ["openai", "deployments", _, _, ..] => 3,
+        _ => 0,
+    };
+    segments.drain(..prefix);
+    format!("/{}", segments.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoints() {
+        assert_eq!(endpoint_of("/chat/completions"), "/chat/completions");
+        assert_eq!(endpoint_of("//chat//completions/"), "/chat/completions");
+        assert_eq!(
+            endpoint_of("/engines/my-gpt-4/chat/completions"),
+            "/chat/completions"
+        );
+        assert_eq!(
+            endpoint_of("/openai/deployments/my-gpt-4/completions"),
+            "/completions"
+        );
+        assert_eq!(endpoint_of("/engines/my-gpt-4"), "/engines/my-gpt-4");
+        assert_eq!(endpoint_of("/"), "/");
+    }
+}
@@ -1,51 +1,282 @@
 use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
+use std::fmt;
 use std::sync::Arc;
-use std::{fmt, mem};
+use std::time::SystemTime;
 use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
+/// Consecutive throttled or failed upstream responses after which a key is unhealthy.
+const UNHEALTHY_AFTER: usize = 3;
+
+/// Pool of API keys, every key serves one request at a time.
+///
+/// Keys can be added, disabled, drained and removed while the pool is in use. The semaphore
+/// holds a permit for every idle key, a permit whose key was taken out of service is forgotten
+/// instead of being returned. When the permit of an idle key was already acquired by a request
+/// about to take the lock, that request forgets it instead.
 pub struct KeyPool {
-    total: usize,
-    keys: Mutex<VecDeque<String>>,
+    inner: Mutex<Inner>,
     semaphore: Arc<Semaphore>,
 }
 
+struct Inner {
+    /// Removed keys leave an empty slot, so that indices stay valid
+    slots: Vec<Option<KeyEntry>>,
+    /// Indices of the enabled keys not in use
+    idle: VecDeque<usize>,
+    /// Permits of keys taken out of service, acquired by requests not holding the lock yet
+    unclaimed: usize,
+}
+
+struct KeyEntry {
+    key: Arc<str>,
+    mode: KeyMode,
+    state: KeyState,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyMode {
+    Enabled,
+    /// Not handed out to new requests
+    Disabled,
+    /// Not handed out to new requests, removed once its request finished
+    Draining,
+}
+
+/// Health of a key, as seen from the upstream responses to requests made with it.
+#[derive(Clone, Debug, Default)]
+pub struct KeyState {
+    pub in_use: bool,
+    pub requests: u64,
+    /// Consecutive failed responses
+    pub failures: usize,
+    /// Rejected by upstream as invalid or revoked
+    pub rejected: bool,
+    pub last_status: Option<u16>,
+    pub last_used: Option<SystemTime>,
+}
+
+impl KeyState {
+    pub fn is_healthy(&self) -> bool {
+        !self.rejected && self.failures < UNHEALTHY_AFTER
+    }
+}
+
+/// A key of the pool as reported to admins.
+#[cfg(feature = "jwt-auth")]
+pub struct KeyInfo {
+    pub id: String,
+    /// The key masked
+    pub key: String,
+    pub mode: KeyMode,
+    pub state: KeyState,
+}
+
+#[cfg(feature = "jwt-auth")]
+#[derive(Debug, thiserror::Error)]
+pub enum KeyPoolError {
+    #[error("key {0} not found")]
+    NotFound(String),
+    #[error("key {0} already exists")]
+    AlreadyExists(String),
+}
+
 #[clippy::has_significant_drop]
 pub struct KeyGuard {
-    key: String,
+    index: usize,
+    key: Arc<str>,
     pool: Arc<KeyPool>,
-    _permit: OwnedSemaphorePermit,
+    /// Taken when the key was taken out of service
+    permit: Option<OwnedSemaphorePermit>,
 }
 
 impl KeyPool {
     pub fn new(iter: impl IntoIterator<Item = String>) -> Self {
-        let keys = VecDeque::from_iter(iter);
-        let semaphore = Semaphore::new(keys.len());
+        let slots: Vec<Option<KeyEntry>> = iter
+            .into_iter()
+            .map(|key| {
+                Some(KeyEntry {
+                    key: key.into(),
+                    mode: KeyMode::Enabled,
+                    state: KeyState::default(),
+                })
+            })
+            .collect();
+        let semaphore = Semaphore::new(slots.len());
 
         Self {
-            total: keys.len(),
-            keys: Mutex::new(keys),
+            inner: Mutex::new(Inner {
+                idle: (0..slots.len()).collect(),
+                slots,
+                unclaimed: 0,
+            }),
             semaphore: Arc::new(semaphore),
         }
     }
 
     pub async fn get(self: Arc<Self>) -> KeyGuard {
-        let permit = self.semaphore.clone().acquire_owned().await.unwrap();
-        let key = self.keys.lock().pop_front().unwrap();
+        #[cfg(feature = "metrics")]
+        let start = std::time::Instant::now();
+        loop {
+            let permit = self.semaphore.clone().acquire_owned().await.unwrap();
+            let mut inner = self.inner.lock();
+            // a key was taken out of service after its permit was acquired
+            if inner.unclaimed > 0 {
+                inner.unclaimed -= 1;
+                permit.forget();
+                continue;
+            }
+            let index = inner.idle.pop_front().unwrap();
+            let entry = inner.slots[index].as_mut().unwrap();
+            entry.state.in_use = true;
+            #[cfg(feature = "metrics")]
+            crate::metrics::METRICS
+                .key_pool_wait
+                .observe(&[], start.elapsed().as_secs_f64());
+            return KeyGuard {
+                index,
+                key: entry.key.clone(),
+                pool: self.clone(),
+                permit: Some(permit),
+            };
+        }
+    }
+
+    /// Keys in the pool, including disabled and draining ones.
+    pub fn total(&self) -> usize {
+        self.inner.lock().slots.iter().flatten().count()
+    }
 
-        KeyGuard {
-            key,
-            pool: self.clone(),
-            _permit: permit,
+    /// Enabled keys not held by an in-flight request.
+    pub fn available(&self) -> usize {
+        self.inner.lock().idle.len()
+    }
+
+    /// Enabled keys not rejected nor repeatedly failing upstream.
+    pub fn healthy(&self) -> usize {
+        self.inner
+            .lock()
+            .slots
+            .iter()
+            .flatten()
+            .filter(|entry| entry.mode == KeyMode::Enabled && entry.state.is_healthy())
+            .count()
+    }
+}
+
+#[cfg(feature = "jwt-auth")]
+impl KeyPool {
+    pub fn keys(&self) -> Vec<KeyInfo> {
+        self.inner
+            .lock()
+            .slots
+            .iter()
+            .flatten()
+            .map(|entry| KeyInfo {
+                id: key_id(&entry.key),
+                key: mask_key(&entry.key),
+                mode: entry.mode,
+                state: entry.state.clone(),
+            })
+            .collect()
+    }
+
+    /// The key of an id.
+    pub fn key(&self, id: &str) -> Result<String, KeyPoolError> {
+        let inner = self.inner.lock();
+        let index = inner.find(id)?;
+        Ok(inner.slots[index].as_ref().unwrap().key.to_string())
+    }
+
+    /// Add an enabled key, returning its id.
+    pub fn add(&self, key: String) -> Result<String, KeyPoolError> {
+        let id = key_id(&key);
+        let mut inner = self.inner.lock();
+        if inner.find(&id).is_ok() {
+            return Err(KeyPoolError::AlreadyExists(id));
         }
+        inner.slots.push(Some(KeyEntry {
+            key: key.into(),
+            mode: KeyMode::Enabled,
+            state: KeyState::default(),
+        }));
+        let index = inner.slots.len() - 1;
+        inner.idle.push_back(index);
+        self.semaphore.add_permits(1);
+        Ok(id)
+    }
+
+    /// Change the mode of a key, returning the key.
+    pub fn set_mode(&self, id: &str, mode: KeyMode) -> Result<String, KeyPoolError> {
+        let mut inner = self.inner.lock();
+        let index = inner.find(id)?;
+        let entry = inner.slots[index].as_mut().unwrap();
+        entry.mode = mode;
+        let key = entry.key.to_string();
+        if entry.state.in_use {
+            // the request holding it returns it to the pool or drops it
+            return Ok(key);
+        }
+        match mode {
+            KeyMode::Enabled => {
+                if !inner.idle.contains(&index) {
+                    inner.idle.push_back(index);
+                    self.semaphore.add_permits(1);
+                }
+            }
+            KeyMode::Disabled => {
+                self.reclaim(&mut inner, index);
+            }
+            KeyMode::Draining => {
+                self.reclaim(&mut inner, index);
+                inner.slots[index] = None;
+            }
+        }
+        Ok(key)
+    }
+
+    /// Remove a key at once, a request using it is not interrupted.
+    pub fn remove(&self, id: &str) -> Result<String, KeyPoolError> {
+        let mut inner = self.inner.lock();
+        let index = inner.find(id)?;
+        let entry = inner.slots[index].take().unwrap();
+        self.reclaim(&mut inner, index);
+        Ok(entry.key.to_string())
+    }
+
+    /// Take the key at `index` out of the idle queue with its permit, if it is idle.
+    ///
+    /// Without a permit left, a request acquired it already and forgets it once it holds the
+    /// lock.
+    fn reclaim(&self, inner: &mut Inner, index: usize) {
+        let Some(position) = inner.idle.iter().position(|i| *i == index) else {
+            return;
+        };
+        inner.idle.remove(position);
+        match self.semaphore.try_acquire() {
+            Ok(permit) => permit.forget(),
+            Err(_) => inner.unclaimed += 1,
+        }
+    }
+}
+
+#[cfg(feature = "jwt-auth")]
+impl Inner {
+    fn find(&self, id: &str) -> Result<usize, KeyPoolError> {
+        self.slots
+            .iter()
+            .position(|slot| matches!(slot, Some(entry) if key_id(&entry.key) == id))
+            .ok_or_else(|| KeyPoolError::NotFound(id.to_string()))
     }
 }
 
 impl fmt::Debug for KeyPool {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("KeyPool")
-            .field("available", &self.semaphore.available_permits())
-            .field("total", &self.total)
+            .field("available", &self.available())
+            .field("total", &self.total())
             .finish()
     }
 }
@@ -54,11 +285,190 @@ impl KeyGuard {
     pub fn as_str(&self) -> &str {
         &self.key
     }
+
+    /// Record the status upstream responded with to a request made with the key.
+    pub fn report(&self, status: u16) {
+        let mut inner = self.pool.inner.lock();
+        let Some(ref mut entry) = inner.slots[self.index] else {
+            return;
+        };
+        let state = &mut entry.state;
+        state.requests += 1;
+        state.last_status = Some(status);
+        state.last_used = Some(SystemTime::now());
+        match status {
+            401 | 403 => state.rejected = true,
+            429 | 500..=599 => state.failures += 1,
+            _ => {
+                state.rejected = false;
+                state.failures = 0;
+            }
+        }
+    }
+
+    /// A non-reversible identifier of the key, safe to be logged.
+    #[cfg(feature = "audit")]
+    pub fn fingerprint(&self) -> String {
+        format!("sha256:{}", key_id(self.as_str()))
+    }
 }
 
 impl Drop for KeyGuard {
     fn drop(&mut self) {
-        let key = mem::take(&mut self.key);
-        self.pool.keys.lock().push_back(key);
+        let mut inner = self.pool.inner.lock();
+        let mode = inner.slots[self.index].as_mut().map(|entry| {
+            entry.state.in_use = false;
+            entry.mode
+        });
+        match mode {
+            Some(KeyMode::Enabled) => inner.idle.push_back(self.index),
+            Some(KeyMode::Draining) => {
+                inner.slots[self.index] = None;
+                self.permit.take().unwrap().forget();
+            }
+            Some(KeyMode::Disabled) | None => self.permit.take().unwrap().forget(),
+        }
+    }
+}
+
+/// Hex of the first 8 bytes of the SHA-256 digest of a key, the id of the key in the admin API.
+#[cfg(any(feature = "audit", feature = "jwt-auth"))]
+pub fn key_id(key: &str) -> String {
+    use sha2::{Digest, Sha256};
+    let digest = Sha256::digest(key.as_bytes());
+    digest[..8].iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Keep the prefix and the last 4 characters of a key, e.g. `sk-...abcd`.
+pub fn mask_key(key: &str) -> String {
+    let prefix = key.split_once('-').map(|(prefix, _)| prefix).unwrap_or("");
+    let chars: Vec<char> = key.chars().collect();
+    if chars.len() <= 8 {
+        return "****".to_string();
+    }
+    let suffix: String = chars[chars.len() - 4..].iter().collect();
+    if prefix.is_empty() || prefix.len() > 8 {
+        format!("...{suffix}")
+    } else {
+        format!("{prefix}-...{suffix}")
+    }
+}
+
+#[cfg(all(test, feature = "jwt-auth"))]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn pool(n: usize) -> Arc<KeyPool> {
+        Arc::new(KeyPool::new((0..n).map(|i| format!("sk-key{i}"))))
+    }
+
+    fn id(i: usize) -> String {
+        key_id(&format!("sk-key{i}"))
+    }
+
+    fn assert_counts(pool: &KeyPool, available: usize, total: usize) {
+        assert_eq!(
+            (pool.available(), pool.total()),
+            (available, total),
+            "available, total"
+        );
+    }
+
+    async fn no_key(pool: &Arc<KeyPool>) -> bool {
+        tokio::time::timeout(Duration::from_millis(20), pool.clone().get())
+            .await
+            .is_err()
+    }
+
+    #[tokio::test]
+    async fn disable_idle_and_in_use_keys() {
+        let pool = pool(2);
+        let guard = pool.clone().get().await;
+        assert_eq!(guard.as_str(), "sk-key0");
+        assert_counts(&pool, 1, 2);
+
+        pool.set_mode(&id(1), KeyMode::Disabled).unwrap();
+        assert_counts(&pool, 0, 2);
+        pool.set_mode(&id(0), KeyMode::Disabled).unwrap();
+        assert_counts(&pool, 0, 2);
+        drop(guard);
+        assert_counts(&pool, 0, 2);
+        assert!(no_key(&pool).await);
+    }
+
+    #[tokio::test]
+    async fn drain_idle_and_in_use_keys() {
+        let pool = pool(2);
+        let guard = pool.clone().get().await;
+
+        pool.set_mode(&id(1), KeyMode::Draining).unwrap();
+        assert_counts(&pool, 0, 1);
+        pool.set_mode(&id(0), KeyMode::Draining).unwrap();
+        assert_counts(&pool, 0, 1);
+        drop(guard);
+        assert_counts(&pool, 0, 0);
+        assert!(no_key(&pool).await);
+    }
+
+    #[tokio::test]
+    async fn remove_key_in_use() {
+        let pool = pool(2);
+        let guard = pool.clone().get().await;
+        assert_eq!(pool.remove(&id(0)).unwrap(), "sk-key0");
+        assert_counts(&pool, 1, 1);
+        // the request finishes with the removed key
+        guard.report(200);
+        drop(guard);
+        assert_counts(&pool, 1, 1);
+        assert_eq!(pool.clone().get().await.as_str(), "sk-key1");
+        assert!(matches!(
+            pool.remove(&id(0)),
+            Err(KeyPoolError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn remove_idle_key() {
+        let pool = pool(2);
+        pool.remove(&id(1)).unwrap();
+        assert_counts(&pool, 1, 1);
+        let guard = pool.clone().get().await;
+        assert_eq!(guard.as_str(), "sk-key0");
+        assert!(no_key(&pool).await);
+    }
+
+    #[tokio::test]
+    async fn re_enable_key() {
+        let pool = pool(1);
+        pool.set_mode(&id(0), KeyMode::Disabled).unwrap();
+        assert_counts(&pool, 0, 1);
+        pool.set_mode(&id(0), KeyMode::Enabled).unwrap();
+        pool.set_mode(&id(0), KeyMode::Enabled).unwrap();
+        assert_counts(&pool, 1, 1);
+
+        let guard = pool.clone().get().await;
+        assert_counts(&pool, 0, 1);
+        // disabled and enabled again while in use, returned to the pool once
+        pool.set_mode(&id(0), KeyMode::Disabled).unwrap();
+        pool.set_mode(&id(0), KeyMode::Enabled).unwrap();
+        drop(guard);
+        assert_counts(&pool, 1, 1);
+        let _guard = pool.clone().get().await;
+        assert!(no_key(&pool).await);
+    }
+
+    #[tokio::test]
+    async fn waiting_request_gets_enabled_key() {
+        let pool = pool(1);
+        pool.set_mode(&id(0), KeyMode::Disabled).unwrap();
+        let waiting = tokio::spawn(pool.clone().get());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+
+        pool.add("sk-added".to_string()).unwrap();
+        let guard = waiting.await.unwrap();
+        assert_eq!(guard.as_str(), "sk-added");
+        assert_counts(&pool, 0, 2);
     }
 }
@@ -7,8 +7,13 @@
 mod acl;
 #[cfg(feature = "audit")]
 mod audit;
+/// Configuration file validation
+mod check;
 /// Configuration
 pub mod config;
+#[cfg(feature = "content-filter")]
+/// Request content filter
+mod content_filter;
 /// Error handling
 mod error;
 /// Request handlers
@@ -17,30 +22,111 @@ mod handler;
 mod helpers;
 /// API Key Pool
 mod key;
+/// TCP, unix and socket activated listeners
+mod listener;
+#[cfg(feature = "jwt-auth")]
+/// Keys, users, roles and quotas managed through the admin API
+mod managed;
+#[cfg(feature = "metrics")]
+/// Prometheus metrics
+mod metrics;
+#[cfg(feature = "audit")]
+/// Audit log redaction
+mod redact;
+#[cfg(feature = "tls")]
+/// TLS termination
+mod tls;
 
 #[cfg(feature = "acl")]
-pub use acl::ApiAcl;
+pub use acl::{AclExplanation, ApiAcl};
+#[cfg(feature = "acl")]
+pub use check::check_acl;
+pub use check::{check_config, Diagnostic, Severity};
 
-use crate::handler::RequestHandler;
+use crate::handler::{
+    client_ip_layer, healthz_handler, readyz_handler, HealthState, RequestHandler,
+};
 use crate::key::KeyPool;
+use crate::listener::{Connections, Listener};
 use axum::handler::HandlerWithoutStateExt;
+use axum::Router;
 use config::ServerConfig;
+use listenfd::ListenFd;
+use std::future::Future;
 use std::io;
+#[cfg(feature = "jwt-auth")]
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::net::TcpListener;
+use std::time::Duration;
+use tokio::time::Instant;
 use tracing::{event, Level};
 
-#[cfg(any(feature = "acl", feature = "jwt-auth", feature = "audit"))]
+#[cfg(any(
+    feature = "acl",
+    feature = "jwt-auth",
+    feature = "audit",
+    feature = "content-filter",
+    feature = "moderation"
+))]
 use axum::handler::Handler;
-#[cfg(any(feature = "acl", feature = "jwt-auth", feature = "audit"))]
 use axum::middleware::from_fn_with_state;
+use axum::routing::get;
+#[cfg(feature = "jwt-auth")]
+use axum::routing::patch;
+#[cfg(all(feature = "acl", feature = "jwt-auth"))]
+use axum::routing::post;
+#[cfg(all(feature = "jwt-auth", any(feature = "acl", feature = "audit")))]
+use axum::routing::put;
 
+#[cfg(feature = "content-filter")]
+use crate::content_filter::ContentFilter;
+#[cfg(all(feature = "acl", feature = "jwt-auth"))]
+use crate::handler::acl_check_handler;
+#[cfg(all(feature = "tls", not(feature = "jwt-auth")))]
+use crate::handler::client_cert_layer;
+#[cfg(feature = "content-filter")]
+use crate::handler::content_filter_layer;
 #[cfg(feature = "acl")]
 use crate::handler::global_acl_layer;
 #[cfg(feature = "jwt-auth")]
 use crate::handler::jwt_auth_layer;
+#[cfg(feature = "jwt-auth")]
+use crate::handler::{
+    add_key_handler, admin_layer, hub_status_handler, list_keys_handler, remove_key_handler,
+    set_key_mode_handler,
+};
+#[cfg(all(feature = "audit", feature = "jwt-auth"))]
+use crate::handler::{
+    admin_audit_layer, audit_access_handler, audit_usage_handler, list_quotas_handler, quota_layer,
+    remove_quota_handler, set_quota_handler, user_usage_handler, AuditQueryState, QuotaGuard,
+};
 #[cfg(feature = "audit")]
 use crate::handler::{audit_access_layer, audit_tokens_layer};
+#[cfg(all(feature = "acl", feature = "jwt-auth"))]
+use crate::handler::{
+    list_roles_handler, list_users_handler, remove_role_handler, remove_user_handler,
+    set_role_handler, set_user_handler,
+};
+#[cfg(feature = "metrics")]
+use crate::handler::{metrics_handler, metrics_layer, MetricsState};
+#[cfg(feature = "moderation")]
+use crate::handler::{moderation_layer, ModerationGate};
+#[cfg(feature = "otel")]
+use crate::handler::{trace_layer, trace_model_layer};
+#[cfg(feature = "jwt-auth")]
+use crate::managed::Managed;
+#[cfg(feature = "tls")]
+use crate::tls::TlsServer;
+#[cfg(any(
+    feature = "metrics",
+    feature = "otel",
+    all(feature = "tls", not(feature = "jwt-auth"))
+))]
+use axum::middleware::from_fn;
+#[cfg(feature = "acl")]
+use parking_lot::RwLock;
+#[cfg(feature = "metrics")]
+use tokio::net::TcpListener;
 
 static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
 
@@ -62,6 +148,15 @@ pub enum ServerError {
     #[cfg(feature = "audit")]
     #[error(transparent)]
     Audit(#[from] audit::BackendCreationError),
+    #[cfg(feature = "content-filter")]
+    #[error(transparent)]
+    ContentFilter(#[from] regex::Error),
+    #[cfg(feature = "jwt-auth")]
+    #[error(transparent)]
+    Managed(#[from] managed::ManagedError),
+    #[cfg(feature = "tls")]
+    #[error(transparent)]
+    Tls(#[from] tls::TlsError),
 }
 
 impl Server {
@@ -76,43 +171,311 @@ impl Server {
 
     /// Start the server and listen for incoming connections.
     pub async fn serve(self) -> Result<(), ServerError> {
+        self.serve_with_shutdown(std::future::pending()).await
+    }
+
+    /// Start the server, until `signal` completes.
+    ///
+    /// The listeners are then closed and in-flight requests are given `shutdown_timeout`
+    /// to finish, before the audit records are flushed.
+    pub async fn serve_with_shutdown<F>(self, signal: F) -> Result<(), ServerError>
+    where
+        F: Future<Output = ()>,
+    {
         event!(Level::INFO, "{:?}", self.config);
-        let listener = TcpListener::bind(self.config.addr).await?;
+        let mut fds = ListenFd::from_env();
+        let mut listeners = vec![];
+        for config in self.config.listeners.iter() {
+            let config = Arc::new(config.clone());
+            for listener in Listener::bind(&config.addr, &mut fds).await? {
+                event!(Level::INFO, "listening on {}", listener);
+                listeners.push((config.clone(), listener));
+            }
+        }
+        #[cfg(feature = "tls")]
+        let tls = self
+            .config
+            .tls
+            .clone()
+            .map(TlsServer::load)
+            .transpose()?
+            .map(Arc::new);
         let client = reqwest::Client::builder()
             .user_agent(APP_USER_AGENT)
             .build()?;
+        let openai_config = Arc::new(self.config.openai.clone());
         let handler = RequestHandler {
             key_pool: self.api_key_pool.clone(),
-            client,
-            config: Arc::new(self.config.openai.clone()),
+            client: client.clone(),
+            config: openai_config.clone(),
         };
 
         #[cfg(feature = "audit")]
+        let (audit_state, audit_backend) = if let Some(ref audit_config) = self.config.audit {
+            let backend = audit::Backend::create_with(audit_config).await?;
+            audit::spawn_retention(backend.clone(), audit_config.retention.clone());
+            let writer = audit::AuditWriter::spawn(backend.clone(), &audit_config.writer);
+            (
+                Some((Arc::new(audit_config.clone()), writer)),
+                Some(backend),
+            )
+        } else {
+            (None, None)
+        };
+
+        #[cfg(feature = "audit")]
+        let audit_shutdown = audit_state
+            .as_ref()
+            .map(|(_, writer)| writer.clone())
+            .zip(audit_backend.clone());
+
+        #[cfg(all(feature = "audit", feature = "jwt-auth"))]
+        let audit_writer = audit_state.as_ref().map(|(_, writer)| writer.clone());
+
+        #[cfg(feature = "metrics")]
+        if let Some(ref metrics_config) = self.config.metrics {
+            let state = MetricsState {
+                key_pool: self.api_key_pool.clone(),
+                #[cfg(feature = "audit")]
+                audit_writer: audit_state.as_ref().map(|(_, writer)| writer.clone()),
+            };
+            let metrics_app =
+                Router::new().route("/metrics", get(metrics_handler).with_state(state));
+            let listener = TcpListener::bind(metrics_config.bind).await?;
+            tokio::spawn(async move {
+                if let Err(e) = axum::serve(listener, metrics_app.into_make_service()).await {
+                    event!(Level::ERROR, error = ?e, "metrics endpoint failed");
+                }
+            });
+        }
+
+        #[cfg(feature = "acl")]
+        let acl = self
+            .config
+            .global_api_acl
+            .clone()
+            .map(|acl| Arc::new(RwLock::new(acl)));
+
+        #[cfg(feature = "jwt-auth")]
+        let managed = Arc::new(Managed::load(
+            self.config
+                .admin
+                .as_ref()
+                .and_then(|admin| admin.state_file.as_ref())
+                .map(PathBuf::from),
+            self.api_key_pool.clone(),
+            #[cfg(feature = "acl")]
+            acl.clone(),
+            #[cfg(feature = "audit")]
+            self.config.audit.as_ref(),
+        )?);
+
+        #[cfg(all(feature = "audit", feature = "jwt-auth"))]
+        let query_state: AuditQueryState = audit_backend
+            .clone()
+            .zip(self.config.audit.clone())
+            .map(|(backend, config)| (backend, Arc::new(config)));
+
+        #[cfg(feature = "audit")]
+        let handler = handler.layer(from_fn_with_state(audit_state.clone(), audit_tokens_layer));
+
+        #[cfg(feature = "moderation")]
+        let handler = handler.layer(from_fn_with_state(
+            self.config.moderation.clone().map(|config| ModerationGate {
+                config: Arc::new(config),
+                key_pool: self.api_key_pool.clone(),
+                client: client.clone(),
+                openai: openai_config.clone(),
+            }),
+            moderation_layer,
+        ));
+
+        #[cfg(feature = "content-filter")]
         let handler = {
-            let state = if let Some(ref audit_config) = self.config.audit {
-                let backend = audit::Backend::create_with(audit_config).await?;
-                Some((Arc::new(audit_config.clone()), backend))
-            } else {
-                None
+            let filter = match self.config.content_filter {
+                Some(ref filter_config) => {
+                    Some(Arc::new(ContentFilter::create_with(filter_config)?))
+                }
+                None => None,
             };
-            handler
-                .layer(from_fn_with_state(state.clone(), audit_tokens_layer))
-                .layer(from_fn_with_state(state, audit_access_layer))
+            handler.layer(from_fn_with_state(filter, content_filter_layer))
         };
 
-        #[cfg(feature = "acl")]
+        // requests over quota are rejected before they are moderated or proxied
+        #[cfg(all(feature = "audit", feature = "jwt-auth"))]
         let handler = handler.layer(from_fn_with_state(
-            self.config.global_api_acl.clone().map(Arc::new),
-            global_acl_layer,
+            query_state
+                .clone()
+                .and_then(|(backend, config)| QuotaGuard::new(backend, config, managed.clone())),
+            quota_layer,
         ));
 
+        #[cfg(feature = "audit")]
+        let handler = handler.layer(from_fn_with_state(audit_state, audit_access_layer));
+
+        #[cfg(feature = "acl")]
+        let handler = handler.layer(from_fn_with_state(acl.clone(), global_acl_layer));
+
+        let health_state = HealthState {
+            key_pool: self.api_key_pool.clone(),
+            #[cfg(feature = "jwt-auth")]
+            config: self.config.clone(),
+            #[cfg(feature = "audit")]
+            audit_backend: audit_backend.clone(),
+        };
+
+        #[allow(unused_mut)]
+        let mut app = Router::new().fallback_service(handler.into_service());
+
         #[cfg(feature = "jwt-auth")]
-        let handler = handler.layer(from_fn_with_state(
+        {
+            let hub = Router::new().route(
+                "/status",
+                get(hub_status_handler).with_state(health_state.clone()),
+            );
+
+            let managed_api = Router::new()
+                .route("/keys", get(list_keys_handler).post(add_key_handler))
+                .route(
+                    "/keys/:id",
+                    patch(set_key_mode_handler).delete(remove_key_handler),
+                );
+
+            #[cfg(feature = "acl")]
+            let hub = hub.route("/acl/check", post(acl_check_handler).with_state(acl));
+
+            #[cfg(feature = "acl")]
+            let managed_api = managed_api
+                .route("/roles", get(list_roles_handler))
+                .route(
+                    "/roles/:name",
+                    put(set_role_handler).delete(remove_role_handler),
+                )
+                .route("/users", get(list_users_handler))
+                .route(
+                    "/users/:name",
+                    put(set_user_handler).delete(remove_user_handler),
+                );
+
+            #[cfg(feature = "audit")]
+            let hub = hub
+                .route(
+                    "/audit/access",
+                    get(audit_access_handler).with_state(query_state.clone()),
+                )
+                .route(
+                    "/audit/usage",
+                    get(audit_usage_handler).with_state(query_state.clone()),
+                );
+
+            #[cfg(feature = "audit")]
+            let managed_api = managed_api
+                .route("/quotas", get(list_quotas_handler))
+                .route(
+                    "/quotas/:user",
+                    put(set_quota_handler).delete(remove_quota_handler),
+                );
+
+            let hub = hub.merge(managed_api.with_state(managed.clone()));
+
+            #[cfg(feature = "audit")]
+            let hub = hub.layer(from_fn_with_state(audit_writer, admin_audit_layer));
+
+            let hub = hub.layer(from_fn_with_state(
+                self.config.admin.clone().map(Arc::new),
+                admin_layer,
+            ));
+
+            // routes added after the admin layer are open to every authenticated subject
+            #[cfg(feature = "audit")]
+            let hub = hub.route(
+                "/usage",
+                get(user_usage_handler).with_state((query_state, managed)),
+            );
+
+            app = app.nest("/hub", hub);
+        }
+
+        // inside the auth layer to label requests with the authenticated subject
+        #[cfg(feature = "metrics")]
+        let app = app.layer(from_fn(metrics_layer));
+
+        #[cfg(feature = "otel")]
+        let app = app.layer(from_fn(trace_model_layer));
+
+        #[cfg(feature = "jwt-auth")]
+        let app = app.layer(from_fn_with_state(
             self.config.jwt_auth.clone().map(Arc::new),
             jwt_auth_layer,
         ));
 
-        axum::serve(listener, handler.into_service()).await?;
+        // without JWT, only the client certificate can authenticate a request
+        #[cfg(all(feature = "tls", not(feature = "jwt-auth")))]
+        let app = app.layer(from_fn(client_cert_layer));
+
+        // outside the auth layer, for probes without credentials
+        let app = app
+            .route("/healthz", get(healthz_handler))
+            .route("/readyz", get(readyz_handler).with_state(health_state));
+
+        let app = app.layer(from_fn_with_state(
+            Arc::new(self.config.network.clone()),
+            client_ip_layer,
+        ));
+
+        #[cfg(feature = "otel")]
+        let app = app.layer(from_fn(trace_layer));
+
+        let connections = Connections::default();
+        let serving =
+            futures::future::try_join_all(listeners.into_iter().map(|(config, listener)| {
+                #[cfg(feature = "tls")]
+                let tls = tls.clone().filter(|_| config.tls);
+                listener.serve(
+                    config,
+                    app.clone(),
+                    connections.clone(),
+                    #[cfg(feature = "tls")]
+                    tls,
+                )
+            }));
+        tokio::select! {
+            result = serving => {
+                result?;
+            }
+            _ = signal => {}
+        }
+
+        // the listeners are closed once `serving` is dropped
+        event!(
+            Level::INFO,
+            "shutting down, waiting for {} connections",
+            connections.len()
+        );
+        let deadline = Instant::now() + Duration::from_secs(self.config.shutdown_timeout);
+        let open = connections.drain(deadline).await;
+        if open > 0 {
+            event!(
+                Level::WARN,
+                "shutdown timeout elapsed, dropping {} connections",
+                open
+            );
+        }
+        #[cfg(feature = "audit")]
+        if let Some((writer, backend)) = audit_shutdown {
+            writer.close(deadline).await;
+            // closing waits for the connections still writing
+            if tokio::time::timeout_at(deadline, backend.close())
+                .await
+                .is_err()
+            {
+                event!(
+                    Level::WARN,
+                    "audit backend connections not closed by the deadline"
+                );
+            }
+        }
+        event!(Level::INFO, "shut down");
         Ok(())
     }
 }
@@ -0,0 +1,259 @@
+use crate::config::{ListenAddr, ListenerConfig};
+use axum::extract::ConnectInfo;
+use axum::http::{Extensions, Request};
+use axum::Router;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::service::TowerToHyperService;
+use listenfd::ListenFd;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tower::ServiceExt;
+use tracing::{event, Level};
+
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+#[cfg(feature = "tls")]
+use crate::tls::TlsServer;
+
+/// The listener a request came in through, inserted into the request extensions
+#[cfg(feature = "jwt-auth")]
+#[derive(Debug, Clone)]
+pub struct ListenerProfile(pub Arc<ListenerConfig>);
+
+/// Connections accepted on the listeners, drained on shutdown.
+#[derive(Clone, Default)]
+pub struct Connections {
+    tracker: TaskTracker,
+    shutdown: CancellationToken,
+}
+
+impl Connections {
+    /// Ask every connection to close once its in-flight requests are done, and wait for
+    /// them until the deadline. Returns the number of connections still open.
+    pub async fn drain(&self, deadline: Instant) -> usize {
+        self.shutdown.cancel();
+        self.tracker.close();
+        match tokio::time::timeout_at(deadline, self.tracker.wait()).await {
+            Ok(_) => 0,
+            Err(_) => self.tracker.len(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracker.len()
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Bind the sockets of an address, a `systemd` address takes every socket passed by
+    /// socket activation not taken yet.
+    pub async fn bind(addr: &ListenAddr, fds: &mut ListenFd) -> io::Result<Vec<Self>> {
+        let listeners = match addr {
+            ListenAddr::Tcp(addr) => vec![Self::Tcp(TcpListener::bind(addr).await?)],
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                // left behind by a previous run, binding fails otherwise
+                if std::fs::symlink_metadata(path)
+                    .map(|meta| std::os::unix::fs::FileTypeExt::is_socket(&meta.file_type()))
+                    .unwrap_or(false)
+                {
+                    std::fs::remove_file(path)?;
+                }
+                vec![Self::Unix(UnixListener::bind(path)?)]
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "unix sockets are not supported on this platform",
+                ))
+            }
+            ListenAddr::Systemd(Some(index)) => Self::from_fd(fds, *index)?.into_iter().collect(),
+            ListenAddr::Systemd(None) => (0..fds.len())
+                .map(|index| Self::from_fd(fds, index))
+                .collect::<io::Result<Vec<_>>>()?
+                .into_iter()
+                .flatten()
+                .collect(),
+        };
+        if listeners.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no socket passed by systemd for {addr}"),
+            ));
+        }
+        Ok(listeners)
+    }
+
+    fn from_fd(fds: &mut ListenFd, index: usize) -> io::Result<Option<Self>> {
+        match fds.take_tcp_listener(index) {
+            Ok(Some(listener)) => {
+                listener.set_nonblocking(true)?;
+                return Ok(Some(Self::Tcp(TcpListener::from_std(listener)?)));
+            }
+            Ok(None) => return Ok(None),
+            // not a TCP socket, left in place
+            Err(_) => {}
+        }
+        #[cfg(unix)]
+        if let Some(listener) = fds.take_unix_listener(index)? {
+            listener.set_nonblocking(true)?;
+            return Ok(Some(Self::Unix(UnixListener::from_std(listener)?)));
+        }
+        Ok(None)
+    }
+
+    /// Accept connections and serve `app` on them with the profile of the listener, until
+    /// dropped.
+    pub async fn serve(
+        self,
+        config: Arc<ListenerConfig>,
+        app: Router,
+        connections: Connections,
+        #[cfg(feature = "tls")] tls: Option<Arc<TlsServer>>,
+    ) -> io::Result<()> {
+        loop {
+            match self {
+                Self::Tcp(ref listener) => {
+                    let Some((stream, peer)) =
+                        accept_or_wait(&config, listener.accept().await).await
+                    else {
+                        continue;
+                    };
+                    let mut extensions = Extensions::new();
+                    #[cfg(feature = "jwt-auth")]
+                    extensions.insert(ListenerProfile(config.clone()));
+                    extensions.insert(ConnectInfo(peer));
+                    let app = app.clone();
+                    let shutdown = connections.shutdown.clone();
+                    #[cfg(feature = "tls")]
+                    if let Some(ref tls) = tls {
+                        let tls = tls.clone();
+                        connections.tracker.spawn(async move {
+                            match tls.accept(stream).await {
+                                Ok((stream, subject)) => {
+                                    if let Some(subject) = subject {
+                                        event!(
+                                            Level::DEBUG,
+                                            "client {} presented certificate of {}",
+                                            peer,
+                                            subject.0
+                                        );
+                                        extensions.insert(subject);
+                                    }
+                                    serve_connection(stream, app, extensions, shutdown).await
+                                }
+                                Err(e) => {
+                                    event!(
+                                        Level::DEBUG,
+                                        "TLS handshake with {} failed: {}",
+                                        peer,
+                                        e
+                                    )
+                                }
+                            }
+                        });
+                        continue;
+                    }
+                    connections
+                        .tracker
+                        .spawn(serve_connection(stream, app, extensions, shutdown));
+                }
+                #[cfg(unix)]
+                Self::Unix(ref listener) => {
+                    let Some((stream, _)) = accept_or_wait(&config, listener.accept().await).await
+                    else {
+                        continue;
+                    };
+                    #[allow(unused_mut)]
+                    let mut extensions = Extensions::new();
+                    #[cfg(feature = "jwt-auth")]
+                    extensions.insert(ListenerProfile(config.clone()));
+                    connections.tracker.spawn(serve_connection(
+                        stream,
+                        app.clone(),
+                        extensions,
+                        connections.shutdown.clone(),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+async fn accept_or_wait<T>(config: &ListenerConfig, accepted: io::Result<T>) -> Option<T> {
+    match accepted {
+        Ok(conn) => Some(conn),
+        Err(e) => {
+            // most likely out of file descriptors, give connections a chance to close
+            event!(Level::ERROR, error = %e, "failed to accept connection on {}", config.addr);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            None
+        }
+    }
+}
+
+/// Serve `app` on a connection, inserting `extensions` into every request.
+async fn serve_connection<S>(
+    stream: S,
+    app: Router,
+    extensions: Extensions,
+    shutdown: CancellationToken,
+) where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let service = app.map_request(move |mut req: Request<_>| {
+        req.extensions_mut().extend(extensions.clone());
+        req
+    });
+    let builder = Builder::new(TokioExecutor::new());
+    let conn = builder
+        .serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(service));
+    tokio::pin!(conn);
+    let result = tokio::select! {
+        result = conn.as_mut() => result,
+        _ = shutdown.cancelled() => {
+            // let the in-flight requests and streamed responses finish, then close
+            conn.as_mut().graceful_shutdown();
+            conn.await
+        }
+    };
+    if let Err(e) = result {
+        event!(Level::DEBUG, "connection failed: {}", e);
+    }
+}
+
+/// Address of a bound listener, for logging.
+impl std::fmt::Display for Listener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{addr}"),
+                Err(_) => write!(f, "tcp"),
+            },
+            #[cfg(unix)]
+            Self::Unix(listener) => match listener
+                .local_addr()
+                .ok()
+                .and_then(|addr| addr.as_pathname().map(|path| path.display().to_string()))
+            {
+                Some(path) => write!(f, "unix:{path}"),
+                None => write!(f, "unix"),
+            },
+        }
+    }
+}
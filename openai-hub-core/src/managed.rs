@@ -0,0 +1,487 @@
+use crate::key::{key_id, KeyMode, KeyPool, KeyPoolError};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{event, Level};
+
+#[cfg(feature = "acl")]
+use crate::acl::{ApiAcl, LoadError, Role, Subject};
+#[cfg(feature = "audit")]
+use crate::config::{AuditConfig, UsageQuota, UsageWindowConfig};
+#[cfg(any(feature = "acl", feature = "audit"))]
+use parking_lot::RwLock;
+#[cfg(feature = "audit")]
+use std::collections::HashMap;
+
+/// Changes made through the admin API, applied over the configuration on start.
+///
+/// `None` marks an entry removed, including ones of the configuration.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Overrides {
+    #[serde(default)]
+    keys: BTreeMap<String, Option<KeyMode>>,
+    #[cfg(feature = "acl")]
+    #[serde(default)]
+    roles: BTreeMap<String, Option<Role>>,
+    #[cfg(feature = "acl")]
+    #[serde(default)]
+    users: BTreeMap<String, Option<Subject>>,
+    /// Quotas by window name of a user
+    #[cfg(feature = "audit")]
+    #[serde(default)]
+    quotas: BTreeMap<String, Option<BTreeMap<String, UsageQuota>>>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ManagedError {
+    #[error(transparent)]
+    KeyPool(#[from] KeyPoolError),
+    #[cfg(feature = "acl")]
+    #[error(transparent)]
+    Acl(#[from] LoadError),
+    #[error("{0} is not configured")]
+    NotConfigured(&'static str),
+    #[error("{0} not found")]
+    NotFound(String),
+    #[error("unknown usage window {0}")]
+    UnknownWindow(String),
+    #[error("invalid state file: {0}")]
+    InvalidState(#[from] serde_json::Error),
+    #[error("failed to access state file: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// Keys, users, roles and quotas managed at runtime.
+///
+/// Every change is saved to the state file, if any, then applied to the running hub.
+pub struct Managed {
+    state_file: Option<PathBuf>,
+    /// Also serializes the changes
+    overrides: Mutex<Overrides>,
+    key_pool: Arc<KeyPool>,
+    #[cfg(feature = "acl")]
+    acl: Option<Arc<RwLock<ApiAcl>>>,
+    /// Quotas by user and window name, replacing the default quota of the window
+    #[cfg(feature = "audit")]
+    quotas: RwLock<HashMap<String, BTreeMap<String, UsageQuota>>>,
+    #[cfg(feature = "audit")]
+    windows: Option<Vec<String>>,
+}
+
+impl Managed {
+    /// Apply the state file over the configuration, if it exists.
+    pub fn load(
+        state_file: Option<PathBuf>,
+        key_pool: Arc<KeyPool>,
+        #[cfg(feature = "acl")] acl: Option<Arc<RwLock<ApiAcl>>>,
+        #[cfg(feature = "audit")] audit: Option<&AuditConfig>,
+    ) -> Result<Self, ManagedError> {
+        let overrides: Overrides = match state_file {
+            Some(ref path) if path.exists() => {
+                event!(Level::INFO, "loading state file {}", path.display());
+                serde_json::from_slice(&std::fs::read(path)?)?
+            }
+            _ => Overrides::default(),
+        };
+
+        for (key, mode) in overrides.keys.iter() {
+            let id = key_id(key);
+            match mode {
+                Some(mode) => {
+                    match key_pool.add(key.clone()) {
+                        Ok(_) | Err(KeyPoolError::AlreadyExists(_)) => {}
+                        Err(e) => return Err(e.into()),
+                    }
+                    key_pool.set_mode(&id, *mode)?;
+                }
+                None => match key_pool.remove(&id) {
+                    Ok(_) | Err(KeyPoolError::NotFound(_)) => {}
+                    Err(e) => return Err(e.into()),
+                },
+            }
+        }
+
+        #[cfg(feature = "acl")]
+        if let Some(ref acl) = acl {
+            let mut acl = acl.write();
+            // roles first, so that users can refer to them, and removed last
+            for (name, role) in overrides.roles.iter() {
+                if let Some(role) = role {
+                    acl.set_role(name.clone(), role.clone());
+                }
+            }
+            for (name, user) in overrides.users.iter() {
+                match user {
+                    Some(user) => acl.set_subject(name.clone(), user.clone())?,
+                    None => {
+                        acl.remove_subject(name);
+                    }
+                }
+            }
+            for (name, role) in overrides.roles.iter() {
+                if role.is_none() {
+                    acl.remove_role(name)?;
+                }
+            }
+        }
+
+        #[cfg(feature = "audit")]
+        let (quotas, windows) = {
+            let mut quotas: HashMap<String, BTreeMap<String, UsageQuota>> = HashMap::new();
+            for window in audit.iter().flat_map(|audit| audit.usage.windows.iter()) {
+                for (user, quota) in window.users.iter() {
+                    quotas
+                        .entry(user.clone())
+                        .or_default()
+                        .insert(window.name.clone(), quota.clone());
+                }
+            }
+            for (user, quota) in overrides.quotas.iter() {
+                match quota {
+                    Some(quota) => quotas.insert(user.clone(), quota.clone()),
+                    None => quotas.remove(user),
+                };
+            }
+            let windows = audit.map(|audit| {
+                audit
+                    .usage
+                    .windows
+                    .iter()
+                    .map(|window| window.name.clone())
+                    .collect()
+            });
+            (RwLock::new(quotas), windows)
+        };
+
+        Ok(Self {
+            state_file,
+            overrides: Mutex::new(overrides),
+            key_pool,
+            #[cfg(feature = "acl")]
+            acl,
+            #[cfg(feature = "audit")]
+            quotas,
+            #[cfg(feature = "audit")]
+            windows,
+        })
+    }
+
+    pub fn key_pool(&self) -> &KeyPool {
+        &self.key_pool
+    }
+
+    /// Add a key, returning its id.
+    pub async fn add_key(&self, key: String) -> Result<String, ManagedError> {
+        let id = key_id(&key);
+        self.update(
+            |overrides| {
+                if self.key_pool.key(&id).is_ok() {
+                    return Err(KeyPoolError::AlreadyExists(id.clone()).into());
+                }
+                overrides.keys.insert(key.clone(), Some(KeyMode::Enabled));
+                Ok(())
+            },
+            || Ok(self.key_pool.add(key.clone())?),
+        )
+        .await
+    }
+
+    /// Enable, disable or drain a key. A drained key is saved as removed.
+    pub async fn set_key_mode(&self, id: &str, mode: KeyMode) -> Result<(), ManagedError> {
+        self.update(
+            |overrides| {
+                let key = self.key_pool.key(id)?;
+                let mode = match mode {
+                    KeyMode::Draining => None,
+                    mode => Some(mode),
+                };
+                overrides.keys.insert(key, mode);
+                Ok(())
+            },
+            || {
+                self.key_pool.set_mode(id, mode)?;
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    pub async fn remove_key(&self, id: &str) -> Result<(), ManagedError> {
+        self.update(
+            |overrides| {
+                overrides.keys.insert(self.key_pool.key(id)?, None);
+                Ok(())
+            },
+            || {
+                self.key_pool.remove(id)?;
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    #[cfg(feature = "acl")]
+    fn acl(&self) -> Result<&RwLock<ApiAcl>, ManagedError> {
+        self.acl
+            .as_deref()
+            .ok_or(ManagedError::NotConfigured("acl"))
+    }
+
+    #[cfg(feature = "acl")]
+    pub fn roles(&self) -> Result<BTreeMap<String, Role>, ManagedError> {
+        Ok(self.acl()?.read().roles.clone().into_iter().collect())
+    }
+
+    #[cfg(feature = "acl")]
+    pub async fn set_role(&self, name: String, role: Role) -> Result<(), ManagedError> {
+        self.update(
+            |overrides| {
+                self.acl()?;
+                overrides.roles.insert(name.clone(), Some(role.clone()));
+                Ok(())
+            },
+            || {
+                self.acl()?.write().set_role(name.clone(), role.clone());
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    #[cfg(feature = "acl")]
+    pub async fn remove_role(&self, name: &str) -> Result<(), ManagedError> {
+        self.update(
+            |overrides| {
+                // fails on roles still in use
+                self.acl()?
+                    .read()
+                    .clone()
+                    .remove_role(name)?
+                    .ok_or_else(|| ManagedError::NotFound(format!("role {name}")))?;
+                overrides.roles.insert(name.to_string(), None);
+                Ok(())
+            },
+            || {
+                self.acl()?.write().remove_role(name)?;
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    #[cfg(feature = "acl")]
+    pub fn users(&self) -> Result<BTreeMap<String, Subject>, ManagedError> {
+        Ok(self.acl()?.read().subjects.clone().into_iter().collect())
+    }
+
+    #[cfg(feature = "acl")]
+    pub async fn set_user(&self, name: String, user: Subject) -> Result<(), ManagedError> {
+        self.update(
+            |overrides| {
+                // fails on unknown roles
+                self.acl()?
+                    .read()
+                    .clone()
+                    .set_subject(name.clone(), user.clone())?;
+                overrides.users.insert(name.clone(), Some(user.clone()));
+                Ok(())
+            },
+            || {
+                Ok(self
+                    .acl()?
+                    .write()
+                    .set_subject(name.clone(), user.clone())?)
+            },
+        )
+        .await
+    }
+
+    #[cfg(feature = "acl")]
+    pub async fn remove_user(&self, name: &str) -> Result<(), ManagedError> {
+        self.update(
+            |overrides| {
+                if !self.acl()?.read().subjects.contains_key(name) {
+                    return Err(ManagedError::NotFound(format!("user {name}")));
+                }
+                overrides.users.insert(name.to_string(), None);
+                Ok(())
+            },
+            || {
+                self.acl()?.write().remove_subject(name);
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    /// Quota of the user in the window.
+    #[cfg(feature = "audit")]
+    pub fn quota_of(&self, window: &UsageWindowConfig, user: &str) -> UsageQuota {
+        self.quotas
+            .read()
+            .get(user)
+            .and_then(|quotas| quotas.get(&window.name))
+            .unwrap_or(&window.quota)
+            .clone()
+    }
+
+    #[cfg(feature = "audit")]
+    pub fn quotas(&self) -> Result<BTreeMap<String, BTreeMap<String, UsageQuota>>, ManagedError> {
+        self.windows
+            .as_ref()
+            .ok_or(ManagedError::NotConfigured("audit"))?;
+        Ok(self.quotas.read().clone().into_iter().collect())
+    }
+
+    /// Replace the quotas of a user, by window name.
+    #[cfg(feature = "audit")]
+    pub async fn set_quota(
+        &self,
+        user: String,
+        quota: BTreeMap<String, UsageQuota>,
+    ) -> Result<(), ManagedError> {
+        let windows = self
+            .windows
+            .as_ref()
+            .ok_or(ManagedError::NotConfigured("audit"))?;
+        if let Some(window) = quota.keys().find(|window| !windows.contains(window)) {
+            return Err(ManagedError::UnknownWindow(window.clone()));
+        }
+        self.update(
+            |overrides| {
+                overrides.quotas.insert(user.clone(), Some(quota.clone()));
+                Ok(())
+            },
+            || {
+                self.quotas.write().insert(user.clone(), quota.clone());
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    /// Reset a user to the default quotas of the windows.
+    #[cfg(feature = "audit")]
+    pub async fn remove_quota(&self, user: &str) -> Result<(), ManagedError> {
+        self.windows
+            .as_ref()
+            .ok_or(ManagedError::NotConfigured("audit"))?;
+        self.update(
+            |overrides| {
+                if !self.quotas.read().contains_key(user) {
+                    return Err(ManagedError::NotFound(format!("quota of {user}")));
+                }
+                overrides.quotas.insert(user.to_string(), None);
+                Ok(())
+            },
+            || {
+                self.quotas.write().remove(user);
+                Ok(())
+            },
+        )
+        .await
+    }
+
+    /// Check a change and record it in the overrides, save them, then apply the change.
+    ///
+    /// Nothing is applied when saving fails, so that the running hub and the state file
+    /// stay the same.
+    async fn update<T>(
+        &self,
+        record: impl FnOnce(&mut Overrides) -> Result<(), ManagedError>,
+        apply: impl FnOnce() -> Result<T, ManagedError>,
+    ) -> Result<T, ManagedError> {
+        let mut overrides = self.overrides.lock().await;
+        let mut updated = overrides.clone();
+        record(&mut updated)?;
+        self.save(&updated).await?;
+        let applied = apply()?;
+        *overrides = updated;
+        Ok(applied)
+    }
+
+    /// Write the overrides to a temporary file, readable by the owner only as it holds the
+    /// keys, sync it, then move it over the state file.
+    async fn save(&self, overrides: &Overrides) -> Result<(), ManagedError> {
+        let Some(ref path) = self.state_file else {
+            return Ok(());
+        };
+        let buf = serde_json::to_vec_pretty(overrides)?;
+        let path = path.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut temp = path.clone().into_os_string();
+            temp.push(".tmp");
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            let mut file = options.open(&temp)?;
+            file.write_all(&buf)?;
+            file.sync_all()?;
+            std::fs::rename(&temp, &path)?;
+            // persist the rename
+            #[cfg(unix)]
+            {
+                let dir = path
+                    .parent()
+                    .filter(|dir| !dir.as_os_str().is_empty())
+                    .unwrap_or(std::path::Path::new("."));
+                std::fs::File::open(dir)?.sync_all()?;
+            }
+            Ok::<_, io::Error>(())
+        })
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))??;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn managed(state_file: PathBuf) -> Managed {
+        Managed::load(
+            Some(state_file),
+            Arc::new(KeyPool::new(["sk-configured".to_string()])),
+            #[cfg(feature = "acl")]
+            Some(Arc::new(RwLock::new(ApiAcl::default()))),
+            #[cfg(feature = "audit")]
+            None,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn failed_save_changes_nothing() {
+        let managed = managed(PathBuf::from("/nonexistent/hub-state.json"));
+        assert!(managed.add_key("sk-added".to_string()).await.is_err());
+        assert_eq!(managed.key_pool().total(), 1);
+        let id = key_id("sk-configured");
+        assert!(managed.set_key_mode(&id, KeyMode::Disabled).await.is_err());
+        assert_eq!(managed.key_pool().keys()[0].mode, KeyMode::Enabled);
+        assert!(managed.overrides.lock().await.keys.is_empty());
+    }
+
+    #[tokio::test]
+    async fn saved_changes_are_applied() {
+        let dir = std::env::temp_dir().join(format!("openai-hub-managed-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let state_file = dir.join("hub-state.json");
+        let managed = managed(state_file.clone());
+        let id = managed.add_key("sk-added".to_string()).await.unwrap();
+        assert_eq!(managed.key_pool().total(), 2);
+        assert!(matches!(
+            managed.add_key("sk-added".to_string()).await,
+            Err(ManagedError::KeyPool(KeyPoolError::AlreadyExists(_)))
+        ));
+        managed.remove_key(&id).await.unwrap();
+        assert_eq!(managed.key_pool().total(), 1);
+        let state = std::fs::read_to_string(&state_file).unwrap();
+        assert!(state.contains(r#""sk-added": null"#), "{state}");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
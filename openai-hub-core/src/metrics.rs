@@ -0,0 +1,378 @@
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+
+/// Metrics of the hub, shared by all layers.
+pub static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+const LATENCY_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+const WAIT_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0];
+
+/// Label of the paths and models not labelled by their own value
+pub(crate) const OTHER: &str = "other";
+
+/// Distinct models labelled by name, the models seen after them are labelled `other`
+const MAX_MODELS: usize = 100;
+
+/// Proxied endpoints labelled by their route, more specific routes first. A path matches a
+/// route it ends with, e.g. the Azure `/engines/{deployment}/chat/completions`, and `{…}`
+/// matches any segment.
+const ENDPOINTS: &[&str] = &[
+    "/chat/completions",
+    "/completions",
+    "/embeddings",
+    "/edits",
+    "/moderations",
+    "/images/generations",
+    "/images/edits",
+    "/images/variations",
+    "/audio/transcriptions",
+    "/audio/translations",
+    "/audio/speech",
+    "/models/{model}",
+    "/models",
+    "/files/{file_id}/content",
+    "/files/{file_id}",
+    "/files",
+    "/fine-tunes/{fine_tune_id}/cancel",
+    "/fine-tunes/{fine_tune_id}/events",
+    "/fine-tunes/{fine_tune_id}",
+    "/fine-tunes",
+    "/fine_tuning/jobs/{job_id}/cancel",
+    "/fine_tuning/jobs/{job_id}/events",
+    "/fine_tuning/jobs/{job_id}",
+    "/fine_tuning/jobs",
+];
+
+pub struct Metrics {
+    pub requests: Family<Counter>,
+    pub upstream_latency: Family<Histogram>,
+    pub tokens: Family<Counter>,
+    pub acl_rejections: Family<Counter>,
+    pub key_pool_size: Family<Gauge>,
+    pub key_pool_available: Family<Gauge>,
+    pub key_pool_healthy: Family<Gauge>,
+    pub key_pool_wait: Family<Histogram>,
+    pub audit_queue_depth: Family<Gauge>,
+    pub audit_dropped: Family<Counter>,
+    /// Models labelled by name
+    models: Mutex<HashSet<String>>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Self {
+            requests: Family::new(
+                "openai_hub_requests_total",
+                "Requests handled by the hub",
+                &["path", "status", "user", "model"],
+                Counter::default(),
+            ),
+            upstream_latency: Family::new(
+                "openai_hub_upstream_latency_seconds",
+                "Time until upstream returned the response headers",
+                &["path", "status"],
+                Histogram::new(LATENCY_BUCKETS),
+            ),
+            tokens: Family::new(
+                "openai_hub_tokens_total",
+                "Tokens consumed, estimated for streamed responses without upstream usage",
+                &["model", "user", "type"],
+                Counter::default(),
+            ),
+            acl_rejections: Family::new(
+                "openai_hub_acl_rejections_total",
+                "Requests rejected by the global ACL",
+                &["reason"],
+                Counter::default(),
+            ),
+            key_pool_size: Family::new(
+                "openai_hub_key_pool_size",
+                "API keys in the pool",
+                &[],
+                Gauge::default(),
+            ),
+            key_pool_available: Family::new(
+                "openai_hub_key_pool_available",
+                "API keys not used by an in-flight request",
+                &[],
+                Gauge::default(),
+            ),
+            key_pool_healthy: Family::new(
+                "openai_hub_key_pool_healthy",
+                "API keys not rejected nor repeatedly failing upstream",
+                &[],
+                Gauge::default(),
+            ),
+            key_pool_wait: Family::new(
+                "openai_hub_key_pool_wait_seconds",
+                "Time waited for an API key",
+                &[],
+                Histogram::new(WAIT_BUCKETS),
+            ),
+            audit_queue_depth: Family::new(
+                "openai_hub_audit_queue_depth",
+                "Audit records waiting to be written",
+                &[],
+                Gauge::default(),
+            ),
+            audit_dropped: Family::new(
+                "openai_hub_audit_dropped_total",
+                "Audit records dropped because the queue was full",
+                &[],
+                Counter::default(),
+            ),
+            models: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Label of a model, bounding the series that clients can create with arbitrary models.
+    pub fn model_label<'a>(&self, model: &'a str) -> &'a str {
+        let mut models = self.models.lock();
+        if models.contains(model) {
+            return model;
+        }
+        if models.len() < MAX_MODELS {
+            models.insert(model.to_string());
+            return model;
+        }
+        OTHER
+    }
+
+    /// Encode all metrics in the Prometheus text exposition format.
+    pub fn encode(&self) -> String {
+        let mut out = String::new();
+        self.requests.encode(&mut out);
+        self.upstream_latency.encode(&mut out);
+        self.tokens.encode(&mut out);
+        self.acl_rejections.encode(&mut out);
+        self.key_pool_size.encode(&mut out);
+        self.key_pool_available.encode(&mut out);
+        self.key_pool_healthy.encode(&mut out);
+        self.key_pool_wait.encode(&mut out);
+        self.audit_queue_depth.encode(&mut out);
+        self.audit_dropped.encode(&mut out);
+        out
+    }
+}
+
+pub trait Metric: Clone {
+    const TYPE: &'static str;
+
+    fn encode(&self, name: &str, labels: &str, out: &mut String);
+}
+
+/// Series of a metric by label values.
+pub struct Family<M> {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    init: M,
+    series: Mutex<BTreeMap<Vec<String>, M>>,
+}
+
+impl<M: Metric> Family<M> {
+    fn new(
+        name: &'static str,
+        help: &'static str,
+        labels: &'static [&'static str],
+        init: M,
+    ) -> Self {
+        let mut series = BTreeMap::new();
+        if labels.is_empty() {
+            // exposed as zero before the first sample
+            series.insert(vec![], init.clone());
+        }
+        Self {
+            name,
+            help,
+            labels,
+            init,
+            series: Mutex::new(series),
+        }
+    }
+
+    fn with<F: FnOnce(&mut M)>(&self, values: &[&str], f: F) {
+        debug_assert_eq!(values.len(), self.labels.len());
+        let mut series = self.series.lock();
+        let key: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        f(series.entry(key).or_insert_with(|| self.init.clone()));
+    }
+
+    fn encode(&self, out: &mut String) {
+        writeln!(out, "# HELP {} {}", self.name, self.help).unwrap();
+        writeln!(out, "# TYPE {} {}", self.name, M::TYPE).unwrap();
+        for (values, metric) in self.series.lock().iter() {
+            let labels: Vec<String> = self
+                .labels
+                .iter()
+                .zip(values.iter())
+                .map(|(label, value)| format!("{label}=\"{}\"", escape_label(value)))
+                .collect();
+            metric.encode(self.name, &labels.join(","), out);
+        }
+    }
+}
+
+impl Family<Counter> {
+    pub fn inc(&self, values: &[&str]) {
+        self.inc_by(values, 1.0)
+    }
+
+    pub fn inc_by(&self, values: &[&str], v: f64) {
+        self.with(values, |counter| counter.0 += v)
+    }
+}
+
+impl Family<Gauge> {
+    pub fn set(&self, values: &[&str], v: f64) {
+        self.with(values, |gauge| gauge.0 = v)
+    }
+}
+
+impl Family<Histogram> {
+    pub fn observe(&self, values: &[&str], v: f64) {
+        self.with(values, |histogram| histogram.observe(v))
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Counter(f64);
+
+impl Metric for Counter {
+    const TYPE: &'static str = "counter";
+
+    fn encode(&self, name: &str, labels: &str, out: &mut String) {
+        writeln!(out, "{name}{} {}", braced(labels), self.0).unwrap();
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Gauge(f64);
+
+impl Metric for Gauge {
+    const TYPE: &'static str = "gauge";
+
+    fn encode(&self, name: &str, labels: &str, out: &mut String) {
+        writeln!(out, "{name}{} {}", braced(labels), self.0).unwrap();
+    }
+}
+
+#[derive(Clone)]
+pub struct Histogram {
+    buckets: &'static [f64],
+    /// Observations by bucket, not cumulative
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, v: f64) {
+        if let Some(i) = self.buckets.iter().position(|bound| v <= *bound) {
+            self.counts[i] += 1;
+        }
+        self.sum += v;
+        self.count += 1;
+    }
+}
+
+impl Metric for Histogram {
+    const TYPE: &'static str = "histogram";
+
+    fn encode(&self, name: &str, labels: &str, out: &mut String) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, count) in self.buckets.iter().zip(self.counts.iter()) {
+            cumulative += count;
+            writeln!(
+                out,
+                "{name}_bucket{{{labels}{sep}le=\"{bound}\"}} {cumulative}"
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "{name}_bucket{{{labels}{sep}le=\"+Inf\"}} {}",
+            self.count
+        )
+        .unwrap();
+        writeln!(out, "{name}_sum{} {}", braced(labels), self.sum).unwrap();
+        writeln!(out, "{name}_count{} {}", braced(labels), self.count).unwrap();
+    }
+}
+
+fn braced(labels: &str) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{labels}}}")
+    }
+}
+
+/// Label of a proxied path, its endpoint route or `other`.
+pub fn endpoint_label(path: &str) -> &'static str {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    ENDPOINTS
+        .iter()
+        .find(|endpoint| {
+            let route: Vec<&str> = endpoint.split('/').filter(|s| !s.is_empty()).collect();
+            segments.len() >= route.len()
+                && segments[segments.len() - route.len()..]
+                    .iter()
+                    .zip(route.iter())
+                    .all(|(segment, route)| route.starts_with('{') || segment == route)
+        })
+        .copied()
+        .unwrap_or(OTHER)
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoint_labels() {
+        assert_eq!(endpoint_label("/chat/completions"), "/chat/completions");
+        assert_eq!(endpoint_label("/completions"), "/completions");
+        assert_eq!(
+            endpoint_label("/engines/my-gpt-4/chat/completions"),
+            "/chat/completions"
+        );
+        assert_eq!(endpoint_label("/models/gpt-4"), "/models/{model}");
+        assert_eq!(
+            endpoint_label("/files/file-1/content"),
+            "/files/{file_id}/content"
+        );
+        assert_eq!(endpoint_label("/x/random-1234"), OTHER);
+        assert_eq!(endpoint_label("/"), OTHER);
+    }
+
+    #[test]
+    fn model_labels_are_bounded() {
+        let metrics = Metrics::new();
+        for i in 0..MAX_MODELS {
+            let model = format!("model-{i}");
+            assert_eq!(metrics.model_label(&model), model);
+        }
+        assert_eq!(metrics.model_label("model-0"), "model-0");
+        assert_eq!(metrics.model_label("one-too-many"), OTHER);
+    }
+}
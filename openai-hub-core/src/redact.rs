@@ -0,0 +1,226 @@
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use thiserror::Error;
+
+pub const REDACTED: &str = "[REDACTED]";
+
+/// A simple JSON path like `messages[*].content` or `input[0]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonPath {
+    segments: Vec<Segment>,
+    raw: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+    Wildcard,
+}
+
+#[derive(Debug, Error)]
+#[error("invalid json path `{0}`")]
+pub struct JsonPathError(String);
+
+impl FromStr for JsonPath {
+    type Err = JsonPathError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || JsonPathError(s.to_string());
+        let mut segments = vec![];
+        let path = s.strip_prefix("$.").unwrap_or(s);
+        for part in path.split('.') {
+            let (key, mut rest) = match part.find('[') {
+                Some(i) => part.split_at(i),
+                None => (part, ""),
+            };
+            if key.is_empty() && rest.is_empty() {
+                return Err(err());
+            }
+            if !key.is_empty() {
+                segments.push(Segment::Key(key.to_string()));
+            }
+            while !rest.is_empty() {
+                let end = rest.find(']').ok_or_else(err)?;
+                let index = &rest[1..end];
+                segments.push(match index {
+                    "*" => Segment::Wildcard,
+                    _ => Segment::Index(index.parse().map_err(|_| err())?),
+                });
+                rest = &rest[end + 1..];
+                if !rest.is_empty() && !rest.starts_with('[') {
+                    return Err(err());
+                }
+            }
+        }
+        if segments.is_empty() {
+            return Err(err());
+        }
+        Ok(Self {
+            segments,
+            raw: s.to_string(),
+        })
+    }
+}
+
+impl Display for JsonPath {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.raw)
+    }
+}
+
+impl<'de> Deserialize<'de> for JsonPath {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl JsonPath {
+    /// Replace every value matched by this path with `replacement`.
+    pub fn redact(&self, value: &mut Value, replacement: &Value) -> bool {
+        redact_segments(value, &self.segments, replacement)
+    }
+}
+
+fn redact_segments(value: &mut Value, segments: &[Segment], replacement: &Value) -> bool {
+    let Some((first, rest)) = segments.split_first() else {
+        *value = replacement.clone();
+        return true;
+    };
+    match (first, value) {
+        (Segment::Key(key), Value::Object(map)) => map
+            .get_mut(key)
+            .map(|v| redact_segments(v, rest, replacement))
+            .unwrap_or(false),
+        (Segment::Index(i), Value::Array(items)) => items
+            .get_mut(*i)
+            .map(|v| redact_segments(v, rest, replacement))
+            .unwrap_or(false),
+        (Segment::Wildcard, Value::Array(items)) => {
+            let mut hit = false;
+            for v in items.iter_mut() {
+                hit |= redact_segments(v, rest, replacement);
+            }
+            hit
+        }
+        (Segment::Wildcard, Value::Object(map)) => {
+            let mut hit = false;
+            for v in map.values_mut() {
+                hit |= redact_segments(v, rest, replacement);
+            }
+            hit
+        }
+        _ => false,
+    }
+}
+
+/// Redact the given paths of a JSON body, or of every JSON event of a server-sent events
+/// body. Any other body is replaced as a whole, the paths cannot be told apart in it.
+pub fn redact_json_body(body: Vec<u8>, paths: &[JsonPath]) -> Vec<u8> {
+    if paths.is_empty() || body.is_empty() {
+        return body;
+    }
+    let replacement = Value::String(REDACTED.to_string());
+    let Ok(mut json) = serde_json::from_slice::<Value>(&body) else {
+        return redact_event_stream(&body, paths, &replacement)
+            .unwrap_or_else(|| REDACTED.as_bytes().to_vec());
+    };
+    let mut hit = false;
+    for path in paths {
+        hit |= path.redact(&mut json, &replacement);
+    }
+    if !hit {
+        return body;
+    }
+    serde_json::to_vec(&json).unwrap_or(body)
+}
+
+/// Redact the `data:` lines holding JSON, `None` if there is none.
+fn redact_event_stream(body: &[u8], paths: &[JsonPath], replacement: &Value) -> Option<Vec<u8>> {
+    let mut redacted = Vec::with_capacity(body.len());
+    let mut events = false;
+    for line in body.split_inclusive(|b| *b == b'\n') {
+        let content = line.strip_suffix(b"\n").unwrap_or(line);
+        let content = content.strip_suffix(b"\r").unwrap_or(content);
+        let data = content
+            .strip_prefix(b"data:")
+            .map(|data| data.strip_prefix(b" ").unwrap_or(data));
+        if let Some(mut json) = data.and_then(|data| serde_json::from_slice::<Value>(data).ok()) {
+            events = true;
+            let mut hit = false;
+            for path in paths {
+                hit |= path.redact(&mut json, replacement);
+            }
+            if hit {
+                redacted.extend_from_slice(b"data: ");
+                serde_json::to_writer(&mut redacted, &json).ok()?;
+                redacted.extend_from_slice(&line[content.len()..]);
+                continue;
+            }
+        }
+        redacted.extend_from_slice(line);
+    }
+    events.then_some(redacted)
+}
+
+/// Replace the values of the given headers (case insensitive).
+pub fn redact_headers(headers: &mut BTreeMap<String, String>, names: &HashSet<String>) {
+    for (name, value) in headers.iter_mut() {
+        if names.iter().any(|n| n.eq_ignore_ascii_case(name)) {
+            *value = REDACTED.to_string();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(paths: &[&str]) -> Vec<JsonPath> {
+        paths.iter().map(|path| path.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn redact_json() {
+        let body = br#"{"messages":[{"role":"user","content":"secret"}]}"#.to_vec();
+        let redacted = redact_json_body(body, &paths(&["messages[*].content"]));
+        assert_eq!(
+            redacted,
+            br#"{"messages":[{"content":"[REDACTED]","role":"user"}]}"#
+        );
+    }
+
+    #[test]
+    fn redact_event_stream() {
+        let body = b"data: {\"choices\":[{\"delta\":{\"content\":\"secret\"}}]}\r\n\r\ndata: {\"choices\":[]}\n\ndata: [DONE]\n\n".to_vec();
+        let redacted = redact_json_body(body, &paths(&["choices[*].delta.content"]));
+        assert_eq!(
+            String::from_utf8(redacted).unwrap(),
+            "data: {\"choices\":[{\"delta\":{\"content\":\"[REDACTED]\"}}]}\r\n\r\ndata: {\"choices\":[]}\n\ndata: [DONE]\n\n"
+        );
+    }
+
+    #[test]
+    fn untouched_without_match() {
+        let body = b"data: {\"choices\":[]}\n\nnot json".to_vec();
+        assert_eq!(
+            redact_json_body(body.clone(), &paths(&["choices[*].delta.content"])),
+            body
+        );
+    }
+
+    #[test]
+    fn replaced_unless_json() {
+        let body = b"model=whisper-1&prompt=secret".to_vec();
+        assert_eq!(
+            redact_json_body(body.clone(), &paths(&["prompt"])),
+            REDACTED.as_bytes()
+        );
+        assert_eq!(redact_json_body(body.clone(), &[]), body);
+        assert!(redact_json_body(vec![], &paths(&["prompt"])).is_empty());
+    }
+}
@@ -0,0 +1,347 @@
+use crate::config::{ClientSubject, TlsConfig};
+use parking_lot::RwLock;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient,
+};
+use tokio_rustls::rustls::{self, Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::{event, Level};
+use x509_parser::extensions::GeneralName;
+
+/// Time a client is given to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Subject of the verified client certificate, inserted into the request extensions
+#[derive(Debug, Clone)]
+pub struct ClientCertSubject(pub Arc<str>);
+
+#[derive(Debug, thiserror::Error)]
+pub enum TlsError {
+    #[error("failed to read {0}: {1}")]
+    Io(String, io::Error),
+    #[error("no certificate found in {0}")]
+    NoCertificate(String),
+    #[error("no private key found in {0}")]
+    NoPrivateKey(String),
+    #[error("invalid CA certificate in {0}: {1}")]
+    InvalidCa(String, String),
+    #[error(transparent)]
+    Rustls(#[from] rustls::Error),
+}
+
+/// Terminates TLS on the TCP listeners.
+///
+/// The certificate, key and client CA bundle are checked for changes periodically and
+/// reloaded, connections already established keep the configuration they started with.
+pub struct TlsServer {
+    config: Arc<TlsConfig>,
+    server_config: Arc<RwLock<Arc<ServerConfig>>>,
+}
+
+impl TlsServer {
+    pub fn load(config: TlsConfig) -> Result<Self, TlsError> {
+        let server_config = Arc::new(RwLock::new(Arc::new(server_config(&config)?)));
+        let config = Arc::new(config);
+        spawn_reload(config.clone(), server_config.clone());
+        Ok(Self {
+            config,
+            server_config,
+        })
+    }
+
+    /// Read the certificate, key and client CA bundle without serving them.
+    pub fn check(config: &TlsConfig) -> Result<(), TlsError> {
+        server_config(config).map(|_| ())
+    }
+
+    /// Complete the handshake with a client, returning the subject of its certificate if
+    /// it presented one.
+    pub async fn accept(
+        &self,
+        stream: TcpStream,
+    ) -> io::Result<(TlsStream<TcpStream>, Option<ClientCertSubject>)> {
+        let acceptor = TlsAcceptor::from(self.server_config.read().clone());
+        let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))??;
+        let subject = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| subject_of(cert, self.config.client_subject))
+            .map(|subject| ClientCertSubject(subject.into()));
+        Ok((stream, subject))
+    }
+}
+
+fn spawn_reload(config: Arc<TlsConfig>, server_config: Arc<RwLock<Arc<ServerConfig>>>) {
+    tokio::spawn(async move {
+        let mut modified = modified_times(&config);
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(config.reload_interval.max(1)));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let current = modified_times(&config);
+            if current == modified {
+                continue;
+            }
+            modified = current;
+
+            // the files are small, reading them does not hold up the runtime
+            match self::server_config(&config) {
+                Ok(reloaded) => {
+                    *server_config.write() = Arc::new(reloaded);
+                    event!(Level::INFO, "reloaded TLS certificate");
+                }
+                Err(e) => {
+                    event!(Level::ERROR, error = %e, "failed to reload TLS certificate");
+                }
+            }
+        }
+    });
+}
+
+fn modified_times(config: &TlsConfig) -> Vec<Option<SystemTime>> {
+    [
+        Some(&config.cert),
+        Some(&config.key),
+        config.client_ca.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|path| {
+        std::fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .ok()
+    })
+    .collect()
+}
+
+fn server_config(config: &TlsConfig) -> Result<ServerConfig, TlsError> {
+    let certs = read_certs(&config.cert)?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate(config.cert.display().to_string()));
+    }
+    let key = read_key(&config.key)?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match config.client_ca {
+        Some(ref path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(path)? {
+                roots
+                    .add(&cert)
+                    .map_err(|e| TlsError::InvalidCa(path.display().to_string(), e.to_string()))?;
+            }
+            if roots.is_empty() {
+                return Err(TlsError::NoCertificate(path.display().to_string()));
+            }
+            let verifier = if config.client_cert_required {
+                AllowAnyAuthenticatedClient::new(roots).boxed()
+            } else {
+                AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed()
+            };
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut server_config = builder.with_single_cert(certs, key)?;
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(server_config)
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, TlsError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| TlsError::Io(path.display().to_string(), e))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>, TlsError> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .map_err(|e| TlsError::Io(path.display().to_string(), e))?;
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey, TlsError> {
+    let mut reader = open(path)?;
+    loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .map_err(|e| TlsError::Io(path.display().to_string(), e))?
+        {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(TlsError::NoPrivateKey(path.display().to_string())),
+        }
+    }
+}
+
+/// Name of the certificate taken as the subject of the client.
+fn subject_of(cert: &Certificate, client_subject: ClientSubject) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+    match client_subject {
+        ClientSubject::CommonName => cert
+            .subject()
+            .iter_common_name()
+            .next()?
+            .as_str()
+            .ok()
+            .map(ToString::to_string),
+        ClientSubject::SubjectAltName => cert
+            .subject_alternative_name()
+            .ok()??
+            .value
+            .general_names
+            .iter()
+            .find_map(|name| match name {
+                GeneralName::DNSName(name)
+                | GeneralName::URI(name)
+                | GeneralName::RFC822Name(name) => Some(name.to_string()),
+                _ => None,
+            }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::{ClientConfig, ServerName};
+    use tokio_rustls::TlsConnector;
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/tls")
+            .join(name)
+    }
+
+    fn config(client_cert_required: bool) -> TlsConfig {
+        TlsConfig {
+            cert: testdata("server.pem"),
+            key: testdata("server.key"),
+            client_ca: Some(testdata("ca.pem")),
+            client_cert_required,
+            client_subject: ClientSubject::CommonName,
+            reload_interval: 1,
+        }
+    }
+
+    fn connector(client_cert: bool) -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        for cert in read_certs(&testdata("ca.pem")).unwrap() {
+            roots.add(&cert).unwrap();
+        }
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let config = if client_cert {
+            builder
+                .with_client_auth_cert(
+                    read_certs(&testdata("client.pem")).unwrap(),
+                    read_key(&testdata("client.key")).unwrap(),
+                )
+                .unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+        TlsConnector::from(Arc::new(config))
+    }
+
+    /// Handshake with the server, returning what it accepted.
+    async fn handshake(
+        server: &TlsServer,
+        client_cert: bool,
+    ) -> io::Result<Option<ClientCertSubject>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(addr).await?;
+            let mut stream = connector(client_cert)
+                .connect(ServerName::try_from("localhost").unwrap(), stream)
+                .await?;
+            // wait for the server to hang up
+            let mut buf = Vec::new();
+            let _ = stream.read_to_end(&mut buf).await;
+            io::Result::Ok(())
+        });
+        let (stream, _) = listener.accept().await.unwrap();
+        let subject = server.accept(stream).await.map(|(_, subject)| subject);
+        let _ = client.await;
+        subject
+    }
+
+    #[test]
+    fn subject_from_common_name_and_alt_name() {
+        let cert = &read_certs(&testdata("client.pem")).unwrap()[0];
+        assert_eq!(
+            subject_of(cert, ClientSubject::CommonName).as_deref(),
+            Some("alice")
+        );
+        assert_eq!(
+            subject_of(cert, ClientSubject::SubjectAltName).as_deref(),
+            Some("alice@example.com")
+        );
+        // the server certificate has a DNS name
+        let cert = &read_certs(&testdata("server.pem")).unwrap()[0];
+        assert_eq!(
+            subject_of(cert, ClientSubject::SubjectAltName).as_deref(),
+            Some("localhost")
+        );
+    }
+
+    #[tokio::test]
+    async fn required_client_cert() {
+        let server = TlsServer::load(config(true)).unwrap();
+        let subject = handshake(&server, true).await.unwrap();
+        assert_eq!(subject.unwrap().0.as_ref(), "alice");
+        assert!(handshake(&server, false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn optional_client_cert() {
+        let server = TlsServer::load(config(false)).unwrap();
+        let subject = handshake(&server, true).await.unwrap();
+        assert_eq!(subject.unwrap().0.as_ref(), "alice");
+        assert!(handshake(&server, false).await.unwrap().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reloaded_when_modified() {
+        let dir = std::env::temp_dir().join(format!("openai-hub-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut config = config(true);
+        for path in [&mut config.cert, &mut config.key] {
+            let copy = dir.join(path.file_name().unwrap());
+            std::fs::copy(&*path, &copy).unwrap();
+            *path = copy;
+        }
+        let server = TlsServer::load(config.clone()).unwrap();
+        let loaded = server.server_config.read().clone();
+
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert!(Arc::ptr_eq(&loaded, &server.server_config.read()));
+
+        File::options()
+            .append(true)
+            .open(&config.cert)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert!(!Arc::ptr_eq(&loaded, &server.server_config.read()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
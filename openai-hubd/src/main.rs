@@ -1,12 +1,23 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use openai_hub_core::config::ServerConfig;
-use openai_hub_core::Server;
+use openai_hub_core::{check_config, Diagnostic, Server, Severity};
+use std::fmt::Display;
 use std::fs::read_to_string;
-use std::path::PathBuf;
-use tracing_subscriber::EnvFilter;
+use std::io;
+#[cfg(feature = "acl")]
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+#[cfg(feature = "otel")]
+use openai_hub_core::config::OtelConfig;
+#[cfg(feature = "otel")]
+use tracing_subscriber::registry::LookupSpan;
 
 #[cfg(feature = "acl")]
-use openai_hub_core::ApiAcl;
+use openai_hub_core::{check_acl, ApiAcl};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -16,32 +27,276 @@ struct Cli {
     #[cfg(feature = "acl")]
     #[arg(short, long, value_name = "FILE")]
     acl: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            EnvFilter::builder()
-                .with_default_directive("openai_hub_core=debug".parse().unwrap())
-                .from_env_lossy(),
+#[derive(Subcommand)]
+enum Command {
+    /// Inspect the ACL rules
+    #[cfg(feature = "acl")]
+    Acl {
+        #[command(subcommand)]
+        command: AclCommand,
+    },
+    /// Validate the configuration and ACL files, and connect to the audit database
+    Check,
+}
+
+#[cfg(feature = "acl")]
+#[derive(Subcommand)]
+enum AclCommand {
+    /// Explain how a request would be handled by the ACL
+    Check {
+        #[arg(short, long, default_value = "POST")]
+        method: String,
+        #[arg(short, long)]
+        path: String,
+        /// JSON request body
+        #[arg(short, long, value_name = "FILE")]
+        body: Option<PathBuf>,
+        /// Authenticated subject of the request
+        #[arg(short, long, value_name = "SUB")]
+        sub: Option<String>,
+        /// Client address of the request
+        #[arg(short, long, value_name = "IP")]
+        ip: Option<IpAddr>,
+    },
+}
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Log to stdout, and export spans over OTLP when configured.
+fn init_tracing(#[cfg(feature = "otel")] otel: Option<&OtelConfig>) -> Result<(), Error> {
+    let fmt_layer = tracing_subscriber::fmt::layer().with_filter(
+        EnvFilter::builder()
+            .with_default_directive("openai_hub_core=debug".parse().unwrap())
+            .from_env_lossy(),
+    );
+    let registry = tracing_subscriber::registry().with(fmt_layer);
+
+    #[cfg(feature = "otel")]
+    let registry = registry.with(match otel {
+        Some(otel) => Some(otel_layer(otel)?),
+        None => None,
+    });
+
+    registry.init();
+    Ok(())
+}
+
+#[cfg(feature = "otel")]
+fn otel_layer<S>(otel: &OtelConfig) -> Result<impl Layer<S>, Error>
+where
+    S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+{
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::trace::{self, Sampler};
+    use opentelemetry_sdk::{runtime, Resource};
+    use tracing_subscriber::filter::{LevelFilter, Targets};
+
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(&otel.endpoint),
+        )
+        .with_trace_config(
+            trace::config()
+                .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                    otel.sample_ratio,
+                ))))
+                .with_resource(Resource::new([KeyValue::new(
+                    "service.name",
+                    otel.service_name.clone(),
+                )])),
         )
-        .init();
+        .install_batch(runtime::Tokio)?;
+    // spans of the hub only, independent of the log level
+    Ok(tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(Targets::new().with_target("openai_hub_core", LevelFilter::INFO)))
+}
 
+#[tokio::main]
+async fn main() -> Result<(), Error> {
     let cli = Cli::parse();
+
+    if let Some(Command::Check) = cli.command {
+        if !check(cli).await {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    #[cfg(feature = "acl")]
+    if let Some(Command::Acl { command }) = cli.command {
+        init_tracing(
+            #[cfg(feature = "otel")]
+            None,
+        )?;
+        let acl_path = cli.acl.unwrap_or_else(|| PathBuf::from("acl.toml"));
+        let acl = ApiAcl::load(&read_to_string(acl_path)?)?;
+        match command {
+            AclCommand::Check {
+                method,
+                path,
+                body,
+                sub,
+                ip,
+            } => {
+                let body = match body {
+                    Some(body) => Some(serde_json::from_str(&read_to_string(body)?)?),
+                    None => None,
+                };
+                let explanation =
+                    acl.explain(&method.parse()?, &path, body.as_ref(), sub.as_deref(), ip);
+                println!("{}", explanation);
+                if !explanation.allowed {
+                    std::process::exit(1);
+                }
+            }
+        }
+        return Ok(());
+    }
+
     let config_path = cli.config.unwrap_or_else(|| PathBuf::from("config.toml"));
 
+    let config = read_to_string(&config_path)
+        .unwrap_or_else(|e| fail(format!("failed to read {}: {e}", config_path.display())));
     #[allow(unused_mut)]
-    let mut config = ServerConfig::load(&read_to_string(config_path).unwrap())?;
+    let mut config =
+        ServerConfig::load(&config).unwrap_or_else(|e| fail(format!("invalid configuration: {e}")));
+    init_tracing(
+        #[cfg(feature = "otel")]
+        config.otel.as_ref(),
+    )?;
 
     #[cfg(feature = "acl")]
-    {
-        let acl_path = cli.acl.unwrap_or_else(|| PathBuf::from("acl.toml"));
-        if let Ok(acl) = read_to_string(acl_path) {
-            config.set_global_api_acl(ApiAcl::load(&acl)?);
+    match read_acl(cli.acl) {
+        Ok(Some((_, acl))) => {
+            let acl = ApiAcl::load(&acl).unwrap_or_else(|e| fail(format!("invalid ACL: {e}")));
+            config.set_global_api_acl(acl);
         }
+        Ok(None) => {}
+        Err((path, e)) => fail(format!("failed to read {}: {e}", path.display())),
     }
 
-    Server::from_config(config).serve().await?;
+    Server::from_config(config)
+        .serve_with_shutdown(shutdown_signal())
+        .await?;
+
+    // export the spans still batched
+    #[cfg(feature = "otel")]
+    tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider).await?;
     Ok(())
 }
+
+fn fail(message: impl Display) -> ! {
+    eprintln!("{message}");
+    std::process::exit(1)
+}
+
+/// Read the ACL file, optional unless given on the command line.
+#[cfg(feature = "acl")]
+fn read_acl(path: Option<PathBuf>) -> Result<Option<(PathBuf, String)>, (PathBuf, io::Error)> {
+    let required = path.is_some();
+    let path = path.unwrap_or_else(|| PathBuf::from("acl.toml"));
+    match read_to_string(&path) {
+        Ok(acl) => Ok(Some((path, acl))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound && !required => Ok(None),
+        Err(e) => Err((path, e)),
+    }
+}
+
+/// Check the configuration files, printing what is wrong with them. Returns whether they
+/// are free of errors.
+async fn check(cli: Cli) -> bool {
+    #[cfg(feature = "acl")]
+    let acl = read_acl(cli.acl);
+    let config_path = cli.config.unwrap_or_else(|| PathBuf::from("config.toml"));
+    #[allow(unused_mut)]
+    let mut valid = match read_to_string(&config_path) {
+        Ok(config) => {
+            #[cfg(feature = "acl")]
+            let acl = match acl {
+                Ok(Some((_, ref acl))) => Some(acl.as_str()),
+                _ => None,
+            };
+            let diagnostics = check_config(
+                &config,
+                #[cfg(feature = "acl")]
+                acl,
+            )
+            .await;
+            report(&config_path, &diagnostics)
+        }
+        Err(e) => report(&config_path, &[read_error(e)]),
+    };
+
+    #[cfg(feature = "acl")]
+    match acl {
+        Ok(Some((path, acl))) => valid &= report(&path, &check_acl(&acl)),
+        Ok(None) => {}
+        Err((path, e)) => valid &= report(&path, &[read_error(e)]),
+    }
+    valid
+}
+
+fn read_error(e: io::Error) -> Diagnostic {
+    Diagnostic {
+        severity: Severity::Error,
+        line: None,
+        message: format!("failed to read: {e}"),
+    }
+}
+
+/// Print the diagnostics of a file, returning whether none of them is an error.
+fn report(path: &Path, diagnostics: &[Diagnostic]) -> bool {
+    if diagnostics.is_empty() {
+        println!("{}: ok", path.display());
+    }
+    for diagnostic in diagnostics {
+        match diagnostic.line {
+            Some(line) => print!("{}:{}", path.display(), line),
+            None => print!("{}", path.display()),
+        }
+        println!(": {}: {}", diagnostic.severity, diagnostic.message);
+    }
+    diagnostics
+        .iter()
+        .all(|diagnostic| diagnostic.severity != Severity::Error)
+}
+
+/// Completes on SIGINT, or SIGTERM on unix.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            eprintln!("failed to listen for SIGINT: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                eprintln!("failed to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
+}
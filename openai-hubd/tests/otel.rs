@@ -0,0 +1,179 @@
+//! Trace context propagation, against stand-ins of the upstream API and of an OTLP collector.
+#![cfg(all(feature = "otel", unix))]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+const PARENT_ID: &str = "00f067aa0ba902b7";
+
+/// A request received by a stand-in server.
+struct Received {
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Received {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Serve every request with `response`, sending what was received.
+fn stand_in(response: &'static str) -> (u16, Receiver<Received>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else { continue };
+            let tx = tx.clone();
+            thread::spawn(move || {
+                while let Some(received) = read_request(&stream) {
+                    let reply = format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{response}",
+                        response.len()
+                    );
+                    if (&stream).write_all(reply.as_bytes()).is_err() || tx.send(received).is_err()
+                    {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    (port, rx)
+}
+
+fn read_request(stream: &TcpStream) -> Option<Received> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok().filter(|n| *n > 0)?;
+    let path = line.split_whitespace().nth(1)?.to_string();
+    let mut headers = vec![];
+    loop {
+        line.clear();
+        reader.read_line(&mut line).ok()?;
+        let Some((name, value)) = line.trim_end().split_once(':') else {
+            break;
+        };
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+    let length = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+    Some(Received {
+        path,
+        headers,
+        body,
+    })
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+struct Hub(Child);
+
+impl Drop for Hub {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+    }
+}
+
+fn hex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+#[test]
+fn propagates_trace_context() {
+    let (upstream, upstream_rx) = stand_in(
+        r#"{"id":"chatcmpl-1","object":"chat.completion","model":"gpt-4","choices":[],"usage":{"prompt_tokens":1,"completion_tokens":1,"total_tokens":2}}"#,
+    );
+    let (collector, collector_rx) = stand_in("");
+    let port = free_port();
+
+    let dir = std::env::temp_dir().join(format!("openai-hubd-otel-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("config.toml"),
+        format!(
+            "bind = \"127.0.0.1:{port}\"\napi_keys = [\"sk-test\"]\napi_base = \"http://127.0.0.1:{upstream}/v1\"\n\n[otel]\nendpoint = \"http://127.0.0.1:{collector}\"\n"
+        ),
+    )
+    .unwrap();
+    let mut hub = Hub(Command::new(env!("CARGO_BIN_EXE_openai-hubd"))
+        .current_dir(&dir)
+        .env("RUST_LOG", "off")
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap());
+
+    let started = Instant::now();
+    let mut client = loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(stream) => break stream,
+            Err(_) if started.elapsed() < Duration::from_secs(10) => {
+                thread::sleep(Duration::from_millis(50))
+            }
+            Err(e) => panic!("hub did not start: {e}"),
+        }
+    };
+    let body = r#"{"model":"gpt-4","messages":[{"role":"user","content":"hi"}]}"#;
+    write!(
+        client,
+        "POST /chat/completions HTTP/1.1\r\nhost: localhost\r\ncontent-type: application/json\r\ntraceparent: 00-{TRACE_ID}-{PARENT_ID}-01\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+        body.len()
+    )
+    .unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+
+    let received = upstream_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(received.path, "/v1/chat/completions");
+    let traceparent = received
+        .header("traceparent")
+        .expect("traceparent upstream");
+    let parts: Vec<&str> = traceparent.split('-').collect();
+    assert_eq!(parts[1], TRACE_ID, "{traceparent}");
+    assert_ne!(parts[2], PARENT_ID, "upstream request is a child span");
+
+    // spans are exported in batches, at the latest when the hub shuts down
+    Command::new("kill")
+        .args(["-TERM", &hub.0.id().to_string()])
+        .status()
+        .unwrap();
+    let exported = collector_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(exported.path, "/v1/traces");
+    assert_eq!(
+        exported.header("content-type"),
+        Some("application/x-protobuf")
+    );
+    let trace_id = hex(TRACE_ID);
+    let contains = |bytes: &[u8]| exported.body.windows(bytes.len()).any(|w| w == bytes);
+    assert!(contains(&trace_id));
+    // recorded by the layer inside the auth layer
+    assert!(contains(b"gpt-4"));
+    assert!(hub.0.wait().unwrap().success());
+    std::fs::remove_dir_all(&dir).unwrap();
+}